@@ -1,7 +1,6 @@
 use crate::{
-    codegen::{BcArr, Program},
+    codegen::{BcArr, Instr, Program, Value},
     vm::Interpreter,
-    Value, Instr,
 };
 
 use rustc_hash::FxHashMap;
@@ -15,12 +14,12 @@ pub enum MemVal {
 
 #[derive(Clone, Debug, Default)]
 struct Interval {
-    bottom: usize,
-    top: usize
+    bottom: i64,
+    top: i64
 }
 
 impl Interval {
-    pub fn new(bottom: usize, top: usize) -> Self {
+    pub fn new(bottom: i64, top: i64) -> Self {
         Self {
             bottom,
             top,
@@ -123,7 +122,7 @@ impl AbstractInterpreter {
         let v = self.fetch_val();
 
         //let register_index = Reg(Interpreter::unpack_register(reg));
-        //let val = Interpreter::unpack_number(&Interpreter::unpack_value(v)) as usize;
+        //let val = Interpreter::unpack_number(&Interpreter::unpack_value(v)) as i64;
 
         //self.add_new_reg_var(register_index, Interval::new(val, val));
     }
@@ -1,4 +1,4 @@
-use crate::tokens::{Token};
+use crate::tokens::{Token, TokenType};
 
 #[derive(Clone, Debug)]
 pub enum Expr {
@@ -18,9 +18,33 @@ pub enum Expr {
     Grouping {
         expr: Box<Expr>,
     },
+    Array {
+        elements: Vec<Expr>,
+    },
+    Object {
+        entries: Vec<(String, Expr)>,
+    },
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
+    IndexAssignment {
+        target: Box<Expr>,
+        index: Box<Expr>,
+        expr: Box<Expr>,
+    },
+    Property {
+        target: Box<Expr>,
+        name: Token,
+    },
     Literal {
         literal: Literal,
     },
+    Ternary {
+        cond: Box<Expr>,
+        then_e: Box<Expr>,
+        else_e: Box<Expr>,
+    },
     Logical {
         l_expr: Box<Expr>,
         operator: LogicalOp,
@@ -33,6 +57,10 @@ pub enum Expr {
     Variable {
         name: Token,
     },
+    Function {
+        params: Vec<Token>,
+        body: Vec<Stmt>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +72,7 @@ pub enum LogicalOp {
 #[derive(Debug, Clone)]
 pub enum Literal {
     Number(f64),
+    Int(i64),
     StringLiteral(String),
     True,
     False,
@@ -59,5 +88,582 @@ pub enum Stmt {
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
     Return(Option<Expr>),
     While(Expr, Box<Stmt>),
-    Print(Expr),
+    DoWhile(Box<Stmt>, Expr),
+    Print(Vec<Expr>),
+    Import(Token),
+    Break(Token),
+    Continue(Token),
+    ForOf {
+        name: Token,
+        iterable: Expr,
+        body: Box<Stmt>,
+    },
+    /// C-style for loop, a missing condition is parsed as `true`
+    For {
+        init: Option<Box<Stmt>>,
+        cond: Expr,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+}
+
+/// Call to `main` that starts a program without any top-level code, `None` if
+/// the program has top-level code or does not declare `main`. Top-level code
+/// takes precedence, next to it `main` is an ordinary function that only runs
+/// when it is called
+pub fn implicit_main(stmts: &[Stmt]) -> Option<Stmt> {
+    if stmts.iter().any(|s| !matches!(s, Stmt::Function(..) | Stmt::Import(_))) {
+        return None;
+    }
+    stmts.iter().find_map(|s| match s {
+        Stmt::Function(name, _, _) if name.value == "main" => Some(Stmt::Expression(Expr::Call {
+            callee: Box::new(Expr::Variable { name: name.clone() }),
+            arguments: Vec::new(),
+        })),
+        _ => None,
+    })
+}
+
+/// Serialize syntax tree nodes as JSON so editors and other tools can consume
+/// the parse tree. Nodes are objects tagged with their variant name in
+/// `"type"`, tokens keep their value and position
+pub trait ToJson {
+    fn to_json(&self) -> String;
+}
+
+/// JSON object with the variant name `ty` followed by `fields`
+fn node(ty: &str, fields: &[(&str, String)]) -> String {
+    let mut out = format!("{{\"type\":{}", json_string(ty));
+    for (name, value) in fields {
+        out.push_str(&format!(",{}:{}", json_string(name), value));
+    }
+    out.push('}');
+    out
+}
+
+/// Quote and escape `s` as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> String {
+        let items: Vec<String> = self.iter().map(ToJson::to_json).collect();
+        format!("[{}]", items.join(","))
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for Box<T> {
+    fn to_json(&self) -> String {
+        (**self).to_json()
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> String {
+        self.as_ref().map_or_else(|| "null".to_string(), ToJson::to_json)
+    }
+}
+
+impl ToJson for Token {
+    fn to_json(&self) -> String {
+        format!("{{\"value\":{},\"line\":{},\"col\":{}}}",
+                json_string(&self.value), self.line_num, self.col)
+    }
+}
+
+impl ToJson for Literal {
+    /// Integers and floats are both numbers, floats always keep a fraction
+    /// so they can be told apart
+    fn to_json(&self) -> String {
+        match self {
+            Literal::Number(n) => node("Number", &[("value", format!("{:?}", n))]),
+            Literal::Int(i) => node("Number", &[("value", i.to_string())]),
+            Literal::StringLiteral(s) => node("String", &[("value", json_string(s))]),
+            Literal::True => node("Bool", &[("value", "true".to_string())]),
+            Literal::False => node("Bool", &[("value", "false".to_string())]),
+            Literal::Nil => node("Nil", &[]),
+        }
+    }
+}
+
+impl ToJson for Expr {
+    fn to_json(&self) -> String {
+        match self {
+            Expr::Assignment { name, expr } => {
+                node("Assignment", &[("name", name.to_json()), ("expr", expr.to_json())])
+            }
+            Expr::Binary { left, op, right } => node("Binary", &[
+                ("left", left.to_json()),
+                ("op", op.to_json()),
+                ("right", right.to_json()),
+            ]),
+            Expr::Call { callee, arguments } => node("Call", &[
+                ("callee", callee.to_json()),
+                ("arguments", arguments.to_json()),
+            ]),
+            Expr::Grouping { expr } => node("Grouping", &[("expr", expr.to_json())]),
+            Expr::Array { elements } => node("Array", &[("elements", elements.to_json())]),
+            Expr::Object { entries } => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{{\"key\":{},\"value\":{}}}", json_string(k),
+                                          v.to_json()))
+                    .collect();
+                node("Object", &[("entries", format!("[{}]", entries.join(",")))])
+            }
+            Expr::Index { target, index } => node("Index", &[
+                ("target", target.to_json()),
+                ("index", index.to_json()),
+            ]),
+            Expr::IndexAssignment { target, index, expr } => node("IndexAssignment", &[
+                ("target", target.to_json()),
+                ("index", index.to_json()),
+                ("expr", expr.to_json()),
+            ]),
+            Expr::Property { target, name } => node("Property", &[
+                ("target", target.to_json()),
+                ("name", name.to_json()),
+            ]),
+            Expr::Literal { literal } => node("Literal", &[("literal", literal.to_json())]),
+            Expr::Ternary { cond, then_e, else_e } => node("Ternary", &[
+                ("cond", cond.to_json()),
+                ("then_e", then_e.to_json()),
+                ("else_e", else_e.to_json()),
+            ]),
+            Expr::Logical { l_expr, operator, r_expr } => node("Logical", &[
+                ("l_expr", l_expr.to_json()),
+                ("operator", json_string(&format!("{:?}", operator))),
+                ("r_expr", r_expr.to_json()),
+            ]),
+            Expr::Unary { op, right } => node("Unary", &[
+                ("op", op.to_json()),
+                ("right", right.to_json()),
+            ]),
+            Expr::Variable { name } => node("Variable", &[("name", name.to_json())]),
+            Expr::Function { params, body } => node("Function", &[
+                ("params", params.to_json()),
+                ("body", body.to_json()),
+            ]),
+        }
+    }
+}
+
+impl ToJson for Stmt {
+    fn to_json(&self) -> String {
+        match self {
+            Stmt::Expression(expr) => node("Expression", &[("expr", expr.to_json())]),
+            Stmt::Variable(name, init) => node("Variable", &[
+                ("name", name.to_json()),
+                ("init", init.to_json()),
+            ]),
+            Stmt::Block(body) => node("Block", &[("body", body.to_json())]),
+            Stmt::Function(name, params, body) => node("Function", &[
+                ("name", name.to_json()),
+                ("params", params.to_json()),
+                ("body", body.to_json()),
+            ]),
+            Stmt::If(cond, then_branch, else_branch) => node("If", &[
+                ("cond", cond.to_json()),
+                ("then", then_branch.to_json()),
+                ("else", else_branch.to_json()),
+            ]),
+            Stmt::Return(value) => node("Return", &[("value", value.to_json())]),
+            Stmt::While(cond, body) => node("While", &[
+                ("cond", cond.to_json()),
+                ("body", body.to_json()),
+            ]),
+            Stmt::DoWhile(body, cond) => node("DoWhile", &[
+                ("body", body.to_json()),
+                ("cond", cond.to_json()),
+            ]),
+            Stmt::Print(args) => node("Print", &[("args", args.to_json())]),
+            Stmt::Import(path) => node("Import", &[("path", path.to_json())]),
+            Stmt::Break(token) => node("Break", &[("token", token.to_json())]),
+            Stmt::Continue(token) => node("Continue", &[("token", token.to_json())]),
+            Stmt::ForOf { name, iterable, body } => node("ForOf", &[
+                ("name", name.to_json()),
+                ("iterable", iterable.to_json()),
+                ("body", body.to_json()),
+            ]),
+            Stmt::For { init, cond, increment, body } => node("For", &[
+                ("init", init.to_json()),
+                ("cond", cond.to_json()),
+                ("increment", increment.to_json()),
+                ("body", body.to_json()),
+            ]),
+        }
+    }
+}
+
+/// Render syntax tree nodes back into source code that parses into the same
+/// tree, eg. to check what the parser made of a program
+pub trait ToSource {
+    fn to_source(&self) -> String {
+        self.source(0)
+    }
+
+    /// Source with nested blocks indented by `indent` levels
+    fn source(&self, indent: usize) -> String;
+}
+
+/// Spaces per level of indentation
+const INDENT: usize = 4;
+
+impl<T: ToSource> ToSource for [T] {
+    fn source(&self, indent: usize) -> String {
+        self.iter().map(|s| s.source(indent)).collect::<Vec<_>>().join("\n")
+    }
+}
+
+impl Expr {
+    /// How tightly the expression binds, following the levels of the parser
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Assignment { .. } | Expr::IndexAssignment { .. } => 1,
+            Expr::Ternary { .. } => 2,
+            Expr::Logical { operator: LogicalOp::Or, .. } => 3,
+            Expr::Logical { operator: LogicalOp::And, .. } => 4,
+            Expr::Binary { op, .. } => match op.t_type {
+                TokenType::BitOr => 5,
+                TokenType::BitXor => 6,
+                TokenType::BitAnd => 7,
+                TokenType::Equals | TokenType::NEqual => 8,
+                TokenType::Greater | TokenType::GreaterEq |
+                TokenType::Less | TokenType::LessEq => 9,
+                TokenType::ShiftLeft | TokenType::ShiftRight => 10,
+                TokenType::Plus | TokenType::Minus => 11,
+                _ => 12,
+            },
+            Expr::Unary { .. } => 13,
+            Expr::Call { .. } | Expr::Index { .. } | Expr::Property { .. } => 14,
+            _ => 15,
+        }
+    }
+
+    /// Source of `self` as an operand that needs to bind at least as tightly
+    /// as `min`. Function bodies are indented by `indent` levels
+    fn operand(&self, min: u8, indent: usize) -> String {
+        if self.precedence() < min {
+            format!("({})", self.source(indent))
+        } else {
+            self.source(indent)
+        }
+    }
+
+}
+
+/// Parentheses are only added where precedence requires them, explicit
+/// groupings of the input are kept
+impl ToSource for Expr {
+    fn source(&self, indent: usize) -> String {
+        let list = |exprs: &[Expr]| {
+            exprs.iter().map(|e| e.source(indent)).collect::<Vec<_>>().join(", ")
+        };
+        let prec = self.precedence();
+        match self {
+            Expr::Assignment { name, expr } => {
+                format!("{} = {}", name.value, expr.operand(prec, indent))
+            }
+            Expr::IndexAssignment { target, index, expr } => {
+                format!("{}[{}] = {}", target.operand(14, indent), index.source(indent),
+                        expr.operand(prec, indent))
+            }
+            // Operators are left associative, so a right operand on the same
+            // level needs parentheses
+            Expr::Binary { left, op, right } => {
+                format!("{} {} {}", left.operand(prec, indent), op.value,
+                        right.operand(prec + 1, indent))
+            }
+            Expr::Logical { l_expr, operator, r_expr } => {
+                let op = match operator {
+                    LogicalOp::Or => "||",
+                    LogicalOp::And => "&&",
+                };
+                format!("{} {} {}", l_expr.operand(prec, indent), op,
+                        r_expr.operand(prec + 1, indent))
+            }
+            Expr::Ternary { cond, then_e, else_e } => {
+                format!("{} ? {} : {}", cond.operand(prec + 1, indent),
+                        then_e.operand(1, indent), else_e.operand(prec, indent))
+            }
+            // `- -x` would otherwise be lexed as a decrement
+            Expr::Unary { op, right } => {
+                let right = right.operand(prec, indent);
+                let space = if right.starts_with(op.value.as_str()) { " " } else { "" };
+                format!("{}{}{}", op.value, space, right)
+            }
+            Expr::Call { callee, arguments } => {
+                format!("{}({})", callee.operand(prec, indent), list(arguments))
+            }
+            Expr::Index { target, index } => {
+                format!("{}[{}]", target.operand(prec, indent), index.source(indent))
+            }
+            Expr::Property { target, name } => {
+                format!("{}.{}", target.operand(prec, indent), name.value)
+            }
+            Expr::Grouping { expr } => format!("({})", expr.source(indent)),
+            Expr::Array { elements } => format!("[{}]", list(elements)),
+            Expr::Object { entries } => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", object_key(k), v.source(indent)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Expr::Literal { literal } => literal.to_source(),
+            Expr::Variable { name } => name.value.clone(),
+            Expr::Function { params, body } => {
+                format!("function({}) {}", params_source(params), function_body(body, indent))
+            }
+        }
+    }
+}
+
+impl Literal {
+    /// Floats keep a fraction so they are not read back as integers
+    fn to_source(&self) -> String {
+        match self {
+            Literal::Number(n) => {
+                let s = n.to_string();
+                if s.parse::<i64>().is_ok() { s + ".0" } else { s }
+            }
+            Literal::Int(i) => i.to_string(),
+            Literal::StringLiteral(s) => format!("\"{}\"", s),
+            Literal::True => "true".to_string(),
+            Literal::False => "false".to_string(),
+            Literal::Nil => "nil".to_string(),
+        }
+    }
+}
+
+/// Keys that are not identifiers have to be quoted
+fn object_key(key: &str) -> String {
+    let mut chars = key.chars();
+    let ident = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if ident { key.to_string() } else { format!("\"{}\"", key) }
+}
+
+fn params_source(params: &[Token]) -> String {
+    params.iter().map(|p| p.value.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+/// The body of a function is a single block
+fn function_body(body: &[Stmt], indent: usize) -> String {
+    let stmts = match body {
+        [Stmt::Block(stmts)] => stmts,
+        stmts => stmts,
+    };
+    block_source(stmts, indent)
+}
+
+/// `{`, the statements indented by one more level and `}` at `indent`
+fn block_source(stmts: &[Stmt], indent: usize) -> String {
+    if stmts.is_empty() {
+        return "{}".to_string();
+    }
+    let mut out = String::from("{\n");
+    for stmt in stmts {
+        out.push_str(&stmt.source(indent + 1));
+        out.push('\n');
+    }
+    out.push_str(&" ".repeat(indent * INDENT));
+    out.push('}');
+    out
+}
+
+impl Stmt {
+    /// Body of a statement like `while` at `indent`, a block stays on the
+    /// line of the statement while anything else goes on its own line
+    fn nested(&self, indent: usize) -> String {
+        match self {
+            Stmt::Block(stmts) => format!(" {}", block_source(stmts, indent)),
+            stmt => format!("\n{}", stmt.source(indent + 1)),
+        }
+    }
+
+    /// Whether the source of the statement ends with an `if` that has no
+    /// `else`
+    fn ends_in_if(&self) -> bool {
+        match self {
+            Stmt::If(_, _, None) => true,
+            Stmt::If(_, _, Some(stmt)) | Stmt::While(_, stmt) |
+            Stmt::ForOf { body: stmt, .. } | Stmt::For { body: stmt, .. } => stmt.ends_in_if(),
+            _ => false,
+        }
+    }
+}
+
+impl ToSource for Stmt {
+    fn source(&self, indent: usize) -> String {
+        let pad = " ".repeat(indent * INDENT);
+        let list = |exprs: &[Expr]| {
+            exprs.iter().map(|e| e.source(indent)).collect::<Vec<_>>().join(", ")
+        };
+        match self {
+            // A statement starting with a curly brace or `function` would be
+            // read as a block or a declaration
+            Stmt::Expression(expr) => match expr {
+                Expr::Object { .. } | Expr::Function { .. } => {
+                    format!("{}({});", pad, expr.source(indent))
+                }
+                _ => format!("{}{};", pad, expr.source(indent)),
+            },
+            Stmt::Variable(name, None) => format!("{}var {};", pad, name.value),
+            Stmt::Variable(name, Some(init)) => {
+                format!("{}var {} = {};", pad, name.value, init.source(indent))
+            }
+            Stmt::Block(stmts) => format!("{}{}", pad, block_source(stmts, indent)),
+            Stmt::Function(name, params, body) => {
+                format!("{}function {}({}) {}", pad, name.value, params_source(params),
+                        function_body(body, indent))
+            }
+            Stmt::If(cond, then_branch, else_branch) => {
+                let mut out = format!("{}if ({})", pad, cond.source(indent));
+                let else_branch = match else_branch {
+                    Some(else_branch) => else_branch,
+                    None => return out + &then_branch.nested(indent),
+                };
+
+                // An `if` without an else at the end of the branch would take
+                // the `else`
+                if then_branch.ends_in_if() {
+                    out.push_str(&format!(" {}", block_source(&[(**then_branch).clone()],
+                                                               indent)));
+                } else {
+                    out.push_str(&then_branch.nested(indent));
+                }
+                if out.ends_with('}') {
+                    out.push_str(" else");
+                } else {
+                    out.push_str(&format!("\n{}else", pad));
+                }
+                match &**else_branch {
+                    Stmt::If(..) => out + " " + else_branch.source(indent).trim_start(),
+                    stmt => out + &stmt.nested(indent),
+                }
+            }
+            Stmt::Return(None) => format!("{}return;", pad),
+            Stmt::Return(Some(expr)) => format!("{}return {};", pad, expr.source(indent)),
+            Stmt::While(cond, body) => {
+                format!("{}while ({}){}", pad, cond.source(indent), body.nested(indent))
+            }
+            Stmt::DoWhile(body, cond) => {
+                let sep = if matches!(**body, Stmt::Block(_)) {
+                    " ".to_string()
+                } else {
+                    format!("\n{}", pad)
+                };
+                format!("{}do{}{}while ({});", pad, body.nested(indent), sep,
+                        cond.source(indent))
+            }
+            Stmt::Print(args) => format!("{}console.log({});", pad, list(args)),
+            Stmt::Import(path) => format!("{}import \"{}\";", pad, path.value),
+            Stmt::Break(_) => format!("{}break;", pad),
+            Stmt::Continue(_) => format!("{}continue;", pad),
+            Stmt::ForOf { name, iterable, body } => {
+                format!("{}for (var {} of {}){}", pad, name.value, iterable.source(indent),
+                        body.nested(indent))
+            }
+            Stmt::For { init, cond, increment, body } => {
+                let init = match init {
+                    Some(init) => init.source(0),
+                    None => ";".to_string(),
+                };
+                let increment = match increment {
+                    Some(expr) => format!(" {}", expr.source(indent)),
+                    None => String::new(),
+                };
+                format!("{}for ({} {};{}){}", pad, init, cond.source(indent), increment,
+                        body.nested(indent))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser};
+
+    fn json(source: &str) -> String {
+        let (stmts, errors) = Parser::new(tokenize(source)).parse();
+        assert!(errors.is_empty(), "failed to parse test program");
+        stmts.to_json()
+    }
+
+    #[test]
+    fn variable_declaration_as_json() {
+        assert_eq!(json("var x = 1;"),
+                   "[{\"type\":\"Variable\",\"name\":{\"value\":\"x\",\"line\":1,\"col\":5},\
+                    \"init\":{\"type\":\"Literal\",\"literal\":{\"type\":\"Number\",\"value\":1}}}]");
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let out = json("console.log(\"a\tb\", 1.5);");
+        assert!(out.contains("{\"type\":\"String\",\"value\":\"a\\tb\"}"), "{}", out);
+        assert!(out.contains("{\"type\":\"Number\",\"value\":1.5}"));
+        assert_eq!(json_string("\"q\"\\\n\u{1}"), "\"\\\"q\\\"\\\\\\n\\u0001\"");
+    }
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let (stmts, errors) = Parser::new(tokenize(source)).parse();
+        assert!(errors.is_empty(), "failed to parse {}", source);
+        stmts
+    }
+
+    /// JSON of the tree without the positions of its tokens
+    fn shape(stmts: &[Stmt]) -> String {
+        let json = stmts.to_json();
+        let mut out = String::new();
+        let mut rest = json.as_str();
+        while let Some(i) = rest.find(",\"line\":") {
+            out.push_str(&rest[..i]);
+            rest = &rest[i + rest[i..].find('}').unwrap()..];
+        }
+        out + rest
+    }
+
+    #[test]
+    fn source_parses_back_into_the_same_tree() {
+        let source = "function fib(n) { if (n < 2) { return n; } \
+                      return fib(n - 1) + fib(n - 2); }\n\
+                      var i = 0; var o = {a: [1, 2.0], \"b c\": nil};\n\
+                      while (i < 10 && !(i == 5)) { if (i % 2 == 0) console.log(fib(i)); \
+                      else if (i > 7) { break; } else { i = i + 1; continue; } i = i + 1; }\n\
+                      for (var j = 0; j < 3; j = j + 1) { var f = function(x) { return x * -j; }; }\n\
+                      do { i = i - (1 - 2) * 3; } while (i > 0 ? true : o.a[0] >= 1);";
+        let stmts = parse(source);
+        let printed = stmts.to_source();
+        assert_eq!(shape(&parse(&printed)), shape(&stmts), "{}", printed);
+
+        // Formatting is stable
+        assert_eq!(parse(&printed).to_source(), printed);
+        assert!(printed.starts_with("function fib(n) {\n    if (n < 2) {\n        return n;\n    }\n"));
+    }
+
+    #[test]
+    fn parentheses_follow_precedence() {
+        let print = |source: &str| parse(source).to_source();
+        // Compound assignments and increments are desugared without groupings
+        assert_eq!(print("x *= a + b;"), "x = x * (a + b);");
+        assert_eq!(print("x -= a - b;"), "x = x - (a - b);");
+        assert_eq!(print("y = x++;"), "y = (x = x + 1) - 1;");
+        assert_eq!(print("console.log(- -a, 1 + 2 * 3);"), "console.log(- -a, 1 + 2 * 3);");
+    }
 }
@@ -0,0 +1,184 @@
+/// Options passed on the command line, every debug dump is off by default
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Options {
+    /// Source file, or bytecode file if `run_bytecode` is set. Empty when
+    /// starting the repl
+    pub file: String,
+
+    /// Start an interactive session instead of running a file
+    pub repl: bool,
+
+    pub source: bool,
+    pub tokens: bool,
+    pub ast: bool,
+
+    /// Print the AST as JSON, for use by other tools
+    pub ast_json: bool,
+
+    /// Print the AST rendered back into source code
+    pub ast_source: bool,
+    pub bytecode: bool,
+    pub stats: bool,
+
+    /// Print the control flow graph of every function in DOT format
+    pub cfg_dot: bool,
+
+    /// Run the abstract interpreter before executing the program
+    pub analyze: bool,
+
+    /// Analyze using the sign domain instead of intervals
+    pub sign_domain: bool,
+
+    /// Report declarations that shadow a variable of an outer scope
+    pub lint_shadowing: bool,
+
+    /// How often a loop may grow the analyzed state before it is widened
+    pub widen_after: usize,
+
+    /// Replace computations with constant results and remove redundant
+    /// register moves before running
+    pub optimize: bool,
+
+    /// Execute with the tree-walking interpreter instead of the vm
+    pub tree_walk: bool,
+
+    /// The file holds bytecode written by `Program::write_to_file`
+    pub run_bytecode: bool,
+
+    /// Write the compiled program to this file, it can be run later with
+    /// `run_bytecode`
+    pub emit_bytecode: Option<String>,
+
+    /// Stop after compiling (and dumping) the program
+    pub no_run: bool,
+
+    /// Seed for the random builtins
+    pub seed: Option<u64>,
+
+    /// Maximum number of instructions the vm executes
+    pub budget: Option<u64>,
+
+    /// Maximum number of nested function calls in the vm
+    pub max_call_depth: Option<usize>,
+
+    /// Print how often each instruction was executed
+    pub profile: bool,
+
+    /// Print how long each phase of the pipeline took to stderr
+    pub time: bool,
+
+    /// Only print the usage message
+    pub help: bool,
+}
+
+pub const USAGE: &str = "\
+Usage: baby_interp [options] [file]
+
+Options:
+  --source          Print the source code
+  --tokens          Print the tokens produced by the lexer
+  --ast             Print the AST produced by the parser
+  --ast-json        Print the AST as JSON
+  --ast-source      Print the AST rendered back into source code
+  --bytecode        Print the generated bytecode
+  --stats           Print statistics about the generated bytecode
+  --cfg-dot         Print the control flow graphs in Graphviz DOT format
+  --analyze         Run the abstract interpreter on the bytecode
+  --sign-domain     Use the sign domain for --analyze instead of intervals
+  --lint-shadowing  Warn about shadowed variables in --analyze
+  --widen-after <n> Iterate loops up to n times before widening in --analyze
+  --optimize        Propagate constants and remove redundant moves before running
+  --tree-walk       Execute the AST directly instead of the bytecode
+  --run-bytecode    Execute a compiled bytecode file (default for .bcode files)
+  --emit-bytecode <out> Write the compiled bytecode to the file out
+  --no-run          Compile the program without executing it
+  --seed <n>        Seed for random() and randomInt()
+  --budget <n>      Abort after executing n instructions
+  --max-call-depth <n> Report a stack overflow beyond n nested calls
+  --profile         Count how often each instruction is executed
+  --time            Print how long each phase took to stderr
+  --repl            Start an interactive session (default without a file)
+  --help            Print this message";
+
+impl Options {
+    /// Parse the arguments following the program name
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut file = None;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    opts.help = true;
+                    return Ok(opts);
+                }
+                "--repl" => opts.repl = true,
+                "--source" => opts.source = true,
+                "--tokens" => opts.tokens = true,
+                "--ast" => opts.ast = true,
+                "--ast-json" => opts.ast_json = true,
+                "--ast-source" => opts.ast_source = true,
+                "--bytecode" => opts.bytecode = true,
+                "--stats" => opts.stats = true,
+                "--cfg-dot" => opts.cfg_dot = true,
+                "--analyze" => opts.analyze = true,
+                "--sign-domain" => opts.sign_domain = true,
+                "--lint-shadowing" => opts.lint_shadowing = true,
+                "--optimize" => opts.optimize = true,
+                "--tree-walk" => opts.tree_walk = true,
+                "--run-bytecode" => opts.run_bytecode = true,
+                "--no-run" => opts.no_run = true,
+                "--profile" => opts.profile = true,
+                "--time" => opts.time = true,
+                "--widen-after" => {
+                    let n = args.next().ok_or("--widen-after expects a number")?;
+                    opts.widen_after = n.parse().map_err(|_| {
+                        format!("Invalid widening threshold '{}', expected a number", n)
+                    })?;
+                }
+                "--budget" => {
+                    let budget = args.next().ok_or("--budget expects a number")?;
+                    opts.budget = Some(budget.parse().map_err(|_| {
+                        format!("Invalid budget '{}', expected a number", budget)
+                    })?);
+                }
+                "--emit-bytecode" => {
+                    let out = args.next().ok_or("--emit-bytecode expects a file name")?;
+                    opts.emit_bytecode = Some(out.to_string());
+                }
+                "--max-call-depth" => {
+                    let depth = args.next().ok_or("--max-call-depth expects a number")?;
+                    opts.max_call_depth = Some(depth.parse().map_err(|_| {
+                        format!("Invalid call depth '{}', expected a number", depth)
+                    })?);
+                }
+                "--seed" => {
+                    let seed = args.next().ok_or("--seed expects a number")?;
+                    opts.seed = Some(seed.parse().map_err(|_| {
+                        format!("Invalid seed '{}', expected a number", seed)
+                    })?);
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unknown option '{}'", flag));
+                }
+                name => {
+                    if file.is_some() {
+                        return Err("Only a single file can be provided".to_string());
+                    }
+                    file = Some(name.to_string());
+                }
+            }
+        }
+
+        match file {
+            Some(file) if !opts.repl => opts.file = file,
+            Some(_) => return Err("The repl does not take a file".to_string()),
+            None => opts.repl = true,
+        }
+        if opts.file.ends_with(".bcode") {
+            opts.run_bytecode = true;
+        }
+        Ok(opts)
+    }
+}
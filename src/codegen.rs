@@ -1,25 +1,126 @@
 use crate::{
-    ast::{Expr, Expr::Variable, Literal, LogicalOp::And, LogicalOp::Or, Stmt},
-    tokens::Token,
-    tokens::TokenType::*,
-    vm,
+    ast::{implicit_main, Expr, Expr::Variable, Literal, LogicalOp::And, LogicalOp::Or, Stmt},
+    err::Error,
+    tokens::{Token, TokenType, TokenType::*},
+    native, vm,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    mem,
 };
-use std::collections::HashMap;
 use rustc_hash::FxHashMap;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Value {
     Nil,
     Number(f64),
+    Int(i64),
     Bool(bool),
     StringLiteral(String),
     Reg(u16),
     Pool(u16),
     CPool(usize),
     VAddr(isize),
+    Array(Vec<Value>),
+    Object(HashMap<String, Value>),
+
+    /// Address of the first instruction of a function
+    Function(usize),
+
+    /// Value of a variable that was declared without being initialized
+    Undefined,
+}
+
+impl Value {
+    /// Whether the value counts as true in a condition. Like in javascript
+    /// `0`, `NaN`, `""`, `nil`, `undefined` and `false` are falsy, everything
+    /// else is truthy
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Nil | Value::Undefined => false,
+            Value::Number(n) => *n != 0.0 && !n.is_nan(),
+            Value::Int(i) => *i != 0,
+            Value::Bool(b) => *b,
+            Value::StringLiteral(s) => !s.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+/// Format a number the way programs print it. Integral values below 1e21 are
+/// written out without a fraction, very large and very small values use
+/// exponent notation and everything else the shortest decimal that reads back
+/// as the same number
+pub fn format_number(n: f64) -> String {
+    let magnitude = n.abs();
+    if !n.is_finite() || (n.fract() == 0.0 && magnitude < 1e21) {
+        return n.to_string();
+    }
+    if !(1e-6..1e21).contains(&magnitude) {
+        let s = format!("{:e}", n);
+        return match s.split_once('e') {
+            Some((mantissa, exp)) if !exp.starts_with('-') => format!("{}e+{}", mantissa, exp),
+            _ => s,
+        };
+    }
+    n.to_string()
+}
+
+/// Numbers are compared by their bits, which makes the equality total so
+/// values can be used as map keys: `NaN` is equal to itself, while `0.0` and
+/// `-0.0` are different values. Comparisons in programs do not use this, they
+/// follow javascript in `Interpreter::binary_op`
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) | (Value::Undefined, Value::Undefined) => true,
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::StringLiteral(a), Value::StringLiteral(b)) => a == b,
+            (Value::Reg(a), Value::Reg(b)) => a == b,
+            (Value::Pool(a), Value::Pool(b)) => a == b,
+            (Value::CPool(a), Value::CPool(b)) => a == b,
+            (Value::VAddr(a), Value::VAddr(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (Value::Object(a), Value::Object(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Value::Nil | Value::Undefined => {}
+            Value::Number(n) => n.to_bits().hash(state),
+            Value::Int(i) => i.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::StringLiteral(s) => s.hash(state),
+            Value::Reg(r) | Value::Pool(r) => r.hash(state),
+            Value::CPool(i) | Value::Function(i) => i.hash(state),
+            Value::VAddr(a) => a.hash(state),
+            Value::Array(elements) => elements.hash(state),
+            Value::Object(map) => {
+                // Equal maps may iterate in different orders
+                let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                entries.hash(state);
+            }
+        }
+    }
+}
+
+/// The discriminants are used as opcodes in bytecode files, so new
+/// instructions have to be added at the end to keep old files readable
+#[derive(PartialEq, Debug, Copy, Clone, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
 pub enum Instr {
     // Load Immediate into register
     LoadI,
@@ -54,25 +155,31 @@ pub enum Instr {
     // res = r1 / r2
     Div,
 
-    // Compare if r1 < r2 and set flag accordingly
+    // res = r1 % r2
+    Mod,
+
+    // res = r1 < r2
     CmpLT,
 
-    // Compare if r1 <= r2 and set flag accordingly
+    // res = r1 <= r2
     CmpLE,
 
-    // Compare if r1 > r2 and set flag accordingly
+    // res = r1 > r2
     CmpGT,
 
-    // Compare if r1 >= r2 and set flag accordingly
+    // res = r1 >= r2
     CmpGE,
 
-    // Compare if r1 == r2 and set flag accordingly
+    // res = r1 == r2
     CmpEq,
 
-    // Jump if flag is set using offset relative to IP
+    // res = r1 != r2
+    CmpNe,
+
+    // Jump to offset relative to IP if the condition register is truthy
     JmpIf,
 
-    // Jump to offset relative to IP if flag is not set
+    // Jump to offset relative to IP if the condition register is falsy
     JmpIN,
 
     // Unconditional jump using offset relative to IP
@@ -86,6 +193,85 @@ pub enum Instr {
 
     // Builtin - print r1 to console
     Print,
+
+    // Builtin - print r1 followed by a space, without ending the line
+    PrintS,
+
+    // Builtin - res = random float in [0, 1)
+    Rand,
+
+    // Builtin - res = random integer in [r1, r2]
+    RandInt,
+
+    // Create an array in res from the last r1 values on the arguments stack
+    MakeArray,
+
+    // res = r1[r2]
+    IndexGet,
+
+    // res[r1] = r2
+    IndexSet,
+
+    // res = number of elements in r1
+    Len,
+
+    // res = r1 & r2
+    BitAnd,
+
+    // res = r1 | r2
+    BitOr,
+
+    // res = r1 ^ r2
+    BitXor,
+
+    // res = r1 << r2
+    Shl,
+
+    // res = r1 >> r2
+    Shr,
+
+    // Call the function stored in r1
+    CallR,
+
+    // Create an object in res from the last r1 key/value pairs on the
+    // arguments stack
+    MakeObject,
+
+    // res = native function r1 applied to its arguments, which are taken
+    // from the arguments stack
+    CallNative,
+
+    // Load a global variable, which lives in the pool of the top-level code
+    LoadG,
+
+    // Store a value to a global variable
+    PushG,
+
+    // Stop the program with exit code r1
+    Halt,
+
+    // res = method r2 of r1, r2 holds the name of the method
+    CallMethod,
+}
+
+impl Instr {
+    /// Number of bytecode slots taken up by the instruction and its operands
+    pub fn size(&self) -> usize {
+        match self {
+            Instr::Ret => 1,
+            Instr::LoadA | Instr::PushA | Instr::Jmp | Instr::Call |
+            Instr::Print | Instr::PrintS | Instr::Rand | Instr::CallR | Instr::Halt => 2,
+            Instr::LoadI | Instr::LoadR | Instr::LoadP | Instr::LoadC |
+            Instr::PushP | Instr::MakeArray | Instr::Len | Instr::JmpIf |
+            Instr::JmpIN | Instr::MakeObject | Instr::CallNative | Instr::LoadG |
+            Instr::PushG => 3,
+            Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Mod |
+            Instr::CmpLT | Instr::CmpLE | Instr::CmpGT | Instr::CmpGE |
+            Instr::CmpEq | Instr::CmpNe | Instr::RandInt | Instr::IndexGet |
+            Instr::IndexSet | Instr::BitAnd | Instr::BitOr | Instr::BitXor |
+            Instr::Shl | Instr::Shr | Instr::CallMethod => 4,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -94,10 +280,25 @@ pub enum BcArr {
     V(Value),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct Vars {
-    name: String,
-    depth: u8,
+/// Variable declared in one of the scopes that are currently active
+#[derive(Debug, Clone)]
+struct Local {
+    /// Token of the declaration
+    name: Token,
+
+    /// Whether the value of the variable is loaded anywhere
+    read: bool,
+}
+
+/// Jumps out of a loop that still need to be patched once the loop is done
+#[derive(Debug, Clone, Default)]
+struct LoopContext {
+    /// Operand addresses of `break` jumps, these target the end of the loop
+    breaks: Vec<usize>,
+
+    /// Operand addresses of `continue` jumps, these target the condition or
+    /// the increment of a for loop
+    continues: Vec<usize>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -113,14 +314,38 @@ pub struct Cfg {
     pub blocks: FxHashMap<usize, Block>,
 }
 
-#[derive(Default, Debug, Clone)]
-pub struct Metadata {
-    /// <JmpIf/JmpIn address, (T, F)>
-    /// Maps start of the if-statement cfg-split to the t & Option<f> blocks
-    if_blocks: FxHashMap<usize, (usize, Option<usize>)>,
-
-    // <JmpIf/JmpIn address, (T, F)>
-    while_blocks: FxHashMap<usize, (usize, Option<usize>)>,
+impl Cfg {
+    /// Render the graph in Graphviz DOT format, one node per block labeled
+    /// with the addresses it spans. The entry block is drawn as a diamond and
+    /// blocks without successors as a double box
+    pub fn to_dot(&self) -> String {
+        use std::fmt::Write;
+
+        let mut ids: Vec<&usize> = self.blocks.keys().collect();
+        ids.sort();
+
+        let mut out = String::from("digraph cfg {\n    node [shape=box];\n");
+        for id in &ids {
+            let block = &self.blocks[id];
+            let first = block.instrs.first().map_or(0, |(ip, _)| *ip);
+            let last = block.instrs.last().map_or(0, |(ip, _)| *ip);
+            let shape = if **id == 0 {
+                " shape=Mdiamond"
+            } else if block.edges.is_empty() {
+                " shape=Msquare"
+            } else {
+                ""
+            };
+            writeln!(out, "    b{} [label=\"IP {}-{}\"{}];", id, first, last, shape).unwrap();
+        }
+        for id in &ids {
+            for edge in &self.blocks[id].edges {
+                writeln!(out, "    b{} -> b{};", id, edge).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -130,108 +355,424 @@ enum BlockType {
     IfConvergence,
 }
 
+/// Entry of the function list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FuncInfo {
+    /// Address of the first instruction of the function
+    pub addr: usize,
+
+    /// Number of parameters the function is declared with
+    pub arity: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Program {
     pub bytecode: Vec<BcArr>,
 
+    /// Source line every bytecode entry was generated from, 0 if unknown
+    pub lines: Vec<u32>,
+
     pub entry_point: usize,
 
-    pub function_list: HashMap<String, usize>,
+    pub function_list: HashMap<String, FuncInfo>,
 
     pub const_pool: Vec<Value>,
 
-    pub metadata: Metadata,
+    /// <Operand address, function name, argument count>
+    /// Calls to functions that are not defined in this program, and loads of
+    /// them as values which have no argument count. These are patched once
+    /// the defining program is merged in
+    pub unresolved: Vec<(usize, Token, Option<usize>)>,
+
+    /// Lints reported by the code generator, eg. unused variables
+    pub warnings: Vec<Error>,
+}
+
+/// Size and register usage of a compiled program
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramStats {
+    /// Number of entries in the bytecode vector (instructions and operands)
+    pub bytecode_len: usize,
+
+    /// Number of instructions in the bytecode vector
+    pub instr_count: usize,
+
+    pub function_count: usize,
+
+    pub const_pool_size: usize,
+
+    /// Highest virtual register index that is referenced
+    pub max_reg: u16,
 }
 
 impl Program {
-    /// TODO: Breaks on a lot of code structures, including loops
-    /// Prob full rewrite to prepass that finds all labels
-    fn compute_func_cfg(&self, start: usize, end: usize, cfg: &mut Cfg) {
-        let mut block_id = 0;
-        let mut cur_label = start;
-        let mut block_worklist: FxHashMap<usize, usize> = FxHashMap::default();
-        let mut cur_block = Block::default();
-        let mut ip_to_block_id: FxHashMap<usize, usize> = FxHashMap::default();
+    /// Human readable listing of the bytecode, followed by the const pool.
+    /// Addresses are printed starting at 1
+    pub fn disassemble(&self) -> String {
+        use std::fmt::Write;
+
+        let names = self.function_names();
+        let mut out = String::new();
+        for (j, instr) in self.bytecode.iter().enumerate() {
+            if let Some(name) = names.get(&j) {
+                write!(out, "\n\n\t< {} >", name).unwrap();
+            }
+            if j == self.entry_point {
+                write!(out, "\n\n\t< Entry Point >").unwrap();
+            }
+            let i = j + 1;
+            match instr {
+                BcArr::I(Instr::Add) => write!(out, "\n{:4}   Add     ", i),
+                BcArr::I(Instr::Sub) => write!(out, "\n{:4}   Sub     ", i),
+                BcArr::I(Instr::Div) => write!(out, "\n{:4}   Div     ", i),
+                BcArr::I(Instr::Mul) => write!(out, "\n{:4}   Mul     ", i),
+                BcArr::I(Instr::Mod) => write!(out, "\n{:4}   Mod     ", i),
+                BcArr::I(Instr::Jmp) => write!(out, "\n{:4}   Jmp     ", i),
+                BcArr::I(Instr::Call) => write!(out, "\n{:4}   Call    ", i),
+                BcArr::I(Instr::Rand) => write!(out, "\n{:4}   Rand    ", i),
+                BcArr::I(v) => write!(out, "\n{:4}   {:?}   ", i, v),
+                BcArr::V(Value::Number(v)) => write!(out, "{}, ", format_number(*v)),
+                BcArr::V(Value::Int(v)) => write!(out, "{}, ", v),
+                BcArr::V(Value::StringLiteral(v)) => write!(out, "{:?}, ", v),
+                BcArr::V(Value::Nil) => write!(out, "NIL"),
+                BcArr::V(Value::Function(addr)) => match self.function_at(*addr) {
+                    Some(name) => write!(out, "Function({}) <{}>, ", addr, name),
+                    None => write!(out, "Function({}), ", addr),
+                },
+                BcArr::V(v) => write!(out, "{:?}, ", v),
+            }
+            .unwrap();
+        }
+        if !self.const_pool.is_empty() {
+            writeln!(out, "\n+-----------Const-Pool-------------+\n").unwrap();
+            for (i, c) in self.const_pool.iter().enumerate() {
+                writeln!(out, "[{}] - {:?}", i, c).unwrap();
+            }
+        }
+        out
+    }
 
-        ip_to_block_id.insert(cur_label, block_id);
+    /// Name of the function whose code starts at `addr`
+    pub fn function_at(&self, addr: usize) -> Option<&str> {
+        self.function_list
+            .iter()
+            .filter(|(_, f)| f.addr == addr)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    /// Map from the entry address of every function to its name. If several
+    /// names share an address the smallest one is used, like `function_at`
+    pub fn function_names(&self) -> HashMap<usize, &str> {
+        let mut names: HashMap<usize, &str> = HashMap::new();
+        for (name, f) in &self.function_list {
+            let entry = names.entry(f.addr).or_insert(name);
+            if name.as_str() < *entry {
+                *entry = name;
+            }
+        }
+        names
+    }
+
+    /// Collect statistics about the program, useful to judge how much
+    /// optimization passes shrink the generated code
+    pub fn stats(&self) -> ProgramStats {
+        ProgramStats {
+            bytecode_len: self.bytecode.len(),
+            instr_count: self
+                .bytecode
+                .iter()
+                .filter(|v| matches!(v, BcArr::I(_)))
+                .count(),
+            function_count: self.function_list.len(),
+            const_pool_size: self.const_pool.len(),
+            max_reg: self
+                .bytecode
+                .iter()
+                .filter_map(|v| match v {
+                    BcArr::V(Value::Reg(r)) => Some(*r),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// Link `other` behind this program. Its bytecode is relocated to the end
+    /// of ours, absolute call addresses, function positions, pool slots and
+    /// constant pool indices are rebased, and a jump is inserted so that
+    /// execution continues with `other`'s top-level code once ours finishes.
+    /// Calls that could not be resolved in either program are resolved against
+    /// the merged function list if possible.
+    pub fn merge(mut self, other: Program) -> Result<Program, Error> {
+        for name in other.function_list.keys() {
+            if self.function_list.contains_key(name) {
+                return Err(Error::new(
+                    format!("Cannot merge modules, function '{}' is defined \
+                            in both", name),
+                    0,
+                    0,
+                ));
+            }
+        }
 
-        while cur_label < end {
-            // `JmpIf` hit, allocate new blocks and point edges to them
-            if let Some(x) = self.metadata.if_blocks.get(&cur_label) {
-                cur_block.dsts.push(x.0);
-                block_worklist.insert(x.0, x.0);
-                if let Some(f_block_ip) = x.1 {
-                    cur_block.dsts.push(f_block_ip);
-                    block_worklist.insert(f_block_ip, f_block_ip);
+        let base = self.bytecode.len() + 2;
+        let const_base = self.const_pool.len();
+        let pool_base = self
+            .bytecode
+            .iter()
+            .filter_map(|v| match v {
+                BcArr::V(Value::Pool(p)) => Some(*p + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        self.bytecode.push(BcArr::I(Instr::Jmp));
+        self.bytecode.push(BcArr::V(Value::VAddr(other.entry_point as isize)));
+        self.lines.resize(self.bytecode.len(), 0);
+        self.lines.extend(other.lines);
+
+        let mut prev_call = false;
+        for v in other.bytecode {
+            let v = match v {
+                // Jumps are relative, only call targets are absolute
+                BcArr::V(Value::VAddr(addr)) if prev_call => {
+                    BcArr::V(Value::VAddr(addr + base as isize))
                 }
-                cfg.blocks.insert(block_id, cur_block);
-                block_id += 1;
-                cur_block = Block::default();
-                ip_to_block_id.insert(cur_label, block_id);
-            }
-            
-            if cur_block.instrs.len() != 0 {
-                if let Some(x) = block_worklist.get(&cur_label) {
-                    // If previous label was a jmp and not a normal pc-update, we already handled
-                    // the pc-update
-                    if self.bytecode[cur_label-2] != BcArr::I(Instr::Jmp) {
-                        cur_block.dsts.push(*x);
-                    }
-                    cfg.blocks.insert(block_id, cur_block);
-                    cur_block = Block::default();
-                    block_id += 1;
-                    ip_to_block_id.insert(cur_label, block_id);
+                BcArr::V(Value::Function(addr)) => BcArr::V(Value::Function(addr + base)),
+                BcArr::V(Value::CPool(c)) => BcArr::V(Value::CPool(c + const_base)),
+                BcArr::V(Value::Pool(p)) => BcArr::V(Value::Pool(p + pool_base)),
+                v => v,
+            };
+            prev_call = v == BcArr::I(Instr::Call);
+            self.bytecode.push(v);
+        }
+
+        self.const_pool.extend(other.const_pool);
+        for (name, f) in other.function_list {
+            self.function_list.insert(name, FuncInfo { addr: f.addr + base, ..f });
+        }
+        for (pos, name, argc) in other.unresolved {
+            self.unresolved.push((pos + base, name, argc));
+        }
+        self.warnings.extend(other.warnings);
+        self.resolve_calls();
+
+        Ok(self)
+    }
+
+    /// Report every call to a function that was never defined, or that passes
+    /// the wrong number of arguments
+    pub fn check_linked(&self) -> Result<(), Vec<Error>> {
+        if self.unresolved.is_empty() {
+            return Ok(());
+        }
+        Err(self.unresolved.iter().map(|(_, name, argc)| {
+            let msg = match (self.function_list.get(&name.value), argc) {
+                (Some(f), Some(argc)) => arity_error(name, f.arity, *argc),
+                (_, Some(_)) => format!("function '{}' that you attempt to call on line {} \
+                                         does not exist", name.value, name.line_num),
+                (_, None) => format!("Variable '{}' used on line {} does not exist",
+                                     name.value, name.line_num),
+            };
+            Error::new(msg, name.line_num, name.col)
+        }).collect())
+    }
+
+    /// Combine the result of code generation with the check for undefined
+    /// functions, so both kinds of errors are reported in source order
+    fn linked(self, generated: Result<(), Vec<Error>>) -> Result<Program, Vec<Error>> {
+        let mut errors = generated.err().unwrap_or_default();
+        errors.extend(self.check_linked().err().unwrap_or_default());
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            errors.sort_by_key(|e| (e.line, e.col));
+            Err(errors)
+        }
+    }
+
+    /// Patch every unresolved call whose target is now in the function list.
+    /// Calls with the wrong number of arguments stay unresolved so that
+    /// `check_linked` reports them
+    fn resolve_calls(&mut self) {
+        let function_list = &self.function_list;
+        let bytecode = &mut self.bytecode;
+        self.unresolved.retain(|(pos, name, argc)| {
+            match (function_list.get(&name.value), argc) {
+                (Some(f), Some(argc)) if f.arity == *argc => {
+                    bytecode[*pos] = BcArr::V(Value::VAddr(f.addr as isize));
+                    false
                 }
+                (Some(f), None) => {
+                    bytecode[*pos] = BcArr::V(Value::Function(f.addr));
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    /// Replace the instructions at the addresses in `edits` with new code, an
+    /// empty replacement removes the instruction. Replacements may differ in
+    /// size, so jump offsets, call targets and function addresses are moved
+    /// along with the code. Jumps within a replacement are relative to the
+    /// address of the instruction it replaces
+    pub fn replace_instrs(&mut self, mut edits: Vec<(usize, Vec<BcArr>)>) {
+        edits.sort_by_key(|(ip, _)| *ip);
+        edits.reverse();
+
+        // Address every old instruction moves to, and the old address every
+        // new jump is relative to
+        let mut moved = vec![usize::MAX; self.bytecode.len() + 1];
+        let mut origin = Vec::new();
+        let mut bytecode = Vec::with_capacity(self.bytecode.len());
+        let mut lines = Vec::with_capacity(self.lines.len());
+        let mut ip = 0;
+        while ip < self.bytecode.len() {
+            let size = self.instr_at(ip).size();
+            moved[ip] = bytecode.len();
+            let code = match edits.last() {
+                Some((at, _)) if *at == ip => edits.pop().unwrap().1,
+                _ => self.bytecode[ip..ip + size].to_vec(),
             };
+            let line = self.lines.get(ip).copied().unwrap_or(0);
+            origin.extend(code.iter().map(|_| ip));
+            lines.extend(code.iter().map(|_| line));
+            bytecode.extend(code);
+            ip += size;
+        }
+        moved[ip] = bytecode.len();
 
-            match self.bytecode[cur_label] {
-                BcArr::I(Instr::Ret) => {
-                    cur_block.instrs.push((cur_label, self.bytecode[cur_label].clone()));
-                    // Force end of loop
-                    cur_label = end;
-                },
-                BcArr::I(Instr::PushA) |
-                BcArr::I(Instr::Print) |
-                BcArr::I(Instr::LoadA) |
-                BcArr::I(Instr::Call)  |
-                BcArr::I(Instr::JmpIf) => {
-                    cur_block.instrs.push((cur_label, self.bytecode[cur_label].clone()));
-                    cur_label += 2;
+        let mut new_ip = 0;
+        while new_ip < bytecode.len() {
+            let instr = match bytecode[new_ip] {
+                BcArr::I(instr) => instr,
+                _ => panic!("Expected instruction at {}", new_ip),
+            };
+            match instr {
+                Instr::Jmp | Instr::JmpIf | Instr::JmpIN => {
+                    let offset = vm::Interpreter::unpack_vaddr(bytecode[new_ip + 1].clone());
+                    let target = (origin[new_ip] as isize + 2 + offset as isize) as usize;
+                    let offset = moved[target] as isize - (new_ip as isize + 2);
+                    bytecode[new_ip + 1] = BcArr::V(Value::VAddr(offset));
                 }
-                BcArr::I(Instr::LoadP) |
-                BcArr::I(Instr::LoadR) |
-                BcArr::I(Instr::LoadC) |
-                BcArr::I(Instr::PushP) |
-                BcArr::I(Instr::LoadI) => {
-                    cur_block.instrs.push((cur_label, self.bytecode[cur_label].clone()));
-                    cur_label += 3;
-                },
-                BcArr::I(Instr::Add) |
-                BcArr::I(Instr::CmpLE) |
-                BcArr::I(Instr::CmpGT) => {
-                    cur_block.instrs.push((cur_label, self.bytecode[cur_label].clone()));
-                    cur_label += 4;
-                },
-                BcArr::I(Instr::Jmp) => {
-                    cur_block.instrs.push((cur_label, self.bytecode[cur_label].clone()));
-                    cur_label += 2;
+                Instr::Call => {
+                    let addr = vm::Interpreter::unpack_vaddr(bytecode[new_ip + 1].clone());
+                    bytecode[new_ip + 1] = BcArr::V(Value::VAddr(moved[addr] as isize));
+                }
+                _ => {}
+            }
+            for v in &mut bytecode[new_ip + 1..new_ip + instr.size()] {
+                if let BcArr::V(Value::Function(addr)) = v {
+                    *addr = moved[*addr];
+                }
+            }
+            new_ip += instr.size();
+        }
 
-                    let dst = vm::Interpreter::unpack_vaddr(self.bytecode[cur_label-1].clone());
-                    cur_block.dsts.push(cur_label + dst);
-                    block_worklist.insert(cur_label + dst, cur_label + dst);
-                },
-                _ => {
-                    panic!("Handling: {:?}", self.bytecode[cur_label]);
+        for f in self.function_list.values_mut() {
+            f.addr = moved[f.addr];
+        }
+        // Call targets are the first operand, function values the second
+        for (pos, _, argc) in &mut self.unresolved {
+            let operand = if argc.is_some() { 1 } else { 2 };
+            *pos = moved[*pos - operand] + operand;
+        }
+        self.entry_point = moved[self.entry_point];
+        self.bytecode = bytecode;
+        self.lines = lines;
+    }
+
+    /// Split the code reachable from `start` into basic blocks. A prepass walks
+    /// every reachable instruction to find the labels that start a block, these
+    /// are jump targets and the instructions right after a conditional jump.
+    /// Block 0 is always the block at `start`
+    fn compute_func_cfg(&self, start: usize, cfg: &mut Cfg) {
+        let end = self.bytecode.len();
+        let mut labels: BTreeSet<usize> = BTreeSet::new();
+        let mut visited: FxHashMap<usize, ()> = FxHashMap::default();
+        let mut worklist = vec![start];
+        labels.insert(start);
+
+        while let Some(mut ip) = worklist.pop() {
+            while ip < end && visited.insert(ip, ()).is_none() {
+                let instr = self.instr_at(ip);
+                let next = ip + instr.size();
+                match instr {
+                    Instr::Jmp => {
+                        labels.insert(self.jmp_target(ip));
+                        worklist.push(self.jmp_target(ip));
+                        break;
+                    }
+                    Instr::JmpIf | Instr::JmpIN => {
+                        labels.insert(self.jmp_target(ip));
+                        labels.insert(next);
+                        worklist.push(self.jmp_target(ip));
+                    }
+                    Instr::Ret | Instr::Halt => break,
+                    _ => {}
                 }
+                ip = next;
             }
         }
-        cfg.blocks.insert(block_id, cur_block);
-        cfg.blocks.iter_mut().for_each(|b| {
-            for dst in &b.1.dsts {
-                b.1.edges.push(*ip_to_block_id.get(&dst).unwrap());
+
+        // Jumps past the end of the bytecode exit the program, they do not
+        // start a block
+        labels.retain(|l| *l < end);
+        let mut ip_to_block_id: FxHashMap<usize, usize> = FxHashMap::default();
+        ip_to_block_id.insert(start, 0);
+        for label in labels.iter().filter(|l| **l != start) {
+            ip_to_block_id.insert(*label, ip_to_block_id.len());
+        }
+
+        for label in &labels {
+            let mut block = Block::default();
+            let mut ip = *label;
+            while ip < end {
+                let instr = self.instr_at(ip);
+                let next = ip + instr.size();
+                block.instrs.push((ip, self.bytecode[ip].clone()));
+                match instr {
+                    Instr::Jmp => {
+                        block.dsts.push(self.jmp_target(ip));
+                        break;
+                    }
+                    Instr::JmpIf | Instr::JmpIN => {
+                        block.dsts.push(self.jmp_target(ip));
+                        block.dsts.push(next);
+                        break;
+                    }
+                    Instr::Ret | Instr::Halt => break,
+                    _ => {}
+                }
+                if labels.contains(&next) {
+                    block.dsts.push(next);
+                    break;
+                }
+                ip = next;
             }
-            
-        });
+            // Keep `dsts` and `edges` aligned so edges can be matched up with
+            // the address they jump to
+            block.dsts.retain(|dst| ip_to_block_id.contains_key(dst));
+            block.edges = block.dsts.iter().map(|dst| ip_to_block_id[dst]).collect();
+            cfg.blocks.insert(ip_to_block_id[label], block);
+        }
+    }
+
+    /// Instruction located at `ip`
+    pub(crate) fn instr_at(&self, ip: usize) -> Instr {
+        match self.bytecode[ip] {
+            BcArr::I(instr) => instr,
+            _ => panic!("Expected instruction at {}", ip),
+        }
+    }
+
+    /// Absolute address targeted by the jump instruction located at `ip`
+    pub(crate) fn jmp_target(&self, ip: usize) -> usize {
+        let offset = vm::Interpreter::unpack_vaddr(self.bytecode[ip + 1].clone());
+        (ip as isize + 2 + offset as isize) as usize
     }
 
     /// Returns Vec<Function-name, Control-flow-graph>
@@ -240,15 +781,14 @@ impl Program {
 
         // Base function describing outer scope
         let start = self.entry_point;
-        let end = self.bytecode.len();
 
         funcs.push(("__init".to_string(), Cfg::default()));
-        self.compute_func_cfg(start, end, &mut funcs[0].1);
+        self.compute_func_cfg(start, &mut funcs[0].1);
 
         let mut i = 1;
         for f in &self.function_list {
             funcs.push((f.0.to_string(), Cfg::default()));
-            self.compute_func_cfg(*f.1, end, &mut funcs[i].1);
+            self.compute_func_cfg(f.1.addr, &mut funcs[i].1);
             i += 1;
         }
 
@@ -256,10 +796,109 @@ impl Program {
     }
 }
 
+/// Whether `code` ends with a return statement, possibly nested in blocks, so
+/// nothing placed behind it can be reached
+fn ends_in_return(code: &[Stmt]) -> bool {
+    match code.last() {
+        Some(Stmt::Return(_)) => true,
+        Some(Stmt::Block(stmts)) => ends_in_return(stmts),
+        _ => false,
+    }
+}
+
+/// Message for a call to `name` with `argc` arguments, while the function is
+/// declared with `arity` parameters
+fn arity_error(name: &Token, arity: usize, argc: usize) -> String {
+    format!("function '{}' called on line {} expects {} arguments, found {}",
+            name.value, name.line_num, arity, argc)
+}
+
+fn literal_value(literal: &Literal) -> Value {
+    match literal {
+        Literal::Number(n) => Value::Number(*n),
+        Literal::Int(i) => Value::Int(*i),
+        Literal::StringLiteral(s) => Value::StringLiteral(s.clone()),
+        Literal::True => Value::Bool(true),
+        Literal::False => Value::Bool(false),
+        Literal::Nil => Value::Nil,
+    }
+}
+
+/// Compile time value of an expression, along with the values of its operands
+/// so they do not have to be folded again when code is generated for them
+struct Folded {
+    value: Option<Value>,
+    operands: Vec<Folded>,
+}
+
+/// Evaluate `expr` and its operands at compile time where they only consist
+/// of literals. Operations that would raise an error in the vm are not folded,
+/// a constant division by zero is reported as a compile error by `expression`
+fn fold(expr: &Expr) -> Folded {
+    let (value, operands) = match expr {
+        Expr::Literal { literal } => (Some(literal_value(literal)), Vec::new()),
+        Expr::Grouping { expr } => {
+            let inner = fold(expr);
+            (inner.value.clone(), vec![inner])
+        }
+        Expr::Unary { op, right } if op.t_type == Minus => {
+            let r = fold(right);
+            let value = match r.value {
+                Some(Value::Number(n)) => Some(Value::Number(-n)),
+                Some(Value::Int(i)) => {
+                    Some(i.checked_neg().map_or(Value::Number(-(i as f64)), Value::Int))
+                }
+                _ => None,
+            };
+            (value, vec![r])
+        }
+        Expr::Binary { left, op, right } => {
+            let (l, r) = (fold(left), fold(right));
+            let value = match (&l.value, &r.value, binary_instr(op.t_type)) {
+                (Some(a), Some(b), Some(instr)) => vm::Interpreter::binary_op(instr, a, b).ok(),
+                _ => None,
+            };
+            (value, vec![l, r])
+        }
+        _ => (None, Vec::new()),
+    };
+    Folded { value, operands }
+}
+
+/// Instruction that implements the binary operator `op`
+pub fn binary_instr(op: TokenType) -> Option<Instr> {
+    Some(match op {
+        Plus => Instr::Add,
+        Minus => Instr::Sub,
+        Multiply => Instr::Mul,
+        Divide => Instr::Div,
+        Modulo => Instr::Mod,
+        Less => Instr::CmpLT,
+        LessEq => Instr::CmpLE,
+        Greater => Instr::CmpGT,
+        GreaterEq => Instr::CmpGE,
+        Equals => Instr::CmpEq,
+        NEqual => Instr::CmpNe,
+        BitAnd => Instr::BitAnd,
+        BitOr => Instr::BitOr,
+        BitXor => Instr::BitXor,
+        ShiftLeft => Instr::Shl,
+        ShiftRight => Instr::Shr,
+        _ => return None,
+    })
+}
+
+#[derive(Clone)]
 pub struct Codegen {
     /// Holds bytecode that is later passed on to interpreter
     pub bytecode: Vec<BcArr>,
 
+    /// Source line of every bytecode entry
+    lines: Vec<u32>,
+
+    /// Source line of the node code is currently generated for
+    line: u32,
+
     /// Pool of constant global variables
     const_pool: Vec<Value>,
 
@@ -267,61 +906,185 @@ pub struct Codegen {
     const_counter: usize,
 
     /// List of all functions in the program
-    function_list: HashMap<String, usize>,
+    function_list: HashMap<String, FuncInfo>,
 
     /// Increments for each new virtual register
     reg_counter: u16,
 
-    /// Holds current depth counter used for scoping
-    cur_depth: u8,
+    /// Variables that are currently in scope, one frame per block with the
+    /// innermost block last. A variable is stored in the local pool slot given
+    /// by its position across all frames
+    scopes: Vec<Vec<Local>>,
+
+    /// Names of the functions that are currently being generated, the
+    /// innermost one last
+    functions: Vec<String>,
 
-    /// Pool of local variables
-    pool: Vec<Vars>,
+    /// <Scope depth, name, name in the function list>
+    /// Functions declared inside of other functions, they are only visible
+    /// until the scope they are declared in ends. Their name in the function
+    /// list is prefixed with the enclosing function, eg. `outer.inner`
+    local_functions: Vec<(usize, String, String)>,
 
     /// Entrypoint within bytecode array (necessary because no main function is
     /// used)
     entry_point: Option<usize>,
 
-    metadata: Metadata,
+    /// <Operand address, function name, argument count>
+    /// Calls and function values whose target was not known when they were
+    /// emitted
+    unresolved: Vec<(usize, Token, Option<usize>)>,
+
+    /// One entry for each loop that is currently being generated
+    loops: Vec<LoopContext>,
+
+    /// Compile errors found while generating the current statements
+    errors: Vec<Error>,
+
+    /// Lints found while generating the current statements, they do not
+    /// prevent the program from running
+    warnings: Vec<Error>,
+
+    /// Warn about declarations that shadow a variable of an outer scope
+    lint_shadowing: bool,
+}
+
+impl Default for Codegen {
+    fn default() -> Self {
+        Codegen::new()
+    }
 }
 
 impl Codegen {
-    /// Convert ast into bytecodearray
-    pub fn bytecode_gen(ast: Vec<Stmt>) -> Program {
-        let mut codegen = Codegen {
+    /// Convert ast into bytecodearray. Every called function has to be
+    /// defined in `ast`. The driver configures lints, so this is only used by
+    /// the tests
+    #[allow(dead_code)]
+    pub fn bytecode_gen(ast: Vec<Stmt>) -> Result<Program, Vec<Error>> {
+        Codegen::new().into_bytecode(ast)
+    }
+
+    /// Convert the ast of a module into bytecode. Calls to functions that are
+    /// not defined in `ast` are left in `Program::unresolved` so the result
+    /// can later be linked against other modules using `Program::merge`
+    pub fn module_gen(ast: Vec<Stmt>) -> Result<Program, Vec<Error>> {
+        Codegen::new().into_module(ast)
+    }
+
+    /// Like `bytecode_gen`, using the lints enabled on this code generator
+    pub fn into_bytecode(mut self, mut ast: Vec<Stmt>) -> Result<Program, Vec<Error>> {
+        ast.extend(implicit_main(&ast));
+        let generated = self.generate(ast);
+
+        // Without any top-level code the program still needs an entry point
+        // for the analyses to start from, it exits right away
+        if self.entry_point.is_none() {
+            let reg = self.get_next_reg();
+            self.emit_instr(
+                BcArr::I(Instr::LoadI),
+                BcArr::V(Value::Number(0.0)),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Reg(reg)),
+            );
+            self.emit_instr(
+                BcArr::I(Instr::Halt),
+                BcArr::V(Value::Reg(reg)),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Nil),
+            );
+        }
+        self.into_program().linked(generated)
+    }
+
+    /// Like `module_gen`, using the lints enabled on this code generator
+    pub fn into_module(mut self, ast: Vec<Stmt>) -> Result<Program, Vec<Error>> {
+        self.generate(ast)?;
+        Ok(self.into_program())
+    }
+
+    /// Warn about variables that are declared with the same name as a
+    /// variable of an enclosing scope, including top-level variables that a
+    /// function declares again
+    pub fn set_lint_shadowing(&mut self, enabled: bool) {
+        self.lint_shadowing = enabled;
+    }
+
+    pub fn new() -> Self {
+        Codegen {
             bytecode: Vec::new(),
+            lines: Vec::new(),
+            line: 0,
             const_pool: Vec::new(),
             const_counter: 0,
             function_list: HashMap::new(),
             reg_counter: 1,
-            cur_depth: 0,
-            pool: Vec::new(),
+            scopes: vec![Vec::new()],
+            functions: Vec::new(),
+            local_functions: Vec::new(),
             entry_point: None,
-            metadata: Metadata::default(),
-        };
+            unresolved: Vec::new(),
+            loops: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            lint_shadowing: false,
+        }
+    }
 
-        for node in ast {
-            codegen.interpret_node(&node);
+    /// Append the code for `ast` to the code generated so far. Variables and
+    /// functions declared by earlier calls stay visible, this is used by the
+    /// repl to compile one line at a time. The returned program holds all of
+    /// the bytecode, its entry point is the start of the newly added code.
+    /// On error the code generator is left unchanged
+    pub fn compile(&mut self, ast: Vec<Stmt>) -> Result<Program, Vec<Error>> {
+        let snapshot = self.clone();
+        let generated = self.generate(ast);
+        let program = self.clone().into_program().linked(generated);
+        if program.is_err() {
+            *self = snapshot;
         }
+        program
+    }
 
-        match codegen.entry_point {
-            Some(v) => Program {
-                bytecode: codegen.bytecode,
-                entry_point: v,
-                function_list: codegen.function_list,
-                const_pool: codegen.const_pool,
-                metadata: codegen.metadata,
-            },
-            None => {
-                panic!("Runtime Error: Could not determine entry point");
-            }
+    /// Generate code for every statement of `ast`
+    fn generate(&mut self, ast: Vec<Stmt>) -> Result<(), Vec<Error>> {
+        self.entry_point = None;
+        self.warnings.clear();
+        for node in ast {
+            self.interpret_node(&node);
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
         }
     }
 
+    /// Record a compile error at the location of `token`. Code generation
+    /// continues so that every error in the program is reported
+    fn error(&mut self, msg: String, token: &Token) {
+        self.errors.push(Error::new(msg, token.line_num, token.col));
+    }
+
+    fn into_program(self) -> Program {
+        // A module that only declares functions has no top-level code to run,
+        // so its entry point is the end of the bytecode
+        let mut program = Program {
+            entry_point: self.entry_point.unwrap_or(self.bytecode.len()),
+            bytecode: self.bytecode,
+            lines: self.lines,
+            function_list: self.function_list,
+            const_pool: self.const_pool,
+            unresolved: self.unresolved,
+            warnings: self.warnings,
+        };
+        program.resolve_calls();
+        program
+    }
+
     /// Emit instructions
     fn emit_instr(&mut self, instr: BcArr, r1: BcArr, r2: BcArr, res: BcArr) {
-        // Set entrypoint on first instruction outside of a function/block
-        if self.cur_depth == 0 && self.entry_point == None {
+        // Set entrypoint on first instruction outside of a function
+        if self.functions.is_empty() && self.entry_point.is_none() {
             self.entry_point = Some(self.bytecode.len());
         }
         match instr {
@@ -335,7 +1098,7 @@ impl Codegen {
                 self.bytecode.push(res);
                 self.bytecode.push(r1);
             }
-            BcArr::I(Instr::LoadP) => {
+            BcArr::I(Instr::LoadP) | BcArr::I(Instr::LoadG) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(res);
                 self.bytecode.push(r1);
@@ -344,7 +1107,7 @@ impl Codegen {
                 self.bytecode.push(instr);
                 self.bytecode.push(r1);
             }
-            BcArr::I(Instr::PushP) => {
+            BcArr::I(Instr::PushP) | BcArr::I(Instr::PushG) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(res);
                 self.bytecode.push(r1);
@@ -358,9 +1121,33 @@ impl Codegen {
                 self.bytecode.push(res);
                 self.bytecode.push(r1);
             }
-            BcArr::I(Instr::Print) => {
+            BcArr::I(Instr::Print) | BcArr::I(Instr::PrintS) | BcArr::I(Instr::Halt) => {
+                self.bytecode.push(instr);
+                self.bytecode.push(r1);
+            }
+            BcArr::I(Instr::Rand) => {
+                self.bytecode.push(instr);
+                self.bytecode.push(res);
+            }
+            BcArr::I(Instr::RandInt) => {
+                self.bytecode.push(instr);
+                self.bytecode.push(res);
+                self.bytecode.push(r1);
+                self.bytecode.push(r2);
+            }
+            BcArr::I(Instr::MakeArray) | BcArr::I(Instr::MakeObject) | BcArr::I(Instr::Len) |
+            BcArr::I(Instr::CallNative) => {
+                self.bytecode.push(instr);
+                self.bytecode.push(res);
+                self.bytecode.push(r1);
+            }
+            BcArr::I(Instr::IndexGet) | BcArr::I(Instr::IndexSet) |
+            BcArr::I(Instr::BitAnd) | BcArr::I(Instr::BitOr) | BcArr::I(Instr::BitXor) |
+            BcArr::I(Instr::Shl) | BcArr::I(Instr::Shr) | BcArr::I(Instr::CallMethod) => {
                 self.bytecode.push(instr);
+                self.bytecode.push(res);
                 self.bytecode.push(r1);
+                self.bytecode.push(r2);
             }
             BcArr::I(Instr::Add) => {
                 self.bytecode.push(instr);
@@ -386,6 +1173,12 @@ impl Codegen {
                 self.bytecode.push(r1);
                 self.bytecode.push(r2);
             }
+            BcArr::I(Instr::Mod) => {
+                self.bytecode.push(instr);
+                self.bytecode.push(res);
+                self.bytecode.push(r1);
+                self.bytecode.push(r2);
+            }
             BcArr::I(Instr::CmpLT) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(res);
@@ -416,6 +1209,12 @@ impl Codegen {
                 self.bytecode.push(r1);
                 self.bytecode.push(r2);
             }
+            BcArr::I(Instr::CmpNe) => {
+                self.bytecode.push(instr);
+                self.bytecode.push(res);
+                self.bytecode.push(r1);
+                self.bytecode.push(r2);
+            }
             BcArr::I(Instr::Jmp) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(r1);
@@ -423,8 +1222,9 @@ impl Codegen {
             BcArr::I(Instr::JmpIf) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(r1);
+                self.bytecode.push(r2);
             }
-            BcArr::I(Instr::Call) => {
+            BcArr::I(Instr::Call) | BcArr::I(Instr::CallR) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(r1);
             }
@@ -434,15 +1234,25 @@ impl Codegen {
             BcArr::I(Instr::JmpIN) => {
                 self.bytecode.push(instr);
                 self.bytecode.push(r1);
+                self.bytecode.push(r2);
             }
             _ => {
                 panic!("Runtime Error: Unimplemented Instruction: {:?}", instr);
             }
         }
+        self.lines.resize(self.bytecode.len(), self.line);
     }
 
     /// Match different kinds of statements
     fn interpret_node(&mut self, node: &Stmt) {
+        match node {
+            Stmt::Variable(t, _) | Stmt::Function(t, _, _) | Stmt::Break(t) |
+            Stmt::Continue(t) | Stmt::ForOf { name: t, .. } => self.line = t.line_num,
+            _ => {}
+        }
+        // Registers only hold temporaries, none of them are still needed once
+        // the statement is done
+        let regs = self.reg_counter;
         match node.clone() {
             Stmt::Function(n, a, e) => {
                 self.function_decl(n, a, e);
@@ -463,20 +1273,74 @@ impl Codegen {
                 self.ret(e);
             }
             Stmt::While(e, b) => {
-                self.while_stmt(e, b);
+                self.while_stmt(e, *b, None);
+            }
+            Stmt::DoWhile(b, e) => {
+                self.do_while_stmt(*b, e);
+            }
+            Stmt::Print(args) => {
+                self.print(args);
+            }
+            // Imports are resolved by the driver before codegen runs
+            Stmt::Import(_) => {}
+            Stmt::Break(t) => {
+                self.loop_jump(t, true);
+            }
+            Stmt::Continue(t) => {
+                self.loop_jump(t, false);
+            }
+            Stmt::ForOf { name, iterable, body } => {
+                self.for_of_stmt(name, iterable, body);
             }
-            Stmt::Print(e) => {
-                self.print(e);
+            Stmt::For { init, cond, increment, body } => {
+                self.for_stmt(init, cond, increment, *body);
             }
         }
+        self.reg_counter = regs;
     }
 
-    /// Return next free virtual register
-    fn get_next_reg(&mut self) -> u16 {
-        let ret = self.reg_counter;
-        self.reg_counter += 1;
-        ret
-    }
+    /// Store `val` at `target[index]`. Arrays and objects are values, so the
+    /// modified value has to be written back to wherever `target` was loaded
+    /// from
+    fn index_assignment(&mut self, target: &Expr, index: &Expr, val: u16) {
+        let arr = self.expression(target);
+        let i = self.expression(index);
+        self.emit_instr(
+            BcArr::I(Instr::IndexSet),
+            BcArr::V(Value::Reg(i)),
+            BcArr::V(Value::Reg(val)),
+            BcArr::V(Value::Reg(arr)),
+        );
+
+        match target {
+            Expr::Variable { name } => {
+                let pool_index = self.get_pool(name);
+                let (_, store) = self.access_instrs(&name.value);
+                self.emit_instr(
+                    BcArr::I(store),
+                    BcArr::V(Value::Reg(arr)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Pool(pool_index)),
+                );
+            }
+            Expr::Index { target, index } => {
+                self.index_assignment(target, index, arr);
+            }
+            Expr::Property { target, name } => {
+                let key = Expr::Literal { literal: Literal::StringLiteral(name.value.clone()) };
+                self.index_assignment(target, &key, arr);
+            }
+            // Temporary arrays are not stored anywhere
+            _ => {}
+        }
+    }
+
+    /// Return next free virtual register
+    fn get_next_reg(&mut self) -> u16 {
+        let ret = self.reg_counter;
+        self.reg_counter += 1;
+        ret
+    }
 
     /// Return next free slot in constant pool
     fn get_next_const(&mut self) -> usize {
@@ -485,81 +1349,319 @@ impl Codegen {
         ret
     }
 
-    /// Return index of value from pool given name
-    fn get_pool(&mut self, name: &str) -> u16 {
-        let arr: Vec<Vars> = self
-            .pool
-            .clone()
-            .into_iter()
-            .filter(|v| v.name.clone() == name)
-            .collect();
-        if arr.is_empty() {
-            panic!("Runtime Error: Variable does not exist");
+    /// Return index of value from pool given name. Scopes are searched from
+    /// the innermost one outwards, so inner declarations shadow outer ones
+    fn get_pool(&mut self, name: &Token) -> u16 {
+        if let Some(index) = self.find_pool(&name.value) {
+            return index;
+        }
+        self.error(format!("Variable '{}' used on line {} does not exist",
+                           name.value, name.line_num), name);
+        0
+    }
+
+    /// Pool index of the variable `name`, None if it is not in scope
+    fn find_pool(&self, name: &str) -> Option<u16> {
+        let (frame, i) = self.lookup(name)?;
+        Some((self.scopes[..frame].iter().map(|s| s.len()).sum::<usize>() + i) as u16)
+    }
+
+    /// Frame and position within that frame of the variable `name`
+    fn lookup(&self, name: &str) -> Option<(usize, usize)> {
+        self.scopes.iter().enumerate().rev().find_map(|(frame, scope)| {
+            scope.iter().position(|v| v.name.value == name).map(|i| (frame, i))
+        })
+    }
+
+    /// Instructions that load and store the variable `name`. Functions access
+    /// top-level variables through the pool of the top-level code, their own
+    /// pool only starts out as a copy of their caller's
+    fn access_instrs(&self, name: &str) -> (Instr, Instr) {
+        match self.lookup(name) {
+            Some((0, _)) if !self.functions.is_empty() => (Instr::LoadG, Instr::PushG),
+            _ => (Instr::LoadP, Instr::PushP),
+        }
+    }
+
+    /// Like `get_pool`, but the variable is also marked as read
+    fn read_pool(&mut self, name: &Token) -> u16 {
+        if let Some((frame, i)) = self.lookup(&name.value) {
+            self.scopes[frame][i].read = true;
         }
-        let max = arr.iter().map(|v| v.depth).max().unwrap();
-        let index = self
-            .pool
+        self.get_pool(name)
+    }
+
+    /// Declare `name` in the innermost scope and return its pool index
+    fn declare(&mut self, name: &Token) -> u16 {
+        if self.scopes.last().unwrap().iter().any(|v| v.name.value == name.value) {
+            self.error(format!("Cannot redeclare already existing variable '{}' on \
+                               line {}", name.value, name.line_num), name);
+        } else {
+            if let Some((frame, i)) = self.lookup(&name.value).filter(|_| self.lint_shadowing) {
+                let outer = self.scopes[frame][i].name.line_num;
+                self.warnings.push(Error::new(
+                    format!("Variable '{}' declared on line {} shadows the variable \
+                             declared on line {}", name.value, name.line_num, outer),
+                    name.line_num, name.col));
+            }
+            self.scopes.last_mut().unwrap().push(Local { name: name.clone(), read: false });
+        }
+        self.get_pool(name)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Leave the innermost scope, its pool slots are reused by later
+    /// declarations. Local variables that were never read are reported, the
+    /// global scope is never left since the repl keeps using it
+    fn end_scope(&mut self) {
+        for local in self.scopes.pop().unwrap() {
+            if !local.read {
+                self.warnings.push(Error::new(
+                    format!("Variable '{}' declared on line {} is never read",
+                            local.name.value, local.name.line_num),
+                    local.name.line_num, local.name.col));
+            }
+        }
+        let depth = self.scopes.len();
+        self.local_functions.retain(|(d, _, _)| *d <= depth);
+    }
+
+    /// Make the functions declared directly in the current scope visible
+    /// before their declaration, so they can be called from anywhere in it.
+    /// Only needed inside of functions, calls to top-level functions are
+    /// resolved by name once everything is generated
+    fn declare_local_functions(&mut self, stmts: &[Stmt]) {
+        let outer = match self.functions.last() {
+            Some(outer) => outer.clone(),
+            None => return,
+        };
+        let depth = self.scopes.len();
+        for stmt in stmts {
+            if let Stmt::Function(name, _, _) = stmt {
+                self.local_functions.push((depth, name.value.clone(),
+                                           format!("{}.{}", outer, name.value)));
+            }
+        }
+    }
+
+    /// Name in the function list of the function that `name` refers to
+    fn function_key(&self, name: &str) -> String {
+        self.local_functions
             .iter()
-            .position(|v| v.depth == max && v.name == name)
-            .unwrap();
-        index as u16
+            .rev()
+            .find(|(_, n, _)| n == name)
+            .map_or_else(|| name.to_string(), |(_, _, key)| key.clone())
     }
 
-    /// Removes every variable at current depth level
-    fn clear_depth(&mut self, depth: u8) {
-        self.pool.retain(|v| v.depth != depth);
+    /// Call the method `name` on `target`. Methods are looked up on the value
+    /// at runtime, only strings have any
+    fn method_call(&mut self, target: &Expr, name: &Token, arguments: &[Expr]) -> u16 {
+        if !native::STRING_METHODS.iter().any(|m| m.name == name.value) {
+            self.error(format!("Unknown method '{}' called on line {}", name.value,
+                               name.line_num), name);
+        } else if !arguments.is_empty() {
+            self.error(format!("method '{}' called on line {} expects 0 arguments",
+                               name.value, name.line_num), name);
+        }
+
+        let start = self.reg_counter;
+        let receiver = self.expression(target);
+        self.line = name.line_num;
+        self.reg_counter = start;
+        let res = self.get_next_reg();
+        self.emit_instr(
+            BcArr::I(Instr::CallMethod),
+            BcArr::V(Value::Reg(receiver)),
+            BcArr::V(Value::StringLiteral(name.value.clone())),
+            BcArr::V(Value::Reg(res)),
+        );
+        res
     }
 
-    /// Interpret if statements
+    /// Interpret if statements. `else if` chains are flattened: every
+    /// condition jumps past its branch with a JmpIN when it is false, and
+    /// every branch that is followed by more code of the chain jumps straight
+    /// to its end. An `if` without an else only takes a single conditional
+    /// jump
+    ///
+    ///     cond 1; JmpIN next 1
+    ///     branch 1; Jmp end
+    ///     next 1: cond 2; JmpIN next 2
+    ///     branch 2; Jmp end
+    ///     next 2: else branch
+    ///     end:
     fn if_stmt(&mut self, expr: Expr, t: Box<Stmt>, f: Option<Box<Stmt>>) {
-        // Sets flag to true/false depending on expression result
-        self.expression(&expr);
+        let mut branches = vec![(expr, t)];
+        let mut otherwise = f;
+        while let Some(stmt) = otherwise {
+            match *stmt {
+                Stmt::If(expr, t, f) => {
+                    branches.push((expr, t));
+                    otherwise = f;
+                }
+                stmt => {
+                    otherwise = Some(Box::new(stmt));
+                    break;
+                }
+            }
+        }
+
         let tmp = self.reg_counter;
-        let offset1 = self.bytecode.len() + 1;
+        let last = branches.len() - 1;
+        let mut end_jumps = Vec::new();
+        for (i, (expr, body)) in branches.into_iter().enumerate() {
+            let cond = self.expression(&expr);
+            let skip = self.bytecode.len() + 1;
+            self.emit_instr(
+                BcArr::I(Instr::JmpIN),
+                BcArr::V(Value::VAddr(0)),
+                BcArr::V(Value::Reg(cond)),
+                BcArr::V(Value::Nil),
+            );
+            self.reg_counter = tmp;
+            self.interpret_node(&body);
+            self.reg_counter = tmp;
+
+            if i != last || otherwise.is_some() {
+                end_jumps.push(self.bytecode.len() + 1);
+                self.emit_instr(
+                    BcArr::I(Instr::Jmp),
+                    BcArr::V(Value::VAddr(0)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Nil),
+                );
+            }
+            let next = self.bytecode.len();
+            self.patch_jmp(skip, next);
+        }
+
+        if let Some(x) = otherwise {
+            self.interpret_node(&x);
+        }
+
+        // Patch in correct offsets after calculating them
+        let end = self.bytecode.len();
+        for pos in end_jumps {
+            self.patch_jmp(pos, end);
+        }
+    }
+
+    /// Interpret while statements. The increment of a for loop runs between
+    /// the body and the condition, `continue` jumps to it
+    fn while_stmt(&mut self, expr: Expr, b: Stmt, increment: Option<Expr>) {
+        let tmp_reg = self.reg_counter;
+        let offset = self.bytecode.len() + 1;
 
         self.emit_instr(
-            BcArr::I(Instr::JmpIf),
+            BcArr::I(Instr::Jmp),
             BcArr::V(Value::VAddr(0)),
             BcArr::V(Value::Nil),
             BcArr::V(Value::Nil),
         );
 
-        let a1 = self.bytecode.len();
+        self.loops.push(LoopContext::default());
+        self.interpret_node(&b);
+        let ctx = self.loops.pop().unwrap();
 
-        let a3 = if let Some(x) = f {
-            let tmp = self.bytecode.len();
-            self.interpret_node(&*x);
-            Some(tmp)
-        } else {
-            Option::None
-        };
+        self.reg_counter = tmp_reg;
+        let next = self.bytecode.len();
+        if let Some(increment) = increment {
+            self.expression(&increment);
+            self.reg_counter = tmp_reg;
+        }
+        let cond = self.bytecode.len();
+        let flag = self.expression(&expr);
+        let jmp1: isize = (self.bytecode.len() - offset + 1) as isize;
 
-        let offset2 = self.bytecode.len() + 1;
         self.emit_instr(
-            BcArr::I(Instr::Jmp),
-            BcArr::V(Value::VAddr(0)),
-            BcArr::V(Value::Nil),
+            BcArr::I(Instr::JmpIf),
+            BcArr::V(Value::VAddr(-jmp1)),
+            BcArr::V(Value::Reg(flag)),
             BcArr::V(Value::Nil),
         );
-
-        let jmp_1: isize = (self.bytecode.len() - offset1 - 1) as isize;
-        self.reg_counter = tmp;
-        let a2 = self.bytecode.len();
-        self.interpret_node(&*t); // Interpret true block
-        let jmp_2: isize = (self.bytecode.len() - offset2 - 1) as isize;
+        let end = self.bytecode.len();
 
         // Patch in correct offsets after calculating them
-        self.bytecode[offset1] = BcArr::V(Value::VAddr(jmp_1));
-        self.bytecode[offset2] = BcArr::V(Value::VAddr(jmp_2));
+        self.patch_jmp(offset, cond);
+        for pos in ctx.breaks {
+            self.patch_jmp(pos, end);
+        }
+        for pos in ctx.continues {
+            self.patch_jmp(pos, next);
+        }
+    }
 
-        self.metadata.if_blocks.insert(a1, (a2, a3));
+    /// Interpret for statements, the initializer gets its own scope around
+    /// the loop
+    fn for_stmt(&mut self, init: Option<Box<Stmt>>, cond: Expr, increment: Option<Expr>,
+                body: Stmt) {
+        self.begin_scope();
+        if let Some(init) = init {
+            self.interpret_node(&init);
+        }
+        self.while_stmt(cond, body, increment);
+        self.end_scope();
     }
 
-    /// Interpret while statements
-    fn while_stmt(&mut self, expr: Expr, b: Box<Stmt>) {
+    /// Interpret do-while statements. The body comes first so it runs once
+    /// before the condition is checked, `continue` jumps to the condition
+    fn do_while_stmt(&mut self, b: Stmt, expr: Expr) {
         let tmp_reg = self.reg_counter;
+        let start = self.bytecode.len();
+
+        self.loops.push(LoopContext::default());
+        self.interpret_node(&b);
+        let ctx = self.loops.pop().unwrap();
+
+        self.reg_counter = tmp_reg;
+        let cond = self.bytecode.len();
+        let flag = self.expression(&expr);
         let offset = self.bytecode.len() + 1;
+        self.emit_instr(
+            BcArr::I(Instr::JmpIf),
+            BcArr::V(Value::VAddr(0)),
+            BcArr::V(Value::Reg(flag)),
+            BcArr::V(Value::Nil),
+        );
+        let end = self.bytecode.len();
+
+        self.patch_jmp(offset, start);
+        for pos in ctx.breaks {
+            self.patch_jmp(pos, end);
+        }
+        for pos in ctx.continues {
+            self.patch_jmp(pos, cond);
+        }
+    }
+
+    /// Interpret for...of statements. The array, its length and the current
+    /// index are kept in registers, the length is read once before the first
+    /// iteration
+    fn for_of_stmt(&mut self, name: Token, iterable: Expr, body: Box<Stmt>) {
+        let arr = self.expression(&iterable);
+        let len = self.get_next_reg();
+        self.emit_instr(
+            BcArr::I(Instr::Len),
+            BcArr::V(Value::Reg(arr)),
+            BcArr::V(Value::Nil),
+            BcArr::V(Value::Reg(len)),
+        );
+        let idx = self.get_next_reg();
+        let one = self.get_next_reg();
+        for (r, n) in [(idx, 0), (one, 1)] {
+            self.emit_instr(
+                BcArr::I(Instr::LoadI),
+                BcArr::V(Value::Int(n)),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Reg(r)),
+            );
+        }
 
+        let tmp_reg = self.reg_counter;
+        let offset = self.bytecode.len() + 1;
         self.emit_instr(
             BcArr::I(Instr::Jmp),
             BcArr::V(Value::VAddr(0)),
@@ -567,34 +1669,116 @@ impl Codegen {
             BcArr::V(Value::Nil),
         );
 
-        self.interpret_node(&*b);
-        self.reg_counter = tmp_reg;
-        self.expression(&expr);
-        let jmp1: isize = (self.bytecode.len() - offset + 1) as isize;
+        // Load the current element into the loop variable and advance the
+        // index before the body runs, so `continue` can jump to the condition
+        let start = self.bytecode.len();
+        self.begin_scope();
+        let elem = self.get_next_reg();
+        self.emit_instr(
+            BcArr::I(Instr::IndexGet),
+            BcArr::V(Value::Reg(arr)),
+            BcArr::V(Value::Reg(idx)),
+            BcArr::V(Value::Reg(elem)),
+        );
+        let index = self.declare(&name);
+        self.emit_instr(
+            BcArr::I(Instr::PushP),
+            BcArr::V(Value::Reg(elem)),
+            BcArr::V(Value::Nil),
+            BcArr::V(Value::Pool(index)),
+        );
+        self.emit_instr(
+            BcArr::I(Instr::Add),
+            BcArr::V(Value::Reg(idx)),
+            BcArr::V(Value::Reg(one)),
+            BcArr::V(Value::Reg(idx)),
+        );
+
+        self.loops.push(LoopContext::default());
+        self.interpret_node(&body);
+        let ctx = self.loops.pop().unwrap();
+        self.end_scope();
 
+        self.reg_counter = tmp_reg;
+        let cond = self.bytecode.len();
+        let flag = self.get_next_reg();
+        self.emit_instr(
+            BcArr::I(Instr::CmpLT),
+            BcArr::V(Value::Reg(idx)),
+            BcArr::V(Value::Reg(len)),
+            BcArr::V(Value::Reg(flag)),
+        );
+        let jmp = self.bytecode.len() + 1;
         self.emit_instr(
             BcArr::I(Instr::JmpIf),
-            BcArr::V(Value::VAddr(-jmp1)),
+            BcArr::V(Value::VAddr(0)),
+            BcArr::V(Value::Reg(flag)),
+            BcArr::V(Value::Nil),
+        );
+        let end = self.bytecode.len();
+        self.reg_counter = tmp_reg;
+
+        self.patch_jmp(offset, cond);
+        self.patch_jmp(jmp, start);
+        for pos in ctx.breaks {
+            self.patch_jmp(pos, end);
+        }
+        for pos in ctx.continues {
+            self.patch_jmp(pos, cond);
+        }
+    }
+
+    /// Patch the jump operand at `pos` so that it jumps to `target`
+    fn patch_jmp(&mut self, pos: usize, target: usize) {
+        let jmp = target as isize - (pos + 1) as isize;
+        self.bytecode[pos] = BcArr::V(Value::VAddr(jmp));
+    }
+
+    /// Emit the jump for a break or continue statement, it is patched once the
+    /// enclosing loop is done
+    fn loop_jump(&mut self, t: Token, is_break: bool) {
+        if self.loops.is_empty() {
+            self.error(format!("'{}' on line {} is not inside of a loop",
+                               t.value, t.line_num), &t);
+        }
+        let pos = self.bytecode.len() + 1;
+        self.emit_instr(
+            BcArr::I(Instr::Jmp),
+            BcArr::V(Value::VAddr(0)),
             BcArr::V(Value::Nil),
             BcArr::V(Value::Nil),
         );
-        let jmp2: isize = (self.bytecode.len() - offset - 13) as isize;
 
-        // Patch in correct offset after calculating it
-        self.bytecode[offset] = BcArr::V(Value::VAddr(jmp2));
+        let ctx = match self.loops.last_mut() {
+            Some(ctx) => ctx,
+            None => return,
+        };
+        if is_break {
+            ctx.breaks.push(pos);
+        } else {
+            ctx.continues.push(pos);
+        }
     }
 
-    /// Interpret a block of code while maintaining proper scopes
+    /// Interpret a block of code while maintaining proper scopes. Statements
+    /// after a return can never run so no code is emitted for them, except
+    /// for function declarations which can be called from before the return
     fn block(&mut self, stmts: Vec<Stmt>) {
-        self.cur_depth += 1;
+        self.begin_scope();
+        self.declare_local_functions(&stmts);
+        let mut returned = false;
         for stmt in stmts.iter() {
+            if returned && !matches!(stmt, Stmt::Function(..)) {
+                continue;
+            }
             self.interpret_node(stmt);
+            returned |= matches!(stmt, Stmt::Return(_));
         }
-        self.clear_depth(self.cur_depth);
-        self.cur_depth -= 1;
+        self.end_scope();
     }
 
-    /// If the function attempts to return a value, load it into r0
+    /// If the function attempts to return a value, load it into r0 before
+    /// returning to the caller
     fn ret(&mut self, expr: Option<Expr>) {
         match expr {
             Some(e) => {
@@ -609,87 +1793,272 @@ impl Codegen {
             None => {
                 self.emit_instr(
                     BcArr::I(Instr::LoadI),
-                    BcArr::V(Value::Number(0.0)),
+                    BcArr::V(Value::Nil),
                     BcArr::V(Value::Nil),
                     BcArr::V(Value::Reg(0)),
                 );
             }
         }
+        self.emit_instr(
+            BcArr::I(Instr::Ret),
+            BcArr::V(Value::Nil),
+            BcArr::V(Value::Nil),
+            BcArr::V(Value::Nil),
+        );
     }
 
     /// Helper to add a new function to the list of functions
-    fn register_function(&mut self, name: String, pos: usize) {
-        if self.function_list.contains_key(&name) {
-            panic!(
-                "Runtime Error: Cannot redeclare function with name: {}",
-                name
-            );
+    fn register_function(&mut self, name: &Token, pos: usize, arity: usize) {
+        if self.function_list.contains_key(&name.value) {
+            self.error(format!("Cannot redeclare function '{}' on line {}",
+                               name.value, name.line_num), name);
+            return;
         }
-        self.function_list.insert(name, pos);
+        self.function_list.insert(name.value.clone(), FuncInfo { addr: pos, arity });
     }
 
     /// Generate code for function declarations
     fn function_decl(&mut self, name: Token, args: Vec<Token>, code: Vec<Stmt>) {
-        let name = name.value;
         let tmp_reg = self.reg_counter;
+
+        // Nested functions and functions declared after top-level code would
+        // otherwise be executed in place, so jump over their body
+        let skip = if !self.functions.is_empty() || self.entry_point.is_some() {
+            self.emit_instr(
+                BcArr::I(Instr::Jmp),
+                BcArr::V(Value::VAddr(0)),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Nil),
+            );
+            Some(self.bytecode.len() - 1)
+        } else {
+            None
+        };
         let pos = self.bytecode.len();
 
-        self.register_function(name, pos);
+        let key = match self.functions.last() {
+            Some(outer) => {
+                let key = format!("{}.{}", outer, name.value);
+                let depth = self.scopes.len();
+                if !self.local_functions.iter().any(|(d, _, k)| *d == depth && *k == key) {
+                    self.local_functions.push((depth, name.value.clone(), key.clone()));
+                }
+                key
+            }
+            None => name.value.clone(),
+        };
+        self.register_function(&Token { value: key.clone(), ..name.clone() }, pos,
+                               args.len());
+        self.function_body(key, args, &code);
+
+        self.reg_counter = tmp_reg;
+        if let Some(skip) = skip {
+            let end = self.bytecode.len();
+            self.patch_jmp(skip, end);
+        }
+    }
 
-        // depth increased to mirror depth of function block
-        self.cur_depth += 1;
-        for arg in args.into_iter() {
-            self.pool.push(Vars {
-                name: arg.value,
-                depth: self.cur_depth,
-            });
+    /// Generate the parameters and body of a function, `key` is its name in
+    /// the function list. A parameter may share its name with a function, it
+    /// then shadows that function inside of the body like any other variable
+    fn function_body(&mut self, key: String, args: Vec<Token>, code: &[Stmt]) {
+        // Arguments share their scope with the body of the function
+        self.functions.push(key);
+        self.begin_scope();
+        for (i, arg) in args.iter().enumerate() {
+            if args[..i].iter().any(|a| a.value == arg.value) {
+                self.error(format!("Duplicate parameter '{}' on line {}", arg.value,
+                                   arg.line_num), arg);
+                continue;
+            }
+            let index = self.declare(arg);
+            // Parameters are part of the signature, they do not need to be used
+            self.scopes.last_mut().unwrap().last_mut().unwrap().read = true;
             self.emit_instr(
                 BcArr::I(Instr::LoadA),
-                BcArr::V(Value::Pool((self.pool.len() - 1) as u16)),
+                BcArr::V(Value::Pool(index)),
                 BcArr::V(Value::Nil),
                 BcArr::V(Value::Nil),
             );
         }
-        self.cur_depth -= 1;
-        self.block(code);
-        self.cur_depth += 1;
 
-        self.emit_instr(
-            BcArr::I(Instr::Ret),
-            BcArr::V(Value::Nil),
-            BcArr::V(Value::Nil),
-            BcArr::V(Value::Nil),
-        );
+        // Loops around the declaration can not be exited from inside of it
+        let loops = std::mem::take(&mut self.loops);
+        for stmt in code.iter() {
+            self.interpret_node(stmt);
+        }
+        self.loops = loops;
+        self.end_scope();
 
-        self.cur_depth -= 1;
-        self.reg_counter = tmp_reg;
+        // Falling off the end of the body returns nil like a bare `return;`,
+        // instead of whatever was left in r0
+        if !ends_in_return(code) {
+            self.ret(None);
+        }
+        self.functions.pop();
     }
 
-    /// Builtins, currently only supports console.log()
-    fn print(&mut self, expr: Expr) {
-        let e = self.expression(&expr);
-        self.emit_instr(
-            BcArr::I(Instr::Print),
-            BcArr::V(Value::Reg(e)),
-            BcArr::V(Value::Nil),
-            BcArr::V(Value::Nil),
-        );
+    /// console.log(), every argument but the last is printed with a trailing
+    /// space so they all end up on a single line
+    fn print(&mut self, args: Vec<Expr>) {
+        let mut regs: Vec<u16> = args.iter().map(|e| self.expression(e)).collect();
+
+        // Without arguments only an empty line is printed
+        if regs.is_empty() {
+            let r = self.get_next_reg();
+            self.emit_instr(
+                BcArr::I(Instr::LoadI),
+                BcArr::V(Value::StringLiteral(String::new())),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Reg(r)),
+            );
+            regs.push(r);
+        }
+
+        let last = regs.len() - 1;
+        for (i, r) in regs.into_iter().enumerate() {
+            let instr = if i == last { Instr::Print } else { Instr::PrintS };
+            self.emit_instr(
+                BcArr::I(instr),
+                BcArr::V(Value::Reg(r)),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Nil),
+            );
+        }
     }
 
-    /// Emit instructions for variable assignment
-    fn assignment(&mut self, name: Token, expr: Option<Expr>) -> u16 {
-        let e = self.expression(&expr.unwrap());
-        let depth = self.cur_depth;
-        let var = Vars {
-            name: name.value.clone(),
-            depth,
+    /// Load a constant into a new register, strings are stored in the
+    /// constant pool
+    fn load_constant(&mut self, v: Value) -> u16 {
+        let res = self.get_next_reg();
+        if let Value::StringLiteral(_) = v {
+            self.const_pool.push(v);
+            let const_index = self.get_next_const();
+            self.emit_instr(
+                BcArr::I(Instr::LoadC),
+                BcArr::V(Value::CPool(const_index)),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Reg(res)),
+            );
+        } else {
+            self.emit_instr(
+                BcArr::I(Instr::LoadI),
+                BcArr::V(v),
+                BcArr::V(Value::Nil),
+                BcArr::V(Value::Reg(res)),
+            );
+        }
+        res
+    }
+
+    /// Emit instructions for calls to builtin functions, including the ones
+    /// in `native::NATIVES`. Returns the result register, or None if `name`
+    /// does not refer to a builtin
+    fn builtin(&mut self, name: &Token, arguments: &[Expr]) -> Option<u16> {
+        let native = native::lookup(&name.value);
+        let arity = match (name.value.as_str(), native) {
+            ("random", _) => 0,
+            ("randomInt", _) => 2,
+            ("exit", _) => 1,
+            (_, Some((_, n))) if n.variadic => {
+                if arguments.len() < n.arity - 1 {
+                    self.error(format!("builtin '{}' called on line {} expects at least {} \
+                                       arguments", name.value, name.line_num, n.arity - 1),
+                               name);
+                    return Some(self.get_next_reg());
+                }
+                arguments.len()
+            }
+            (_, Some((_, n))) => n.arity,
+            _ => return None,
         };
+        if arguments.len() != arity {
+            self.error(format!("builtin '{}' called on line {} expects {} arguments",
+                               name.value, name.line_num, arity), name);
+            return Some(self.get_next_reg());
+        }
 
-        if self.pool.contains(&var) {
-            panic!("Runtime Error: Cannot redeclare already existing variable");
+        let start = self.reg_counter;
+        let mut regs: Vec<u16> = arguments.iter().map(|a| self.expression(a)).collect();
+        let rest_reg = self.get_next_reg();
+        self.line = name.line_num;
+        self.reg_counter = start;
+        let res = self.get_next_reg();
+        match name.value.as_str() {
+            "random" => {
+                self.emit_instr(
+                    BcArr::I(Instr::Rand),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            "randomInt" => {
+                self.emit_instr(
+                    BcArr::I(Instr::RandInt),
+                    BcArr::V(Value::Reg(regs[0])),
+                    BcArr::V(Value::Reg(regs[1])),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            "exit" => {
+                self.emit_instr(
+                    BcArr::I(Instr::Halt),
+                    BcArr::V(Value::Reg(regs[0])),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Nil),
+                );
+            }
+            _ => {
+                let (id, native) = native.unwrap();
+                // The trailing arguments of a variadic native are passed as a
+                // single array
+                if native.variadic {
+                    let rest = regs.split_off(native.arity - 1);
+                    for r in &rest {
+                        self.emit_instr(
+                            BcArr::I(Instr::PushA),
+                            BcArr::V(Value::Reg(*r)),
+                            BcArr::V(Value::Nil),
+                            BcArr::V(Value::Nil),
+                        );
+                    }
+                    self.emit_instr(
+                        BcArr::I(Instr::MakeArray),
+                        BcArr::V(Value::Number(rest.len() as f64)),
+                        BcArr::V(Value::Nil),
+                        BcArr::V(Value::Reg(rest_reg)),
+                    );
+                    regs.push(rest_reg);
+                }
+                for r in &regs {
+                    self.emit_instr(
+                        BcArr::I(Instr::PushA),
+                        BcArr::V(Value::Reg(*r)),
+                        BcArr::V(Value::Nil),
+                        BcArr::V(Value::Nil),
+                    );
+                }
+                self.emit_instr(
+                    BcArr::I(Instr::CallNative),
+                    BcArr::V(Value::Number(id as f64)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
         }
-        self.pool.push(var);
-        let index: u16 = self.get_pool(&name.value);
+        Some(res)
+    }
+
+    /// Emit instructions for variable assignment
+    fn assignment(&mut self, name: Token, expr: Option<Expr>) -> u16 {
+        // Pool slots are reused, so declarations without an initializer still
+        // need to overwrite whatever the slot held before
+        let e = match expr {
+            Some(expr) => self.expression(&expr),
+            None => self.load_constant(Value::Undefined),
+        };
+        let index = self.declare(&name);
         self.emit_instr(
             BcArr::I(Instr::PushP),
             BcArr::V(Value::Reg(e)),
@@ -699,13 +2068,37 @@ impl Codegen {
         index
     }
 
-    /// Emit instructions for expressions and return result register
+    /// Emit instructions for expressions and return result register. Registers
+    /// are allocated like a stack, once the operands of an instruction are
+    /// consumed the registers above `start` are reused for its result
     fn expression(&mut self, expr: &Expr) -> u16 {
-        let mut res = 0;
+        self.folded_expression(expr, &fold(expr))
+    }
+
+    /// Like `expression`, with the compile time values `fold` computed for
+    /// `expr` and its operands
+    fn folded_expression(&mut self, expr: &Expr, folded: &Folded) -> u16 {
+        // Operations on literals are evaluated at compile time
+        if matches!(expr, Expr::Binary { .. } | Expr::Unary { .. }) {
+            if let Some(v) = &folded.value {
+                return self.load_constant(v.clone());
+            }
+        }
+
+        let start = self.reg_counter;
+        let res;
         match expr {
             Expr::Binary { left, op, right } => {
-                let r1 = self.expression(left);
-                let r2 = self.expression(right);
+                let (l, r) = (&folded.operands[0], &folded.operands[1]);
+                let zero = matches!(r.value, Some(Value::Int(0)) | Some(Value::Number(0.0)));
+                if matches!(op.t_type, Divide | Modulo) && l.value.is_some() && zero {
+                    self.error(format!("Constant expression on line {} divides by zero",
+                                       op.line_num), op);
+                }
+                let r1 = self.folded_expression(left, l);
+                let r2 = self.folded_expression(right, r);
+                self.line = op.line_num;
+                self.reg_counter = start;
                 res = self.get_next_reg();
                 match op.t_type {
                     Plus => {
@@ -724,17 +2117,40 @@ impl Codegen {
                             BcArr::V(Value::Reg(res)),
                         );
                     }
-                    Divide => {
+                    Divide => {
+                        self.emit_instr(
+                            BcArr::I(Instr::Div),
+                            BcArr::V(Value::Reg(r1)),
+                            BcArr::V(Value::Reg(r2)),
+                            BcArr::V(Value::Reg(res)),
+                        );
+                    }
+                    Multiply => {
+                        self.emit_instr(
+                            BcArr::I(Instr::Mul),
+                            BcArr::V(Value::Reg(r1)),
+                            BcArr::V(Value::Reg(r2)),
+                            BcArr::V(Value::Reg(res)),
+                        );
+                    }
+                    Modulo => {
                         self.emit_instr(
-                            BcArr::I(Instr::Div),
+                            BcArr::I(Instr::Mod),
                             BcArr::V(Value::Reg(r1)),
                             BcArr::V(Value::Reg(r2)),
                             BcArr::V(Value::Reg(res)),
                         );
                     }
-                    Multiply => {
+                    BitAnd | BitOr | BitXor | ShiftLeft | ShiftRight => {
+                        let instr = match op.t_type {
+                            BitAnd => Instr::BitAnd,
+                            BitOr => Instr::BitOr,
+                            BitXor => Instr::BitXor,
+                            ShiftLeft => Instr::Shl,
+                            _ => Instr::Shr,
+                        };
                         self.emit_instr(
-                            BcArr::I(Instr::Mul),
+                            BcArr::I(instr),
                             BcArr::V(Value::Reg(r1)),
                             BcArr::V(Value::Reg(r2)),
                             BcArr::V(Value::Reg(res)),
@@ -780,91 +2196,225 @@ impl Codegen {
                             BcArr::V(Value::Reg(res)),
                         );
                     }
-                    _ => {
-                        panic!("Runtime Error: Operator not supported: {:#?}", expr);
-                    }
-                }
-            }
-            Expr::Literal { literal } => {
-                match literal {
-                    Literal::Number(i) => {
-                        res = self.get_next_reg();
-                        self.emit_instr(
-                            BcArr::I(Instr::LoadI),
-                            BcArr::V(Value::Number(*i)),
-                            BcArr::V(Value::Nil),
-                            BcArr::V(Value::Reg(res)),
-                        );
-                    }
-                    Literal::StringLiteral(s) => {
-                        self.const_pool.push(Value::StringLiteral(s.clone()));
-                        let const_index = self.get_next_const();
-
-                        //let r1 = self.get_next_reg();
-                        res = self.get_next_reg();
-
+                    NEqual => {
                         self.emit_instr(
-                            BcArr::I(Instr::LoadC),
-                            BcArr::V(Value::CPool(const_index)),
-                            BcArr::V(Value::Nil),
+                            BcArr::I(Instr::CmpNe),
+                            BcArr::V(Value::Reg(r1)),
+                            BcArr::V(Value::Reg(r2)),
                             BcArr::V(Value::Reg(res)),
                         );
                     }
                     _ => {
-                        panic!("Runtime ErrorLiteral type not implemented");
+                        self.error(format!("Operator '{}' on line {} is not supported",
+                                           op.value, op.line_num), op);
                     }
                 }
             }
+            Expr::Literal { literal } => {
+                res = self.load_constant(literal_value(literal));
+            }
+            // A name that is not a variable refers to a function, which is
+            // loaded as a value. Functions that are not generated yet are
+            // patched later
+            Expr::Variable { name } if self.find_pool(&name.value).is_none() => {
+                self.line = name.line_num;
+                let key = self.function_key(&name.value);
+                let addr = match self.function_list.get(&key) {
+                    Some(f) => f.addr,
+                    None => {
+                        self.unresolved.push((self.bytecode.len() + 2,
+                                              Token { value: key, ..name.clone() }, None));
+                        0
+                    }
+                };
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::LoadI),
+                    BcArr::V(Value::Function(addr)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
             Expr::Variable { name } => {
-                let index = self.get_pool(&name.value);
+                self.line = name.line_num;
+                let index = self.read_pool(name);
+                let (load, _) = self.access_instrs(&name.value);
                 res = self.get_next_reg();
                 self.emit_instr(
-                    BcArr::I(Instr::LoadP),
+                    BcArr::I(load),
                     BcArr::V(Value::Pool(index)),
                     BcArr::V(Value::Nil),
                     BcArr::V(Value::Reg(res)),
                 );
             }
             Expr::Grouping { expr } => {
+                res = self.folded_expression(expr, &folded.operands[0]);
+            }
+            Expr::Unary { op, right } if op.t_type == Minus => {
+                let r = self.folded_expression(right, &folded.operands[0]);
+                self.line = op.line_num;
+                let zero = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::LoadI),
+                    BcArr::V(Value::Int(0)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(zero)),
+                );
+                self.reg_counter = start;
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::Sub),
+                    BcArr::V(Value::Reg(zero)),
+                    BcArr::V(Value::Reg(r)),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            Expr::Array { elements } => {
+                // Elements are passed through the arguments stack, so all of
+                // them are evaluated before any is pushed
+                let regs: Vec<u16> =
+                    elements.iter().map(|e| self.expression(e)).collect();
+                for r in &regs {
+                    self.emit_instr(
+                        BcArr::I(Instr::PushA),
+                        BcArr::V(Value::Reg(*r)),
+                        BcArr::V(Value::Nil),
+                        BcArr::V(Value::Nil),
+                    );
+                }
+                self.reg_counter = start;
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::MakeArray),
+                    BcArr::V(Value::Number(regs.len() as f64)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            Expr::Object { entries } => {
+                // Keys and values are pushed in pairs, like array elements
+                // all of them are evaluated first
+                let mut regs = Vec::new();
+                for (key, value) in entries {
+                    regs.push(self.load_constant(Value::StringLiteral(key.clone())));
+                    regs.push(self.expression(value));
+                }
+                for r in &regs {
+                    self.emit_instr(
+                        BcArr::I(Instr::PushA),
+                        BcArr::V(Value::Reg(*r)),
+                        BcArr::V(Value::Nil),
+                        BcArr::V(Value::Nil),
+                    );
+                }
+                self.reg_counter = start;
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::MakeObject),
+                    BcArr::V(Value::Number(entries.len() as f64)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            Expr::Index { target, index } => {
+                let t = self.expression(target);
+                let i = self.expression(index);
+                self.reg_counter = start;
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::IndexGet),
+                    BcArr::V(Value::Reg(t)),
+                    BcArr::V(Value::Reg(i)),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            Expr::Property { target, name } => {
+                let t = self.expression(target);
+                self.line = name.line_num;
+
+                // Any other property is a lookup of the key in an object
+                if name.value == "length" {
+                    self.reg_counter = start;
+                    res = self.get_next_reg();
+                    self.emit_instr(
+                        BcArr::I(Instr::Len),
+                        BcArr::V(Value::Reg(t)),
+                        BcArr::V(Value::Nil),
+                        BcArr::V(Value::Reg(res)),
+                    );
+                } else {
+                    let key = self.load_constant(Value::StringLiteral(name.value.clone()));
+                    self.reg_counter = start;
+                    res = self.get_next_reg();
+                    self.emit_instr(
+                        BcArr::I(Instr::IndexGet),
+                        BcArr::V(Value::Reg(t)),
+                        BcArr::V(Value::Reg(key)),
+                        BcArr::V(Value::Reg(res)),
+                    );
+                }
+            }
+            Expr::IndexAssignment { target, index, expr } => {
                 res = self.expression(expr);
+                self.index_assignment(target, index, res);
             }
             Expr::Assignment { name, expr } => {
-                let s = name.value.clone();
                 let register_index = self.expression(expr);
-                let pool_index = self.get_pool(&s);
+                self.line = name.line_num;
+                let pool_index = self.get_pool(name);
+                let (_, store) = self.access_instrs(&name.value);
 
                 self.emit_instr(
-                    BcArr::I(Instr::PushP),
+                    BcArr::I(store),
                     BcArr::V(Value::Reg(register_index)),
                     BcArr::V(Value::Nil),
                     BcArr::V(Value::Pool(pool_index)),
                 );
+                // The assignment evaluates to the assigned value
+                res = register_index;
             }
             Expr::Call { callee, arguments } => {
-                let pos;
-                // Figure out position of called function
-                match &**callee {
-                    Variable { name } => {
-                        pos = match self.function_list.get(&name.value) {
-                            Some(v) => *v as isize,
-                            None => {
-                                panic!(
-                                    "Runtime Error: function: '{}' that you \
-                                    attempt to call on line {} does not exist",
-                                    name.value, name.line_num
-                                );
-                            }
-                        };
+                if let Variable { name } = &**callee {
+                    if let Some(r) = self.builtin(name, arguments) {
+                        return r;
                     }
-                    _ => {
-                        panic!("Runtime Error: Error during call");
+                }
+                if let Expr::Property { target, name } = &**callee {
+                    return self.method_call(target, name, arguments);
+                }
+
+                // Calls by name are resolved at compile time, if the position
+                // of the function is not known yet the call is patched later.
+                // Functions stored in variables or returned by other
+                // expressions are only known at runtime
+                let direct = match &**callee {
+                    Variable { name } if self.find_pool(&name.value).is_none() => {
+                        let key = self.function_key(&name.value);
+                        Some((self.function_list.get(&key).copied(),
+                              Token { value: key, ..name.clone() }))
+                    }
+                    _ => None,
+                };
+                let target = match direct {
+                    Some(_) => 0,
+                    None => self.expression(callee),
+                };
+                if let Some((Some(f), name)) = &direct {
+                    if f.arity != arguments.len() {
+                        self.error(arity_error(name, f.arity, arguments.len()), name);
                     }
                 }
 
-                // Emit push argument instructions for every argument
-                for arg in arguments.into_iter() {
-                    let register_index = self.expression(arg);
+                // Evaluate every argument before pushing any of them, so calls
+                // nested inside of the arguments do not consume them
+                let arg_regs: Vec<u16> =
+                    arguments.iter().map(|arg| self.expression(arg)).collect();
+                if let Variable { name } = &**callee {
+                    self.line = name.line_num;
+                }
 
+                // Emit push argument instructions for every argument
+                for register_index in arg_regs {
                     self.emit_instr(
                         BcArr::I(Instr::PushA),
                         BcArr::V(Value::Reg(register_index)),
@@ -873,60 +2423,156 @@ impl Codegen {
                     );
                 }
 
+                match direct {
+                    Some((func, name)) => {
+                        if func.is_none() {
+                            self.unresolved.push((self.bytecode.len() + 1, name,
+                                                  Some(arguments.len())));
+                        }
+                        self.emit_instr(
+                            BcArr::I(Instr::Call),
+                            BcArr::V(Value::VAddr(func.map_or(0, |f| f.addr) as isize)),
+                            BcArr::V(Value::Nil),
+                            BcArr::V(Value::Nil),
+                        );
+                    }
+                    None => {
+                        self.emit_instr(
+                            BcArr::I(Instr::CallR),
+                            BcArr::V(Value::Reg(target)),
+                            BcArr::V(Value::Nil),
+                            BcArr::V(Value::Nil),
+                        );
+                    }
+                }
+
+                self.reg_counter = start;
+                res = self.get_next_reg();
+
+                self.emit_instr(
+                    BcArr::I(Instr::LoadR),
+                    BcArr::V(Value::Reg(0)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            Expr::Function { params, body } => {
+                // The body is generated in place, so jump over it
                 self.emit_instr(
-                    BcArr::I(Instr::Call),
-                    BcArr::V(Value::VAddr(pos)),
+                    BcArr::I(Instr::Jmp),
+                    BcArr::V(Value::VAddr(0)),
                     BcArr::V(Value::Nil),
                     BcArr::V(Value::Nil),
                 );
-
+                let skip = self.bytecode.len() - 1;
+                let pos = self.bytecode.len();
+
+                // Not part of the function list, the key only names functions
+                // declared inside of it
+                let key = match self.functions.last() {
+                    Some(outer) => format!("{}.<anonymous@{}>", outer, pos),
+                    None => format!("<anonymous@{}>", pos),
+                };
+                self.function_body(key, params.clone(), body);
+                let end = self.bytecode.len();
+                self.patch_jmp(skip, end);
+
+                self.reg_counter = start;
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::LoadI),
+                    BcArr::V(Value::Function(pos)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+            }
+            Expr::Ternary {
+                cond,
+                then_e,
+                else_e,
+            } => {
+                // Same layout as if-statements, false branch falls through
+                let c = self.expression(cond);
                 res = self.get_next_reg();
+                let tmp = self.reg_counter;
+                let offset1 = self.bytecode.len() + 1;
+                self.emit_instr(
+                    BcArr::I(Instr::JmpIf),
+                    BcArr::V(Value::VAddr(0)),
+                    BcArr::V(Value::Reg(c)),
+                    BcArr::V(Value::Nil),
+                );
 
+                let f = self.expression(else_e);
                 self.emit_instr(
                     BcArr::I(Instr::LoadR),
-                    BcArr::V(Value::Reg(0)),
+                    BcArr::V(Value::Reg(f)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+
+                let offset2 = self.bytecode.len() + 1;
+                self.emit_instr(
+                    BcArr::I(Instr::Jmp),
+                    BcArr::V(Value::VAddr(0)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Nil),
+                );
+
+                let jmp_1: isize = (self.bytecode.len() - offset1 - 1) as isize;
+                self.reg_counter = tmp;
+                let t = self.expression(then_e);
+                self.emit_instr(
+                    BcArr::I(Instr::LoadR),
+                    BcArr::V(Value::Reg(t)),
                     BcArr::V(Value::Nil),
                     BcArr::V(Value::Reg(res)),
                 );
+                let jmp_2: isize = (self.bytecode.len() - offset2 - 1) as isize;
+
+                self.bytecode[offset1] = BcArr::V(Value::VAddr(jmp_1));
+                self.bytecode[offset2] = BcArr::V(Value::VAddr(jmp_2));
+                self.reg_counter = tmp;
             }
             Expr::Logical {
                 l_expr,
                 operator,
                 r_expr,
-            } => match operator {
-                And => {
-                    self.expression(l_expr);
-                    let offset = self.bytecode.len() + 1;
-                    self.emit_instr(
-                        BcArr::I(Instr::JmpIN),
-                        BcArr::V(Value::VAddr(0)),
-                        BcArr::V(Value::Nil),
-                        BcArr::V(Value::Nil),
-                    );
-
-                    let tmp = self.reg_counter;
-                    self.expression(r_expr);
-                    self.reg_counter = tmp;
-                    let jmp: isize = (self.bytecode.len() - offset - 1) as isize;
-                    self.bytecode[offset] = BcArr::V(Value::VAddr(jmp));
-                }
-                Or => {
-                    self.expression(l_expr);
-                    let offset = self.bytecode.len() + 1;
-                    self.emit_instr(
-                        BcArr::I(Instr::JmpIf),
-                        BcArr::V(Value::VAddr(0)),
-                        BcArr::V(Value::Nil),
-                        BcArr::V(Value::Nil),
-                    );
+            } => {
+                // The result is the left operand if it decides the outcome,
+                // otherwise the right one
+                let l = self.expression(l_expr);
+                res = self.get_next_reg();
+                self.emit_instr(
+                    BcArr::I(Instr::LoadR),
+                    BcArr::V(Value::Reg(l)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+                let jmp_instr = match operator {
+                    And => Instr::JmpIN,
+                    Or => Instr::JmpIf,
+                };
+                let offset = self.bytecode.len() + 1;
+                self.emit_instr(
+                    BcArr::I(jmp_instr),
+                    BcArr::V(Value::VAddr(0)),
+                    BcArr::V(Value::Reg(res)),
+                    BcArr::V(Value::Nil),
+                );
 
-                    let tmp = self.reg_counter;
-                    self.expression(r_expr);
-                    self.reg_counter = tmp;
-                    let jmp: isize = (self.bytecode.len() - offset - 1) as isize;
-                    self.bytecode[offset] = BcArr::V(Value::VAddr(jmp));
-                }
-            },
+                let tmp = self.reg_counter;
+                let r = self.expression(r_expr);
+                self.emit_instr(
+                    BcArr::I(Instr::LoadR),
+                    BcArr::V(Value::Reg(r)),
+                    BcArr::V(Value::Nil),
+                    BcArr::V(Value::Reg(res)),
+                );
+                self.reg_counter = tmp;
+                let end = self.bytecode.len();
+                self.patch_jmp(offset, end);
+            }
             _ => {
                 panic!("Expression not yet implemented in codegen: {:#?}", expr);
             }
@@ -934,3 +2580,506 @@ impl Codegen {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser, vm::Interpreter};
+
+    fn compile(source: &str) -> Program {
+        let (stmts, errors) = Parser::new(tokenize(source)).parse();
+        assert!(errors.is_empty(), "failed to parse test program");
+        Codegen::bytecode_gen(stmts).unwrap()
+    }
+
+    #[test]
+    fn numbers_are_keyed_by_their_bits() {
+        let mut counts: HashMap<Value, usize> = HashMap::new();
+        for v in [Value::Number(0.0), Value::Number(-0.0), Value::Number(f64::NAN),
+                  Value::Number(f64::NAN), Value::Number(1.0), Value::Int(1),
+                  Value::Number(0.0)] {
+            *counts.entry(v).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 5);
+        assert_eq!(counts[&Value::Number(0.0)], 2);
+        assert_eq!(counts[&Value::Number(-0.0)], 1);
+        assert_eq!(counts[&Value::Number(f64::NAN)], 2);
+        assert_eq!(counts[&Value::Int(1)], 1);
+    }
+
+    /// Output printed when running `source`
+    fn run(source: &str) -> String {
+        let mut out = Vec::new();
+        Interpreter::with_writer(compile(source), &mut out).interpret().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn cfg_renders_as_dot() {
+        let cfg = compile("var a = 1;\nif (a < 2) { console.log(a); }\nconsole.log(a);")
+            .generate_cfg()
+            .remove(0)
+            .1;
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        // The condition either falls into the body or jumps past it
+        assert_eq!(cfg.blocks.len(), 3);
+        assert_eq!(dot.matches("[label=").count(), 3);
+        assert_eq!(dot.matches(" -> ").count(), 3);
+        assert!(dot.contains("b0 [label=\"IP 0-") && dot.contains("shape=Mdiamond"));
+        assert_eq!(dot.matches("shape=Msquare").count(), 1);
+    }
+
+    #[test]
+    fn disassemble_lists_instructions() {
+        let listing = compile("console.log(1);").disassemble();
+        assert!(listing.contains("< Entry Point >"));
+        assert!(listing.contains("\n   1   LoadI   Reg(1), 1, "));
+        assert!(listing.contains("\n   4   Print   Reg(1), "));
+    }
+
+    #[test]
+    fn else_if_chains_run_the_matching_branch() {
+        let chain = "if (x == 0) { console.log(\"zero\"); } \
+                     else if (x == 1) { console.log(\"one\"); } \
+                     else if (x == 2) { console.log(\"two\"); } \
+                     else { console.log(\"many\"); }";
+        let source = format!("for (var x = 0; x < 4; x = x + 1) {{ {} }}", chain);
+        assert_eq!(run(&source), "zero\none\ntwo\nmany\n");
+
+        // One conditional and one unconditional jump per condition
+        let program = compile(&format!("var x = 5;\n{}", chain));
+        let count = |instr| program.bytecode.iter().filter(|v| **v == BcArr::I(instr)).count();
+        assert_eq!(count(Instr::JmpIN), 3);
+        assert_eq!(count(Instr::Jmp), 3);
+    }
+
+    #[test]
+    fn if_without_else_takes_one_jump() {
+        let program = compile("var x = 5;\nif (x > 3) { console.log(x); }\nconsole.log(0);");
+        let count = |instr| program.bytecode.iter().filter(|v| **v == BcArr::I(instr)).count();
+        assert_eq!((count(Instr::JmpIN), count(Instr::JmpIf), count(Instr::Jmp)), (1, 0, 0));
+        assert_eq!(run("var x = 5;\nif (x > 3) { console.log(x); }\nif (x > 9) { console.log(9); }"),
+                   "5\n");
+    }
+
+    #[test]
+    fn empty_programs_exit_cleanly() {
+        for source in ["", "// nothing to do\n", "function f() {}"] {
+            let program = compile(source);
+            assert!(program.verify().is_ok());
+            assert!(!program.generate_cfg()[0].1.blocks.is_empty());
+            let mut out = Vec::new();
+            let exit = Interpreter::with_writer(program, &mut out).interpret().unwrap();
+            assert_eq!((exit, out.is_empty()), (Some(0), true));
+        }
+    }
+
+    #[test]
+    fn empty_functions_return_nil() {
+        let source = "function g() { return 7; }\nfunction f() {}\nfunction h(x) { x = 1; }\n\
+                      g();\nconsole.log(f(), h(2));";
+        assert_eq!(run(source), "NIL NIL\n");
+
+        let program = compile("function g() { return 7; }\nconsole.log(g());");
+        assert_eq!(program.bytecode.iter().filter(|b| **b == BcArr::I(Instr::Ret)).count(), 1);
+    }
+
+    #[test]
+    fn stats_count_the_generated_code() {
+        let program = compile("function id(x) { return x; }\nconsole.log(\"hi\", id(2));");
+        // id: LoadA, LoadP, LoadR, Ret
+        // top level: LoadC, LoadI, PushA, Call, LoadR, PrintS, Print
+        let stats = program.stats();
+        assert_eq!(stats.instr_count, 11);
+        assert_eq!(stats.bytecode_len, 26);
+        assert_eq!(stats.function_count, 1);
+        assert_eq!(stats.const_pool_size, 1);
+        assert_eq!(stats.max_reg, 2);
+    }
+
+    #[test]
+    fn merged_modules_are_rebased() {
+        let module = |source| {
+            let (stmts, errors) = Parser::new(tokenize(source)).parse();
+            assert!(errors.is_empty(), "failed to parse test program");
+            Codegen::module_gen(stmts).unwrap()
+        };
+        let a = module("console.log(\"a\", twice(3));");
+        let b = module("function twice(n) {\n\
+                          var i = 0;\nvar s = 0;\n\
+                          while (i < 2) { s = s + n; i = i + 1; }\n\
+                          return s;\n}\n\
+                        console.log(\"b\", twice(4));");
+        let (a_len, a_consts) = (a.bytecode.len(), a.const_pool.len());
+        let (b_addr, b_consts) = (b.function_list["twice"].addr, b.const_pool.clone());
+
+        let merged = a.clone().merge(b.clone()).unwrap();
+        assert!(merged.check_linked().is_ok());
+        assert!(merged.verify().is_ok());
+        // The second module starts behind the first and the jump into it
+        assert_eq!(merged.function_list["twice"].addr, b_addr + a_len + 2);
+        assert_eq!(merged.const_pool[a_consts..], b_consts[..]);
+        let strings: Vec<usize> = merged.bytecode[a_len + 2..]
+            .iter()
+            .filter_map(|v| match v {
+                BcArr::V(Value::CPool(c)) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        assert!(!strings.is_empty() && strings.iter().all(|c| *c >= a_consts));
+
+        let mut out = Vec::new();
+        Interpreter::with_writer(merged, &mut out).interpret().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a 6\nb 8\n");
+
+        let err = b.clone().merge(b).unwrap_err();
+        assert_eq!(err.err, "Cannot merge modules, function 'twice' is defined in both");
+    }
+
+    #[test]
+    fn ternary_evaluates_only_the_selected_branch() {
+        assert_eq!(run("var m = (3 > 2) ? 10 : 20;\nconsole.log(m);"), "10\n");
+        let source = "function side(v) { console.log(\"side\", v); return v; }\n\
+                      var a = 1;\nvar b = 2;\n\
+                      console.log(a > b ? side(a) : side(b));\n\
+                      console.log(a < b ? a == 1 ? \"one\" : \"other\" : \"never\");";
+        assert_eq!(run(source), "side 2\n2\none\n");
+    }
+
+    #[test]
+    fn compound_assignments_update_variables() {
+        let source = "var x = 5;\nx += 3;\nconsole.log(x);\n\
+                      x -= 1;\nx *= 4;\nx /= 2;\nconsole.log(x);\n\
+                      var s = \"foo\";\ns += \"bar\";\nconsole.log(s);";
+        assert_eq!(run(source), "8\n14\nfoobar\n");
+    }
+
+    #[test]
+    fn break_and_continue_leave_the_loop_early() {
+        let source = "var i = 0;\nwhile (i < 100) {\n\
+                        if (i == 5) { break; }\nconsole.log(i);\ni = i + 1;\n}\n\
+                      var j = 0;\nwhile (j < 6) {\n\
+                        j = j + 1;\nif (j % 2 == 1) { continue; }\nconsole.log(\"even\", j);\n}";
+        assert_eq!(run(source), "0\n1\n2\n3\n4\neven 2\neven 4\neven 6\n");
+
+        let errors = compile_errors("var i = 0;\nfunction f() { continue; }\nwhile (true) { f(); }");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].err, "'continue' on line 2 is not inside of a loop");
+    }
+
+    #[test]
+    fn function_at_finds_entry_addresses() {
+        let program = compile("function f() { return 1; }\nfunction g(a) { return a; }\nf();");
+        for (name, f) in &program.function_list {
+            assert_eq!(program.function_at(f.addr), Some(name.as_str()));
+        }
+        let addrs: Vec<usize> = program.function_list.values().map(|f| f.addr).collect();
+        for addr in (0..program.bytecode.len()).filter(|a| !addrs.contains(a)) {
+            assert_eq!(program.function_at(addr), None);
+        }
+        assert_eq!(program.function_names().len(), 2);
+    }
+
+    #[test]
+    fn inner_declarations_shadow_outer_ones() {
+        let src = "var x = 1;\n\
+                   { var x = 2; { var x = 3; console.log(x); } console.log(x); }\n\
+                   { var x = 4; console.log(x); }\n\
+                   console.log(x);";
+        assert_eq!(run(src), "3\n2\n4\n1\n");
+    }
+
+    #[test]
+    fn blocks_update_outer_variables() {
+        let src = "var x = 1;\n\
+                   var i = 0;\n\
+                   while (i < 3) { var step = 2; { x = x + step; } i = i + 1; }\n\
+                   { var y = 10; { y = y + x; } console.log(y); }\n\
+                   console.log(x);";
+        assert_eq!(run(src), "17\n7\n");
+    }
+
+    #[test]
+    fn top_level_blocks_are_executed() {
+        assert_eq!(run("{ console.log(1); }\nconsole.log(2);"), "1\n2\n");
+    }
+
+    /// Errors reported when compiling `source`
+    fn compile_errors(source: &str) -> Vec<Error> {
+        let (stmts, errors) = Parser::new(tokenize(source)).parse();
+        assert!(errors.is_empty(), "failed to parse test program");
+        Codegen::bytecode_gen(stmts).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn undefined_function_is_a_compile_error() {
+        let errors = compile_errors("var a = 1;\nconsole.log(foo(a));");
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].col), (2, 13));
+        assert!(errors[0].err.contains("'foo'"));
+    }
+
+    #[test]
+    fn every_compile_error_is_reported() {
+        let src = "console.log(x);\n\
+                   var a = 1;\n\
+                   var a = 2;\n\
+                   function f() { return 1; }\n\
+                   function f() { return 2; }\n\
+                   break;";
+        let lines: Vec<u32> = compile_errors(src).iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 3, 5, 6]);
+    }
+
+    #[test]
+    fn parameters_must_have_distinct_names() {
+        let errors = compile_errors("function f(a, a) {}");
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].err.as_str(), errors[0].col),
+                   ("Duplicate parameter 'a' on line 1", 15));
+
+        // Parameters may shadow functions
+        assert_eq!(run("function g() { return 1; }\n\
+                        function f(g) { return g + 1; }\n\
+                        console.log(f(5), g());"), "6 1\n");
+    }
+
+    #[test]
+    fn failed_compile_leaves_codegen_unchanged() {
+        let mut codegen = Codegen::new();
+        let parse = |src| Parser::new(tokenize(src)).parse().0;
+        codegen.compile(parse("var a = 1;")).unwrap();
+        let len = codegen.bytecode.len();
+        assert!(codegen.compile(parse("var b = 2; var a = b;")).is_err());
+        assert_eq!(codegen.bytecode.len(), len);
+        assert!(codegen.compile(parse("var b = a;")).is_ok());
+    }
+
+    #[test]
+    fn calls_must_match_the_declared_arity() {
+        let errors = compile_errors("function f(a, b) { return a + b; }\nconsole.log(f(1));");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert!(errors[0].err.contains("expects 2 arguments, found 1"));
+
+        // Calls that precede the declaration are checked once it is known
+        let errors = compile_errors("function g() { return f(1, 2, 3); }\n\
+                                     function f(a, b) { return a + b; }");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].err.contains("expects 2 arguments, found 3"));
+
+        assert_eq!(run("function f(a, b) { return a + b; }\nconsole.log(f(1, 2));"), "3\n");
+    }
+
+    #[test]
+    fn for_of_sums_an_array() {
+        let src = "var sum = 0;\nfor (var x of [1, 2, 3]) { sum = sum + x; }\nconsole.log(sum);";
+        assert_eq!(run(src), "6\n");
+    }
+
+    #[test]
+    fn strings_can_be_indexed() {
+        let src = "var s = \"abc\"; console.log(s[1]); console.log(s.length);";
+        assert_eq!(run(src), "b\n3\n");
+        assert_eq!(run("var a = [1, [2, 3]]; console.log(a.length, a[1].length);"), "2 2\n");
+
+        let mut out = Vec::new();
+        let err = Interpreter::with_writer(compile("var s = \"abc\"; console.log(s[3]);"), &mut out)
+            .interpret()
+            .unwrap_err();
+        assert!(err.to_string().contains("out of bounds for string of length 3"));
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_eq!(run("console.log(6 & 3);"), "2\n");
+        assert_eq!(run("console.log(6 | 3, 6 ^ 3, 1 << 4, -16 >> 2, 1 | 2 & 3);"),
+                   "7 5 16 -4 3\n");
+        assert_eq!(run("var a = 1; if (a == 1 && 2 > 1) { console.log(\"ok\"); }"), "ok\n");
+
+        let mut out = Vec::new();
+        let err = Interpreter::with_writer(compile("console.log(1.5 & 1);"), &mut out)
+            .interpret()
+            .unwrap_err();
+        assert!(err.to_string().contains("requires integer operands"));
+    }
+
+    #[test]
+    fn conditions_use_their_own_result() {
+        // The comparison in the first `if` runs after `c` was computed
+        let src = "var a = 1; var b = 2;\n\
+                   var c = a < b;\n\
+                   if (b < a) { console.log(\"wrong\"); }\n\
+                   if (c) { console.log(\"c\"); }";
+        assert_eq!(run(src), "c\n");
+
+        let src = "var done = 1 > 2; var i = 0;\n\
+                   while (i < 3) { i = i + 1; }\n\
+                   if (done) { console.log(\"wrong\"); } else { console.log(i); }";
+        assert_eq!(run(src), "3\n");
+
+        let src = "var x = 5;\n\
+                   if (x > 1 && x < 3) { console.log(\"in\"); } else { console.log(\"out\"); }\n\
+                   console.log(x < 1 || x > 4, x > 1 && x > 9);";
+        assert_eq!(run(src), "out\ntrue false\n");
+    }
+
+    #[test]
+    fn conditions_use_truthiness() {
+        assert_eq!(run("if (\"\") { console.log(1); } else { console.log(2); }"), "2\n");
+        assert_eq!(run("while (0) { console.log(1); } console.log(2);"), "2\n");
+        let src = "var i = 3;\n\
+                   while (i) { console.log(i); i = i - 1; }\n\
+                   if ([0]) { console.log(\"array\"); }";
+        assert_eq!(run(src), "3\n2\n1\narray\n");
+    }
+
+    #[test]
+    fn nested_functions() {
+        let src = "function square(x) { return x * x; }\n\
+                   function sum_squares(a, b) {\n\
+                       function square(x) { return x * x * 1; }\n\
+                       return square(a) + square(b);\n\
+                   }\n\
+                   console.log(sum_squares(2, 3), square(4));";
+        assert_eq!(run(src), "13 16\n");
+
+        let listing = compile(src).disassemble();
+        assert!(listing.contains("< square >"));
+        assert!(listing.contains("< sum_squares.square >"));
+    }
+
+    #[test]
+    fn object_literals() {
+        assert_eq!(run("var o = {\"x\": 42}; console.log(o.x);"), "42\n");
+        assert_eq!(run("var o = {a: 1, \"b\": {c: 2}};\n\
+                        o.b.c = 3;\n\
+                        o[\"d\"] = o.a + o.b.c;\n\
+                        console.log(o, o.missing);"),
+                   "{a: 1, b: {c: 3}, d: 4} NIL\n");
+    }
+
+    #[test]
+    fn do_while_runs_body_first() {
+        assert_eq!(run("var n = 0;\ndo { n = n + 1; } while (false);\nconsole.log(n);"), "1\n");
+        assert_eq!(run("var i = 0;\ndo i = i + 2; while (i < 7);\nconsole.log(i);"), "8\n");
+    }
+
+    #[test]
+    fn unused_variables_are_reported() {
+        let src = "var g = 1;\n\
+                   function f(a, unused_param) {\n\
+                       var used = a;\n\
+                       var unused = 2;\n\
+                       unused = used;\n\
+                       for (var x of [1]) { console.log(x); }\n\
+                       return used;\n\
+                   }";
+        let warnings = compile(src).warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].err, "Variable 'unused' declared on line 4 is never read");
+        assert_eq!(warnings[0].line, 4);
+    }
+
+    #[test]
+    fn shadowed_variables_are_reported() {
+        let src = "var x = 1;\n\
+                   {\n\
+                       var x = 2;\n\
+                       var y = x;\n\
+                       console.log(y);\n\
+                   }\n\
+                   { var y = x; console.log(y); }\n\
+                   console.log(x);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        assert!(Codegen::bytecode_gen(stmts.clone()).unwrap().warnings.is_empty());
+
+        let mut codegen = Codegen::new();
+        codegen.set_lint_shadowing(true);
+        let warnings = codegen.into_bytecode(stmts).unwrap().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].err,
+                   "Variable 'x' declared on line 3 shadows the variable declared on line 1");
+        assert_eq!(warnings[0].line, 3);
+    }
+
+    #[test]
+    fn function_expressions_can_be_called() {
+        assert_eq!(run("var add = function(a,b){return a+b;}; console.log(add(2,3));"), "5\n");
+
+        let program = compile("var n = 1;\nn(2);");
+        let mut out = Vec::new();
+        let err = Interpreter::with_writer(program, &mut out).interpret().unwrap_err();
+        assert!(err.to_string().contains("Only functions can be called"));
+    }
+
+    #[test]
+    fn functions_after_top_level_code_are_skipped() {
+        let src = "console.log(1);\n\
+                   function f(a) { console.log(a); }\n\
+                   f(2);";
+        assert_eq!(run(src), "1\n2\n");
+    }
+
+    #[test]
+    fn constant_expressions_are_folded() {
+        let program = compile("console.log(2 + 3);");
+        let loads: Vec<_> = program.bytecode.windows(3)
+            .filter(|w| w[0] == BcArr::I(Instr::LoadI))
+            .map(|w| w[2].clone())
+            .collect();
+        assert_eq!(loads, vec![BcArr::V(Value::Int(5))]);
+        assert!(!program.bytecode.contains(&BcArr::I(Instr::Add)));
+
+        assert_eq!(run("console.log(2 + 3 * -4, \"a\" + \"b\" + 1, \"1\" == 1, 6 & 3);"),
+                   "-10 ab1 true 2\n");
+        let program = compile("var x = 2; console.log(x * (3 + 4));");
+        assert_eq!(program.bytecode.iter().filter(|b| **b == BcArr::I(Instr::Mul)).count(), 1);
+        assert!(!program.bytecode.contains(&BcArr::I(Instr::Add)));
+
+        let errors = compile_errors("var a = 1;\nconsole.log(a + 4 / (2 - 2));");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert!(errors[0].err.contains("divides by zero"));
+    }
+
+    #[test]
+    fn folded_and_computed_initializers_in_one_function() {
+        let src = "function f(y) { var a = 1 + 2; var b = y + 10; console.log(a, b); } f(100);";
+        assert_eq!(run(src), "3 110\n");
+    }
+
+    #[test]
+    fn code_after_return_is_not_emitted() {
+        let src = "function f(a) {\n\
+                       return g(a);\n\
+                       console.log(\"unreachable\");\n\
+                       a = a * 3;\n\
+                       function g(x) { return x + 1; }\n\
+                   }\n\
+                   console.log(f(1));";
+        let program = compile(src);
+        assert_eq!(program.bytecode.iter().filter(|b| matches!(b, BcArr::I(Instr::Print))).count(),
+                   1);
+        assert!(!program.bytecode.contains(&BcArr::I(Instr::Mul)));
+        assert!(program.const_pool.is_empty());
+        assert_eq!(run(src), "2\n");
+    }
+
+    #[test]
+    fn registers_are_reused() {
+        let sum = vec!["a * (a - 1)"; 50].join(" + ");
+        let src = format!("var a = 3;\nvar b = {};\nconsole.log(b);\nconsole.log(b + {});",
+                          sum, sum);
+        let program = compile(&src);
+        let regs: BTreeSet<u16> = program.bytecode.iter()
+            .filter_map(|b| match b {
+                BcArr::V(Value::Reg(r)) => Some(*r),
+                _ => None,
+            })
+            .collect();
+        assert!(regs.len() <= 6, "{} registers used", regs.len());
+        assert_eq!(run(&src), "300\n600\n");
+    }
+}
@@ -1,10 +1,10 @@
 use crate::{
-    codegen::{BcArr, Program, Cfg, Block},
+    codegen::{BcArr, Program, Cfg, Block, Instr, Value},
     vm::Interpreter,
-    Instr,
 };
 
 use rustc_hash::FxHashMap;
+use std::{fmt, rc::Rc};
 
 /*
 #[derive(Clone, Debug, Default)]
@@ -37,14 +37,76 @@ struct BoolDomain {
 }
 */
 
-#[derive(Clone, Debug, Default)]
-struct Interval {
-    bottom: usize,
-    top: usize
+/// Abstract domain used to represent numbers during the analysis
+pub trait Domain: Clone + fmt::Debug + PartialEq {
+    /// Value that covers every possible number
+    fn top() -> Self;
+
+    /// Abstraction of the constant `n`
+    fn loadi_const(n: f64) -> Self;
+
+    /// Abstraction of the number `v`. Only domains that tell integers and
+    /// floats apart have to look at more than its value as a float
+    fn from_value(v: &Value) -> Self {
+        Self::loadi_const(Interpreter::unpack_number(v))
+    }
+
+    /// Smallest value that covers both `self` and `other`
+    fn join(&self, other: &Self) -> Self;
+
+    /// Used instead of `join` where loops merge back, has to guarantee that
+    /// the analysis reaches a fixpoint. Domains of finite height can just join
+    fn widen(&self, other: &Self) -> Self {
+        self.join(other)
+    }
+
+    /// Refine `self`, a result of widening, with a newer value `other`. Has
+    /// to stop refining after finitely many steps. Keeps `self` by default
+    fn narrow(&self, _other: &Self) -> Self {
+        self.clone()
+    }
+
+    fn add(&self, other: &Self) -> Self;
+    fn sub(&self, other: &Self) -> Self;
+    fn mul(&self, other: &Self) -> Self;
+    fn div(&self, other: &Self) -> Self;
+    fn modulo(&self, other: &Self) -> Self;
+
+    /// Whether `self < other` holds for all, none or only some of the values
+    fn less_than(&self, other: &Self) -> BoolState;
+
+    /// Whether `self == other` holds for all, none or only some of the values
+    fn equals(&self, other: &Self) -> BoolState;
+
+    /// Restrict `self` and `other` to the values for which `self < other`
+    /// holds, or `self <= other` if `or_equal` is set. Returns None if there
+    /// are no such values. Domains that can not express this keep both as is
+    fn assume_less(&self, other: &Self, _or_equal: bool) -> Option<(Self, Self)> {
+        Some((self.clone(), other.clone()))
+    }
+
+    /// Whether the arithmetic instruction `instr` applied to `self` and
+    /// `other` leaves the range of integers for all, none or only some of the
+    /// values. Domains that do not track ranges never report overflows
+    fn overflows(&self, _instr: Instr, _other: &Self) -> BoolState {
+        BoolState::F
+    }
+
+    /// True if the value is known to be 0
+    fn is_zero(&self) -> bool;
+
+    /// True if the value may be 0
+    fn may_be_zero(&self) -> bool;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Interval {
+    bottom: i64,
+    top: i64
 }
 
 impl Interval {
-    pub fn new(bottom: usize, top: usize) -> Self {
+    pub fn new(bottom: i64, top: i64) -> Self {
         Self {
             bottom,
             top,
@@ -52,70 +114,759 @@ impl Interval {
     }
 }
 
+impl Domain for Interval {
+    /// Interval that covers every representable value
+    fn top() -> Self {
+        Self::new(i64::MIN, i64::MAX)
+    }
 
-#[derive(Clone, Debug, Default)]
-enum BoolState {
+    /// Fractions are rounded outwards so the interval still contains them
+    fn loadi_const(n: f64) -> Self {
+        if !n.is_finite() {
+            return Self::top();
+        }
+        Self::new(n.floor() as i64, n.ceil() as i64)
+    }
+
+    fn add(&self, other: &Interval) -> Self {
+        Self::new(self.bottom.saturating_add(other.bottom),
+                  self.top.saturating_add(other.top))
+    }
+
+    fn sub(&self, other: &Interval) -> Self {
+        Self::new(self.bottom.saturating_sub(other.top),
+                  self.top.saturating_sub(other.bottom))
+    }
+
+    /// Signs may flip, so the bounds are the extremes of all corner products
+    fn mul(&self, other: &Interval) -> Self {
+        let corners = [
+            self.bottom.saturating_mul(other.bottom),
+            self.bottom.saturating_mul(other.top),
+            self.top.saturating_mul(other.bottom),
+            self.top.saturating_mul(other.top),
+        ];
+        Self::new(*corners.iter().min().unwrap(), *corners.iter().max().unwrap())
+    }
+
+    /// Division is done on floats at runtime, so the bounds are rounded
+    /// outwards. A divisor that may be 0 makes the result unknown
+    fn div(&self, other: &Interval) -> Self {
+        if other.may_be_zero() {
+            return Self::top();
+        }
+        let corners = [
+            self.bottom as f64 / other.bottom as f64,
+            self.bottom as f64 / other.top as f64,
+            self.top as f64 / other.bottom as f64,
+            self.top as f64 / other.top as f64,
+        ];
+        let min = corners.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = corners.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        Self::new(min.floor() as i64, max.ceil() as i64)
+    }
+
+    /// `x % m` takes the sign of `x` and its magnitude is below |m| as well as
+    /// |x|. If the divisor may be 0 the result is unknown, so fall back to top.
+    fn modulo(&self, other: &Interval) -> Self {
+        if other.may_be_zero() {
+            return Self::top();
+        }
+
+        // Largest possible magnitude of the result
+        let k = std::cmp::max(other.bottom.unsigned_abs(), other.top.unsigned_abs())
+            .saturating_sub(1).min(i64::MAX as u64) as i64;
+        if self.bottom >= 0 {
+            Self::new(0, std::cmp::min(self.top, k))
+        } else if self.top <= 0 {
+            Self::new(std::cmp::max(self.bottom, -k), 0)
+        } else {
+            Self::new(-k, k)
+        }
+    }
+
+    /// Smallest interval that contains both `self` and `other`
+    fn join(&self, other: &Interval) -> Self {
+        Self::new(std::cmp::min(self.bottom, other.bottom),
+                  std::cmp::max(self.top, other.top))
+    }
+
+    /// Widen `self` with a newer interval `other`. Any bound that is still
+    /// growing is pushed to the extreme, which guarantees that loops reach a
+    /// fixpoint
+    fn widen(&self, other: &Interval) -> Self {
+        let bottom = if other.bottom < self.bottom { i64::MIN } else { self.bottom };
+        let top = if other.top > self.top { i64::MAX } else { self.top };
+        Self::new(bottom, top)
+    }
+
+    /// Only bounds that widening pushed to the extreme are replaced by the
+    /// bounds of `other`, so each bound can be narrowed at most once
+    fn narrow(&self, other: &Interval) -> Self {
+        let bottom = if self.bottom == i64::MIN { other.bottom } else { self.bottom };
+        let top = if self.top == i64::MAX { other.top } else { self.top };
+        Self::new(bottom, top)
+    }
+
+    fn less_than(&self, other: &Interval) -> BoolState {
+        if self.top < other.bottom {
+            BoolState::T
+        } else if self.bottom >= other.top {
+            BoolState::F
+        } else {
+            BoolState::Unknown
+        }
+    }
+
+    fn equals(&self, other: &Interval) -> BoolState {
+        if self.bottom == self.top && *self == *other {
+            BoolState::T
+        } else if self.top < other.bottom || other.top < self.bottom {
+            BoolState::F
+        } else {
+            BoolState::Unknown
+        }
+    }
+
+    fn assume_less(&self, other: &Interval, or_equal: bool) -> Option<(Self, Self)> {
+        let gap = if or_equal { 0 } else { 1 };
+        let a = Self::new(self.bottom,
+                          std::cmp::min(self.top, other.top.saturating_sub(gap)));
+        let b = Self::new(std::cmp::max(other.bottom, self.bottom.saturating_add(gap)),
+                          other.top);
+        if a.bottom > a.top || b.bottom > b.top {
+            return None;
+        }
+        Some((a, b))
+    }
+
+    /// The exact bounds of the result are computed on i128. Bounds at the
+    /// extremes of i64 stand for unknown values rather than large ones, so
+    /// operands with such a bound are not reported
+    fn overflows(&self, instr: Instr, other: &Interval) -> BoolState {
+        let bounded = |i: &Interval| i.bottom != i64::MIN && i.top != i64::MAX;
+        if !bounded(self) || !bounded(other) {
+            return BoolState::F;
+        }
+        let (a, b) = (self.bottom as i128, self.top as i128);
+        let (c, d) = (other.bottom as i128, other.top as i128);
+        let corners = match instr {
+            Instr::Add => vec![a + c, b + d],
+            Instr::Sub => vec![a - d, b - c],
+            Instr::Mul => vec![a * c, a * d, b * c, b * d],
+            _ => return BoolState::F,
+        };
+        let min = *corners.iter().min().unwrap();
+        let max = *corners.iter().max().unwrap();
+        let range = i64::MIN as i128..=i64::MAX as i128;
+        if min > i64::MAX as i128 || max < i64::MIN as i128 {
+            BoolState::T
+        } else if !range.contains(&min) || !range.contains(&max) {
+            BoolState::Unknown
+        } else {
+            BoolState::F
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.bottom == 0 && self.top == 0
+    }
+
+    fn may_be_zero(&self) -> bool {
+        self.bottom <= 0 && self.top >= 0
+    }
+}
+
+/// Exact value of a number if it is the same on every path, used to propagate
+/// constants. Numbers stay integers or floats and results are computed like
+/// in the vm, so a known value can replace the computation that produced it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    /// No value, eg. an operand that was never assigned
+    Bottom,
+    Known(Value),
+    Top,
+}
+
+impl Constant {
+    /// Apply `instr` like the vm would. An operation that fails at runtime
+    /// has no known result, so the error is still raised
+    fn apply(&self, instr: Instr, other: &Self) -> Self {
+        match (self, other) {
+            (Constant::Bottom, _) | (_, Constant::Bottom) => Constant::Bottom,
+            (Constant::Known(a), Constant::Known(b)) => {
+                Interpreter::binary_op(instr, a, b).map_or(Constant::Top, Constant::Known)
+            }
+            _ => Constant::Top,
+        }
+    }
+
+    /// Outcome of the comparison `instr`. NaN compares false both ways, so
+    /// comparisons involving it are left unknown as the analysis derives
+    /// some comparisons by negating others
+    fn compare(&self, instr: Instr, other: &Self) -> BoolState {
+        let nan = |c: &Constant| matches!(c, Constant::Known(Value::Number(n)) if n.is_nan());
+        if nan(self) || nan(other) {
+            return BoolState::Unknown;
+        }
+        match self.apply(instr, other) {
+            Constant::Known(Value::Bool(true)) => BoolState::T,
+            Constant::Known(Value::Bool(false)) => BoolState::F,
+            _ => BoolState::Unknown,
+        }
+    }
+}
+
+impl Domain for Constant {
+    fn top() -> Self {
+        Constant::Top
+    }
+
+    fn loadi_const(n: f64) -> Self {
+        Constant::Known(Value::Number(n))
+    }
+
+    fn from_value(v: &Value) -> Self {
+        Constant::Known(v.clone())
+    }
+
+    fn join(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Constant::Bottom, c) | (c, Constant::Bottom) => c.clone(),
+            (a, b) if a == b => a.clone(),
+            _ => Constant::Top,
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        self.apply(Instr::Add, other)
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        self.apply(Instr::Sub, other)
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        self.apply(Instr::Mul, other)
+    }
+
+    fn div(&self, other: &Self) -> Self {
+        self.apply(Instr::Div, other)
+    }
+
+    fn modulo(&self, other: &Self) -> Self {
+        self.apply(Instr::Mod, other)
+    }
+
+    fn less_than(&self, other: &Self) -> BoolState {
+        self.compare(Instr::CmpLT, other)
+    }
+
+    fn equals(&self, other: &Self) -> BoolState {
+        self.compare(Instr::CmpEq, other)
+    }
+
+    fn is_zero(&self) -> bool {
+        matches!(self, Constant::Known(v)
+                 if Interpreter::check_num(v) && Interpreter::unpack_number(v) == 0.0)
+    }
+
+    /// NaN is falsy like 0, so it counts as possibly zero
+    fn may_be_zero(&self) -> bool {
+        match self {
+            Constant::Bottom => false,
+            Constant::Known(v) => !v.is_truthy(),
+            Constant::Top => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum BoolState {
     #[default] Unknown,
     T,
     F,
     Either,
 }
 
-#[derive(Clone, Debug)]
-enum Mem {
-    I(Interval),
+impl BoolState {
+    fn not(&self) -> BoolState {
+        match self {
+            BoolState::T => BoolState::F,
+            BoolState::F => BoolState::T,
+            b => b.clone(),
+        }
+    }
+}
+
+/// Abstract value of a memory location, either a number in domain `D` or a
+/// boolean
+#[derive(Clone, Debug, PartialEq)]
+enum Mem<D: Domain> {
+    I(D),
     B(BoolState),
 }
 
-/// Used to index memory-map, indicating if this is reg or pool-indexed memory
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+impl<D: Domain> Mem<D> {
+    fn join(&self, other: &Mem<D>) -> Mem<D> {
+        match (self, other) {
+            (Mem::I(a), Mem::I(b)) => Mem::I(a.join(b)),
+            (Mem::B(a), Mem::B(b)) if a == b => Mem::B(a.clone()),
+            (Mem::B(_), Mem::B(_)) => Mem::B(BoolState::Either),
+            // Memory location was reused for a different type
+            _ => Mem::I(D::top()),
+        }
+    }
+
+    fn narrow(&self, other: &Mem<D>) -> Mem<D> {
+        match (self, other) {
+            (Mem::I(a), Mem::I(b)) => Mem::I(a.narrow(b)),
+            _ => self.clone(),
+        }
+    }
+
+    fn widen(&self, other: &Mem<D>) -> Mem<D> {
+        match (self, other) {
+            (Mem::I(a), Mem::I(b)) => Mem::I(a.widen(b)),
+            (Mem::B(a), Mem::B(b)) if a == b => Mem::B(a.clone()),
+            (Mem::B(_), Mem::B(_)) => Mem::B(BoolState::Either),
+            // Memory location was reused for a different type
+            _ => Mem::I(D::top()),
+        }
+    }
+}
+
+/// Used to index memory-map, indicating if this is reg or pool-indexed memory.
+/// Arguments that were pushed for the next call are kept in argument slots
+#[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 enum MemIdx {
     R(usize),
     P(usize),
+    A(usize),
+}
+
+/// Abstract value of every memory location at a given program point
+type State<D> = FxHashMap<MemIdx, Mem<D>>;
+
+/// How often a block may be revisited while narrowing
+const NARROWING_ROUNDS: usize = 8;
+
+/// How deep calls are followed into their callees. Results of calls below this
+/// depth, eg. of recursive functions, are unknown
+const CALL_DEPTH_LIMIT: usize = 4;
+
+/// Problem found by the analysis that may cause a runtime error
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalysisWarning {
+    /// Address of the instruction the warning is about
+    pub ip: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AnalysisWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Warning: {} at IP={}.", self.msg, self.ip)
+    }
 }
 
 #[derive(Clone, Debug)]
-pub struct AbstractInterpreter {
+pub struct AbstractInterpreter<D: Domain> {
     /// Holds bytecode that is used to retrieve instructions and operands
-    bytecode: Vec<BcArr>,
+    bytecode: Rc<[BcArr]>,
 
     /// Holds program counter
     ip: usize,
 
     /// Interpreter has 2 different types of memory locations (Reg & Pool), both of which can 
-    memory: FxHashMap<MemIdx, Mem>,
+    memory: State<D>,
+
+    /// Warnings collected while analyzing, at most one per instruction
+    pub warnings: Vec<AnalysisWarning>,
+
+    /// Address ranges (inclusive) of blocks that can never be reached
+    pub dead_code: Vec<(usize, usize)>,
+
+    /// How often the state at a loop head may grow before it is widened
+    widening_threshold: usize,
+
+    /// State at the end of the cfg after the last call to `run`
+    exit_state: State<D>,
+
+    /// Input state of every reachable block after the last call to `run`
+    block_states: FxHashMap<usize, State<D>>,
+
+    /// Global variables that functions store to, calls may change them
+    stored_globals: Vec<MemIdx>,
+
+    /// Cfg of every function keyed by its address, calls are analyzed by
+    /// running the callee's cfg
+    functions: Rc<FxHashMap<usize, Cfg>>,
+
+    /// State at the start of the cfg, holds the arguments of a callee
+    entry_state: State<D>,
+
+    /// Number of calls that were followed to get to the analyzed cfg
+    call_depth: usize,
 }
 
-impl AbstractInterpreter {
+impl<D: Domain> AbstractInterpreter<D> {
     pub fn new(program: &Program) -> Self {
+        let functions = program.generate_cfg().into_iter()
+            .filter_map(|(name, cfg)| Some((program.function_list.get(&name)?.addr, cfg)))
+            .collect();
         Self {
-            bytecode: program.bytecode.clone(),
+            bytecode: program.bytecode.clone().into(),
             ip: program.entry_point,
             memory: FxHashMap::default(),
+            warnings: Vec::new(),
+            dead_code: Vec::new(),
+            widening_threshold: 0,
+            exit_state: State::default(),
+            block_states: FxHashMap::default(),
+            stored_globals: program.bytecode.windows(2)
+                .filter_map(|w| match w {
+                    [BcArr::I(Instr::PushG), BcArr::V(Value::Pool(p))] => {
+                        Some(MemIdx::P(*p as usize))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            functions: Rc::new(functions),
+            entry_state: State::default(),
+            call_depth: 0,
         }
     }
 
+    /// Only widen the state at a loop head after it has grown `n` times.
+    /// Loops that finish within `n` iterations then keep their exact bounds
+    pub fn set_widening_threshold(&mut self, n: usize) {
+        self.widening_threshold = n;
+    }
+
+    /// Analyze the cfg until a fixpoint is reached, narrow the states that
+    /// were widened on the way and compute the state at the end of the cfg,
+    /// joined over every block that exits it
     pub fn run(&mut self, cfg: &Cfg) {
         let mut block_worklist = vec![0];
-        let mut handled_blocks: FxHashMap<usize, usize> = FxHashMap::default();
+
+        // Last input state that each block was analyzed with. A block is
+        // analyzed again whenever the state flowing into it changes
+        let mut handled_blocks: FxHashMap<usize, State<D>> = FxHashMap::default();
+        handled_blocks.insert(0, self.entry_state.clone());
+
+        // Latest state that flowed along each (source, destination) edge
+        let mut edge_states: FxHashMap<(usize, usize), State<D>> = FxHashMap::default();
+
+        // Number of times the state at each loop head was joined instead of
+        // widened, and the loop heads that were widened at least once
+        let mut grown: FxHashMap<usize, usize> = FxHashMap::default();
+        let mut widened: Vec<usize> = Vec::new();
 
         while !block_worklist.is_empty() {
             let block_id = block_worklist.remove(0);
+            let block = cfg.blocks.get(&block_id).expect("CFG references non-existing block");
+
+            self.memory = handled_blocks[&block_id].clone();
+            self.handle_block(block);
+
+            let start = block.instrs.first().map(|i| i.0).unwrap_or(0);
+            for (e, dst) in block.edges.iter().zip(&block.dsts) {
+                if !self.edge_feasible(block, *dst) {
+                    continue;
+                }
+                let incoming = match self.refine_edge(block, *dst) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                edge_states.insert((block_id, *e), incoming.clone());
+                let state = match handled_blocks.get(e) {
+                    // Jumping backwards means this is a loop, widen so it
+                    // converges after a finite number of iterations
+                    Some(old) if *dst <= start => {
+                        let count = grown.entry(*e).or_insert(0);
+                        if *count < self.widening_threshold {
+                            let joined = Self::join(old, &incoming);
+                            if joined != *old {
+                                *count += 1;
+                            }
+                            joined
+                        } else {
+                            if !widened.contains(e) {
+                                widened.push(*e);
+                            }
+                            Self::widen(old, &incoming)
+                        }
+                    }
+                    // Merge point, the block has to cover every incoming state
+                    Some(old) => Self::join(old, &incoming),
+                    None => incoming,
+                };
+                if handled_blocks.get(e) == Some(&state) {
+                    continue;
+                }
+                handled_blocks.insert(*e, state);
+                if !block_worklist.contains(e) {
+                    block_worklist.push(*e);
+                }
+            }
+        }
+
+        self.narrow_widened(cfg, widened, &mut handled_blocks, &mut edge_states);
 
-            // Don't repeat same block twice
-            // This is bad, can only handle 1 loop iteration
-            if handled_blocks.get(&block_id).is_some() {
+        // Blocks that never received a state are only reachable through edges
+        // that were pruned
+        let mut dead_code: Vec<(usize, usize)> = cfg
+            .blocks
+            .iter()
+            .filter(|(id, _)| !handled_blocks.contains_key(id))
+            .filter_map(|(_, block)| {
+                let (first, _) = block.instrs.first()?;
+                let (last, instr) = block.instrs.last()?;
+                let size = match instr {
+                    BcArr::I(i) => i.size(),
+                    _ => 1,
+                };
+                Some((*first, last + size - 1))
+            })
+            .collect();
+        dead_code.sort();
+        self.dead_code = dead_code;
+
+        let mut exit_state: Option<State<D>> = None;
+        let mut exits: Vec<_> = cfg.blocks.iter()
+            .filter(|(id, block)| block.edges.is_empty() && handled_blocks.contains_key(id))
+            .collect();
+        exits.sort_by_key(|(id, _)| **id);
+        for (id, block) in exits {
+            self.memory = handled_blocks[id].clone();
+            self.handle_block(block);
+            exit_state = Some(match exit_state {
+                Some(s) => Self::join(&s, &self.memory),
+                None => self.memory.clone(),
+            });
+        }
+        self.block_states = handled_blocks;
+
+        self.exit_state = exit_state.unwrap_or_default();
+    }
+
+    /// Print the state at the end of the cfg after the last call to `run`
+    pub fn print_exit_state(&self) {
+        let mut vars: Vec<_> = self.exit_state.iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        for var in &vars {
+            println!("{:?}", var);
+        }
+    }
+
+    /// Recover precision that widening gave up. Starting at the `widened` loop
+    /// heads, the input state of a block is recomputed from its incoming
+    /// edges and the block is analyzed again if that changed it. Loop heads
+    /// narrow their old state, which can only replace bounds that were
+    /// widened, and every block is updated at most `NARROWING_ROUNDS` times so
+    /// this terminates
+    fn narrow_widened(&mut self, cfg: &Cfg, widened: Vec<usize>,
+                      states: &mut FxHashMap<usize, State<D>>,
+                      edge_states: &mut FxHashMap<(usize, usize), State<D>>) {
+        let mut rounds: FxHashMap<usize, usize> = FxHashMap::default();
+        let mut block_worklist = widened.clone();
+
+        while !block_worklist.is_empty() {
+            let block_id = block_worklist.remove(0);
+
+            // The input has to cover the states along every incoming edge
+            let mut input = if block_id == 0 { Some(self.entry_state.clone()) } else { None };
+            for ((_, to), state) in edge_states.iter() {
+                if *to == block_id {
+                    input = Some(match input {
+                        Some(s) => Self::join(&s, state),
+                        None => state.clone(),
+                    });
+                }
+            }
+            let Some(input) = input else { continue };
+            let state = if widened.contains(&block_id) {
+                Self::narrow(&states[&block_id], &input)
+            } else {
+                input
+            };
+
+            let count = rounds.entry(block_id).or_insert(0);
+            if states[&block_id] == state || *count >= NARROWING_ROUNDS {
                 continue;
             }
+            *count += 1;
+            states.insert(block_id, state.clone());
 
             let block = cfg.blocks.get(&block_id).expect("CFG references non-existing block");
-            handled_blocks.insert(block_id, 0);
-            self.handle_block(&block);
-            block.edges.iter().for_each(|e| block_worklist.push(*e));
+            self.memory = state;
+            self.handle_block(block);
+            for (e, dst) in block.edges.iter().zip(&block.dsts) {
+                let incoming = if self.edge_feasible(block, *dst) {
+                    self.refine_edge(block, *dst)
+                } else {
+                    None
+                };
+                match incoming {
+                    Some(state) => edge_states.insert((block_id, *e), state),
+                    None => edge_states.remove(&(block_id, *e)),
+                };
+                if !block_worklist.contains(e) {
+                    block_worklist.push(*e);
+                }
+            }
         }
-        for var in &self.memory {
-            println!("{:?}", var);
+    }
+
+    /// Join every memory location of `a` with its value in `b`
+    fn join(a: &State<D>, b: &State<D>) -> State<D> {
+        let mut res = a.clone();
+        for (idx, val) in b {
+            let joined = match a.get(idx) {
+                Some(v) => v.join(val),
+                None => val.clone(),
+            };
+            res.insert(idx.clone(), joined);
         }
+        res
+    }
+
+    /// Narrow every memory location of `old` with its value in `new`
+    fn narrow(old: &State<D>, new: &State<D>) -> State<D> {
+        let mut res = old.clone();
+        for (idx, val) in new {
+            if let Some(o) = old.get(idx) {
+                res.insert(idx.clone(), o.narrow(val));
+            }
+        }
+        res
+    }
+
+    /// Widen every memory location of `old` with its value in `new`
+    fn widen(old: &State<D>, new: &State<D>) -> State<D> {
+        let mut res = old.clone();
+        for (idx, val) in new {
+            let widened = match old.get(idx) {
+                Some(o) => o.widen(val),
+                None => val.clone(),
+            };
+            res.insert(idx.clone(), widened);
+        }
+        res
+    }
+
+    /// Whether the edge from `block` to `dst` may be taken. Only conditional
+    /// jumps whose condition register has a known value can rule out an edge
+    fn edge_feasible(&self, block: &Block, dst: usize) -> bool {
+        let (ip, negate) = match block.instrs.last() {
+            Some((ip, BcArr::I(Instr::JmpIf))) => (*ip, false),
+            Some((ip, BcArr::I(Instr::JmpIN))) => (*ip, true),
+            _ => return true,
+        };
+        let cond = MemIdx::R(Interpreter::unpack_register(self.bytecode[ip + 2].clone()));
+        let truthy = match self.memory.get(&cond) {
+            Some(Mem::B(b)) => b.clone(),
+            Some(Mem::I(d)) if d.is_zero() => BoolState::F,
+            Some(Mem::I(d)) if !d.may_be_zero() => BoolState::T,
+            _ => BoolState::Unknown,
+        };
+        let taken = if negate { truthy.not() } else { truthy };
+        let offset = Interpreter::unpack_vaddr(self.bytecode[ip + 1].clone());
+        let target = (ip as isize + 2 + offset as isize) as usize;
+        match taken {
+            BoolState::T => dst == target,
+            BoolState::F => dst != target,
+            _ => true,
+        }
+    }
+
+    /// State that flows along the edge from `block` to `dst`. If the block
+    /// ends in a conditional jump on a comparison, the compared values are
+    /// restricted to those that take this edge. Returns None if there are none
+    fn refine_edge(&self, block: &Block, dst: usize) -> Option<State<D>> {
+        let mut state = self.memory.clone();
+        let (ip, negate) = match block.instrs.last() {
+            Some((ip, BcArr::I(Instr::JmpIf))) => (*ip, false),
+            Some((ip, BcArr::I(Instr::JmpIN))) => (*ip, true),
+            _ => return Some(state),
+        };
+        let offset = Interpreter::unpack_vaddr(self.bytecode[ip + 1].clone());
+        let target = (ip as isize + 2 + offset as isize) as usize;
+        if target == ip + Instr::JmpIf.size() {
+            return Some(state);
+        }
+        let holds = (dst == target) != negate;
+
+        // Find the comparison that computed the condition
+        let cond = Interpreter::unpack_register(self.bytecode[ip + 2].clone());
+        let instrs = &block.instrs[..block.instrs.len() - 1];
+        let pos = match instrs.iter().rposition(|(i, _)| self.writes_reg(*i, cond)) {
+            Some(pos) => pos,
+            None => return Some(state),
+        };
+        let (cmp_ip, cmp) = &instrs[pos];
+        if !matches!(cmp, BcArr::I(Instr::CmpLT | Instr::CmpLE | Instr::CmpGT | Instr::CmpGE)) {
+            return Some(state);
+        }
+        let cmp_ip = *cmp_ip;
+        let r1 = Interpreter::unpack_register(self.bytecode[cmp_ip + 2].clone());
+        let r2 = Interpreter::unpack_register(self.bytecode[cmp_ip + 3].clone());
+
+        // Operands ordered as (smaller, larger, may be equal)
+        let (lo, hi, or_equal) = match (cmp, holds) {
+            (BcArr::I(Instr::CmpLT), true) | (BcArr::I(Instr::CmpGE), false) => (r1, r2, false),
+            (BcArr::I(Instr::CmpLE), true) | (BcArr::I(Instr::CmpGT), false) => (r1, r2, true),
+            (BcArr::I(Instr::CmpGT), true) | (BcArr::I(Instr::CmpLE), false) => (r2, r1, false),
+            (BcArr::I(Instr::CmpGE), true) | (BcArr::I(Instr::CmpLT), false) => (r2, r1, true),
+            _ => return Some(state),
+        };
+
+        let lo_locs = self.operand_locations(instrs, pos, lo, cond);
+        let hi_locs = self.operand_locations(instrs, pos, hi, cond);
+        let (a, b) = match (lo_locs.first().and_then(|l| state.get(l)),
+                            hi_locs.first().and_then(|l| state.get(l))) {
+            (Some(Mem::I(a)), Some(Mem::I(b))) => a.assume_less(b, or_equal)?,
+            _ => return Some(state),
+        };
+        for loc in lo_locs {
+            state.insert(loc, Mem::I(a.clone()));
+        }
+        for loc in hi_locs {
+            state.insert(loc, Mem::I(b.clone()));
+        }
+        Some(state)
+    }
+
+    /// Memory locations that still hold the value register `reg` had when
+    /// `instrs[pos]` was executed. `res` is the register that instruction
+    /// overwrote. If the register was loaded from a variable that was not
+    /// stored to since, the variable holds the same value
+    fn operand_locations(&self, instrs: &[(usize, BcArr)], pos: usize, reg: usize,
+                         res: usize) -> Vec<MemIdx> {
+        let mut locs = Vec::new();
+        if let Some(def) = instrs[..pos].iter().rposition(|(i, _)| self.writes_reg(*i, reg)) {
+            let (ip, instr) = &instrs[def];
+            if *instr == BcArr::I(Instr::LoadP) {
+                let pool = self.bytecode[ip + 2].clone();
+                let stored = instrs[def + 1..pos].iter().any(|(i, instr)| {
+                    *instr == BcArr::I(Instr::PushP) && self.bytecode[i + 1] == pool
+                });
+                if !stored {
+                    locs.push(MemIdx::P(Interpreter::unpack_pool(pool)));
+                }
+            }
+        }
+        if reg != res {
+            locs.push(MemIdx::R(reg));
+        }
+        locs
+    }
+
+    /// Whether the instruction at `ip` overwrites register `reg`
+    fn writes_reg(&self, ip: usize, reg: usize) -> bool {
+        self.bytecode[ip] != BcArr::I(Instr::PushP)
+            && matches!(self.bytecode.get(ip + 1),
+                        Some(BcArr::V(Value::Reg(r))) if *r as usize == reg)
     }
 
     pub fn handle_block(&mut self, block: &Block) {
@@ -142,7 +893,6 @@ impl AbstractInterpreter {
     }
     fn handle_label(&mut self, ip: usize) {
         let op = self.fetch_val_at(ip);
-        println!("Handling: {:?}", op);
 
         match op {
             BcArr::I(Instr::LoadI) => {
@@ -154,21 +904,69 @@ impl AbstractInterpreter {
             BcArr::I(Instr::LoadP) => {
                 self.loadp();
             }
-            BcArr::I(Instr::CmpGT) => {
-                self.cmpgt();
+            BcArr::I(Instr::CmpLT) |
+            BcArr::I(Instr::CmpLE) |
+            BcArr::I(Instr::CmpGT) |
+            BcArr::I(Instr::CmpGE) |
+            BcArr::I(Instr::CmpEq) |
+            BcArr::I(Instr::CmpNe) => {
+                self.cmp(op);
             }
-            BcArr::I(Instr::JmpIf) => {
+            BcArr::I(Instr::JmpIf) | BcArr::I(Instr::JmpIN) => {
                 self.jmpif();
             }
             BcArr::I(Instr::Jmp) => {
                 self.jmp();
             }
             BcArr::I(Instr::Add) => {
-                self.add();
+                self.check_overflow(ip, Instr::Add);
+                self.arith(D::add);
+            }
+            BcArr::I(Instr::Sub) => {
+                self.check_overflow(ip, Instr::Sub);
+                self.arith(D::sub);
+            }
+            BcArr::I(Instr::Mul) => {
+                self.check_overflow(ip, Instr::Mul);
+                self.arith(D::mul);
+            }
+            BcArr::I(Instr::Div) => {
+                self.check_divisor(ip);
+                self.arith(D::div);
+            }
+            BcArr::I(Instr::Mod) => {
+                self.check_divisor(ip);
+                self.arith(D::modulo);
+            }
+            BcArr::I(Instr::LoadR) => {
+                self.loadr();
+            }
+            BcArr::I(Instr::Call) => {
+                self.call();
+            }
+            BcArr::I(Instr::CallR) => {
+                self.return_from_call(Mem::I(D::top()));
+            }
+            BcArr::I(Instr::PushA) => {
+                self.pusha();
+            }
+            BcArr::I(Instr::LoadA) => {
+                self.loada();
+            }
+            // Natives, arrays and objects consume the arguments pushed last
+            BcArr::I(Instr::CallNative) | BcArr::I(Instr::MakeArray) |
+            BcArr::I(Instr::MakeObject) => {
+                self.drop_args();
+                self.clobber(ip);
             }
-            BcArr::I(Instr::Print) => {
+            BcArr::I(Instr::Print) | BcArr::I(Instr::PrintS) |
+            BcArr::I(Instr::IndexSet) | BcArr::I(Instr::Ret) => {
+            }
+            // Anything else produces values that are not tracked, eg. strings,
+            // arrays or random numbers
+            _ => {
+                self.clobber(ip);
             }
-            _ => unreachable!(),
         }
 
         //println!("{:?}", instr);
@@ -181,9 +979,14 @@ impl AbstractInterpreter {
         let v = self.fetch_val();
 
         let register_index = MemIdx::R(Interpreter::unpack_register(reg));
-        let val = Interpreter::unpack_number(&Interpreter::unpack_value(v)) as usize;
+        let val = match Interpreter::unpack_value(v) {
+            n @ (Value::Number(_) | Value::Int(_)) => Mem::I(D::from_value(&n)),
+            Value::Bool(true) => Mem::B(BoolState::T),
+            Value::Bool(false) => Mem::B(BoolState::F),
+            _ => Mem::I(D::top()),
+        };
 
-        self.memory.insert(register_index, Mem::I(Interval::new(val, val)));
+        self.memory.insert(register_index, val);
         //self.add_new_reg_var_int(register_index, Interval::new(val, val));
     }
 
@@ -196,7 +999,8 @@ impl AbstractInterpreter {
 
         let register_index = MemIdx::R(Interpreter::unpack_register(reg));
         let pool_index = MemIdx::P(Interpreter::unpack_pool(pool));
-        let val = self.memory.get(&register_index).unwrap().clone();
+        let val = self.memory.get(&register_index).cloned()
+            .unwrap_or(Mem::I(D::top()));
 
         // TODO - this could also be an update I think, not just new
 
@@ -204,32 +1008,139 @@ impl AbstractInterpreter {
         //self.add_new_pool_var_int(pool_index, val);
     }
 
+    fn loadr(&mut self) {
+        let dst = self.fetch_val();
+        let src = self.fetch_val();
+
+        let val = self.memory.get(&MemIdx::R(Interpreter::unpack_register(src))).cloned()
+            .unwrap_or(Mem::I(D::top()));
+        self.memory.insert(MemIdx::R(Interpreter::unpack_register(dst)), val);
+    }
+
+    /// PushA instruction - Put the value of a register into the next free
+    /// argument slot
+    fn pusha(&mut self) {
+        let reg = self.fetch_val();
+
+        let val = self.memory.get(&MemIdx::R(Interpreter::unpack_register(reg))).cloned()
+            .unwrap_or(Mem::I(D::top()));
+        let slot = self.memory.keys().filter(|idx| matches!(idx, MemIdx::A(_))).count();
+        self.memory.insert(MemIdx::A(slot), val);
+    }
+
+    /// LoadA instruction - Move the first remaining argument into the pool.
+    /// Arguments are unknown if the cfg is analyzed on its own
+    fn loada(&mut self) {
+        let pool = self.fetch_val();
+
+        let first = self.memory.keys()
+            .filter(|idx| matches!(idx, MemIdx::A(_)))
+            .min()
+            .cloned();
+        let val = first.and_then(|idx| self.memory.remove(&idx)).unwrap_or(Mem::I(D::top()));
+        self.memory.insert(MemIdx::P(Interpreter::unpack_pool(pool)), val);
+    }
+
+    /// Call instruction - Analyze the callee with the pushed arguments to find
+    /// the value it returns. The callee's cfg is run from scratch for every
+    /// call, so calls nested deeper than `CALL_DEPTH_LIMIT` return top
+    fn call(&mut self) {
+        let addr = Interpreter::unpack_vaddr(self.fetch_val());
+
+        let functions = self.functions.clone();
+        let cfg = match functions.get(&addr) {
+            Some(cfg) if self.call_depth < CALL_DEPTH_LIMIT => cfg,
+            _ => return self.return_from_call(Mem::I(D::top())),
+        };
+        let mut callee = Self {
+            bytecode: self.bytecode.clone(),
+            ip: addr,
+            memory: State::default(),
+            warnings: Vec::new(),
+            dead_code: Vec::new(),
+            widening_threshold: self.widening_threshold,
+            exit_state: State::default(),
+            block_states: FxHashMap::default(),
+            stored_globals: self.stored_globals.clone(),
+            functions: functions.clone(),
+            entry_state: self.memory.iter()
+                .filter(|(idx, _)| matches!(idx, MemIdx::A(_)))
+                .map(|(idx, val)| (idx.clone(), val.clone()))
+                .collect(),
+            call_depth: self.call_depth + 1,
+        };
+        callee.run(cfg);
+        let ret = callee.exit_state.remove(&MemIdx::R(0)).unwrap_or(Mem::I(D::top()));
+        self.return_from_call(ret);
+    }
+
+    /// Callees restore the registers and pool of the caller, only the return
+    /// value in r0 and globals they store to change. Arguments are consumed
+    fn return_from_call(&mut self, ret: Mem<D>) {
+        self.drop_args();
+        self.memory.insert(MemIdx::R(0), ret);
+        for idx in &self.stored_globals {
+            self.memory.insert(idx.clone(), Mem::I(D::top()));
+        }
+    }
+
+    fn drop_args(&mut self) {
+        self.memory.retain(|idx, _| !matches!(idx, MemIdx::A(_)));
+    }
+
+    /// The memory location written by the instruction at `ip` holds an
+    /// unknown value afterwards
+    fn clobber(&mut self, ip: usize) {
+        let idx = match self.bytecode.get(ip + 1) {
+            Some(BcArr::V(Value::Reg(r))) => MemIdx::R(*r as usize),
+            Some(BcArr::V(Value::Pool(p))) => MemIdx::P(*p as usize),
+            _ => return,
+        };
+        self.memory.insert(idx, Mem::I(D::top()));
+    }
+
     fn loadp(&mut self) {
         let reg = self.fetch_val();
         let pool = self.fetch_val();
 
         let register_index = MemIdx::R(Interpreter::unpack_register(reg));
         let pool_index = MemIdx::P(Interpreter::unpack_pool(pool));
-        let val = self.memory.get(&pool_index).unwrap().clone();
+        let val = self.memory.get(&pool_index).cloned()
+            .unwrap_or(Mem::I(D::top()));
 
         self.memory.insert(register_index, val);
         //self.add_new_reg_var_int(register_index, val);
     }
 
-    // Doesn't have to do anything for now
-    fn cmpgt(&mut self) {
+    /// Comparison instructions, the result is only known if it holds for
+    /// either all or none of the possible operand values
+    fn cmp(&mut self, op: BcArr) {
         let res = self.fetch_val();
-        let _r1  = self.fetch_val();
-        let _r2  = self.fetch_val();
+        let r1  = self.fetch_val();
+        let r2  = self.fetch_val();
 
         let register_index = MemIdx::R(Interpreter::unpack_register(res));
-        //self.add_new_var_bool(register_index, BoolState::Unknown);
-        self.memory.insert(register_index, Mem::B(BoolState::Unknown));
+        let a = self.memory.get(&MemIdx::R(Interpreter::unpack_register(r1)));
+        let b = self.memory.get(&MemIdx::R(Interpreter::unpack_register(r2)));
+
+        let val = match (a, b) {
+            (Some(Mem::I(a)), Some(Mem::I(b))) => match op {
+                BcArr::I(Instr::CmpLT) => a.less_than(b),
+                BcArr::I(Instr::CmpLE) => b.less_than(a).not(),
+                BcArr::I(Instr::CmpGT) => b.less_than(a),
+                BcArr::I(Instr::CmpGE) => a.less_than(b).not(),
+                BcArr::I(Instr::CmpEq) => a.equals(b),
+                _ => a.equals(b).not(),
+            },
+            _ => BoolState::Unknown,
+        };
+        self.memory.insert(register_index, Mem::B(val));
     }
 
     // Doesn't have to do anything for now
     fn jmpif(&mut self) {
         let _offset  = self.fetch_val();
+        let _cond  = self.fetch_val();
     }
 
     // Doesn't have to do anything for now
@@ -237,10 +1148,331 @@ impl AbstractInterpreter {
         let _offset  = self.fetch_val();
     }
 
-    // Doesn't have to do anything for now
-    fn add(&mut self) {
-        let _r1  = self.fetch_val();
-        let _r2  = self.fetch_val();
-        let _r3  = self.fetch_val();
+    /// Warn if the divisor of the Div/Mod instruction at `ip` may be 0
+    fn check_divisor(&mut self, ip: usize) {
+        let r2 = MemIdx::R(Interpreter::unpack_register(self.bytecode[ip + 3].clone()));
+        let msg = match self.memory.get(&r2) {
+            Some(Mem::I(d)) if d.is_zero() => "Division by zero",
+            Some(Mem::I(d)) if d.may_be_zero() => "Possible division by zero",
+            _ => return,
+        };
+        self.warn(ip, msg);
+    }
+
+    /// Warn if the integer result of the arithmetic instruction at `ip` may
+    /// not fit into an i64, the vm then continues with a less precise float
+    fn check_overflow(&mut self, ip: usize, instr: Instr) {
+        let r1 = MemIdx::R(Interpreter::unpack_register(self.bytecode[ip + 2].clone()));
+        let r2 = MemIdx::R(Interpreter::unpack_register(self.bytecode[ip + 3].clone()));
+        let state = match (self.memory.get(&r1), self.memory.get(&r2)) {
+            (Some(Mem::I(a)), Some(Mem::I(b))) => a.overflows(instr, b),
+            _ => return,
+        };
+        match state {
+            BoolState::T => self.warn(ip, "Integer overflow"),
+            BoolState::Unknown => self.warn(ip, "Possible integer overflow"),
+            _ => {}
+        }
+    }
+
+    fn warn(&mut self, ip: usize, msg: &str) {
+        // Blocks are analyzed multiple times until a fixpoint is reached, only
+        // keep the warning from the latest state
+        self.warnings.retain(|w| w.ip != ip);
+        self.warnings.push(AnalysisWarning { ip, msg: msg.to_string() });
+    }
+
+    /// Arithmetic instructions. `op` computes the result from the abstract
+    /// values of both operands
+    fn arith(&mut self, op: fn(&D, &D) -> D) {
+        let res = self.fetch_val();
+        let r1  = self.fetch_val();
+        let r2  = self.fetch_val();
+
+        let register_index = MemIdx::R(Interpreter::unpack_register(res));
+        let a = self.memory.get(&MemIdx::R(Interpreter::unpack_register(r1)));
+        let b = self.memory.get(&MemIdx::R(Interpreter::unpack_register(r2)));
+
+        let val = match (a, b) {
+            (Some(Mem::I(a)), Some(Mem::I(b))) => op(a, b),
+            _ => D::top(),
+        };
+        self.memory.insert(register_index, Mem::I(val));
+    }
+}
+
+impl AbstractInterpreter<Constant> {
+    /// Propagate constants through every function of `program`. Loads and
+    /// computations whose result is the same on every path are replaced by a
+    /// LoadI of that result
+    pub fn optimize(program: &Program) -> Program {
+        let mut edits = Vec::new();
+        for (_, cfg) in program.generate_cfg() {
+            let mut ai = Self::new(program);
+            ai.run(&cfg);
+            edits.extend(ai.constant_loads(&cfg));
+        }
+        let mut optimized = program.clone();
+        optimized.replace_instrs(edits);
+        optimized
+    }
+
+    /// LoadI instructions that can replace the instructions of `cfg` whose
+    /// result is known, keyed by the address of the replaced instruction
+    fn constant_loads(&mut self, cfg: &Cfg) -> Vec<(usize, Vec<BcArr>)> {
+        let mut edits = Vec::new();
+        for (id, block) in &cfg.blocks {
+            let Some(state) = self.block_states.get(id) else { continue };
+            self.memory = state.clone();
+            for (ip, instr) in &block.instrs {
+                self.ip = *ip;
+                self.handle_label(*ip);
+                if !matches!(instr, BcArr::I(Instr::LoadP | Instr::LoadR | Instr::Add |
+                                             Instr::Sub | Instr::Mul | Instr::Div |
+                                             Instr::Mod | Instr::CmpLT | Instr::CmpLE |
+                                             Instr::CmpGT | Instr::CmpGE | Instr::CmpEq |
+                                             Instr::CmpNe)) {
+                    continue;
+                }
+                let res = self.bytecode[ip + 1].clone();
+                let reg = MemIdx::R(Interpreter::unpack_register(res.clone()));
+                let val = match self.memory.get(&reg) {
+                    Some(Mem::I(Constant::Known(v))) => v.clone(),
+                    Some(Mem::B(BoolState::T)) => Value::Bool(true),
+                    Some(Mem::B(BoolState::F)) => Value::Bool(false),
+                    _ => continue,
+                };
+                edits.push((*ip, vec![BcArr::I(Instr::LoadI), res, BcArr::V(val)]));
+            }
+        }
+        edits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Codegen, lexer::tokenize, parser::Parser};
+
+    /// Run the analysis over the top-level code of `source` using domain `D`
+    fn run_analysis<D: Domain>(source: &str, widening_threshold: usize)
+            -> (AbstractInterpreter<D>, Cfg) {
+        let (stmts, errors) = Parser::new(tokenize(source)).parse();
+        assert!(errors.is_empty(), "failed to parse test program");
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let cfg = program.generate_cfg().remove(0).1;
+        let mut ai = AbstractInterpreter::<D>::new(&program);
+        ai.set_widening_threshold(widening_threshold);
+        ai.run(&cfg);
+        (ai, cfg)
+    }
+
+    /// Analyze `source`. Returns the value of the first variable it declares
+    /// at the end of the program and at the start of the block that compares
+    /// it, which is the loop head of a while loop
+    fn analyze(source: &str, widening_threshold: usize) -> (Mem<Interval>, Mem<Interval>) {
+        let (ai, cfg) = run_analysis::<Interval>(source, widening_threshold);
+
+        let head = cfg.blocks.iter()
+            .find(|(_, b)| b.instrs.iter().any(|i| i.1 == BcArr::I(Instr::CmpLT)))
+            .map(|(id, _)| id)
+            .unwrap();
+        (ai.exit_state[&MemIdx::P(0)].clone(), ai.block_states[head][&MemIdx::P(0)].clone())
+    }
+
+    #[test]
+    fn unbounded_loops_are_widened() {
+        let source = "var i = 0;\nvar n = randomInt(1, 1000000);\n\
+                      while (i < n) { i = i + 1; }\nconsole.log(i);";
+        let (exit, head) = analyze(source, 0);
+        assert_eq!(head, Mem::I(Interval::new(0, i64::MAX)));
+        assert_eq!(exit, head);
+    }
+
+    #[test]
+    fn branches_are_joined_where_they_merge() {
+        let source = "var x = 0;\nif (random() < 1) { x = 1; } else { x = 5; }\nconsole.log(x);";
+        let (ai, _) = run_analysis::<Interval>(source, 0);
+        assert_eq!(ai.exit_state[&MemIdx::P(0)], Mem::I(Interval::new(1, 5)));
+        assert_eq!(Interval::new(1, 1).join(&Interval::new(5, 5)), Interval::new(1, 5));
+        let (t, f) = (Mem::<Interval>::B(BoolState::T), Mem::B(BoolState::F));
+        assert_eq!(t.join(&f), Mem::B(BoolState::Either));
+        assert_eq!(t.join(&t), t);
+    }
+
+    #[test]
+    fn negative_numbers_have_signed_bounds() {
+        let source = "var x = -3;\nvar y = x - 4;\nconsole.log(x, y);";
+        let (ai, _) = run_analysis::<Interval>(source, 0);
+        assert_eq!(ai.exit_state[&MemIdx::P(0)], Mem::I(Interval::new(-3, -3)));
+        assert_eq!(ai.exit_state[&MemIdx::P(1)], Mem::I(Interval::new(-7, -7)));
+    }
+
+    #[test]
+    fn fractions_are_rounded_outwards() {
+        assert_eq!(Interval::loadi_const(0.5), Interval::new(0, 1));
+        assert_eq!(Interval::loadi_const(-2.5), Interval::new(-3, -2));
+        assert_eq!(Interval::loadi_const(f64::NAN), Interval::top());
+
+        let source = "var b = 0.5;\nconsole.log(1 / b);\nif (b > 0) {\nconsole.log(\"pos\");\n}\n\
+                      console.log(b);";
+        let (ai, _) = run_analysis::<Interval>(source, 0);
+        assert_eq!(ai.exit_state[&MemIdx::P(0)], Mem::I(Interval::new(0, 1)));
+        assert!(ai.warnings.iter().all(|w| w.msg != "Division by zero"));
+        assert!(ai.dead_code.is_empty());
+    }
+
+    #[test]
+    fn divisors_that_may_be_zero_are_reported() {
+        let source = "var i = 0;\nvar d = 0;\nwhile (i < 3) { d = i; i = i + 1; }\n\
+                      var a = 10;\nconsole.log(a / 5, a / i);\nconsole.log(a % d);";
+        let (ai, _) = run_analysis::<Interval>(source, 0);
+        let msgs: Vec<&str> = ai.warnings.iter().map(|w| w.msg.as_str()).collect();
+        assert_eq!(msgs, ["Possible division by zero"]);
+
+        let (ai, _) = run_analysis::<Interval>("var z = 0;\nvar a = 10;\nconsole.log(a / z);", 0);
+        let msgs: Vec<&str> = ai.warnings.iter().map(|w| w.msg.as_str()).collect();
+        assert_eq!(msgs, ["Division by zero"]);
+    }
+
+    #[test]
+    fn infeasible_branches_are_dead_code() {
+        let source = "var a = 0;\nif (1 > 2) {\na = 1;\nconsole.log(\"unreachable\");\n}\n\
+                      console.log(a);";
+        let (ai, cfg) = run_analysis::<Interval>(source, 0);
+        assert_eq!(ai.dead_code.len(), 1);
+        let (start, end) = ai.dead_code[0];
+        let block = cfg.blocks.values().find(|b| b.instrs[0].0 == start).unwrap();
+        assert!(block.instrs.iter().any(|i| i.1 == BcArr::I(Instr::PrintS) ||
+                                             i.1 == BcArr::I(Instr::Print)));
+        assert!(block.instrs.iter().all(|i| i.0 <= end));
+        assert_eq!(ai.exit_state[&MemIdx::P(0)], Mem::I(Interval::new(0, 0)));
+
+        let source = "var a = 0;\nif (random() < 1) { a = 1; }\nconsole.log(a);";
+        assert!(run_analysis::<Interval>(source, 0).0.dead_code.is_empty());
+    }
+
+    #[test]
+    fn widening_threshold_keeps_exact_bounds() {
+        let source = "var i = 0;\nwhile (i < 3) { i = i + 1; }\nconsole.log(i);";
+        assert_eq!(analyze(source, 10).0, Mem::I(Interval::new(3, 3)));
+    }
+
+    #[test]
+    fn interval_arithmetic() {
+        let (a, b) = (Interval::new(2, 4), Interval::new(10, 10));
+        assert_eq!(a.add(&b), Interval::new(12, 14));
+        assert_eq!(a.sub(&b), Interval::new(-8, -6));
+        assert_eq!(Interval::new(-2, 3).mul(&Interval::new(4, 5)), Interval::new(-10, 15));
+        assert_eq!(b.div(&a), Interval::new(2, 5));
+        assert_eq!(Interval::new(7, 7).div(&Interval::new(2, 2)), Interval::new(3, 4));
+        assert_eq!(b.div(&Interval::new(-1, 1)), Interval::top());
+    }
+
+    #[test]
+    fn disjoint_intervals_compare_definitely() {
+        let (one, five) = (Interval::new(1, 1), Interval::new(5, 5));
+        assert_eq!(one.less_than(&five), BoolState::T);
+        assert_eq!(five.less_than(&one), BoolState::F);
+        assert_eq!(Interval::new(1, 5).less_than(&Interval::new(3, 3)), BoolState::Unknown);
+        assert_eq!(one.equals(&five), BoolState::F);
+        assert_eq!(one.equals(&one), BoolState::T);
+
+        let source = "var a = 1;\nvar b = 5;\n\
+                      var lt = a < b;\nvar le = b <= a;\nvar ge = b >= b;\n\
+                      var eq = a == b;\nvar ne = a != b;\nconsole.log(lt, le, ge, eq, ne);";
+        let (ai, _) = run_analysis::<Interval>(source, 0);
+        let flags: Vec<Mem<Interval>> = (2..7)
+            .map(|p| ai.exit_state[&MemIdx::P(p)].clone())
+            .collect();
+        let expected = [BoolState::T, BoolState::F, BoolState::T, BoolState::F, BoolState::T];
+        assert_eq!(flags, expected.map(Mem::B));
+    }
+
+    #[test]
+    fn modulo_is_bounded_by_the_divisor() {
+        let m = Interval::new(3, 3);
+        assert_eq!(Interval::new(0, 10).modulo(&m), Interval::new(0, 2));
+        assert_eq!(Interval::new(0, 1).modulo(&m), Interval::new(0, 1));
+        assert_eq!(Interval::new(-5, 10).modulo(&m), Interval::new(-2, 2));
+        assert_eq!(Interval::new(0, 10).modulo(&Interval::new(0, 3)), Interval::top());
+
+        // The remainder stays below the divisor even once the loop is widened
+        let source = "var r = 0;\nvar i = 0;\n\
+                      while (i < 100) { r = i % 7; i = i + 1; }\nconsole.log(r);";
+        assert_eq!(analyze(source, 3).0, Mem::I(Interval::new(0, 6)));
+    }
+
+    /// Output of running `program` in the vm
+    fn output(program: Program) -> String {
+        let mut out = Vec::new();
+        Interpreter::with_writer(program, &mut out).interpret().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn constant_propagation_keeps_output() {
+        let source = "function sq(n) { var k = 3; return n * n + k; }\n\
+                      var a = 6;\nvar b = a * 7;\nvar i = 0;\n\
+                      while (i < b) { i = i + 10; }\n\
+                      if (a < 10) { console.log(\"small\", b / 4); }\n\
+                      console.log(sq(b), i, a == 6, 7 / 2);";
+        let (stmts, _) = Parser::new(tokenize(source)).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let optimized = AbstractInterpreter::<Constant>::optimize(&program);
+
+        // Loads of `a` and `b`, `a * 7`, `b / 4` and the comparisons of `a`
+        // are folded
+        let loads = |p: &Program| {
+            p.bytecode.iter().filter(|v| **v == BcArr::I(Instr::LoadP)).count()
+        };
+        assert!(loads(&optimized) < loads(&program));
+        assert!(optimized.bytecode.len() < program.bytecode.len());
+        assert!(optimized.verify().is_ok());
+        assert_eq!(output(optimized), output(program));
+    }
+
+    #[test]
+    fn large_products_may_overflow() {
+        let source = "var a = 3000000000;\nvar b = 2;\n\
+                      if (random() < 0.5) { a = 4000000000; b = 3; }\n\
+                      var c = a * a;\nvar d = a * b;\nconsole.log(c + d);";
+        let (stmts, _) = Parser::new(tokenize(source)).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let cfg = program.generate_cfg().remove(0).1;
+        let mut ai = AbstractInterpreter::<Interval>::new(&program);
+        ai.run(&cfg);
+
+        // Only `a * a` may exceed i64::MAX, `c + d` adds to an unknown value
+        let mul = program.bytecode.iter().position(|v| *v == BcArr::I(Instr::Mul));
+        assert_eq!(ai.warnings.len(), 1);
+        assert_eq!(ai.warnings[0].msg, "Possible integer overflow");
+        assert_eq!(Some(ai.warnings[0].ip), mul);
+    }
+
+    #[test]
+    fn calls_return_the_interval_of_the_callee() {
+        let source = "function add(a, b) { return a + b; }\n\
+                      function loop(n) { return loop(n + 1); }\n\
+                      var x = 1;\nif (random() < 0.5) { x = 5; }\n\
+                      var y = add(x, add(10, 20));\nvar z = loop(x);\n\
+                      console.log(y, z);";
+        let (stmts, _) = Parser::new(tokenize(source)).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let cfg = program.generate_cfg().remove(0).1;
+        let mut ai = AbstractInterpreter::<Interval>::new(&program);
+        ai.run(&cfg);
+
+        assert_eq!(ai.exit_state[&MemIdx::P(1)], Mem::I(Interval::new(31, 35)));
+        // Recursion is only followed up to the depth limit
+        assert_eq!(ai.exit_state[&MemIdx::P(2)], Mem::I(Interval::top()));
+    }
+
+    #[test]
+    fn narrowing_recovers_loop_bound() {
+        let source = "var i = 0;\nwhile (i < 100) { i = i + 1; }\nconsole.log(i);";
+        let (exit, head) = analyze(source, 0);
+        assert_eq!(head, Mem::I(Interval::new(0, 100)));
+        assert_eq!(exit, Mem::I(Interval::new(100, 100)));
     }
 }
@@ -0,0 +1,53 @@
+use crate::codegen::Value;
+
+use rustc_hash::FxHashMap;
+use std::{cell::RefCell, rc::Rc};
+
+/// Variables of a single scope. Lookups that fail in this scope continue in
+/// the enclosing one, up to the global scope which has no enclosing scope
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    values: FxHashMap<String, Value>,
+    pub enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    pub fn new(enclosing: Option<Rc<RefCell<Environment>>>) -> Self {
+        Self {
+            values: FxHashMap::default(),
+            enclosing,
+        }
+    }
+
+    /// Declare a new variable in this scope
+    pub fn define(&mut self, name: &str, value: Value) -> Result<(), String> {
+        if self.values.contains_key(name) {
+            return Err("Cannot redeclare already existing variable".to_string());
+        }
+        self.values.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Overwrite the closest variable called `name`
+    pub fn assign(&mut self, name: &str, value: Value) -> Result<(), String> {
+        if let Some(v) = self.values.get_mut(name) {
+            *v = value;
+            return Ok(());
+        }
+        match &self.enclosing {
+            Some(env) => env.borrow_mut().assign(name, value),
+            None => Err(format!("Variable '{}' does not exist", name)),
+        }
+    }
+
+    /// Value of the closest variable called `name`
+    pub fn get(&self, name: &str) -> Result<Value, String> {
+        if let Some(v) = self.values.get(name) {
+            return Ok(v.clone());
+        }
+        match &self.enclosing {
+            Some(env) => env.borrow().get(name),
+            None => Err(format!("Variable '{}' does not exist", name)),
+        }
+    }
+}
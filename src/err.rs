@@ -1,14 +1,49 @@
+use std::fmt;
+
 #[derive(Debug, Clone)]
 pub struct Error {
     pub line: u32,
+    /// Column the error points at, 0 if it is not tied to a specific token
+    pub col: u32,
     pub err: String,
 }
 
 impl Error {
-    pub fn new(s: String, l: u32) -> Error {
+    pub fn new(s: String, l: u32, c: u32) -> Error {
         Error {
             line: l,
+            col: c,
             err: s,
         }
     }
 }
+
+/// Error raised by the vm while executing bytecode
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    /// Address of the instruction that caused the error
+    pub ip: usize,
+    /// Source line the instruction was generated from, 0 if it is unknown
+    pub line: u32,
+    pub msg: String,
+}
+
+impl RuntimeError {
+    pub fn new(s: String, ip: usize, line: u32) -> RuntimeError {
+        RuntimeError {
+            ip,
+            line,
+            msg: s,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "Runtime Error: {} on line {} (IP={}).", self.msg, self.line, self.ip)
+        } else {
+            write!(f, "Runtime Error: {} at IP={}.", self.msg, self.ip)
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::err::Error;
 use crate::tokens::TokenType::*;
 use crate::tokens::{Token, TokenType};
 
@@ -5,7 +9,8 @@ use crate::tokens::{Token, TokenType};
 fn is_keyword(word: &str) -> bool {
     matches!(word, "and" | "else" | "false" | "function" | "for" | 
              "if" | "nil" | "or" | "return" | "this" | 
-             "true" | "var" | "let" | "while" | "console.log")
+             "true" | "var" | "let" | "while" | "import" | "break" | 
+             "continue" | "of" | "do" | "console.log")
 }
 
 /// Returns correct token for provided keyword
@@ -26,6 +31,11 @@ fn get_keyword(word: &str) -> TokenType {
         "var"         => Var,
         "let"         => Let,
         "while"       => While,
+        "import"      => Import,
+        "break"       => Break,
+        "continue"    => Continue,
+        "of"          => Of,
+        "do"          => Do,
         _             => Whitespace
     }
 }
@@ -42,211 +52,423 @@ fn is_digit(c: char) -> bool {
     }
 }
 
-/// Parses the file and create tokens
-pub fn tokenize(file: &str) -> Vec<Token>  {
-
-    #[allow(unused_mut)]
-    let mut tokens = vec![];
-
-    // Initialize a base token that will be changed to represent each individual
-    // token and pushed to the tokens vector whenever a token is completed
-    let mut cur_token: Token = Token {
-        t_type: Whitespace, 
-        value: "".to_string(), 
-        line_num: 1
-    };
-
-    let mut lexer = file.chars().collect::<Vec<_>>().into_iter().peekable();
-
-    while let Some(c) = lexer.next() {
-        match c {
-            // Handle single character tokens
-            '(' | ')' | '{' | '}' | ',' |
-            '.' | '-' | '+' | ';' | '*' => {
-                match c {
-                    '(' => cur_token.t_type = OpenParen,
-                    ')' => cur_token.t_type = CloseParen,
-                    '{' => cur_token.t_type = OpenCurly,
-                    '}' => cur_token.t_type = CloseCurly,
-                    ',' => cur_token.t_type = Comma,
-                    '.' => cur_token.t_type = Dot,
-                    '-' => cur_token.t_type = Minus,
-                    '+' => cur_token.t_type = Plus,
-                    ';' => cur_token.t_type = SemiColon,
-                    '*' => cur_token.t_type = Multiply,
-                    _ => { panic!("unreachable"); }
+/// Parses the file and create tokens, comments are dropped
+pub fn tokenize(file: &str) -> Vec<Token> {
+    collect_tokens(Lexer::new(file), &Limits::default()).unwrap_or_else(limit_exceeded)
+}
+
+/// Like `tokenize`, but comments are kept as `Comment` tokens holding the
+/// full comment text, which a formatter needs to reproduce the source
+pub fn tokenize_with_comments(file: &str) -> Vec<Token> {
+    collect_tokens(Lexer::with_comments(file), &Limits::default()).unwrap_or_else(limit_exceeded)
+}
+
+/// Bounds on the input that is lexed at once, so hosts running untrusted
+/// programs do not run out of memory
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Maximum size of the source in bytes
+    pub max_bytes: usize,
+
+    /// Maximum number of tokens, including comments and `Eof`
+    pub max_tokens: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            max_tokens: 1_000_000,
+        }
+    }
+}
+
+/// Collect every token of `lexer`. Exceeding `limits` is an error, lexing
+/// stops right away so no more than `max_tokens` tokens are kept. Other
+/// errors are passed on as `Invalid` tokens
+pub fn collect_tokens(lexer: Lexer, limits: &Limits) -> Result<Vec<Token>, Error> {
+    if lexer.len > limits.max_bytes {
+        return Err(Error::new(format!("Source is {} bytes long, the limit is {} bytes",
+                                      lexer.len, limits.max_bytes), 1, 0));
+    }
+    let mut tokens = Vec::new();
+    for token in lexer {
+        let token = into_token(token);
+        if tokens.len() == limits.max_tokens {
+            return Err(Error::new(format!("Source has more than {} tokens",
+                                          limits.max_tokens), token.line_num, token.col));
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Tokens for input that exceeded the limits, only the error is reported
+fn limit_exceeded(e: Error) -> Vec<Token> {
+    let eof = Token { t_type: Eof, value: String::new(), line_num: e.line, col: e.col };
+    vec![into_token(Err(e)), eof]
+}
+
+/// Lexer errors are passed on as `Invalid` tokens, which the parser reports
+fn into_token(token: Result<Token, Error>) -> Token {
+    token.unwrap_or_else(|e| Token {
+        t_type: Invalid,
+        value: e.err,
+        line_num: e.line,
+        col: e.col,
+    })
+}
+
+/// Produces the tokens of a file one at a time, ending with a single `Eof`
+/// token. Comments are only emitted if requested
+pub struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+
+    /// Size of the source in bytes
+    len: usize,
+
+    /// Number of characters consumed so far
+    pos: usize,
+
+    /// Index of the first character of the current line
+    line_start: usize,
+
+    line_num: u32,
+
+    keep_comments: bool,
+
+    /// Set once the `Eof` token was returned
+    done: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(file: &'a str) -> Self {
+        Self {
+            chars: file.chars().peekable(),
+            len: file.len(),
+            pos: 0,
+            line_start: 0,
+            line_num: 1,
+            keep_comments: false,
+            done: false,
+        }
+    }
+
+    /// Lexer that also emits `Comment` tokens
+    pub fn with_comments(file: &'a str) -> Self {
+        Self { keep_comments: true, ..Self::new(file) }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    /// Consume the next character if it is `c`
+    fn bump_if(&mut self, c: char) -> bool {
+        if self.chars.peek() == Some(&c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.line_num += 1;
+        self.line_start = self.pos;
+    }
+
+    fn token(&self, t_type: TokenType, value: String, col: u32) -> Token {
+        Token { t_type, value, line_num: self.line_num, col }
+    }
+
+    /// Lex a token of `one` that becomes `two` if followed by `next`
+    fn one_or_two(&mut self, c: char, next: char, one: TokenType, two: TokenType,
+                  col: u32) -> Token {
+        if self.bump_if(next) {
+            self.token(two, format!("{}{}", c, next), col)
+        } else {
+            self.token(one, c.to_string(), col)
+        }
+    }
+
+    /// Lex the comment started by `/` whose second character is `next`.
+    /// Returns None if the comment is dropped
+    fn comment(&mut self, next: char, col: u32) -> Option<Result<Token, Error>> {
+        let mut value = format!("/{}", next);
+        if next == '/' {
+            // Line comments stop before the newline so it is counted by the
+            // caller
+            while let Some(&d) = self.chars.peek() {
+                if d == '\n' || d == '\r' {
+                    break;
+                }
+                value.push(d);
+                self.bump();
+            }
+            return self.keep_comments.then(|| Ok(self.token(Comment, value, col)));
+        }
+
+        // The token is located at the line the comment starts on
+        let start_line = self.line_num;
+        let mut closed = false;
+        while let Some(d) = self.bump() {
+            value.push(d);
+            if d == '\n' || d == '\r' {
+                self.new_line();
+            } else if d == '*' && self.bump_if('/') {
+                value.push('/');
+                closed = true;
+                break;
+            }
+        }
+        if !closed {
+            return Some(Err(Error::new("Unterminated block comment".to_string(),
+                                       start_line, col)));
+        }
+        self.keep_comments.then_some(Ok(Token {
+            t_type: Comment,
+            value,
+            line_num: start_line,
+            col,
+        }))
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let col = (self.pos - self.line_start) as u32 + 1;
+            let c = match self.bump() {
+                Some(c) => c,
+                None if self.done => return None,
+                None => {
+                    self.done = true;
+                    return Some(Ok(self.token(Eof, String::new(), col)));
                 }
-                cur_token.value.push(c);
-                end_token(&mut cur_token, &mut tokens);
-            },
-            // Skip comments
-            '/' => { 
-                if *lexer.peek().unwrap() == '/' {
-                    while *lexer.peek().unwrap() != '\n'  && 
-                            *lexer.peek().unwrap() != '\r' {
-                        lexer.next();
+            };
+
+            let token = match c {
+                // Numbers, including floats that start with their decimal
+                // point. Malformed numbers with several dots are reported by
+                // the parser
+                '.' | '0'..='9' if c != '.' || 
+                        matches!(self.chars.peek(), Some(d) if is_digit(*d)) => {
+                    let mut value = c.to_string();
+                    while let Some(&d) = self.chars.peek() {
+                        if !is_digit(d) && d != '.' {
+                            break;
+                        }
+                        value.push(d);
+                        self.bump();
+                    }
+                    self.token(Number, value, col)
+                },
+                // Handle single character tokens
+                '(' | ')' | '{' | '}' | '[' | ']' | ',' |
+                '.' | ';' | '%' | '?' | ':' | '^' => {
+                    let t_type = match c {
+                        '(' => OpenParen,
+                        ')' => CloseParen,
+                        '{' => OpenCurly,
+                        '}' => CloseCurly,
+                        '[' => OpenBracket,
+                        ']' => CloseBracket,
+                        ',' => Comma,
+                        '.' => Dot,
+                        ';' => SemiColon,
+                        '%' => Modulo,
+                        '?' => Question,
+                        ':' => Colon,
+                        '^' => BitXor,
+                        _ => { panic!("unreachable"); }
+                    };
+                    self.token(t_type, c.to_string(), col)
+                },
+                // Comments, the token is only kept if `keep_comments` is set
+                '/' => {
+                    match self.chars.peek().copied() {
+                        Some(next @ ('/' | '*')) => {
+                            self.bump();
+                            match self.comment(next, col) {
+                                Some(comment) => return Some(comment),
+                                None => continue,
+                            }
+                        },
+                        _ => self.one_or_two(c, '=', Divide, DivideEq, col),
                     }
-                    lexer.next();
-                    cur_token.line_num += 1;
-                } else if *lexer.peek().unwrap() == '*' {
+                },
+                // Arithmetic operators that can be combined with '=' into a
+                // compound assignment, `++` and `--` increment and decrement
+                '+' | '-' if self.bump_if(c) => {
+                    let t_type = if c == '+' { PlusPlus } else { MinusMinus };
+                    self.token(t_type, format!("{}{}", c, c), col)
+                },
+                '+' => self.one_or_two(c, '=', Plus, PlusEq, col),
+                '-' => self.one_or_two(c, '=', Minus, MinusEq, col),
+                '*' => self.one_or_two(c, '=', Multiply, MultiplyEq, col),
+                // Create StringLiteral's
+                '"' => {
+                    let line_num = self.line_num;
+                    let mut value = String::new();
                     loop {
-                        if *lexer.peek().unwrap() == '\n' || 
-                            *lexer.peek().unwrap() == '\r' {
-                            cur_token.line_num += 1;
-                            lexer.next();
-                        } else if lexer.next().unwrap() == '*' &&
-                                   *lexer.peek().unwrap() == '/' {
-                            lexer.next();
-                            break;
+                        match self.bump() {
+                            Some('"') => break,
+                            Some(d) => {
+                                value.push(d);
+                                if d == '\n' || d == '\r' {
+                                    self.new_line();
+                                }
+                            },
+                            None => return Some(Err(Error::new(
+                                        "Unterminated string".to_string(), line_num, col))),
                         }
                     }
-                } else {
-                    cur_token.t_type = Divide;
-                    cur_token.value.push(c);
-                    end_token(&mut cur_token, &mut tokens);
-                }
-            },
-            // Create StringLiteral's
-            '"' => {
-                cur_token.t_type = StringLiteral;
-                let mut d = lexer.next().unwrap();
-                while d != '"' {
-                    cur_token.value.push(d);
-                    d = lexer.next().unwrap();
-                }
-                end_token(&mut cur_token, &mut tokens);
-            },
-            // Escape Characters
-            '\n' | '\r' => {
-                end_token(&mut cur_token, &mut tokens);
-                cur_token.line_num +=1;
-            },
-            '\t' | ' ' => {
-                end_token(&mut cur_token, &mut tokens);
-            },
-            '=' => {
-                if *lexer.peek().unwrap() == '=' {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.value.push(c);
-                    cur_token.value.push(c);
-                    cur_token.t_type = Equals;
-                    lexer.next();
-                    end_token(&mut cur_token, &mut tokens);
-                } else {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.t_type = EqualSign;
-                    cur_token.value.push(c);
-                    end_token(&mut cur_token, &mut tokens);
-                }
-            },
-            '>' => {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.value.push(c);
-                if *lexer.peek().unwrap() == '=' {
-                    cur_token.value.push('=');
-                    cur_token.t_type = GreaterEq;
-                    lexer.next();
-                } else {
-                    cur_token.t_type = Greater;
-                }
-                end_token(&mut cur_token, &mut tokens);
-            },
-            '<' => {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.value.push(c);
-                if *lexer.peek().unwrap() == '=' {
-                    cur_token.value.push('=');
-                    cur_token.t_type = LessEq;
-                    lexer.next();
-                } else {
-                    cur_token.t_type = Less;
-                }
-                end_token(&mut cur_token, &mut tokens);
-            },
-            '!' => {
-                if *lexer.peek().unwrap() == '=' {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.value.push(c);
-                    cur_token.value.push('=');
-                    cur_token.t_type = NEqual;
-                    lexer.next();
-                    end_token(&mut cur_token, &mut tokens);
-                } else {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.t_type = Not;
-                    cur_token.value.push(c);
-                    end_token(&mut cur_token, &mut tokens);
-                }
-            },
-            '&' => {
-                if *lexer.peek().unwrap() == '&' {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.value.push(c);
-                    cur_token.value.push('&');
-                    cur_token.t_type = And;
-                    lexer.next();
-                    end_token(&mut cur_token, &mut tokens);
-                }
-            },
-            '|' => {
-                if *lexer.peek().unwrap() == '|' {
-                    end_token(&mut cur_token, &mut tokens);
-                    cur_token.value.push(c);
-                    cur_token.value.push('|');
-                    cur_token.t_type = Or;
-                    lexer.next();
-                    end_token(&mut cur_token, &mut tokens);
-                }
-            },
-            '0'..='9' => {
-                let mut is_float = false;
-                cur_token.t_type = Number;
-                cur_token.value.push(c);
-                while is_digit(*lexer.peek().unwrap()) || 
-                               *lexer.peek().unwrap() == '.' {
-                    if *lexer.peek().unwrap() == '.' {
-                        if !is_float {
-                            is_float = true;
-                        } else { 
-                            panic!("2 dots is invalid syntax");
+                    Token { t_type: StringLiteral, value, line_num, col }
+                },
+                // Escape Characters
+                '\n' | '\r' => {
+                    self.new_line();
+                    continue;
+                },
+                '=' => self.one_or_two(c, '=', EqualSign, Equals, col),
+                '>' if self.bump_if('>') => self.token(ShiftRight, ">>".to_string(), col),
+                '>' => self.one_or_two(c, '=', Greater, GreaterEq, col),
+                '<' if self.bump_if('<') => self.token(ShiftLeft, "<<".to_string(), col),
+                '<' => self.one_or_two(c, '=', Less, LessEq, col),
+                '!' => self.one_or_two(c, '=', Not, NEqual, col),
+                '&' => self.one_or_two(c, '&', BitAnd, And, col),
+                '|' => self.one_or_two(c, '|', BitOr, Or, col),
+                'A'..='z' => {
+                    let mut value = c.to_string();
+                    // Dots are only part of `console.log`, anywhere else they
+                    // access a property
+                    while let Some(&d) = self.chars.peek() {
+                        if !(d.is_alphanumeric() || d == '_' || 
+                             (d == '.' && value == "console")) {
+                            break;
                         }
+                        value.push(d);
+                        self.bump();
                     }
-                    let c = lexer.next().unwrap();
-                    cur_token.value.push(c);
-                }
-                end_token(&mut cur_token, &mut tokens);
-            },
-            'A'..='z' => {
-                end_token(&mut cur_token, &mut tokens);
-                cur_token.value.push(c);
-                cur_token.t_type = Identifier;
-                while char::is_alphanumeric(*lexer.peek().unwrap()) || 
-                        *lexer.peek().unwrap() == '.' ||
-                        *lexer.peek().unwrap() == '_' {
-                    let d = lexer.next().unwrap();
-                    cur_token.value.push(d);
-                }
-                if is_keyword(&cur_token.value) {
-                    cur_token.t_type = get_keyword(&cur_token.value);
-                }
-                end_token(&mut cur_token, &mut tokens);
-            },
-            _ => {},
+                    let t_type = if is_keyword(&value) { get_keyword(&value) } else { Identifier };
+                    self.token(t_type, value, col)
+                },
+                // Whitespace and unknown characters separate tokens
+                _ => continue,
+            };
+            return Some(Ok(token));
         }
     }
-    end_token(&mut cur_token, &mut tokens);
-    cur_token.t_type = Eof;
-    tokens.push(cur_token);
-    tokens
 }
 
-/// Terminate a token and add it to the Token vector before
-/// resetting the token to a fresh state
-fn end_token(token: &mut Token, tokens: &mut Vec<Token>) {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "// first\nvar a = 1; /* second\n spans lines */\nvar b = a;\n";
+
+    #[test]
+    fn comments_are_only_kept_on_request() {
+        let kept = tokenize_with_comments(SOURCE);
+        let comments: Vec<&Token> = kept.iter().filter(|t| t.t_type == Comment).collect();
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0].value, "// first");
+        assert_eq!(comments[1].value, "/* second\n spans lines */");
+        assert_eq!((comments[0].line_num, comments[1].line_num), (1, 2));
+
+        let dropped = tokenize(SOURCE);
+        assert!(dropped.iter().all(|t| t.t_type != Comment));
+
+        // Both modes agree on every other token, including its position
+        let without: Vec<_> = kept.iter()
+            .filter(|t| t.t_type != Comment)
+            .map(|t| (t.t_type, t.value.clone(), t.line_num, t.col))
+            .collect();
+        let plain: Vec<_> = dropped.iter()
+            .map(|t| (t.t_type, t.value.clone(), t.line_num, t.col))
+            .collect();
+        assert_eq!(without, plain);
+        assert_eq!(plain.iter().find(|t| t.1 == "b").unwrap().2, 4);
+    }
+
+    #[test]
+    fn block_comments_count_every_newline_once() {
+        let tokens = tokenize("/* a *\n * b **\n*/ var c = 1; /*/ still open */\nc;");
+        let line_of = |v: &str| tokens.iter().find(|t| t.value == v).unwrap().line_num;
+        assert_eq!(line_of("var"), 3);
+        assert_eq!(line_of("1"), 3);
+        assert_eq!(tokens[tokens.len() - 2].line_num, 4);
+        assert!(tokens.iter().all(|t| t.t_type != Invalid));
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_an_error() {
+        let tokens = tokenize("var a = 1;\n/* never\nclosed");
+        let invalid: Vec<&Token> = tokens.iter().filter(|t| t.t_type == Invalid).collect();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!((invalid[0].value.as_str(), invalid[0].line_num),
+                   ("Unterminated block comment", 2));
+        assert_eq!(tokens.last().unwrap().t_type, Eof);
+
+        // A lone slash at the end of the input is still a division
+        assert_eq!(tokenize("a /")[1].t_type, Divide);
+    }
+
+    #[test]
+    fn lexer_streams_the_same_tokens() {
+        let src = "var a = 1.5;\nfunction f(b) { return b >> 2 != a; } // done\nf(a);";
+        let batch = tokenize_with_comments(src);
+        let mut lexer = Lexer::with_comments(src);
+        for expected in &batch {
+            let token = lexer.next().unwrap().unwrap();
+            assert_eq!((token.t_type, &token.value, token.line_num, token.col),
+                       (expected.t_type, &expected.value, expected.line_num, expected.col));
+        }
+        assert!(lexer.next().is_none());
+
+        // Errors are returned instead of ending the stream early
+        let results: Vec<_> = Lexer::new("a \"open").collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1].as_ref().unwrap_err().err, "Unterminated string");
+        assert_eq!(results[2].as_ref().unwrap().t_type, Eof);
+    }
+
+    #[test]
+    fn tokens_know_their_column() {
+        let tokens = tokenize("var a = 1;\n  console.log(a >= \"x\");");
+        let cols: Vec<(&str, u32, u32)> = tokens.iter()
+            .map(|t| (t.value.as_str(), t.line_num, t.col))
+            .collect();
+        assert_eq!(cols[..4], [("var", 1, 1), ("a", 1, 5), ("=", 1, 7), ("1", 1, 9)]);
+        assert!(cols.contains(&("console.log", 2, 3)));
+        assert!(cols.contains(&(">=", 2, 17)));
+        assert!(cols.contains(&("x", 2, 20)));
+    }
+
+    #[test]
+    fn input_limits_are_errors() {
+        let src = "var a = 1;\n".repeat(100);
+        let limits = Limits { max_bytes: 10_000, max_tokens: 50 };
+        let err = collect_tokens(Lexer::new(&src), &limits).unwrap_err();
+        assert_eq!(err.err, "Source has more than 50 tokens");
+        assert_eq!((err.line, err.col), (11, 1));
+        assert_eq!(collect_tokens(Lexer::new(&src), &Limits::default()).unwrap().len(), 501);
+
+        let limits = Limits { max_bytes: 100, max_tokens: 1000 };
+        let err = collect_tokens(Lexer::new(&src), &limits).unwrap_err();
+        assert_eq!(err.err, "Source is 1100 bytes long, the limit is 100 bytes");
 
-    if !matches!(token.t_type, Whitespace) {
-        tokens.push(token.clone());
+        // The driver reports the limit like any other lexer error
+        let big = "1+".repeat(600_000);
+        let tokens = tokenize(&big);
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].value, "Source has more than 1000000 tokens");
     }
-    token.t_type = Whitespace; 
-    token.value.clear();
 }
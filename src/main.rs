@@ -1,4 +1,5 @@
 mod ast;
+mod cli;
 mod codegen;
 mod err;
 mod lexer;
@@ -7,35 +8,290 @@ mod tokens;
 mod vm;
 mod ai;
 mod comp_ai;
+mod sign;
+mod environment;
+mod tree_walk;
+mod serialize;
+mod verify;
+mod native;
+mod peephole;
 
 extern crate colored;
 
-use codegen::{BcArr, Codegen, Instr, Value};
+use ast::{implicit_main, Stmt, ToJson, ToSource};
+use cli::Options;
+use codegen::{Codegen, Program};
+use err::Error;
 use colored::*;
-use lexer::tokenize;
+use lexer::{tokenize, tokenize_with_comments};
 use parser::Parser;
-use std::{env, fs};
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
 use vm::Interpreter;
 //use ai::AbstractInterpreter;
-use comp_ai::AbstractInterpreter;
+use comp_ai::{AbstractInterpreter, Constant, Domain, Interval};
+use sign::SignDomain;
+use tree_walk::TreeWalker;
 
-const DEBUGSOURCE: bool = true;
-const DEBUGTOKENS: bool = false;
-const DEBUGAST: bool = true;
-const DEBUGBYTECODE: bool = true;
+/// Print line `line` of `file` together with the lines around it. If `col`
+/// is non-zero a caret is drawn underneath that column. Errors reported past
+/// the end of the file, eg. at the end of input, show the last lines
+fn print_line(file: &str, line: u32, col: u32) {
+    let lines: Vec<&str> = file.lines().collect();
+    if lines.is_empty() || line == 0 {
+        return;
+    }
+    let pos = line as usize - 1;
+    let shown = pos.min(lines.len() - 1);
+    let first = shown.saturating_sub(1);
+    let last = (shown + 1).min(lines.len() - 1);
+    let width = (last + 1).to_string().len();
 
-/// Used to print a line until \n (debug purposes)
-fn print_line(file: String, line: u32) {
-    let mut count: u32 = 0;
     println!();
-    for c in file.chars() {
-        if count == line - 1 {
-            print!("{}", c);
+    for (i, text) in lines.iter().enumerate().take(last + 1).skip(first) {
+        println!("{:>width$} | {}", i + 1, text, width = width);
+        if i == pos && col > 0 {
+            // Keep tabs so the caret lines up with the source line
+            let pad: String = text
+                .chars()
+                .take(col as usize - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            println!("{:>width$} | {}{}", "", pad, "^".red().bold(), width = width);
+        }
+    }
+}
+
+/// Print the bytecode listing of `program`
+fn print_bytecode(program: &Program) {
+    print!("+-----------Bytecode--------------+");
+    print!("{}", program.disassemble());
+    println!("\n+----------------------------------+\n");
+}
+
+/// Durations of the phases of the pipeline, only measured with `--time`
+#[derive(Default)]
+struct Timings {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    /// Run `f` as the phase `name`
+    fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let res = f();
+        self.phases.push((name, start.elapsed()));
+        res
+    }
+
+    /// Print the measured phases to stderr, so they do not mix with the
+    /// output of the program
+    fn report(&self) {
+        if !self.enabled {
+            return;
+        }
+        let total: Duration = self.phases.iter().map(|(_, d)| *d).sum();
+        eprintln!("\n+-------------Timings--------------+\n");
+        for (name, d) in self.phases.iter().chain(&[("Total", total)]) {
+            eprintln!("{:<18} {:>10.3} ms", format!("{}:", name), d.as_secs_f64() * 1000.0);
+        }
+        eprintln!("\n+----------------------------------+\n");
+    }
+}
+
+/// Run `program` in the vm, returns the exit code if the program called `exit`
+/// or 1 if it failed with a runtime error
+fn execute(program: &Program, opts: &Options) -> Option<i32> {
+    let mut vm = Interpreter::from_ref(program);
+    if let Some(seed) = opts.seed {
+        vm.set_seed(seed);
+    }
+    if let Some(budget) = opts.budget {
+        vm.set_budget(budget);
+    }
+    if let Some(depth) = opts.max_call_depth {
+        vm.set_max_call_depth(depth);
+    }
+    vm.set_profile(opts.profile);
+    let exit_code = vm.interpret().unwrap_or_else(|e| {
+        println!("{}", e.to_string().red().bold());
+        Some(1)
+    });
+
+    if opts.profile {
+        println!("\n+-------------Profile--------------+\n");
+        print!("{}", vm.profile_report());
+        println!("\n+----------------------------------+\n");
+    }
+    exit_code
+}
+
+/// Read-eval-print loop. Every line is compiled and executed on its own,
+/// variables and functions declared by earlier lines stay available
+fn repl(opts: &Options) {
+    let mut codegen = Codegen::new();
+    let mut vm = Interpreter::new(codegen.compile(Vec::new()).unwrap());
+    if let Some(seed) = opts.seed {
+        vm.set_seed(seed);
+    }
+
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("Unable to write to stdout");
+        line.clear();
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            println!();
+            return;
+        }
+
+        let (stmts, errors) = Parser::new(tokenize(&line)).parse();
+        if !errors.is_empty() {
+            for e in errors {
+                print_line(&line, e.line, e.col);
+                println!("{}", e.err.bold());
+            }
+            continue;
+        }
+
+        // A line that fails to compile does not leave any of its code behind
+        let program = match codegen.compile(stmts) {
+            Ok(program) => program,
+            Err(errors) => {
+                for e in errors {
+                    print_line(&line, e.line, e.col);
+                    println!("{}", e.err.bold());
+                }
+                continue;
+            }
+        };
+
+        vm.load(program);
+        match vm.interpret() {
+            Ok(Some(code)) => process::exit(code),
+            Ok(None) => {}
+            Err(e) => println!("{}", e.to_string().red().bold()),
+        }
+    }
+}
+
+/// Report errors found by the parser or the code generator
+fn print_compile_errors(source: &str, errors: &[Error]) {
+    for e in errors {
+        if e.line > 0 {
+            print_line(source, e.line, e.col);
+        }
+        println!("{}\n\n", e.err.bold());
+    }
+    println!(
+        "{}",
+        "Could not compile program due to above errors\n"
+            .red()
+            .bold()
+    );
+}
+
+/// Report bytecode that failed verification
+fn print_verify_errors(errors: &[Error]) {
+    for e in errors {
+        println!("{}", e.err.bold());
+    }
+    println!("{}", "Refusing to execute malformed bytecode\n".red().bold());
+}
+
+/// Report the lints found by the code generator and run the abstract
+/// interpreter over the program using domain `D`. Loops are widened after
+/// growing `widen_after` times
+fn analyze<D: Domain>(program: &Program, widen_after: usize) {
+    let cfg = program.generate_cfg();
+    //println!("CFG: {:#?}", cfg);
+
+    for w in &program.warnings {
+        println!("{}", format!("Warning: {}.", w.err).yellow().bold());
+    }
+
+    let mut abstract_interpreter = AbstractInterpreter::<D>::new(program);
+    abstract_interpreter.set_widening_threshold(widen_after);
+    abstract_interpreter.run(&cfg[0].1);
+    abstract_interpreter.print_exit_state();
+    for w in &abstract_interpreter.warnings {
+        println!("{}", w.to_string().yellow().bold());
+    }
+    for (start, end) in &abstract_interpreter.dead_code {
+        println!("{}", format!("Warning: Unreachable code from IP={} to IP={}.",
+                               start, end).yellow().bold());
+    }
+}
+
+/// Compile every module imported by `stmts` and link them together.
+/// Import paths are resolved relative to `dir`, the directory of the importing
+/// file. `stack` holds the modules that are currently being loaded and is used
+/// to detect import cycles, `loaded` holds every module that was already
+/// linked so a module that is imported several times is only included once.
+fn link_imports(stmts: &[Stmt], dir: &Path, stack: &mut Vec<PathBuf>,
+                loaded: &mut Vec<PathBuf>) -> Result<Option<Program>, String> {
+    let mut linked: Option<Program> = None;
+
+    for stmt in stmts {
+        let name = match stmt {
+            Stmt::Import(name) => name,
+            _ => continue,
+        };
+        let path = fs::canonicalize(dir.join(&name.value)).map_err(|_| {
+            format!("Unable to read file '{}' imported on line {}",
+                    name.value, name.line_num)
+        })?;
+
+        if let Some(pos) = stack.iter().position(|p| *p == path) {
+            let cycle: Vec<String> = stack[pos..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("Import cycle detected: {}", cycle.join(" -> ")));
+        }
+        if loaded.contains(&path) {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).map_err(|_| {
+            format!("Unable to read file '{}' imported on line {}",
+                    name.value, name.line_num)
+        })?;
+        let (module_stmts, errors) = Parser::new(tokenize(&source)).parse();
+        if let Some(e) = errors.first() {
+            return Err(format!("{}: line {}, column {}: {}", path.display(),
+                               e.line, e.col, e.err));
         }
-        if c == '\n' {
-            count += 1;
+
+        stack.push(path.clone());
+        let module_dir = path.parent().unwrap_or(dir).to_path_buf();
+        let imports = link_imports(&module_stmts, &module_dir, stack, loaded)?;
+        stack.pop();
+
+        let mut module = Codegen::module_gen(module_stmts).map_err(|errors| {
+            let e = &errors[0];
+            format!("{}: line {}, column {}: {}", path.display(), e.line, e.col, e.err)
+        })?;
+        loaded.push(path);
+        if let Some(imports) = imports {
+            module = imports.merge(module).map_err(|e| e.err)?;
         }
+        linked = match linked {
+            Some(p) => Some(p.merge(module).map_err(|e| e.err)?),
+            None => Some(module),
+        };
     }
+    Ok(linked)
 }
 
 /// Read source code (required syntax is similar to javascript) before passing
@@ -45,139 +301,200 @@ fn print_line(file: String, line: u32) {
 /// 3. Codegen:     Walk the AST and generate bytecode
 /// 4. Interpreter: Iterate through the bytecode and execute the instructions
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Please provide your .js file as the sole argument");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match Options::parse(&args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e.red().bold(), cli::USAGE);
+            process::exit(1);
+        }
+    };
+    if opts.help {
+        println!("{}", cli::USAGE);
         return;
     }
+    if opts.repl {
+        repl(&opts);
+        return;
+    }
+
+    let mut timings = Timings { enabled: opts.time, ..Timings::default() };
+    let exit_code = run(&opts, &mut timings);
+    timings.report();
+    if let Some(code) = exit_code {
+        process::exit(code);
+    }
+}
+
+/// Compile and run the file given on the command line, phases are measured in
+/// `timings`. Returns the exit code if the program called `exit` or failed
+/// with a runtime error
+fn run(opts: &Options, timings: &mut Timings) -> Option<i32> {
+    let file_name = opts.file.clone();
+
+    // Compiled programs skip the frontend and are executed right away
+    if opts.run_bytecode {
+        let program = match Program::read_from_file(&file_name) {
+            Ok(program) => program,
+            Err(e) => {
+                println!("{}", format!("{}: {}", file_name, e.err).red().bold());
+                return None;
+            }
+        };
+        if opts.bytecode {
+            print_bytecode(&program);
+        }
+        if let Err(errors) = program.verify() {
+            print_verify_errors(&errors);
+            return None;
+        }
+        if opts.no_run {
+            return None;
+        }
+        return timings.time("Interpreting", || execute(&program, opts));
+    }
 
-    // Get file_name from argv and read the entire file into file_string
-    let file_name = args[1].to_string();
-    let file_string = fs::read_to_string(file_name).expect("Unable to read file");
+    // Read the entire file into file_string
+    let file_string = fs::read_to_string(&file_name).expect("Unable to read file");
 
-    if DEBUGSOURCE {
+    if opts.source {
         println!("\n+-----------Source-Code-----------+");
         println!("{}", file_string);
     }
 
     #[allow(unused_mut)]
-    let mut tokens = tokenize(&file_string);
+    // Comments show up in the token dump, the parser skips them
+    let mut tokens = timings.time("Lexing", || tokenize_with_comments(&file_string));
 
-    if DEBUGTOKENS {
+    if opts.tokens {
         println!("\n+-------------Tokens--------------+");
         for token in tokens.clone() {
             println!("{:?}", token);
         }
     }
 
-    let mut parser = Parser::new(tokens);
-    let stmts = match parser.parse() {
-        Ok(stmts) => stmts,
-        Err(err) => {
-            for e in err {
-                print_line(file_string.clone(), e.line);
-                println!("{}\n\n", e.err.bold());
-            }
-            println!(
-                "{}",
-                "Could not compile program due to above errors\n"
-                    .red()
-                    .bold()
-            );
-            return;
-        }
-    };
+    let (stmts, errors) = timings.time("Parsing", || Parser::new(tokens).parse());
 
-    if DEBUGAST {
+    if opts.ast {
         println!("+----------------AST-----------------+");
         for stmt in stmts.clone() {
             println!("{:#?}", stmt);
         }
     }
+    if opts.ast_json {
+        println!("{}", stmts.to_json());
+    }
+    if opts.ast_source {
+        println!("{}", stmts.to_source());
+    }
 
-    let program = Codegen::bytecode_gen(stmts);
-    let mut vals = Vec::new();
-    for (_, value) in program.clone().function_list.into_iter() {
-        vals.push(value);
+    if !errors.is_empty() {
+        print_compile_errors(&file_string, &errors);
+        return None;
     }
 
-    if DEBUGBYTECODE {
-        print!("+-----------Bytecode--------------+");
-        for (j, instr) in program.bytecode.iter().enumerate() {
-            if vals.contains(&j) {
-                for (key, value) in program.clone().function_list.into_iter() {
-                    if value == j {
-                        print!("\n\n\t< {} >", key);
-                    }
-                }
-            }
-            if j == program.entry_point {
-                print!("\n\n\t< Entry Point >");
-            }
-            let i = j + 1;
-            match instr.clone() {
-                BcArr::I(Instr::Add) => {
-                    print!("\n{:4}   Add     ", i)
-                }
-                BcArr::I(Instr::Sub) => {
-                    print!("\n{:4}   Sub     ", i)
-                }
-                BcArr::I(Instr::Div) => {
-                    print!("\n{:4}   Div     ", i)
-                }
-                BcArr::I(Instr::Mul) => {
-                    print!("\n{:4}   Mul     ", i)
-                }
-                BcArr::I(Instr::Jmp) => {
-                    print!("\n{:4}   Jmp     ", i)
-                }
-                BcArr::I(Instr::Call) => {
-                    print!("\n{:4}   Call    ", i)
-                }
-                BcArr::I(v) => {
-                    print!("\n{:4}   {:?}   ", i, v)
-                }
-                BcArr::V(Value::Number(v)) => {
-                    print!("{:?}, ", v)
-                }
-                BcArr::V(Value::Reg(v)) => {
-                    print!("{:?}, ", Value::Reg(v))
-                }
-                BcArr::V(Value::Pool(v)) => {
-                    print!("{:?}, ", Value::Pool(v))
-                }
-                BcArr::V(Value::StringLiteral(v)) => {
-                    print!("{:?}, ", v)
-                }
-                BcArr::V(Value::CPool(v)) => {
-                    print!("{:?}, ", Value::CPool(v))
-                }
-                BcArr::V(Value::Bool(v)) => {
-                    print!("{:?}, ", Value::Bool(v))
-                }
-                BcArr::V(Value::VAddr(v)) => {
-                    print!("{:?}, ", Value::VAddr(v))
-                }
-                BcArr::V(Value::Nil) => {
-                    print!("NIL")
-                }
-            }
+    if opts.tree_walk {
+        if opts.no_run {
+            return None;
         }
-        if !program.const_pool.is_empty() {
-            println!("\n+-----------Const-Pool-------------+\n");
-            for (i, c) in program.const_pool.iter().enumerate() {
-                println!("[{}] - {:?}", i, c);
+        let mut walker = TreeWalker::new(std::io::stdout());
+        return match timings.time("Interpreting", || walker.interpret(&stmts)) {
+            Ok(code) => code,
+            Err(e) => {
+                println!("{}", format!("Runtime Error: {} on line {}.", e.err, e.line)
+                         .red().bold());
+                Some(1)
             }
+        };
+    }
+
+    // Imported modules are linked in front of this one
+    let file_path = fs::canonicalize(&file_name).expect("Unable to read file");
+    let file_dir = file_path.parent().unwrap().to_path_buf();
+    let imports = timings.time("Imports", || {
+        link_imports(&stmts, &file_dir, &mut vec![file_path], &mut Vec::new())
+    });
+    let imports = match imports {
+        Ok(imports) => imports,
+        Err(e) => {
+            println!("{}", e.red().bold());
+            return Some(1);
+        }
+    };
+
+    // Calls into imported modules are resolved once the modules are linked
+    let mut codegen = Codegen::new();
+    codegen.set_lint_shadowing(opts.lint_shadowing);
+    let program = timings.time("Codegen", || match imports {
+        None => codegen.into_bytecode(stmts),
+        Some(imports) => {
+            let mut stmts = stmts;
+            stmts.extend(implicit_main(&stmts));
+            codegen.into_module(stmts).and_then(|program| {
+                let program = imports.merge(program).map_err(|e| vec![e])?;
+                program.check_linked()?;
+                Ok(program)
+            })
+        }
+    });
+    let program = match program {
+        Ok(program) if opts.optimize => {
+            timings.time("Optimizing", || {
+                AbstractInterpreter::<Constant>::optimize(&program).peephole()
+            })
+        }
+        Ok(program) => program,
+        Err(errors) => {
+            print_compile_errors(&file_string, &errors);
+            return None;
         }
+    };
+    if opts.bytecode {
+        print_bytecode(&program);
+    }
+
+    if opts.stats {
+        let stats = program.stats();
+        println!("+-----------Statistics-------------+\n");
+        println!("Bytecode length:   {}", stats.bytecode_len);
+        println!("Instructions:      {}", stats.instr_count);
+        println!("Functions:         {}", stats.function_count);
+        println!("Const-pool size:   {}", stats.const_pool_size);
+        println!("Max register:      r{}", stats.max_reg);
         println!("\n+----------------------------------+\n");
     }
 
-    let cfg = program.generate_cfg();
-    //println!("CFG: {:#?}", cfg);
+    // Listings are printed first, they help to track down malformed code
+    if let Err(errors) = program.verify() {
+        print_verify_errors(&errors);
+        return None;
+    }
 
-    let mut abstract_interpreter= AbstractInterpreter::new(&program);
-    abstract_interpreter.run(&cfg[0].1);
+    if let Some(out) = &opts.emit_bytecode {
+        if let Err(e) = program.write_to_file(out) {
+            println!("{}", format!("Unable to write '{}': {}", out, e).red().bold());
+            return Some(1);
+        }
+    }
+
+    if opts.cfg_dot {
+        for (name, cfg) in program.generate_cfg() {
+            println!("// {}\n{}", name, cfg.to_dot());
+        }
+    }
 
-    let mut vm = Interpreter::new(program);
-    vm.interpret();
+    if opts.analyze {
+        timings.time("Analysis", || {
+            if opts.sign_domain {
+                analyze::<SignDomain>(&program, opts.widen_after);
+            } else {
+                analyze::<Interval>(&program, opts.widen_after);
+            }
+        });
+    }
+
+    if opts.no_run {
+        return None;
+    }
+    timings.time("Interpreting", || execute(&program, opts))
 }
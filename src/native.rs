@@ -0,0 +1,171 @@
+//! Functions implemented in Rust that are called with the normal call syntax,
+//! eg. `max(a, b)`. The code generator resolves calls to them by name and
+//! emits a `CallNative` instruction holding the index into `NATIVES`.
+
+use crate::{codegen::Value, vm::Interpreter};
+
+/// Native function, always called with exactly `arity` arguments. The last
+/// parameter of a variadic native takes any number of arguments, they are
+/// collected into an array that is passed in its place
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub variadic: bool,
+    pub func: fn(&[Value]) -> Result<Value, String>,
+}
+
+pub const NATIVES: &[Native] = &[
+    Native { name: "abs", arity: 1, variadic: false, func: abs },
+    Native { name: "min", arity: 2, variadic: false, func: min },
+    Native { name: "max", arity: 2, variadic: false, func: max },
+    Native { name: "clamp", arity: 3, variadic: false, func: clamp },
+    Native { name: "sqrt", arity: 1, variadic: false, func: sqrt },
+    Native { name: "floor", arity: 1, variadic: false, func: floor },
+    Native { name: "assert", arity: 1, variadic: false, func: assert },
+    Native { name: "format", arity: 2, variadic: true, func: format },
+];
+
+/// Method of strings, called as `s.name()` without arguments
+pub struct Method {
+    pub name: &'static str,
+    pub func: fn(&str) -> String,
+}
+
+pub const STRING_METHODS: &[Method] = &[
+    Method { name: "toUpperCase", func: str::to_uppercase },
+    Method { name: "toLowerCase", func: str::to_lowercase },
+    Method { name: "trim", func: |s| s.trim().to_string() },
+];
+
+/// Call the method `name` on `receiver`, only strings have methods
+pub fn call_method(receiver: &Value, name: &str) -> Result<Value, String> {
+    let s = match receiver {
+        Value::StringLiteral(s) => s,
+        _ => return Err(format!("Method '{}' can only be called on strings", name)),
+    };
+    let method = STRING_METHODS
+        .iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| format!("Strings have no method '{}'", name))?;
+    Ok(Value::StringLiteral((method.func)(s)))
+}
+
+/// Index into `NATIVES` and definition of the native function called `name`
+pub fn lookup(name: &str) -> Option<(usize, &'static Native)> {
+    NATIVES.iter().enumerate().find(|(_, n)| n.name == name)
+}
+
+/// Make sure all arguments passed to the native function `name` are numbers
+fn numbers(name: &str, args: &[Value]) -> Result<(), String> {
+    if args.iter().all(Interpreter::check_num) {
+        Ok(())
+    } else {
+        Err(format!("Arguments of '{}' need to be numbers", name))
+    }
+}
+
+fn abs(args: &[Value]) -> Result<Value, String> {
+    numbers("abs", args)?;
+    Ok(match &args[0] {
+        Value::Int(i) => i.checked_abs().map_or(Value::Number((*i as f64).abs()), Value::Int),
+        v => Value::Number(Interpreter::unpack_number(v).abs()),
+    })
+}
+
+fn min(args: &[Value]) -> Result<Value, String> {
+    numbers("min", args)?;
+    Ok(match (&args[0], &args[1]) {
+        (Value::Int(a), Value::Int(b)) => Value::Int(*a.min(b)),
+        (a, b) => Value::Number(Interpreter::unpack_number(a).min(Interpreter::unpack_number(b))),
+    })
+}
+
+fn max(args: &[Value]) -> Result<Value, String> {
+    numbers("max", args)?;
+    Ok(match (&args[0], &args[1]) {
+        (Value::Int(a), Value::Int(b)) => Value::Int(*a.max(b)),
+        (a, b) => Value::Number(Interpreter::unpack_number(a).max(Interpreter::unpack_number(b))),
+    })
+}
+
+/// `clamp(x, lo, hi)` restricts `x` to the range [lo, hi]
+fn clamp(args: &[Value]) -> Result<Value, String> {
+    numbers("clamp", args)?;
+    let upper = min(&[args[0].clone(), args[2].clone()])?;
+    max(&[args[1].clone(), upper])
+}
+
+fn sqrt(args: &[Value]) -> Result<Value, String> {
+    numbers("sqrt", args)?;
+    Ok(Value::Number(Interpreter::unpack_number(&args[0]).sqrt()))
+}
+
+fn floor(args: &[Value]) -> Result<Value, String> {
+    numbers("floor", args)?;
+    Ok(match &args[0] {
+        Value::Int(i) => Value::Int(*i),
+        v => Value::Number(Interpreter::unpack_number(v).floor()),
+    })
+}
+
+/// Raise an error if the argument is falsy, lets test programs check their
+/// own results
+fn assert(args: &[Value]) -> Result<Value, String> {
+    if args[0].is_truthy() {
+        Ok(Value::Nil)
+    } else {
+        Err("Assertion failed".to_string())
+    }
+}
+
+/// `format(fmt, ...args)` replaces every `{}` in `fmt` with the next argument,
+/// formatted the way it is printed
+fn format(args: &[Value]) -> Result<Value, String> {
+    let (fmt, values) = match args {
+        [Value::StringLiteral(fmt), Value::Array(values)] => (fmt, values),
+        _ => return Err("Format string of 'format' needs to be a string".to_string()),
+    };
+    let placeholders = fmt.matches("{}").count();
+    if placeholders != values.len() {
+        return Err(format!("Format string has {} placeholders but {} arguments were given",
+                           placeholders, values.len()));
+    }
+
+    let mut out = String::new();
+    let mut parts = fmt.split("{}");
+    out.push_str(parts.next().unwrap_or_default());
+    for (part, v) in parts.zip(values) {
+        let s = Interpreter::format_value(v)
+            .ok_or_else(|| format!("Type can not be formatted: {:?}", v))?;
+        out.push_str(&s);
+        out.push_str(part);
+    }
+    Ok(Value::StringLiteral(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::StringLiteral(s.to_string())
+    }
+
+    #[test]
+    fn format_fills_in_placeholders() {
+        let args = [string("{} + {} = {}!"),
+                    Value::Array(vec![Value::Int(1), Value::Number(1.5), string("x")])];
+        assert_eq!(format(&args), Ok(string("1 + 1.5 = x!")));
+        assert_eq!(format(&[string("none"), Value::Array(vec![])]), Ok(string("none")));
+    }
+
+    #[test]
+    fn format_needs_one_argument_per_placeholder() {
+        let too_few = [string("{} and {}"), Value::Array(vec![Value::Int(1)])];
+        assert_eq!(format(&too_few),
+                   Err("Format string has 2 placeholders but 1 arguments were given".to_string()));
+        let too_many = [string("{}"), Value::Array(vec![Value::Int(1), Value::Int(2)])];
+        assert_eq!(format(&too_many),
+                   Err("Format string has 1 placeholders but 2 arguments were given".to_string()));
+    }
+}
@@ -7,13 +7,21 @@ use crate::err::{Error};
 pub struct Parser {
     tokens: Vec<Token>,
     index: usize,
+
+    /// Syntax errors found so far
+    errors: Vec<Error>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
+        let (invalid, tokens): (Vec<Token>, Vec<Token>) = tokens
+            .into_iter()
+            .filter(|t| t.t_type != Comment)
+            .partition(|t| t.t_type == Invalid);
         Self {
             tokens,
             index: 0,
+            errors: invalid.into_iter().map(|t| Error::new(t.value, t.line_num, t.col)).collect(),
         }
     }
 
@@ -51,46 +59,91 @@ impl Parser {
         return self.peek().t_type == t_type;
     }
 
-    fn lc(&self) -> u32 {
-        return self.peek().line_num;
+    /// Line and column of the next token
+    fn lc(&self) -> (u32, u32) {
+        (self.peek().line_num, self.peek().col)
     }
 
     /// Consume a token if it has the correct type and advance the parser
-    fn consume(&mut self, t_type: TokenType, msg: &str, l: u32)
+    fn consume(&mut self, t_type: TokenType, msg: &str, l: (u32, u32))
             -> Result<Token, Error> {
         if self.check(t_type) {
             Ok(self.next().clone())
         } else {
-            Err(Error::new(msg.to_string(), l))
+            Err(Error::new(msg.to_string(), l.0, l.1))
         }
     }
 
-    /// Parse the program, and return either a vector of statements if the
-    /// parsing is successful or a vector of errors containing all found errors
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<Error>> {
+    /// Parse the program, and return every statement that could be parsed
+    /// alongside all errors that were found. After an error the parser skips
+    /// ahead to the next statement boundary, so tooling still receives an AST
+    /// for the valid parts of a file that contains errors.
+    pub fn parse(&mut self) -> (Vec<Stmt>, Vec<Error>) {
         let mut stmts:  Vec<Stmt> = Vec::new();
-        let mut errors: Vec<Error> = Vec::new();
 
         while !self.is_at_end() {
-            match self.declaration() {
-                Ok(stmt) => stmts.push(stmt),
-                Err(err) => {
-                    errors.push(err.clone());
-                    //self.synchronize();
+            let start = self.index;
+            // Imports are only allowed at the top level
+            if self.match_tokens(&[Import]) {
+                match self.import_decl() {
+                    Ok(stmt) => stmts.push(stmt),
+                    Err(err) => self.recover(err, start),
                 }
+            } else if let Some(stmt) = self.declaration() {
+                stmts.push(stmt);
             }
         }
+        (stmts, std::mem::take(&mut self.errors))
+    }
 
-        if !errors.is_empty() {
-           Err(errors)
-        } else {
-            Ok(stmts)
+    /// Record `err` and skip ahead to the next statement. `start` is the
+    /// index of the first token of the statement that failed to parse
+    fn recover(&mut self, err: Error, start: usize) {
+        self.errors.push(err);
+        // Guarantee progress if the error did not consume anything
+        if self.index == start {
+            self.next();
+        }
+        self.synchronize();
+    }
+
+    /// Discard tokens until the start of the next statement is reached. The
+    /// end of a block is a boundary as well, so an error inside of a block
+    /// does not swallow the rest of the file
+    fn synchronize(&mut self) {
+        while !self.is_at_end() {
+            if self.index > 0 && self.previous().t_type == SemiColon {
+                return;
+            }
+            match self.peek().t_type {
+                Var | Let | Function | If | While | Do | For | Return | Print |
+                Break | Continue | CloseCurly => {
+                    return;
+                }
+                _ => {
+                    self.next();
+                }
+            }
         }
     }
 
     // Statements ====================================================
 
-    fn declaration(&mut self) -> Result<Stmt, Error> {
+    /// Parse a declaration. Syntax errors are recorded and None is returned
+    /// once the parser recovered, so the enclosing block can continue with
+    /// its next statement
+    fn declaration(&mut self) -> Option<Stmt> {
+        let start = self.index;
+        match self.try_declaration() {
+            Ok(stmt) => Some(stmt),
+            Err(err) => {
+                self.recover(err, start);
+                None
+            }
+        }
+    }
+
+    fn try_declaration(&mut self) -> Result<Stmt, Error> {
         match self.peek().t_type {
             Var => {
                 self.next();
@@ -108,9 +161,23 @@ impl Parser {
         }
     }
 
+    fn import_decl(&mut self) -> Result<Stmt, Error> {
+        let path = self.consume(StringLiteral, "Expected file name after import",
+                                self.lc())?;
+        self.consume(SemiColon, "Expected ';' after import", self.lc())?;
+        Ok(Stmt::Import(path))
+    }
+
     fn fun_decl(&mut self) -> Result<Stmt, Error> {
         let fun_name = self.consume(Identifier, "Expected function name",
                                     self.lc())?;
+        let (args, fun_body) = self.fun_rest()?;
+        Ok(Stmt::Function(fun_name, args, fun_body))
+    }
+
+    /// Parameters and body of a function, shared by declarations and
+    /// function expressions
+    fn fun_rest(&mut self) -> Result<(Vec<Token>, Vec<Stmt>), Error> {
         self.consume(OpenParen, "Expected '(' after function declaration",
                     self.lc())?;
 
@@ -118,8 +185,8 @@ impl Parser {
         if !self.check(CloseParen) {
             args.push(self.consume(Identifier, "Expected parameter name",
                                    self.lc())?);
-            while self.check(Comma) {
-                self.consume(Comma, "weird error", self.lc())?;
+            // A trailing comma is allowed before the closing parenthesis
+            while self.match_tokens(&[Comma]) && !self.check(CloseParen) {
                 args.push(self.consume(Identifier, "Expected parameter name",
                                        self.lc())?);
             }
@@ -129,7 +196,7 @@ impl Parser {
         self.consume(OpenCurly, "Expected '{' after function header",
                      self.lc())?;
         let fun_body = self.block_statement()?;
-        Ok(Stmt::Function(fun_name, args, vec![fun_body]))
+        Ok((args, vec![fun_body]))
     }
 
     fn var_decl(&mut self) -> Result<Stmt, Error> {
@@ -179,25 +246,47 @@ impl Parser {
         if self.match_tokens(&[While]) {
             return self.while_statement();
         }
+        if self.match_tokens(&[Do]) {
+            return self.do_while_statement();
+        }
         if self.match_tokens(&[For]) {
             return self.for_statement();
         }
         if self.match_tokens(&[Return]) {
             return self.return_statement();
         }
+        if self.match_tokens(&[Break, Continue]) {
+            let token = self.previous().clone();
+            self.consume(SemiColon, &format!("Expected ';' after {}", token.value),
+                         self.lc())?;
+            if token.t_type == Break {
+                return Ok(Stmt::Break(token));
+            }
+            return Ok(Stmt::Continue(token));
+        }
         self.expr_statement()
     }
 
     fn print_statement(&mut self) -> Result<Stmt, Error> {
-        let expr = self.expression()?;
+        self.consume(OpenParen, "Expected '(' after console.log", self.lc())?;
+        let mut args: Vec<Expr> = Vec::new();
+        if !self.check(CloseParen) {
+            args.push(self.expression()?);
+            while self.match_tokens(&[Comma]) && !self.check(CloseParen) {
+                args.push(self.expression()?);
+            }
+        }
+        self.consume(CloseParen, "Expected closing parantheses", self.lc())?;
         self.consume(SemiColon, "Expected ';' after value", self.lc())?;
-        Ok(Stmt::Print(expr))
+        Ok(Stmt::Print(args))
     }
 
     fn block_statement(&mut self) -> Result<Stmt, Error> {
         let mut stmts: Vec<Stmt> = Vec::new();
         while !self.check(CloseCurly) && !self.is_at_end() {
-            stmts.push(self.declaration()?);
+            if let Some(stmt) = self.declaration() {
+                stmts.push(stmt);
+            }
         }
         self.consume(CloseCurly, "Expected '}' after block.", self.lc())?;
         Ok(Stmt::Block(stmts))
@@ -227,8 +316,32 @@ impl Parser {
         Ok(Stmt::While(cond, body))
     }
 
+    fn do_while_statement(&mut self) -> Result<Stmt, Error> {
+        let body = Box::new(self.statement()?);
+        self.consume(While, "Expected 'while' after do body", self.lc())?;
+        self.consume(OpenParen, "Expected '(' after while", self.lc())?;
+        let cond = self.expression()?;
+        self.consume(CloseParen, "Expected ')' after while condition",
+                     self.lc())?;
+        self.consume(SemiColon, "Expected ';' after do-while statement",
+                     self.lc())?;
+        Ok(Stmt::DoWhile(body, cond))
+    }
+
     fn for_statement(&mut self) -> Result<Stmt, Error> {
         self.consume(OpenParen, "Expected '(' after for statement", self.lc())?;
+
+        // for (var NAME of EXPR) BODY
+        if self.check(Var) && self.tokens.get(self.index + 2).map(|t| t.t_type) == Some(Of) {
+            self.next();
+            let name = self.consume(Identifier, "Expected variable name", self.lc())?;
+            self.next();
+            let iterable = self.expression()?;
+            self.consume(CloseParen, "Expected ')' after for clause", self.lc())?;
+            let body = Box::new(self.statement()?);
+            return Ok(Stmt::ForOf { name, iterable, body });
+        }
+
         let initializer = if self.match_tokens(&[SemiColon]) {
             None
         } else if self.match_tokens(&[Var]) {
@@ -252,18 +365,9 @@ impl Parser {
         };
         self.consume(CloseParen, "Expected ')' after for clause", self.lc())?;
 
-        let mut body = self.statement()?;
-
-        if let Some(i) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(i)]);
-        }
-
-        body = Stmt::While(cond.unwrap(), Box::new(body));
-
-        if let Some(init) = initializer {
-            body = Stmt::Block(vec![init, body]);
-        }
-        Ok(body)
+        let body = Box::new(self.statement()?);
+        let cond = cond.unwrap_or(Expr::Literal { literal: Literal::True });
+        Ok(Stmt::For { init: initializer.map(Box::new), cond, increment, body })
     }
 
     fn return_statement(&mut self) -> Result<Stmt, Error> {
@@ -290,12 +394,35 @@ impl Parser {
     }
 
     fn assignment(&mut self) -> Result<Expr, Error> {
-        let expr = self.or()?;
+        let expr = self.ternary()?;
 
-        if self.match_tokens(&[EqualSign]) {
-            let _equals = self.previous();
+        if self.match_tokens(&[EqualSign, PlusEq, MinusEq, MultiplyEq, DivideEq]) {
+            let equals = self.previous().clone();
             let value = self.assignment()?;
 
+            // Compound assignments are desugared into `x = x <op> value`
+            let op = match equals.t_type {
+                PlusEq     => Some((Plus, "+")),
+                MinusEq    => Some((Minus, "-")),
+                MultiplyEq => Some((Multiply, "*")),
+                DivideEq   => Some((Divide, "/")),
+                _          => None,
+            };
+
+            let value = match op {
+                Some((t_type, v)) => Expr::Binary {
+                    left: Box::new(expr.clone()),
+                    op: Token {
+                        t_type,
+                        value: v.to_string(),
+                        line_num: equals.line_num,
+                        col: equals.col,
+                    },
+                    right: Box::new(value),
+                },
+                None => value,
+            };
+
             match expr {
                 Expr::Variable { name, ..} => {
                     return Ok(Expr::Assignment {
@@ -303,13 +430,50 @@ impl Parser {
                         expr: Box::new(value),
                     })
                 },
-                _ => { return Err(Error::new("Invalid assignment target"
-                                             .to_string(), self.lc())); }
+                Expr::Index { target, index } => {
+                    return Ok(Expr::IndexAssignment {
+                        target,
+                        index,
+                        expr: Box::new(value),
+                    })
+                },
+                // `o.key = v` is the same as `o["key"] = v`
+                Expr::Property { target, name } => {
+                    return Ok(Expr::IndexAssignment {
+                        target,
+                        index: Box::new(Expr::Literal {
+                            literal: Literal::StringLiteral(name.value)
+                        }),
+                        expr: Box::new(value),
+                    })
+                },
+                target => {
+                    return Err(Error::new(format!("Cannot assign to {}",
+                                                  target_kind(&target)),
+                                          equals.line_num, equals.col));
+                }
             }
         }
         Ok(expr)
     }
 
+    fn ternary(&mut self) -> Result<Expr, Error> {
+        let expr = self.or()?;
+
+        if self.match_tokens(&[Question]) {
+            let then_e = self.assignment()?;
+            self.consume(Colon, "Expected ':' in conditional expression",
+                         self.lc())?;
+            let else_e = self.ternary()?;
+            return Ok(Expr::Ternary {
+                cond: Box::new(expr),
+                then_e: Box::new(then_e),
+                else_e: Box::new(else_e),
+            });
+        }
+        Ok(expr)
+    }
+
     fn or(&mut self) -> Result<Expr, Error> {
         let mut expr = self.and()?;
 
@@ -325,9 +489,9 @@ impl Parser {
     }
 
     fn and(&mut self) -> Result<Expr, Error> {
-        let mut expr = self.equality()?;
+        let mut expr = self.bit_or()?;
         while self.match_tokens(&[And]) {
-            let right = self.equality()?;
+            let right = self.bit_or()?;
             expr = Expr::Logical {
                 l_expr: Box::new(expr),
                 operator: LogicalOp::And,
@@ -337,6 +501,51 @@ impl Parser {
         Ok(expr)
     }
 
+    fn bit_or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.bit_xor()?;
+
+        while self.match_tokens(&[BitOr]) {
+            let op = self.previous().clone();
+            let right = self.bit_xor()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                op,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn bit_xor(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.bit_and()?;
+
+        while self.match_tokens(&[BitXor]) {
+            let op = self.previous().clone();
+            let right = self.bit_and()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                op,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn bit_and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.equality()?;
+
+        while self.match_tokens(&[BitAnd]) {
+            let op = self.previous().clone();
+            let right = self.equality()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                op,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
     fn equality(&mut self) -> Result<Expr, Error> {
         let mut expr = self.comparison()?;
 
@@ -353,9 +562,24 @@ impl Parser {
     }
 
     fn comparison(&mut self) -> Result<Expr, Error> {
-        let mut expr = self.term()?;
+        let mut expr = self.shift()?;
 
         while self.match_tokens(&[Greater, GreaterEq, Less, LessEq]) {
+            let op = self.previous().clone();
+            let right = self.shift()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                op,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn shift(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.term()?;
+
+        while self.match_tokens(&[ShiftLeft, ShiftRight]) {
             let op = self.previous().clone();
             let right = self.term()?;
             expr = Expr::Binary {
@@ -385,7 +609,7 @@ impl Parser {
     fn factor(&mut self) -> Result<Expr, Error> {
         let mut expr = self.unary()?;
 
-        while self.match_tokens(&[Divide, Multiply]) {
+        while self.match_tokens(&[Divide, Multiply, Modulo]) {
             let op = self.previous().clone();
             let right = self.unary()?;
             expr = Expr::Binary {
@@ -398,6 +622,11 @@ impl Parser {
     }
 
     fn unary(&mut self) -> Result<Expr, Error> {
+        if self.match_tokens(&[PlusPlus, MinusMinus]) {
+            let op = self.previous().clone();
+            let target = self.unary()?;
+            return increment(target, op, true);
+        }
         if self.match_tokens(&[Not, Minus]) {
             let op = self.previous().clone();
             let right = self.unary()?;
@@ -412,8 +641,32 @@ impl Parser {
 
     fn call(&mut self) -> Result<Expr, Error> {
         let mut expr = self.primary()?;
-        if self.match_tokens(&[OpenParen]) {
-            expr = self.finish_call(expr)?;
+        // Calls, indexing and member access apply to the result of whatever
+        // precedes them, eg. `f(x)(y)` or `s.trim().length`
+        while self.match_tokens(&[OpenParen, OpenBracket, Dot]) {
+            if self.previous().t_type == OpenParen {
+                expr = self.finish_call(expr)?;
+                continue;
+            }
+            if self.previous().t_type == Dot {
+                let name = self.consume(Identifier, "Expected property name after '.'",
+                                        self.lc())?;
+                expr = Expr::Property {
+                    target: Box::new(expr),
+                    name,
+                };
+                continue;
+            }
+            let index = self.expression()?;
+            self.consume(CloseBracket, "Expected ']' after index", self.lc())?;
+            expr = Expr::Index {
+                target: Box::new(expr),
+                index: Box::new(index),
+            };
+        }
+        if self.match_tokens(&[PlusPlus, MinusMinus]) {
+            let op = self.previous().clone();
+            return increment(expr, op, false);
         }
         Ok(expr)
     }
@@ -422,7 +675,7 @@ impl Parser {
         let mut args: Vec<Expr> = Vec::new();
         if !self.check(CloseParen) {
             args.push(self.expression()?);
-            while self.match_tokens(&[Comma]) {
+            while self.match_tokens(&[Comma]) && !self.check(CloseParen) {
                 args.push(self.expression()?);
             }
         }
@@ -433,6 +686,19 @@ impl Parser {
             })
     }
 
+    /// `key: value` pair of an object literal, keys are strings or bare
+    /// identifiers
+    fn object_entry(&mut self) -> Result<(String, Expr), Error> {
+        if !self.match_tokens(&[StringLiteral, Identifier]) {
+            let token = self.peek().clone();
+            return Err(Error::new(format!("Expected object key on line {}", token.line_num),
+                                  token.line_num, token.col));
+        }
+        let key = self.previous().value.clone();
+        self.consume(Colon, "Expected ':' after object key", self.lc())?;
+        Ok((key, self.expression()?))
+    }
+
     fn primary(&mut self) -> Result<Expr, Error> {
         if self.match_tokens(&[True]) {
             return Ok(Expr::Literal { literal: Literal::True });
@@ -446,10 +712,47 @@ impl Parser {
             return Ok(Expr::Literal { literal: Literal::Nil });
         }
 
+        if self.match_tokens(&[OpenBracket]) {
+            let mut elements: Vec<Expr> = Vec::new();
+            if !self.check(CloseBracket) {
+                elements.push(self.expression()?);
+                while self.match_tokens(&[Comma]) {
+                    elements.push(self.expression()?);
+                }
+            }
+            self.consume(CloseBracket, "Expected ']' after array elements",
+                         self.lc())?;
+            return Ok(Expr::Array { elements });
+        }
+
+        // Blocks are statements, so a curly brace in expression position
+        // starts an object literal
+        if self.match_tokens(&[OpenCurly]) {
+            let mut entries: Vec<(String, Expr)> = Vec::new();
+            if !self.check(CloseCurly) {
+                entries.push(self.object_entry()?);
+                while self.match_tokens(&[Comma]) {
+                    entries.push(self.object_entry()?);
+                }
+            }
+            self.consume(CloseCurly, "Expected '}' after object entries",
+                         self.lc())?;
+            return Ok(Expr::Object { entries });
+        }
+
         if self.match_tokens(&[Number]) {
-            return Ok(Expr::Literal { literal: Literal::Number(
-                        self.previous().value.parse::<f32>().unwrap() as f64)
-            });
+            // Literals without a decimal point are integers, unless they are
+            // too large to be represented as one
+            if let Ok(i) = self.previous().value.parse::<i64>() {
+                return Ok(Expr::Literal { literal: Literal::Int(i) });
+            }
+            let token = self.previous();
+            return match token.value.parse::<f64>() {
+                Ok(n) => Ok(Expr::Literal { literal: Literal::Number(n) }),
+                Err(_) => Err(Error::new(format!("Invalid number literal '{}' on line {}",
+                                                 token.value, token.line_num),
+                                         token.line_num, token.col)),
+            };
         }
 
         if self.match_tokens(&[StringLiteral]) {
@@ -471,8 +774,178 @@ impl Parser {
             return Ok(Expr::Grouping { expr: Box::new(expr), });
         }
 
-        self.next();
+        // Anonymous function in expression position
+        if self.match_tokens(&[Function]) {
+            let (params, body) = self.fun_rest()?;
+            return Ok(Expr::Function { params, body });
+        }
+
+        // There are no methods or constructors for `this` to refer to
+        if self.match_tokens(&[This]) {
+            let token = self.previous();
+            return Err(Error::new(format!("'this' on line {} is not supported in this context",
+                        token.line_num), token.line_num, token.col));
+        }
+
+        let token = self.next().clone();
         Err(Error::new(format!("Error on line: {} at token: {}",
-                    self.peek().line_num, self.previous().value), self.lc()))
+                    token.line_num, token.value), token.line_num, token.col))
+    }
+}
+
+/// Desugar `++x`, `x++` and the `--` forms into an assignment of `x + 1` or
+/// `x - 1`. The postfix forms undo the change on the result of the assignment
+/// so they evaluate to the old value. Only variables can be incremented
+fn increment(target: Expr, op: Token, prefix: bool) -> Result<Expr, Error> {
+    let name = match target {
+        Expr::Variable { name } => name,
+        target => {
+            return Err(Error::new(format!("Cannot apply '{}' to {}", op.value,
+                                          target_kind(&target)),
+                                  op.line_num, op.col));
+        }
+    };
+    let (apply, undo) = if op.t_type == PlusPlus { (Plus, Minus) } else { (Minus, Plus) };
+    let binary = |left: Expr, t_type: TokenType| Expr::Binary {
+        left: Box::new(left),
+        op: Token {
+            t_type,
+            value: if t_type == Plus { "+" } else { "-" }.to_string(),
+            ..op.clone()
+        },
+        right: Box::new(Expr::Literal { literal: Literal::Int(1) }),
+    };
+
+    let assignment = Expr::Assignment {
+        name: name.clone(),
+        expr: Box::new(binary(Expr::Variable { name }, apply)),
+    };
+    Ok(if prefix { assignment } else { binary(assignment, undo) })
+}
+
+/// Description of an expression that can not be assigned to, used in error
+/// messages
+fn target_kind(expr: &Expr) -> &'static str {
+    match expr {
+        Expr::Literal { .. } => "a literal",
+        Expr::Call { .. } => "a call result",
+        Expr::Grouping { .. } => "a parenthesized expression",
+        Expr::Array { .. } => "an array literal",
+        Expr::Object { .. } => "an object literal",
+        Expr::Function { .. } => "a function expression",
+        Expr::Ternary { .. } => "a conditional expression",
+        Expr::Binary { .. } | Expr::Unary { .. } | Expr::Logical { .. } => {
+            "the result of an operator"
+        }
+        Expr::Assignment { .. } | Expr::IndexAssignment { .. } => "an assignment",
+        Expr::Variable { .. } | Expr::Index { .. } | Expr::Property { .. } => {
+            "this expression"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn errors(src: &str) -> Vec<Error> {
+        Parser::new(tokenize(src)).parse().1
+    }
+
+    #[test]
+    fn reports_every_broken_statement_once() {
+        let errs = errors("var a = ;\nvar b = 2;\nconsole.log(b +);");
+        assert_eq!(errs.len(), 2);
+        assert_eq!((errs[0].line, errs[1].line), (1, 3));
+        assert_ne!(errs[0].err, errs[1].err);
+    }
+
+    #[test]
+    fn increments_need_a_variable() {
+        let errs = errors("var a = [1];\n5++;\n--a[0];");
+        assert_eq!(errs.len(), 2);
+        assert_eq!((errs[0].err.as_str(), errs[0].line), ("Cannot apply '++' to a literal", 2));
+        assert_eq!(errs[1].line, 3);
+        assert!(errors("var i = 0;\ni++;\n--i;").is_empty());
+    }
+
+    #[test]
+    fn reports_lexer_errors() {
+        let errs = errors("var a = 1;\n/* never closed");
+        assert_eq!(errs.len(), 1);
+        assert_eq!((errs[0].err.as_str(), errs[0].line), ("Unterminated block comment", 2));
+    }
+
+    #[test]
+    fn reports_malformed_numbers() {
+        let errs = errors("var a = 1.2.3;");
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].err, "Invalid number literal '1.2.3' on line 1");
+        assert!(errors("var b = .25;\nvar c = [1].length;").is_empty());
+    }
+
+    #[test]
+    fn allows_trailing_commas_in_calls_and_parameters() {
+        let parse = |src| format!("{:?}", Parser::new(tokenize(src)).parse());
+        assert_eq!(parse("f(1, 2,);"), parse("f(1, 2);"));
+        assert_eq!(parse("function g(a, b,) {}"), parse("function g(a, b) {}"));
+        assert_eq!(parse("console.log(1,);"), parse("console.log(1);"));
+        assert_eq!(errors("f(,);").len(), 1);
+        assert_eq!(errors("function g(,) {}")[0].err, "Expected parameter name");
+        assert_eq!(errors("f(1,,);").len(), 1);
+    }
+
+    #[test]
+    fn compound_assignments_are_desugared() {
+        let source = |src: &str| Parser::new(tokenize(src)).parse().0.to_source();
+        for op in ["+", "-", "*", "/"] {
+            assert_eq!(source(&format!("x {}= y - 2;", op)), format!("x = x {} (y - 2);", op));
+        }
+        assert_eq!(errors("var x = 1;\n3 += x;")[0].line, 2);
+    }
+
+    #[test]
+    fn errors_point_at_the_column_of_the_token() {
+        let errs = errors("var a = 1;\nvar b = 2 + * 3;");
+        assert_eq!((errs[0].line, errs[0].col), (2, 13));
+    }
+
+    #[test]
+    fn rejects_this() {
+        let errs = errors("var a = 1;\nthis;");
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].err, "'this' on line 2 is not supported in this context");
+    }
+
+    #[test]
+    fn describes_invalid_assignment_targets() {
+        let errs = errors("var x = 1;\n5 = x;\nfunction f() {}\n\nf() = 1;");
+        assert_eq!(errs.len(), 2);
+        assert_eq!((errs[0].err.as_str(), errs[0].line, errs[0].col),
+                   ("Cannot assign to a literal", 2, 3));
+        assert_eq!((errs[1].err.as_str(), errs[1].line), ("Cannot assign to a call result", 5));
+
+        // Index and member targets are valid
+        assert!(errors("var a = [1];\na[0] = 2;\nvar o = {};\no.x = a[0];").is_empty());
+    }
+
+    #[test]
+    fn keeps_the_statements_around_an_error() {
+        let (stmts, errs) = Parser::new(tokenize("var a = 1;\nvar b = ;\nconsole.log(a);")).parse();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].line, 2);
+        assert_eq!(stmts.len(), 2);
+        assert!(matches!(&stmts[0], Stmt::Variable(name, _) if name.value == "a"));
+        assert!(matches!(&stmts[1], Stmt::Print(args) if args.len() == 1));
+    }
+
+    #[test]
+    fn recovers_inside_blocks() {
+        // The closing braces must not be reported as errors themselves
+        let errs = errors("function f() {\n  var a = ;\n  var x = 1;\n}\n\
+                           while (true) { console.log(; }\nvar c = 1;");
+        let lines: Vec<u32> = errs.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![2, 5]);
     }
 }
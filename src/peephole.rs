@@ -0,0 +1,168 @@
+use crate::codegen::{BcArr, Instr, Program, Value};
+use std::collections::BTreeSet;
+
+impl Program {
+    /// Remove register moves that codegen leaves behind. A value that is
+    /// computed into a register only to be copied into another one right away
+    /// is computed into the destination directly, and moves of a register
+    /// into itself are dropped. Jumps and calls are kept pointing at the same
+    /// code
+    pub fn peephole(&self) -> Program {
+        let labels = self.labels();
+        let mut edits: Vec<(usize, Vec<BcArr>)> = Vec::new();
+        let mut prev: Option<usize> = None;
+
+        let mut ip = 0;
+        while ip < self.bytecode.len() {
+            let next = ip + self.instr_at(ip).size();
+            let (dst, src) = match self.move_at(ip) {
+                Some(regs) => regs,
+                None => {
+                    prev = Some(ip);
+                    ip = next;
+                    continue;
+                }
+            };
+            if dst == src {
+                edits.push((ip, Vec::new()));
+            } else if let Some(p) = prev.filter(|p| {
+                !labels.contains(&ip) && self.result_reg(*p) == Some(src)
+                    && !edits.iter().any(|(e, _)| e == p) && self.dead_after(next, src)
+            }) {
+                let mut code = self.bytecode[p..ip].to_vec();
+                code[1] = BcArr::V(Value::Reg(dst));
+                edits.push((p, code));
+                edits.push((ip, Vec::new()));
+            }
+            prev = Some(ip);
+            ip = next;
+        }
+
+        let mut program = self.clone();
+        program.replace_instrs(edits);
+        program
+    }
+
+    /// Addresses that code other than the previous instruction can continue
+    /// at: jump targets, functions and the entry point
+    fn labels(&self) -> BTreeSet<usize> {
+        let mut labels: BTreeSet<usize> = self.function_list.values().map(|f| f.addr).collect();
+        labels.insert(self.entry_point);
+
+        let mut ip = 0;
+        while ip < self.bytecode.len() {
+            let instr = self.instr_at(ip);
+            if matches!(instr, Instr::Jmp | Instr::JmpIf | Instr::JmpIN) {
+                labels.insert(self.jmp_target(ip));
+            }
+            for v in &self.bytecode[ip + 1..ip + instr.size()] {
+                if let BcArr::V(Value::Function(addr)) = v {
+                    labels.insert(*addr);
+                }
+            }
+            ip += instr.size();
+        }
+        labels
+    }
+
+    /// Destination and source register of the LoadR instruction at `ip`
+    fn move_at(&self, ip: usize) -> Option<(u16, u16)> {
+        match self.instr_at(ip) {
+            Instr::LoadR => Some((self.reg_at(ip + 1)?, self.reg_at(ip + 2)?)),
+            _ => None,
+        }
+    }
+
+    fn reg_at(&self, ip: usize) -> Option<u16> {
+        match self.bytecode[ip] {
+            BcArr::V(Value::Reg(r)) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Register that the instruction at `ip` writes its result to, if any.
+    /// Calls only write r0, which is not encoded in the instruction
+    fn result_reg(&self, ip: usize) -> Option<u16> {
+        match self.instr_at(ip) {
+            Instr::Ret | Instr::LoadA | Instr::PushA | Instr::PushP | Instr::PushG |
+            Instr::IndexSet | Instr::Print | Instr::PrintS | Instr::Halt | Instr::Jmp |
+            Instr::JmpIf | Instr::JmpIN | Instr::Call | Instr::CallR => None,
+            _ => self.reg_at(ip + 1),
+        }
+    }
+
+    /// Whether the value of `reg` is never read by the code from `ip` on. Only
+    /// straight-line code is followed, anything else counts as a read
+    fn dead_after(&self, mut ip: usize, reg: u16) -> bool {
+        while ip < self.bytecode.len() {
+            let instr = self.instr_at(ip);
+            let result = self.result_reg(ip);
+            let operands = if result.is_some() { ip + 2 } else { ip + 1 };
+            let reads = (operands..ip + instr.size()).any(|i| self.reg_at(i) == Some(reg));
+
+            // The caller receives the return value in r0
+            if reads || (instr == Instr::Ret && reg == 0) {
+                return false;
+            }
+            match instr {
+                _ if result == Some(reg) => return true,
+                Instr::Call | Instr::CallR if reg == 0 => return true,
+                Instr::Ret | Instr::Halt => return true,
+                Instr::Jmp | Instr::JmpIf | Instr::JmpIN => return false,
+                _ => ip += instr.size(),
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        codegen::{BcArr, Codegen, Instr, Program, Value},
+        lexer::tokenize,
+        parser::Parser,
+        vm::Interpreter,
+    };
+
+    fn output(program: Program) -> String {
+        let mut out = Vec::new();
+        Interpreter::with_writer(program, &mut out).interpret().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn count(program: &Program, instr: Instr) -> usize {
+        program.bytecode.iter().filter(|v| **v == BcArr::I(instr)).count()
+    }
+
+    #[test]
+    fn redundant_moves_are_removed() {
+        let source = "function sq(n) { return n * n; }\n\
+                      var i = 0;\n\
+                      while (i < 3) { console.log(sq(i) + 1); i = i + 1; }";
+        let (stmts, _) = Parser::new(tokenize(source)).parse();
+        let mut program = Codegen::bytecode_gen(stmts).unwrap();
+
+        // `n * n` is moved into r0 before returning
+        let mul = program.bytecode.iter().position(|v| *v == BcArr::I(Instr::Mul)).unwrap();
+        assert_eq!(program.bytecode[mul + 4], BcArr::I(Instr::LoadR));
+        assert_eq!(program.bytecode[mul + 5], BcArr::V(Value::Reg(0)));
+
+        // A move of a register into itself at the start of the loop body,
+        // which is the target of the jump back
+        let jmp = program.bytecode.iter().rposition(|v| *v == BcArr::I(Instr::JmpIf)).unwrap();
+        let body = program.jmp_target(jmp);
+        let mut code = vec![BcArr::I(Instr::LoadR), BcArr::V(Value::Reg(1)),
+                            BcArr::V(Value::Reg(1))];
+        code.extend(program.bytecode[body..body + 3].to_vec());
+        program.replace_instrs(vec![(body, code)]);
+
+        let optimized = program.peephole();
+        assert_eq!(count(&optimized, Instr::LoadR), count(&program, Instr::LoadR) - 2);
+        assert_eq!(optimized.bytecode.len(), program.bytecode.len() - 6);
+        let mul = optimized.bytecode.iter().position(|v| *v == BcArr::I(Instr::Mul)).unwrap();
+        assert_eq!(optimized.bytecode[mul + 1], BcArr::V(Value::Reg(0)));
+        assert!(optimized.verify().is_ok());
+        assert_eq!(output(optimized), output(program));
+    }
+}
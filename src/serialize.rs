@@ -0,0 +1,384 @@
+//! Binary bytecode file format, used to compile a program once and run it
+//! later without going through the frontend again.
+//!
+//! All integers are little-endian. The file starts with `MAGIC` and
+//! `VERSION`, followed by:
+//!
+//! ```text
+//! entry_point    u32
+//! bytecode       u32 count, then per entry: 0 + opcode u8 | 1 + value
+//! lines          per bytecode entry: u32 source line
+//! function_list  u32 count, then per function: string name + u32 address +
+//!                u32 arity
+//! const_pool     u32 count, then per constant: value
+//! ```
+//!
+//! Values are a tag byte followed by their payload, strings are a u32 byte
+//! length followed by the utf-8 bytes.
+
+use crate::{
+    codegen::{BcArr, FuncInfo, Instr, Program, Value},
+    err::Error,
+};
+
+use std::{collections::HashMap, convert::{TryFrom, TryInto}, fs, io, path::Path};
+
+/// First bytes of every bytecode file
+pub const MAGIC: &[u8; 4] = b"BABY";
+
+/// Bumped whenever the encoding changes in an incompatible way
+pub const VERSION: u8 = 6;
+
+const TAG_INSTR: u8 = 0;
+const TAG_VALUE: u8 = 1;
+
+const VAL_NIL: u8 = 0;
+const VAL_NUMBER: u8 = 1;
+const VAL_BOOL: u8 = 2;
+const VAL_STRING: u8 = 3;
+const VAL_REG: u8 = 4;
+const VAL_POOL: u8 = 5;
+const VAL_CPOOL: u8 = 6;
+const VAL_VADDR: u8 = 7;
+const VAL_ARRAY: u8 = 8;
+const VAL_INT: u8 = 9;
+const VAL_FUNCTION: u8 = 10;
+const VAL_OBJECT: u8 = 11;
+const VAL_UNDEFINED: u8 = 12;
+
+impl Program {
+    /// Serialize the program into the binary bytecode format and write it to
+    /// `path`. Calls have to be resolved, so only fully linked programs can be
+    /// written
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if let Some((_, name, _)) = self.unresolved.first() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("call to undefined function '{}'", name.value),
+            ));
+        }
+        fs::write(path, self.encode())
+    }
+
+    /// Read a program that was written by `write_to_file`
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Program, Error> {
+        let bytes = fs::read(&path).map_err(|e| {
+            file_error(format!("Unable to read '{}': {}", path.as_ref().display(), e))
+        })?;
+        Program::decode(&bytes)
+    }
+
+    /// Binary representation of the program
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        put_u32(&mut buf, self.entry_point);
+
+        put_u32(&mut buf, self.bytecode.len());
+        for entry in &self.bytecode {
+            match entry {
+                BcArr::I(instr) => {
+                    buf.push(TAG_INSTR);
+                    buf.push((*instr).into());
+                }
+                BcArr::V(v) => {
+                    buf.push(TAG_VALUE);
+                    put_value(&mut buf, v);
+                }
+            }
+        }
+        for i in 0..self.bytecode.len() {
+            put_u32(&mut buf, self.lines.get(i).copied().unwrap_or(0) as usize);
+        }
+
+        // Sorted so the same program always produces the same file
+        let mut functions: Vec<_> = self.function_list.iter().collect();
+        functions.sort();
+        put_u32(&mut buf, functions.len());
+        for (name, f) in functions {
+            put_str(&mut buf, name);
+            put_u32(&mut buf, f.addr);
+            put_u32(&mut buf, f.arity);
+        }
+
+        put_u32(&mut buf, self.const_pool.len());
+        for v in &self.const_pool {
+            put_value(&mut buf, v);
+        }
+        buf
+    }
+
+    /// Reconstruct a program from its binary representation
+    fn decode(bytes: &[u8]) -> Result<Program, Error> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(file_error("Not a bytecode file".to_string()));
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(file_error(format!(
+                "Unsupported bytecode version {}, expected {}",
+                version, VERSION
+            )));
+        }
+        let entry_point = r.u32()?;
+
+        let mut bytecode = Vec::new();
+        for _ in 0..r.u32()? {
+            let entry = match r.u8()? {
+                TAG_INSTR => {
+                    let op = r.u8()?;
+                    BcArr::I(Instr::try_from(op)
+                        .map_err(|_| file_error(format!("Invalid opcode {}", op)))?)
+                }
+                TAG_VALUE => BcArr::V(r.value()?),
+                t => return Err(file_error(format!("Invalid bytecode entry tag {}", t))),
+            };
+            bytecode.push(entry);
+        }
+        let mut lines = Vec::new();
+        for _ in 0..bytecode.len() {
+            lines.push(r.u32()? as u32);
+        }
+
+        let mut function_list = HashMap::new();
+        for _ in 0..r.u32()? {
+            let name = r.string()?;
+            let addr = r.u32()?;
+            function_list.insert(name, FuncInfo { addr, arity: r.u32()? });
+        }
+
+        let mut const_pool = Vec::new();
+        for _ in 0..r.u32()? {
+            const_pool.push(r.value()?);
+        }
+
+        if r.pos != bytes.len() {
+            return Err(file_error("Trailing data after the end of the program".to_string()));
+        }
+        Ok(Program {
+            bytecode,
+            lines,
+            entry_point,
+            function_list,
+            const_pool,
+            unresolved: Vec::new(),
+            warnings: Vec::new(),
+        })
+    }
+}
+
+/// Errors in bytecode files are not tied to a source location
+fn file_error(msg: String) -> Error {
+    Error::new(msg, 0, 0)
+}
+
+/// Cursor over the bytes of a bytecode file, every read fails instead of
+/// panicking if the file ends too early
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let slice = &self.bytes[self.pos..end];
+                self.pos = end;
+                Ok(slice)
+            }
+            None => Err(file_error("Unexpected end of bytecode file".to_string())),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<usize, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| file_error("Invalid utf-8 in string".to_string()))
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        Ok(match self.u8()? {
+            VAL_NIL => Value::Nil,
+            VAL_UNDEFINED => Value::Undefined,
+            VAL_NUMBER => Value::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            VAL_INT => Value::Int(i64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            VAL_BOOL => Value::Bool(self.u8()? != 0),
+            VAL_STRING => Value::StringLiteral(self.string()?),
+            VAL_REG => Value::Reg(self.u16()?),
+            VAL_POOL => Value::Pool(self.u16()?),
+            VAL_CPOOL => Value::CPool(self.u32()?),
+            VAL_FUNCTION => Value::Function(self.u32()?),
+            VAL_VADDR => {
+                Value::VAddr(i32::from_le_bytes(self.take(4)?.try_into().unwrap()) as isize)
+            }
+            VAL_ARRAY => {
+                let len = self.u32()?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    elements.push(self.value()?);
+                }
+                Value::Array(elements)
+            }
+            VAL_OBJECT => {
+                let len = self.u32()?;
+                let mut map = HashMap::new();
+                for _ in 0..len {
+                    let key = self.string()?;
+                    map.insert(key, self.value()?);
+                }
+                Value::Object(map)
+            }
+            t => return Err(file_error(format!("Invalid value tag {}", t))),
+        })
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: usize) {
+    buf.extend_from_slice(&(v as u32).to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_value(buf: &mut Vec<u8>, v: &Value) {
+    match v {
+        Value::Nil => buf.push(VAL_NIL),
+        Value::Undefined => buf.push(VAL_UNDEFINED),
+        Value::Number(n) => {
+            buf.push(VAL_NUMBER);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        Value::Int(i) => {
+            buf.push(VAL_INT);
+            buf.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Bool(b) => {
+            buf.push(VAL_BOOL);
+            buf.push(*b as u8);
+        }
+        Value::StringLiteral(s) => {
+            buf.push(VAL_STRING);
+            put_str(buf, s);
+        }
+        Value::Reg(r) => {
+            buf.push(VAL_REG);
+            buf.extend_from_slice(&r.to_le_bytes());
+        }
+        Value::Pool(p) => {
+            buf.push(VAL_POOL);
+            buf.extend_from_slice(&p.to_le_bytes());
+        }
+        Value::CPool(c) => {
+            buf.push(VAL_CPOOL);
+            put_u32(buf, *c);
+        }
+        Value::VAddr(a) => {
+            buf.push(VAL_VADDR);
+            buf.extend_from_slice(&(*a as i32).to_le_bytes());
+        }
+        Value::Function(addr) => {
+            buf.push(VAL_FUNCTION);
+            put_u32(buf, *addr);
+        }
+        Value::Array(elements) => {
+            buf.push(VAL_ARRAY);
+            put_u32(buf, elements.len());
+            for e in elements {
+                put_value(buf, e);
+            }
+        }
+        Value::Object(map) => {
+            buf.push(VAL_OBJECT);
+            put_u32(buf, map.len());
+            // Sorted so the same program always produces the same file
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, v) in entries {
+                put_str(buf, key);
+                put_value(buf, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Codegen, lexer::tokenize, parser::Parser, vm::Interpreter};
+
+    #[test]
+    fn file_starts_with_header() {
+        let src = "function add(a, b) { return a + b; }\n\
+                   console.log(add(1, 2), \"done\");";
+        let (stmts, errors) = Parser::new(tokenize(src)).parse();
+        assert!(errors.is_empty());
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+
+        let path = std::env::temp_dir().join("baby_interp_write_test.bcode");
+        program.write_to_file(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes, program.encode());
+    }
+
+    #[test]
+    fn round_trip_runs_the_same() {
+        let src = "function fac(n) { if (n < 2) { return 1; } return n * fac(n - 1); }\n\
+                   var a = [fac(5), \"str\", -1.5, true];\n\
+                   console.log(a, a[0] / 3);";
+        let (stmts, errors) = Parser::new(tokenize(src)).parse();
+        assert!(errors.is_empty());
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+
+        let path = std::env::temp_dir().join("baby_interp_round_trip.bcode");
+        program.write_to_file(&path).unwrap();
+        let loaded = Program::read_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.bytecode, program.bytecode);
+        assert_eq!(loaded.function_list, program.function_list);
+
+        let mut direct = Vec::new();
+        let mut from_file = Vec::new();
+        Interpreter::with_writer(program, &mut direct).interpret().unwrap();
+        Interpreter::with_writer(loaded, &mut from_file).interpret().unwrap();
+        assert_eq!(direct, b"[120, str, -1.5, true] 40\n");
+        assert_eq!(direct, from_file);
+    }
+
+    #[test]
+    fn corrupt_files_are_rejected() {
+        let (stmts, _) = Parser::new(tokenize("console.log(1 + 2);")).parse();
+        let bytes = Codegen::bytecode_gen(stmts).unwrap().encode();
+
+        for len in 0..bytes.len() {
+            assert!(Program::decode(&bytes[..len]).is_err());
+        }
+        let mut bad_opcode = bytes.clone();
+        bad_opcode[14] = 0xff;
+        assert!(Program::decode(&bad_opcode).is_err());
+        let mut bad_version = bytes;
+        bad_version[4] = VERSION + 1;
+        assert!(Program::decode(&bad_version).is_err());
+    }
+}
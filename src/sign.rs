@@ -0,0 +1,147 @@
+use crate::comp_ai::{BoolState, Domain};
+
+/// Cheaper alternative to the interval domain that only tracks the sign of
+/// each number
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignDomain {
+    /// No value, eg. an operand that was never assigned
+    Bottom,
+    Neg,
+    Zero,
+    Pos,
+    Top,
+}
+
+use SignDomain::*;
+
+impl SignDomain {
+    fn negate(&self) -> Self {
+        match self {
+            Neg => Pos,
+            Pos => Neg,
+            s => *s,
+        }
+    }
+}
+
+impl Domain for SignDomain {
+    fn top() -> Self {
+        Top
+    }
+
+    fn loadi_const(n: f64) -> Self {
+        if n < 0.0 {
+            Neg
+        } else if n > 0.0 {
+            Pos
+        } else if n == 0.0 {
+            Zero
+        } else {
+            // NaN
+            Top
+        }
+    }
+
+    fn join(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Bottom, s) | (s, Bottom) => *s,
+            (a, b) if a == b => *a,
+            _ => Top,
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Bottom, _) | (_, Bottom) => Bottom,
+            (Zero, s) | (s, Zero) => *s,
+            (Pos, Pos) => Pos,
+            (Neg, Neg) => Neg,
+            _ => Top,
+        }
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        self.add(&other.negate())
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Bottom, _) | (_, Bottom) => Bottom,
+            (Zero, _) | (_, Zero) => Zero,
+            (Top, _) | (_, Top) => Top,
+            (a, b) if a == b => Pos,
+            _ => Neg,
+        }
+    }
+
+    /// Division by a value that may be 0 is unknown, otherwise signs behave
+    /// like they do for multiplication
+    fn div(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Bottom, _) | (_, Bottom) => Bottom,
+            (_, Zero) | (_, Top) => Top,
+            _ => self.mul(other),
+        }
+    }
+
+    /// The result takes the sign of the dividend, but may always be 0
+    fn modulo(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Bottom, _) | (_, Bottom) => Bottom,
+            (_, Zero) | (_, Top) => Top,
+            (Zero, _) => Zero,
+            _ => Top,
+        }
+    }
+
+    fn less_than(&self, other: &Self) -> BoolState {
+        match (self, other) {
+            (Neg, Zero) | (Neg, Pos) | (Zero, Pos) => BoolState::T,
+            (Zero, Zero) | (Zero, Neg) | (Pos, Zero) | (Pos, Neg) => BoolState::F,
+            _ => BoolState::Unknown,
+        }
+    }
+
+    fn equals(&self, other: &Self) -> BoolState {
+        match (self, other) {
+            (Zero, Zero) => BoolState::T,
+            (Bottom, _) | (_, Bottom) | (Top, _) | (_, Top) => BoolState::Unknown,
+            (a, b) if a != b => BoolState::F,
+            _ => BoolState::Unknown,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == Zero
+    }
+
+    fn may_be_zero(&self) -> bool {
+        matches!(self, Zero | Top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_of_arithmetic_results() {
+        assert_eq!(Pos.add(&Pos), Pos);
+        assert_eq!(Pos.add(&Neg), Top);
+        assert_eq!(Neg.add(&Zero), Neg);
+        assert_eq!(Pos.sub(&Neg), Pos);
+        assert_eq!(Neg.mul(&Neg), Pos);
+        assert_eq!(Neg.mul(&Pos), Neg);
+        assert_eq!(Pos.div(&Top), Top);
+        assert_eq!(SignDomain::loadi_const(-4.0), Neg);
+    }
+
+    #[test]
+    fn join_and_comparisons() {
+        assert_eq!(Pos.join(&Bottom), Pos);
+        assert_eq!(Pos.join(&Zero), Top);
+        assert_eq!(Neg.less_than(&Pos), BoolState::T);
+        assert_eq!(Pos.less_than(&Pos), BoolState::Unknown);
+        assert_eq!(Zero.equals(&Pos), BoolState::F);
+    }
+}
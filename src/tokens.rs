@@ -4,19 +4,28 @@ pub enum TokenType {
     Whitespace,
 
     // Single Char tokens
-    OpenCurly, CloseCurly, OpenParen, CloseParen, Comma, Dot, Minus, Plus,
-    SemiColon, Divide, Multiply,
+    OpenCurly, CloseCurly, OpenParen, CloseParen, OpenBracket, CloseBracket,
+    Comma, Dot, Minus, Plus,
+    SemiColon, Divide, Multiply, Modulo, Question, Colon,
 
     // One or two character tokens
     Not, NEqual, EqualSign, Equals, Greater, GreaterEq,
-    Less, LessEq,
+    Less, LessEq, PlusEq, MinusEq, MultiplyEq, DivideEq,
+    BitAnd, BitOr, BitXor, ShiftLeft, ShiftRight, PlusPlus, MinusMinus,
 
     // Literals
     Identifier, StringLiteral, Number,
 
     // Keywords
     And, Else, False, Function, For, If, Nil, Or, Print,
-    Return, This, True, Var, Let, While, Eof,
+    Return, This, True, Var, Let, While, Import, Break, Continue, Of, Do, Eof,
+
+    // Only produced by `tokenize_with_comments`, skipped by the parser
+    Comment,
+
+    // Input the lexer could not make sense of, the value holds the error
+    // message which the parser reports
+    Invalid,
 }
 
 #[derive(Debug, Clone)]
@@ -30,4 +39,7 @@ pub struct Token {
 
     /// Line value from which the token was created
     pub line_num: u32,
+
+    /// Column of the first character of the token, starting at 1
+    pub col: u32,
 }
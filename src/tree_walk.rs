@@ -0,0 +1,941 @@
+use crate::{
+    ast::{implicit_main, Expr, Literal, LogicalOp, Stmt},
+    codegen::{self, Instr, Value},
+    environment::Environment,
+    err::Error,
+    native,
+    tokens::{Token, TokenType::*},
+    vm::{self, Interpreter},
+};
+
+use rustc_hash::FxHashMap;
+use std::{cell::RefCell, collections::HashMap, io::Write, mem, rc::Rc};
+
+/// Nested calls are evaluated recursively on the native stack, so this is a
+/// lot lower than the vm's limit
+const MAX_CALL_DEPTH: usize = 500;
+
+/// Parameters and body of a declared function
+type Function = (Vec<Token>, Vec<Stmt>);
+
+/// How control leaves a statement
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// Evaluates the AST directly instead of compiling it to bytecode first. It
+/// is meant as a reference to cross-check the vm against, so it mirrors the
+/// vm's semantics and error messages wherever possible. Imports are not
+/// resolved, only the statements of a single file are executed
+pub struct TreeWalker<W: Write> {
+    /// Scope that is currently active
+    env: Rc<RefCell<Environment>>,
+
+    /// Outermost scope, every function call starts a new scope inside of it
+    globals: Rc<RefCell<Environment>>,
+
+    /// Parameters and body of every declared function
+    functions: FxHashMap<String, Function>,
+
+    /// Functions declared inside of every active call, the innermost call
+    /// last. They shadow top-level functions with the same name
+    local_functions: Vec<FxHashMap<String, Function>>,
+
+    /// Functions created by function expressions, `Value::Function` holds an
+    /// index into this instead of a bytecode address
+    function_values: Vec<Function>,
+
+    /// Number of loops around the statement being executed in the current
+    /// function
+    loop_depth: usize,
+
+    /// Number of active function calls
+    call_depth: usize,
+
+    /// Line of the last token that was evaluated, used for error messages
+    line: u32,
+
+    /// State of the xorshift generator backing the random builtins
+    rng_state: u64,
+
+    /// Code passed to `exit`. Exiting unwinds the program like an error,
+    /// `interpret` turns it back into the exit code
+    exit_code: Option<i32>,
+
+    /// Output of the print statements is written to this
+    out: W,
+}
+
+impl<W: Write> TreeWalker<W> {
+    pub fn new(out: W) -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new(None)));
+        Self {
+            env: globals.clone(),
+            globals,
+            functions: FxHashMap::default(),
+            local_functions: Vec::new(),
+            function_values: Vec::new(),
+            loop_depth: 0,
+            call_depth: 0,
+            line: 0,
+            rng_state: vm::DEFAULT_SEED,
+            exit_code: None,
+            out,
+        }
+    }
+
+    /// Execute every statement of the program. Functions can be called before
+    /// they are declared, so top-level declarations are registered up front.
+    /// Returns the exit code if the program was stopped by `exit`
+    pub fn interpret(&mut self, stmts: &[Stmt]) -> Result<Option<i32>, Error> {
+        for stmt in stmts {
+            if let Stmt::Function(name, args, body) = stmt {
+                if self.functions.contains_key(&name.value) {
+                    return Err(Error::new(
+                        format!("Cannot redeclare function with name: {}", name.value),
+                        name.line_num,
+                        name.col,
+                    ));
+                }
+                self.functions
+                    .insert(name.value.clone(), (args.clone(), body.clone()));
+            }
+        }
+
+        for stmt in stmts.iter().chain(&implicit_main(stmts)) {
+            match self.stmt(stmt) {
+                Ok(Flow::Return(_)) => {
+                    return Err(self.error("Cannot return from outside of a function"));
+                }
+                Ok(_) => {}
+                Err(_) if self.exit_code.is_some() => return Ok(self.exit_code),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Build an error for the line that is currently being executed
+    fn error(&self, msg: &str) -> Error {
+        Error::new(msg.to_string(), self.line, 0)
+    }
+
+    /// Execute a single statement
+    fn stmt(&mut self, stmt: &Stmt) -> Result<Flow, Error> {
+        match stmt {
+            Stmt::Expression(e) => {
+                self.expr(e)?;
+            }
+            Stmt::Variable(name, e) => {
+                let val = match e {
+                    Some(e) => self.expr(e)?,
+                    None => Value::Undefined,
+                };
+                self.line = name.line_num;
+                self.env
+                    .borrow_mut()
+                    .define(&name.value, val)
+                    .map_err(|e| self.error(&e))?;
+            }
+            Stmt::Block(stmts) => {
+                // Functions declared in the body of a function can be called
+                // before their declaration, like top-level ones
+                for stmt in stmts {
+                    if let (Stmt::Function(name, args, body), Some(locals)) =
+                        (stmt, self.local_functions.last_mut())
+                    {
+                        locals.insert(name.value.clone(), (args.clone(), body.clone()));
+                    }
+                }
+                let scope = Environment::new(Some(self.env.clone()));
+                return self.block(stmts, scope);
+            }
+            Stmt::Function(name, args, body) => match self.local_functions.last_mut() {
+                Some(locals) => {
+                    locals.insert(name.value.clone(), (args.clone(), body.clone()));
+                }
+                None => {
+                    // Declarations nested in blocks are registered once reached
+                    self.functions
+                        .entry(name.value.clone())
+                        .or_insert_with(|| (args.clone(), body.clone()));
+                }
+            },
+            Stmt::If(cond, t, f) => {
+                let cond = self.expr(cond)?;
+                if cond.is_truthy() {
+                    return self.stmt(t);
+                } else if let Some(f) = f {
+                    return self.stmt(f);
+                }
+            }
+            Stmt::Return(e) => {
+                let val = match e {
+                    Some(e) => self.expr(e)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(val));
+            }
+            Stmt::While(cond, body) => {
+                self.loop_depth += 1;
+                let res = self.while_stmt(cond, body, None);
+                self.loop_depth -= 1;
+                return res;
+            }
+            Stmt::For { init, cond, increment, body } => {
+                let scope = Environment::new(Some(self.env.clone()));
+                let prev = mem::replace(&mut self.env, Rc::new(RefCell::new(scope)));
+                let mut res = Ok(Flow::Normal);
+                if let Some(init) = init {
+                    res = self.stmt(init);
+                }
+                if res.is_ok() {
+                    self.loop_depth += 1;
+                    res = self.while_stmt(cond, body, increment.as_ref());
+                    self.loop_depth -= 1;
+                }
+                self.env = prev;
+                return res;
+            }
+            Stmt::DoWhile(body, cond) => {
+                self.loop_depth += 1;
+                let res = self.do_while_stmt(body, cond);
+                self.loop_depth -= 1;
+                return res;
+            }
+            Stmt::ForOf { name, iterable, body } => {
+                let elements = match self.expr(iterable)? {
+                    Value::Array(v) => v,
+                    Value::StringLiteral(s) => {
+                        s.chars().map(|c| Value::StringLiteral(c.to_string())).collect()
+                    }
+                    _ => {
+                        self.line = name.line_num;
+                        return Err(self.error("Cannot iterate over a non-array value"));
+                    }
+                };
+                self.loop_depth += 1;
+                let res = self.for_of_stmt(name, elements, body);
+                self.loop_depth -= 1;
+                return res;
+            }
+            Stmt::Print(args) => {
+                let vals = args
+                    .iter()
+                    .map(|a| self.expr(a))
+                    .collect::<Result<Vec<Value>, Error>>()?;
+                self.print(&vals)?;
+            }
+            // Imports are resolved by the driver, not the tree-walker
+            Stmt::Import(_) => {}
+            Stmt::Break(t) | Stmt::Continue(t) => {
+                if self.loop_depth == 0 {
+                    self.line = t.line_num;
+                    return Err(self.error(&format!("'{}' is not inside of a loop", t.value)));
+                }
+                return Ok(if t.t_type == Break { Flow::Break } else { Flow::Continue });
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Execute `stmts` inside of `scope`, the previous scope is restored
+    /// afterwards even if an error occurred
+    fn block(&mut self, stmts: &[Stmt], scope: Environment) -> Result<Flow, Error> {
+        let prev = mem::replace(&mut self.env, Rc::new(RefCell::new(scope)));
+        let mut res = Ok(Flow::Normal);
+        for stmt in stmts {
+            res = self.stmt(stmt);
+            if !matches!(res, Ok(Flow::Normal)) {
+                break;
+            }
+        }
+        self.env = prev;
+        res
+    }
+
+    /// Run `body` while `cond` holds, the increment of a for loop runs after
+    /// every iteration including ones left with `continue`
+    fn while_stmt(&mut self, cond: &Expr, body: &Stmt, increment: Option<&Expr>)
+            -> Result<Flow, Error> {
+        loop {
+            let c = self.expr(cond)?;
+            if !c.is_truthy() {
+                return Ok(Flow::Normal);
+            }
+            match self.stmt(body)? {
+                Flow::Break => return Ok(Flow::Normal),
+                Flow::Return(v) => return Ok(Flow::Return(v)),
+                Flow::Normal | Flow::Continue => {}
+            }
+            if let Some(increment) = increment {
+                self.expr(increment)?;
+            }
+        }
+    }
+
+    fn do_while_stmt(&mut self, body: &Stmt, cond: &Expr) -> Result<Flow, Error> {
+        loop {
+            match self.stmt(body)? {
+                Flow::Break => return Ok(Flow::Normal),
+                Flow::Return(v) => return Ok(Flow::Return(v)),
+                Flow::Normal | Flow::Continue => {}
+            }
+            if !self.expr(cond)?.is_truthy() {
+                return Ok(Flow::Normal);
+            }
+        }
+    }
+
+    /// Run `body` once for every element, each iteration gets a new scope
+    /// holding the loop variable
+    fn for_of_stmt(&mut self, name: &Token, elements: Vec<Value>, body: &Stmt)
+            -> Result<Flow, Error> {
+        for elem in elements {
+            let mut scope = Environment::new(Some(self.env.clone()));
+            scope.define(&name.value, elem).map_err(|e| self.error(&e))?;
+            match self.block(std::slice::from_ref(body), scope)? {
+                Flow::Break => return Ok(Flow::Normal),
+                Flow::Return(v) => return Ok(Flow::Return(v)),
+                Flow::Normal | Flow::Continue => {}
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Print every value on a single line, separated by spaces
+    fn print(&mut self, vals: &[Value]) -> Result<(), Error> {
+        let mut strs = Vec::new();
+        for v in vals {
+            match Interpreter::format_value(v) {
+                Some(s) => strs.push(s),
+                None => {
+                    return Err(self.error(&format!("Type not implemented in print: {:?}", v)));
+                }
+            }
+        }
+        writeln!(self.out, "{}", strs.join(" "))
+            .map_err(|e| self.error(&format!("Failed to write output: {}", e)))
+    }
+
+    /// Evaluate an expression
+    fn expr(&mut self, expr: &Expr) -> Result<Value, Error> {
+        match expr {
+            Expr::Literal { literal } => Ok(match literal {
+                Literal::Number(n) => Value::Number(*n),
+                Literal::Int(i) => Value::Int(*i),
+                Literal::StringLiteral(s) => Value::StringLiteral(s.clone()),
+                Literal::True => Value::Bool(true),
+                Literal::False => Value::Bool(false),
+                Literal::Nil => Value::Nil,
+            }),
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::Variable { name } => {
+                self.line = name.line_num;
+                let err = match self.env.borrow().get(&name.value) {
+                    Ok(v) => return Ok(v),
+                    Err(e) => e,
+                };
+                // Names of functions can be used as values like function
+                // expressions
+                match self.function(&name.value) {
+                    Some(f) => {
+                        self.function_values.push(f);
+                        Ok(Value::Function(self.function_values.len() - 1))
+                    }
+                    None => Err(self.error(&err)),
+                }
+            }
+            Expr::Assignment { name, expr } => {
+                let val = self.expr(expr)?;
+                self.line = name.line_num;
+                self.env
+                    .borrow_mut()
+                    .assign(&name.value, val.clone())
+                    .map_err(|e| self.error(&e))?;
+                Ok(val)
+            }
+            Expr::Binary { left, op, right } => {
+                let l = self.expr(left)?;
+                let r = self.expr(right)?;
+                self.line = op.line_num;
+                self.binary(&l, op, &r)
+            }
+            Expr::Unary { op, right } => {
+                let v = self.expr(right)?;
+                self.line = op.line_num;
+                match op.t_type {
+                    // Compiled to `0 - v` by the codegen
+                    Minus => Interpreter::binary_op(Instr::Sub, &Value::Int(0), &v)
+                        .map_err(|e| self.error(&e)),
+                    _ => Err(self.error(&format!("Operator not supported: {}", op.value))),
+                }
+            }
+            Expr::Logical { l_expr, operator, r_expr } => {
+                let l = self.expr(l_expr)?;
+                let short_circuit = match operator {
+                    LogicalOp::And => !l.is_truthy(),
+                    LogicalOp::Or => l.is_truthy(),
+                };
+                if short_circuit {
+                    return Ok(l);
+                }
+                self.expr(r_expr)
+            }
+            Expr::Ternary { cond, then_e, else_e } => {
+                let c = self.expr(cond)?;
+                if c.is_truthy() {
+                    self.expr(then_e)
+                } else {
+                    self.expr(else_e)
+                }
+            }
+            Expr::Object { entries } => {
+                let mut map = HashMap::new();
+                for (key, value) in entries {
+                    let v = self.expr(value)?;
+                    map.insert(key.clone(), v);
+                }
+                Ok(Value::Object(map))
+            }
+            Expr::Array { elements } => Ok(Value::Array(
+                elements
+                    .iter()
+                    .map(|e| self.expr(e))
+                    .collect::<Result<Vec<Value>, Error>>()?,
+            )),
+            Expr::Index { target, index } => {
+                let arr = self.expr(target)?;
+                let i = self.expr(index)?;
+                if let Value::Object(map) = &arr {
+                    let key = Interpreter::object_key(&i).map_err(|e| self.error(&e))?;
+                    return Ok(map.get(key).cloned().unwrap_or(Value::Nil));
+                }
+                let i = self.array_index(&arr, &i)?;
+                match arr {
+                    Value::Array(v) => Ok(v[i].clone()),
+                    Value::StringLiteral(s) => Ok(Interpreter::char_at(&s, i)),
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Property { target, name } => {
+                let val = self.expr(target)?;
+                self.line = name.line_num;
+                if let Value::Object(map) = &val {
+                    return Ok(map.get(&name.value).cloned().unwrap_or(Value::Nil));
+                }
+                if name.value != "length" {
+                    // Fails the same way as the key lookup in the vm
+                    let key = Value::StringLiteral(name.value.clone());
+                    return Err(self.array_index(&val, &key).unwrap_err());
+                }
+                match val {
+                    Value::Array(v) => Ok(Value::Int(v.len() as i64)),
+                    Value::StringLiteral(s) => Ok(Value::Int(s.chars().count() as i64)),
+                    _ => Err(self.error("Only arrays and strings have a length")),
+                }
+            }
+            Expr::IndexAssignment { target, index, expr } => {
+                let val = self.expr(expr)?;
+                self.index_assignment(target, index, val.clone())?;
+                Ok(val)
+            }
+            Expr::Call { callee, arguments } => {
+                if let Expr::Property { target, name } = &**callee {
+                    let receiver = self.expr(target)?;
+                    self.line = name.line_num;
+                    return native::call_method(&receiver, &name.value)
+                        .map_err(|e| self.error(&e));
+                }
+
+                // Like in the codegen, variables shadow functions declared by
+                // name
+                let name = match &**callee {
+                    Expr::Variable { name } if self.env.borrow().get(&name.value).is_err() => {
+                        Some(name)
+                    }
+                    _ => None,
+                };
+                let target = match name {
+                    Some(_) => Value::Nil,
+                    None => self.expr(callee)?,
+                };
+                let args = arguments
+                    .iter()
+                    .map(|a| self.expr(a))
+                    .collect::<Result<Vec<Value>, Error>>()?;
+                if let Expr::Variable { name } = &**callee {
+                    self.line = name.line_num;
+                }
+                match (name, target) {
+                    (Some(name), _) => self.call(name, args),
+                    (None, Value::Function(i)) => {
+                        let func = self.function_values[i].clone();
+                        self.invoke(func, args)
+                    }
+                    _ => Err(self.error("Only functions can be called")),
+                }
+            }
+            Expr::Function { params, body } => {
+                self.function_values.push((params.clone(), body.clone()));
+                Ok(Value::Function(self.function_values.len() - 1))
+            }
+        }
+    }
+
+    /// Apply the binary operator `op`, shares its semantics with the vm
+    fn binary(&self, l: &Value, op: &Token, r: &Value) -> Result<Value, Error> {
+        let instr = codegen::binary_instr(op.t_type)
+            .ok_or_else(|| self.error(&format!("Operator not supported: {}", op.value)))?;
+        Interpreter::binary_op(instr, l, r).map_err(|e| self.error(&e))
+    }
+
+    /// Validate that `arr[i]` exists and return the index, `arr` can be an
+    /// array or a string
+    fn array_index(&self, arr: &Value, i: &Value) -> Result<usize, Error> {
+        let (len, kind) = match arr {
+            Value::Array(v) => (v.len(), "array"),
+            Value::StringLiteral(s) => (s.chars().count(), "string"),
+            _ => return Err(self.error("Cannot index into a non-array value")),
+        };
+        let index = match i {
+            Value::Number(_) | Value::Int(_) => Interpreter::unpack_number(i),
+            _ => return Err(self.error("Array index must be a number")),
+        };
+        if index < 0.0 || index.fract() != 0.0 || index as usize >= len {
+            return Err(self.error(&format!(
+                "Index {} out of bounds for {} of length {}",
+                index, kind, len
+            )));
+        }
+        Ok(index as usize)
+    }
+
+    /// Store `val` at `target[index]`. Arrays are values, so the modified
+    /// array has to be written back to wherever `target` was loaded from
+    fn index_assignment(&mut self, target: &Expr, index: &Expr, val: Value)
+        -> Result<(), Error> {
+        let mut arr = self.expr(target)?;
+        let i = self.expr(index)?;
+        if let Value::Object(map) = &mut arr {
+            let key = Interpreter::object_key(&i).map_err(|e| self.error(&e))?;
+            map.insert(key.to_string(), val);
+        } else if let Value::StringLiteral(_) = arr {
+            return Err(self.error("Strings cannot be modified"));
+        } else {
+            let i = self.array_index(&arr, &i)?;
+            if let Value::Array(v) = &mut arr {
+                v[i] = val;
+            }
+        }
+
+        match target {
+            Expr::Variable { name } => self
+                .env
+                .borrow_mut()
+                .assign(&name.value, arr)
+                .map_err(|e| self.error(&e)),
+            Expr::Index { target, index } => self.index_assignment(target, index, arr),
+            Expr::Property { target, name } => {
+                let key = Expr::Literal { literal: Literal::StringLiteral(name.value.clone()) };
+                self.index_assignment(target, &key, arr)
+            }
+            // Temporary arrays are not stored anywhere
+            _ => Ok(()),
+        }
+    }
+
+    /// Call the builtin or user-defined function called `name`
+    fn call(&mut self, name: &Token, args: Vec<Value>) -> Result<Value, Error> {
+        if let Some(v) = self.builtin(name, &args)? {
+            return Ok(v);
+        }
+
+        match self.function(&name.value) {
+            Some(f) => self.invoke(f, args),
+            None => Err(self.error(&format!(
+                "function '{}' that you attempt to call does not exist",
+                name.value
+            ))),
+        }
+    }
+
+    /// Function declared as `name` that is visible from the current call
+    fn function(&self, name: &str) -> Option<Function> {
+        self.local_functions
+            .iter()
+            .rev()
+            .find_map(|f| f.get(name))
+            .or_else(|| self.functions.get(name))
+            .cloned()
+    }
+
+    /// Run the body of a function with `args` bound to its parameters
+    fn invoke(&mut self, (params, body): Function, args: Vec<Value>) -> Result<Value, Error> {
+        if args.len() < params.len() {
+            return Err(self.error("Function called with too few arguments"));
+        }
+        if self.call_depth >= MAX_CALL_DEPTH {
+            return Err(self.error("Stack overflow, maximum call depth exceeded"));
+        }
+
+        let mut scope = Environment::new(Some(self.globals.clone()));
+        for (param, arg) in params.iter().zip(args) {
+            scope.define(&param.value, arg).map_err(|e| self.error(&e))?;
+        }
+
+        // Loops around the call can not be exited from inside of it
+        let loop_depth = mem::take(&mut self.loop_depth);
+        self.call_depth += 1;
+        self.local_functions.push(FxHashMap::default());
+        let res = self.block(&body, scope);
+        self.local_functions.pop();
+        self.call_depth -= 1;
+        self.loop_depth = loop_depth;
+
+        match res? {
+            Flow::Return(v) => Ok(v),
+            _ => Ok(Value::Nil),
+        }
+    }
+
+    /// Evaluate calls to builtin functions, None if `name` does not refer to
+    /// a builtin
+    fn builtin(&mut self, name: &Token, args: &[Value]) -> Result<Option<Value>, Error> {
+        let native = native::lookup(&name.value);
+        let arity = match (name.value.as_str(), native) {
+            ("random", _) => 0,
+            ("randomInt", _) => 2,
+            ("exit", _) => 1,
+            (_, Some((_, n))) if n.variadic && args.len() >= n.arity - 1 => args.len(),
+            (_, Some((_, n))) if n.variadic => {
+                return Err(self.error(&format!(
+                    "builtin '{}' expects at least {} arguments",
+                    name.value, n.arity - 1
+                )));
+            }
+            (_, Some((_, n))) => n.arity,
+            _ => return Ok(None),
+        };
+        if args.len() != arity {
+            return Err(self.error(&format!(
+                "builtin '{}' expects {} arguments",
+                name.value, arity
+            )));
+        }
+
+        if let Some((_, n)) = native {
+            let mut args = args.to_vec();
+            if n.variadic {
+                let rest = args.split_off(n.arity - 1);
+                args.push(Value::Array(rest));
+            }
+            return (n.func)(&args).map(Some).map_err(|e| self.error(&e));
+        }
+
+        let res = match (name.value.as_str(), args) {
+            ("exit", [code]) => {
+                self.exit_code = Some(Interpreter::exit_code(code).map_err(|e| self.error(&e))?);
+                return Err(self.error("exit"));
+            }
+            ("random", _) => {
+                // Same generator as the vm, so both produce the same numbers
+                let x = Interpreter::xorshift(&mut self.rng_state);
+                Value::Number((x >> 11) as f64 / (1u64 << 53) as f64)
+            }
+            ("randomInt", [lo, hi]) => {
+                Interpreter::random_int(&mut self.rng_state, lo, hi)
+                    .map_err(|e| self.error(&e))?
+            }
+            _ => unreachable!("builtin '{}' is not implemented", name.value),
+        };
+        Ok(Some(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Codegen, lexer::tokenize, parser::Parser};
+
+    /// Run `source` through the tree-walker and return what it printed
+    fn run(source: &str) -> Result<String, Error> {
+        let (stmts, errors) = Parser::new(tokenize(source)).parse();
+        assert!(errors.is_empty(), "failed to parse test program");
+
+        let mut out = Vec::new();
+        TreeWalker::new(&mut out).interpret(&stmts)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    /// Run `source` through both the tree-walker and the vm, and check that
+    /// they print the same
+    fn cross_check(source: &str) -> String {
+        let (stmts, _) = Parser::new(tokenize(source)).parse();
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+
+        let walked = run(source).unwrap();
+        assert_eq!(walked, String::from_utf8(out).unwrap());
+        walked
+    }
+
+    #[test]
+    fn arithmetic_and_strings() {
+        let src = "var a = 7; var b = 2;\n\
+                   console.log(a + b, a - b, a * b, a / b, a % b);\n\
+                   console.log(\"a is \" + a, -a);";
+        assert_eq!(cross_check(src), "9 5 14 3.5 1\na is 7 -7\n");
+    }
+
+    #[test]
+    fn strings_are_sequences_of_characters() {
+        let src = "var s = \"h\u{e9}llo\";\n\
+                   var out = \"\";\n\
+                   for (var c of s) { out = out + c; }\n\
+                   console.log(out == s, s.length, s[1], s[4]);\n\
+                   var a = \"abc\";\n\
+                   console.log(a[0] == \"a\", a[0] < a[1], a[2] + a[0]);";
+        assert_eq!(cross_check(src), "true 5 \u{e9} o\ntrue true ca\n");
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let src = "var i = 0;\nconsole.log(i++);\nconsole.log(i);\n\
+                   var j = 0;\nconsole.log(++j, j);\n\
+                   console.log(j--, --j, j);\n\
+                   var s = 0;\nfor (var k = 0; k < 4; k++) { s += k; }\nconsole.log(s);";
+        assert_eq!(cross_check(src), "0\n1\n1 1\n1 -1 -1\n6\n");
+    }
+
+    #[test]
+    fn native_functions() {
+        let src = "var x = -4;\n\
+                   console.log(max(3, 7), min(2.5, 1), abs(x), sqrt(16), floor(2.7));\n\
+                   console.log(clamp(x, 0, 10), clamp(12, 0, 10), clamp(5, 0, 10));";
+        assert_eq!(cross_check(src), "7 1 4 4 2\n0 10 5\n");
+        assert_eq!(cross_check("console.log(max(3, 7));"), "7\n");
+
+        let err = run("console.log(sqrt(\"x\"));").unwrap_err();
+        assert!(err.err.contains("Arguments of 'sqrt' need to be numbers"));
+
+        assert_eq!(cross_check("var s = format(\"{} + {} = {}\", 1, 2, 3);\n\
+                                console.log(s, format(\"[{}]\", [1.5, \"a\"]), format(\"-\"));"),
+                   "1 + 2 = 3 [[1.5, a]] -\n");
+        let err = run("console.log(format(\"{}\"));\nconsole.log(format(\"{}\", 1, 2));")
+            .unwrap_err();
+        assert_eq!(err.err, "Format string has 1 placeholders but 0 arguments were given");
+        let err = run("console.log(format(\"{} {}\", 1, 2));\nconsole.log(format(\"{}\", 1, 2));")
+            .unwrap_err();
+        assert_eq!((err.err.as_str(), err.line),
+                   ("Format string has 1 placeholders but 2 arguments were given", 2));
+
+        assert_eq!(cross_check("assert(1 + 1 == 2);"), "");
+        let err = run("assert(1 + 1 == 2);\nassert(1 == 2);").unwrap_err();
+        assert_eq!((err.err.as_str(), err.line), ("Assertion failed", 2));
+    }
+
+    #[test]
+    fn control_flow() {
+        let src = "var i = 0; var sum = 0;\n\
+                   while (i < 10) {\n\
+                       i = i + 1;\n\
+                       if (i == 3) { continue; }\n\
+                       if (i > 6) { break; }\n\
+                       sum += i;\n\
+                   }\n\
+                   console.log(sum, i > 5 ? \"big\" : \"small\");";
+        assert_eq!(cross_check(src), "18 big\n");
+    }
+
+    #[test]
+    fn for_of_loops() {
+        let src = "var sum = 0;\n\
+                   for (var x of [1, 2, 3]) { sum = sum + x; }\n\
+                   console.log(sum);\n\
+                   for (var x of [1, 2, 3, 4, 5]) {\n\
+                       if (x == 2) { continue; }\n\
+                       if (x == 4) { break; }\n\
+                       console.log(x);\n\
+                   }";
+        assert_eq!(cross_check(src), "6\n1\n3\n");
+    }
+
+    #[test]
+    fn string_indexing() {
+        let src = "var s = \"abc\";\n\
+                   console.log(s[1], s.length, [s, s].length);\n\
+                   for (var c of s) { console.log(c); }";
+        assert_eq!(cross_check(src), "b 3 2\na\nb\nc\n");
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let src = "console.log(6 & 3, 6 | 3, 6 ^ 3, 3 << 2, 13 >> 1, 2 + 1 << 1);";
+        assert_eq!(cross_check(src), "2 7 5 12 6 6\n");
+        assert!(run("console.log(\"a\" | 1);").is_err());
+    }
+
+    #[test]
+    fn truthiness() {
+        let src = "if (\"\") { console.log(\"t\"); } else { console.log(\"f\"); }\n\
+                   while (0) { console.log(\"never\"); }\n\
+                   var s = \"x\"; var n = 2;\n\
+                   if (s && n) { console.log(s || n, 0 || \"\" || n, 0 && n); }";
+        assert_eq!(cross_check(src), "f\nx 2 0\n");
+    }
+
+    #[test]
+    fn functions_and_recursion() {
+        let src = "function fib(n) {\n\
+                       if (n < 2) { return n; }\n\
+                       return fib(n - 1) + fib(n - 2);\n\
+                   }\n\
+                   console.log(fib(10));";
+        assert_eq!(cross_check(src), "55\n");
+    }
+
+    #[test]
+    fn nested_functions() {
+        let src = "function helper(x) { return x * 100; }\n\
+                   function outer(n) {\n\
+                       var r = helper(n) + twice(n);\n\
+                       function helper(x) { return x + 1; }\n\
+                       function twice(x) { return helper(helper(x)); }\n\
+                       return r;\n\
+                   }\n\
+                   console.log(outer(1), helper(1));";
+        assert_eq!(cross_check(src), "5 100\n");
+    }
+
+    #[test]
+    fn objects() {
+        let src = "var o = {\"x\": 1, y: [1, 2]};\n\
+                   o.y[0] = o.x + 1;\n\
+                   o[\"z\"] = {};\n\
+                   o.z.w = o.length;\n\
+                   console.log(o, o.x, o[\"nope\"]);";
+        assert_eq!(cross_check(src), "{x: 1, y: [2, 2], z: {w: NIL}} 1 NIL\n");
+    }
+
+    #[test]
+    fn do_while_loops() {
+        let src = "var i = 0;\n\
+                   do {\n\
+                       i = i + 1;\n\
+                       if (i == 2) { continue; }\n\
+                       if (i == 4) { break; }\n\
+                       console.log(i);\n\
+                   } while (i < 10);\n\
+                   do console.log(\"once\"); while (0);";
+        assert_eq!(cross_check(src), "1\n3\nonce\n");
+    }
+
+    #[test]
+    fn function_expressions() {
+        let src = "function twice(f, x) { return f(f(x)); }\n\
+                   var triple = function(n) { return n * 3; };\n\
+                   console.log(twice(triple, 2), (function() { return 7; })(), triple);";
+        assert_eq!(cross_check(src), "18 7 [Function]\n");
+    }
+
+    #[test]
+    fn declarations_only() {
+        assert_eq!(cross_check("function f() { console.log(1); }"), "");
+    }
+
+    #[test]
+    fn main_function() {
+        let src = "function main() { console.log(helper(2)); }\n\
+                   function helper(x) { return x * 3; }";
+        assert_eq!(cross_check(src), "6\n");
+
+        // Top-level code takes precedence, `main` only runs when called
+        let src = "function main() { console.log(1); }\nconsole.log(2);";
+        assert_eq!(cross_check(src), "2\n");
+    }
+
+    #[test]
+    fn functions_use_global_variables() {
+        let src = "var counter = 0;\n\
+                   function get() { return counter; }\n\
+                   function bump() { counter = counter + 1; }\n\
+                   function shadow() { var counter = 10; bump(); return counter; }\n\
+                   bump();\n\
+                   console.log(get(), shadow(), counter);";
+        assert_eq!(cross_check(src), "1 10 2\n");
+    }
+
+    #[test]
+    fn string_methods() {
+        let src = "var s = \"  Hi \";\n\
+                   console.log(s.trim().toUpperCase(), s.toLowerCase().length);";
+        assert_eq!(cross_check(src), "HI 5\n");
+    }
+
+    #[test]
+    fn uninitialized_variables_are_undefined() {
+        let src = "var x; console.log(x); x = 5; console.log(x);";
+        assert_eq!(cross_check(src), "undefined\n5\n");
+
+        let err = run("var y;\nconsole.log(y * 2);").unwrap_err();
+        assert_eq!((err.err.as_str(), err.line), ("Mul operation used an undefined variable", 2));
+    }
+
+    #[test]
+    fn chained_calls() {
+        let src = "function pick(n) {\n\
+                       if (n == 0) { return function(x) { return x * 2; }; }\n\
+                       return function(x) { return function(y) { return y - 1; }; };\n\
+                   }\n\
+                   console.log(pick(0)(21), pick(1)(2)(5), pick(1)\n(1)\n(3));";
+        assert_eq!(cross_check(src), "42 4 2\n");
+    }
+
+    #[test]
+    fn continue_runs_the_for_increment() {
+        let src = "var sum = 0;\n\
+                   for (var i = 0; i < 10; i = i + 1) {\n\
+                       if (i % 2 == 0) { continue; }\n\
+                       sum = sum + i;\n\
+                   }\n\
+                   var n = 0;\n\
+                   for (;; n = n + 1) { if (n < 3) continue; break; }\n\
+                   console.log(sum, n);";
+        assert_eq!(cross_check(src), "25 3\n");
+    }
+
+    #[test]
+    fn functions_are_values() {
+        let src = "var f = add;\n\
+                   function add(a, b) { return a + b; }\n\
+                   function twice(g, x) { return g(g(x, x), x); }\n\
+                   function pick() { function sub(a, b) { return a - b; } return sub; }\n\
+                   console.log(f(1, 2), twice(add, 3), pick()(5, 2), f);";
+        assert_eq!(cross_check(src), "3 9 3 [Function]\n");
+
+        let err = run("var f = add;\nvar g = nope;").unwrap_err();
+        assert_eq!((err.err.as_str(), err.line), ("Variable 'add' does not exist", 1));
+    }
+
+    #[test]
+    fn arrays() {
+        let src = "var a = [1, [2, 3]];\n\
+                   a[1][0] = 5;\n\
+                   a[0] += 1;\n\
+                   console.log(a, a[1][1]);";
+        assert_eq!(cross_check(src), "[2, [5, 3]] 3\n");
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(run("var a = 1 / 0;").unwrap_err().err,
+                   "Div operation attempted to divide by zero");
+        assert_eq!(run("var a = [1];\nconsole.log(a[1]);").unwrap_err().line, 2);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{
+    codegen::{BcArr, Instr, Program, Value},
+    err::Error,
+};
+
+impl Program {
+    /// Check that the bytecode is well formed before it is handed to the vm.
+    /// Every instruction has to be followed by the right number and kind of
+    /// operands, jumps and calls have to land on an instruction and the entry point has
+    /// to be valid. Jumps may also target the end of the bytecode, which ends
+    /// the program
+    pub fn verify(&self) -> Result<(), Vec<Error>> {
+        let len = self.bytecode.len();
+        let mut errors = Vec::new();
+        let mut boundaries = vec![false; len + 1];
+        boundaries[len] = true;
+
+        // Targets are checked once every instruction boundary is known
+        let mut targets: Vec<(usize, Instr, isize)> = Vec::new();
+
+        let mut ip = 0;
+        while ip < len {
+            let instr = match &self.bytecode[ip] {
+                BcArr::I(instr) => *instr,
+                BcArr::V(v) => {
+                    errors.push(verify_error(format!("Expected instruction at IP={}, found {:?}",
+                                                     ip, v)));
+                    ip += 1;
+                    continue;
+                }
+            };
+            boundaries[ip] = true;
+
+            let next = ip + instr.size();
+            if next > len {
+                errors.push(verify_error(format!("{:?} at IP={} is missing operands",
+                                                 instr, ip)));
+                break;
+            }
+            if let Some(i) = (ip + 1..next).find(|i| matches!(self.bytecode[*i], BcArr::I(_))) {
+                errors.push(verify_error(format!("{:?} at IP={} is missing operands, found \
+                                                 an instruction at IP={}", instr, ip, i)));
+                ip = i;
+                continue;
+            }
+
+            for (i, kind) in (ip + 1..next).zip(operand_kinds(instr)) {
+                if let BcArr::V(v) = &self.bytecode[i] {
+                    if !kind.matches(v) {
+                        errors.push(verify_error(format!("{:?} at IP={} expects {} operand at \
+                                                         IP={}, found {:?}", instr, ip,
+                                                         kind.name(), i, v)));
+                    }
+                }
+            }
+
+            match (instr, self.bytecode.get(ip + 1)) {
+                (Instr::Jmp | Instr::JmpIf | Instr::JmpIN, Some(BcArr::V(Value::VAddr(offset)))) => {
+                    targets.push((ip, instr, ip as isize + 2 + offset));
+                }
+                (Instr::Call, Some(BcArr::V(Value::VAddr(addr)))) => {
+                    targets.push((ip, instr, *addr));
+                }
+                _ => {}
+            }
+            ip = next;
+        }
+
+        for (ip, instr, target) in targets {
+            // Calls have to enter a function, they can not target the end
+            let end = if instr == Instr::Call { len } else { len + 1 };
+            if target < 0 || target as usize >= end || !boundaries[target as usize] {
+                errors.push(verify_error(format!("{:?} at IP={} targets IP={}, which is not \
+                                                 the start of an instruction", instr, ip, target)));
+            }
+        }
+
+        if self.entry_point > len || !boundaries[self.entry_point] {
+            errors.push(verify_error(format!("Entry point IP={} is not the start of an \
+                                             instruction", self.entry_point)));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Errors in the bytecode are not tied to a source location
+fn verify_error(msg: String) -> Error {
+    Error::new(msg, 0, 0)
+}
+
+/// Kind of value the vm expects in an operand slot
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg,
+    Pool,
+    CPool,
+    VAddr,
+
+    /// A count or index that is stored as a number
+    Number,
+
+    /// Immediates and method names, their type is checked by the vm
+    Any,
+}
+
+impl Operand {
+    fn matches(self, v: &Value) -> bool {
+        match self {
+            Operand::Reg => matches!(v, Value::Reg(_)),
+            Operand::Pool => matches!(v, Value::Pool(_)),
+            Operand::CPool => matches!(v, Value::CPool(_)),
+            Operand::VAddr => matches!(v, Value::VAddr(_)),
+            Operand::Number => matches!(v, Value::Int(_) | Value::Number(_)),
+            Operand::Any => true,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Operand::Reg => "a register",
+            Operand::Pool => "a pool",
+            Operand::CPool => "a constant pool",
+            Operand::VAddr => "an address",
+            Operand::Number => "a number",
+            Operand::Any => "any",
+        }
+    }
+}
+
+/// Operands of `instr` in the order the vm reads them
+fn operand_kinds(instr: Instr) -> &'static [Operand] {
+    use Operand::*;
+    match instr {
+        Instr::Ret => &[],
+        Instr::LoadI => &[Reg, Any],
+        Instr::LoadR | Instr::Len => &[Reg, Reg],
+        Instr::LoadP | Instr::LoadG => &[Reg, Pool],
+        Instr::PushP | Instr::PushG => &[Pool, Reg],
+        Instr::LoadA => &[Pool],
+        Instr::LoadC => &[Reg, CPool],
+        Instr::PushA | Instr::Print | Instr::PrintS | Instr::Rand | Instr::CallR |
+        Instr::Halt => &[Reg],
+        Instr::Jmp | Instr::Call => &[VAddr],
+        Instr::JmpIf | Instr::JmpIN => &[VAddr, Reg],
+        Instr::MakeArray | Instr::MakeObject | Instr::CallNative => &[Reg, Number],
+        Instr::CallMethod => &[Reg, Reg, Any],
+        Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Mod |
+        Instr::CmpLT | Instr::CmpLE | Instr::CmpGT | Instr::CmpGE |
+        Instr::CmpEq | Instr::CmpNe | Instr::RandInt | Instr::IndexGet |
+        Instr::IndexSet | Instr::BitAnd | Instr::BitOr | Instr::BitXor |
+        Instr::Shl | Instr::Shr => &[Reg, Reg, Reg],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Codegen, lexer::tokenize, parser::Parser};
+    use std::collections::HashMap;
+
+    fn program(bytecode: Vec<BcArr>) -> Program {
+        Program {
+            lines: vec![0; bytecode.len()],
+            bytecode,
+            entry_point: 0,
+            function_list: HashMap::new(),
+            const_pool: Vec::new(),
+            unresolved: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn accepts_generated_code() {
+        let src = "function f(a) { return a * 2; }\n\
+                   var i = 0;\n\
+                   while (i < 3) { if (i == 1) { break; } i = f(i) + 1; }\n\
+                   console.log(i > 1 ? i : 0);";
+        let (stmts, errors) = Parser::new(tokenize(src)).parse();
+        assert!(errors.is_empty());
+        assert!(Codegen::bytecode_gen(stmts).unwrap().verify().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_programs() {
+        // Jump into the operand of the LoadI
+        let bad_jump = program(vec![
+            BcArr::I(Instr::LoadI),
+            BcArr::V(Value::Reg(1)),
+            BcArr::V(Value::Number(1.0)),
+            BcArr::I(Instr::Jmp),
+            BcArr::V(Value::VAddr(-4)),
+        ]);
+        assert_eq!(bad_jump.verify().unwrap_err().len(), 1);
+
+        // Print without its operand, followed by a call past the end
+        let bad = program(vec![
+            BcArr::I(Instr::Print),
+            BcArr::I(Instr::Call),
+            BcArr::V(Value::VAddr(10)),
+        ]);
+        assert_eq!(bad.verify().unwrap_err().len(), 2);
+
+        let mut bad_entry = program(vec![BcArr::I(Instr::Print), BcArr::V(Value::Reg(0))]);
+        bad_entry.entry_point = 1;
+        assert!(bad_entry.verify().is_err());
+    }
+
+    #[test]
+    fn rejects_operands_of_the_wrong_kind() {
+        let pool_for_reg = program(vec![
+            BcArr::I(Instr::LoadI),
+            BcArr::V(Value::Reg(1)),
+            BcArr::V(Value::Int(1)),
+            BcArr::I(Instr::Print),
+            BcArr::V(Value::Pool(1)),
+        ]);
+        let errors = pool_for_reg.verify().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].err,
+                   "Print at IP=3 expects a register operand at IP=4, found Pool(1)");
+
+        let reg_for_addr = program(vec![BcArr::I(Instr::Jmp), BcArr::V(Value::Reg(0))]);
+        assert_eq!(reg_for_addr.verify().unwrap_err().len(), 1);
+    }
+}
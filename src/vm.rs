@@ -1,4 +1,14 @@
-use crate::codegen::{BcArr, Instr, Program, Value};
+use crate::codegen::{format_number, BcArr, Instr, Program, Value};
+use crate::err::RuntimeError;
+use crate::native::{self, NATIVES};
+
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, Stdout, Write},
+};
 
 /// Macro used to extract known enum variants from enums
 #[macro_export]
@@ -11,14 +21,50 @@ macro_rules! extract_enum_value {
     };
 }
 
+/// State of a caller that is saved on a call and restored once the callee
+/// returns, so recursive and nested calls do not clobber each other
 #[derive(Clone, Debug)]
-pub struct Interpreter {
-    /// Holds bytecode that is used to retrieve instructions and operands
-    bytecode: Vec<BcArr>,
+struct Frame {
+    /// Address execution continues at after the call
+    ret_ip: usize,
+
+    /// Registers of the caller
+    regs: Vec<Value>,
+
+    /// Local variables of the caller
+    local_pool: Vec<Value>,
+}
+
+/// Integer and float implementation of an arithmetic instruction, the integer
+/// one returns None on overflow
+type ArithOps = (fn(i64, i64) -> Option<i64>, fn(f64, f64) -> f64);
+
+/// Outcome of executing a single instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepResult {
+    /// There are more instructions to execute
+    Continue,
+
+    /// The end of the bytecode was reached, or the program called `exit`
+    Halted,
+}
+
+#[derive(Clone, Debug)]
+pub struct Interpreter<'a, W: Write = Stdout> {
+    /// Holds bytecode that is used to retrieve instructions and operands.
+    /// Borrowed from the program if the interpreter was created with
+    /// `from_ref`, the program is never modified
+    bytecode: Cow<'a, [BcArr]>,
+
+    /// Source line of every bytecode entry, used for error messages
+    lines: Cow<'a, [u32]>,
 
     /// Holds program counter
     ip: usize,
 
+    /// Address of the instruction that is currently being executed
+    instr_ip: usize,
+
     /// Holds registers
     regs: Vec<Value>,
 
@@ -26,67 +72,92 @@ pub struct Interpreter {
     local_pool: Vec<Value>,
 
     /// Holds constants
-    const_pool: Vec<Value>,
+    const_pool: Cow<'a, [Value]>,
 
     /// Used to pass function arguments
     args: Vec<Value>,
 
-    /// Call stack that holds the return values
-    call_stack: Vec<usize>,
+    /// Call stack that holds the saved state of every active caller
+    call_stack: Vec<Frame>,
+
+    /// State of the xorshift generator backing the random builtins
+    rng_state: u64,
+
+    /// Maximum number of nested function calls before execution is aborted
+    max_call_depth: usize,
+
+    /// Maximum number of instructions to execute, unlimited if None
+    budget: Option<u64>,
+
+    /// Number of instructions executed so far
+    executed: u64,
+
+    /// Code the program passed to `exit`, if it called it
+    exit_code: Option<i32>,
 
-    /// Flag used to determine conditional jumps
-    flag: bool,
+    /// Output of the print instructions is written to this
+    out: W,
+
+    /// Count how often every instruction is executed
+    profile: bool,
+
+    /// Execution count of every instruction, indexed by opcode
+    counts: [u64; 256],
 }
 
-impl Interpreter {
-    /// Returns new interpreter object
-    pub fn new(program: Program) -> Self {
-        Self {
-            bytecode: program.bytecode,
-            ip: program.entry_point,
-            regs: Vec::new(),
-            local_pool: Vec::new(),
-            const_pool: program.const_pool,
-            args: Vec::new(),
-            call_stack: Vec::new(),
-            flag: false,
-        }
-    }
+/// Seed used by the random builtins unless `set_seed` is called, so runs are
+/// reproducible by default
+pub const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
 
-    /// Convert ast into bytecodearray
-    pub fn interpret(&mut self) {
-        let len = self.bytecode.len();
-        // Initialize r0 since it is exclusively used as return value for
-        // functions so other operations do not attempt to use it.
-        self.regs.push(Value::Number(0.0));
+/// Call depth at which runaway recursion is reported as a stack overflow
+const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
 
-        while self.ip < len {
-            self.execute_instr();
-        }
+impl<'a> Interpreter<'a> {
+    /// Returns new interpreter object that prints to stdout
+    pub fn new(program: Program) -> Self {
+        Interpreter::with_writer(program, io::stdout())
     }
 
-    /// Retrieves the next value from the bytecode vector
-    fn fetch_val(&mut self) -> BcArr {
-        self.ip += 1;
-        self.bytecode[self.ip - 1].clone()
+    /// Interpreter that runs `program` without taking or copying it, so the
+    /// same program can be run several times or analyzed first
+    pub fn from_ref(program: &'a Program) -> Self {
+        Interpreter::from_ref_with_writer(program, io::stdout())
     }
 
-    /// Inserts value into specified register vector slot
-    fn register_insert(&mut self, regid: usize, val: Value) {
-        if self.regs.len() > regid {
-            self.regs[regid] = val;
-        } else {
-            self.regs.push(val);
-        }
+    /// Single step of the xorshift64 generator with state `state`
+    pub fn xorshift(state: &mut u64) -> u64 {
+        let mut x = *state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *state = x;
+        x
     }
 
-    /// Inserts value into specified local pool vector slot
-    fn pool_insert(&mut self, index: usize, val: Value) {
-        if self.local_pool.len() > index {
-            self.local_pool[index] = val;
-        } else {
-            self.local_pool.push(val);
+    /// Random integer in [lo, hi] drawn from the xorshift generator with
+    /// state `state`. Fractional bounds are rounded inwards, both bounds have
+    /// to fit into an integer
+    pub fn random_int(state: &mut u64, lo: &Value, hi: &Value) -> Result<Value, String> {
+        if !Interpreter::check_num(lo) || !Interpreter::check_num(hi) {
+            return Err("Both values for 'randomInt' need to be numbers".to_string());
+        }
+        let lo = Interpreter::unpack_number(lo).ceil();
+        let hi = Interpreter::unpack_number(hi).floor();
+        if lo > hi {
+            return Err("randomInt range is empty".to_string());
         }
+        if lo < i64::MIN as f64 || hi > i64::MAX as f64 {
+            return Err("randomInt bounds need to fit into an integer".to_string());
+        }
+
+        // The span of the full integer range does not fit into a u64, every
+        // output of the generator is a valid result then
+        let (lo, hi) = (lo as i64, hi as i64);
+        let x = Interpreter::xorshift(state);
+        Ok(Value::Int(match (hi.wrapping_sub(lo) as u64).checked_add(1) {
+            Some(span) => lo.wrapping_add((x % span) as i64),
+            None => x as i64,
+        }))
     }
 
     /// Unpacks a register from the BcArr enum
@@ -114,19 +185,67 @@ impl Interpreter {
         extract_enum_value!(reg, BcArr::V(Value::CPool(c)) => c) as usize
     }
 
-    /// Unpacks a number from the Value enum
+    /// Unpacks a number from the Value enum, integers are converted to floats
     pub fn unpack_number(num: &Value) -> f64 {
-        *extract_enum_value!(num, Value::Number(c) => c)
+        match num {
+            Value::Int(i) => *i as f64,
+            _ => *extract_enum_value!(num, Value::Number(c) => c),
+        }
+    }
+
+    /// Process exit code passed to `exit`, which has to be an integer
+    pub fn exit_code(v: &Value) -> Result<i32, String> {
+        match v {
+            Value::Int(i) => i32::try_from(*i).ok(),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() <= i32::MAX as f64 => Some(*n as i32),
+            _ => None,
+        }
+        .ok_or_else(|| "Exit code needs to be an integer".to_string())
+    }
+
+    /// Checks if provided value is of type number, either integer or float
+    pub fn check_num(v: &Value) -> bool {
+        matches!(v, Value::Number(_) | Value::Int(_))
+    }
+
+    /// `v` with booleans replaced by the integer they count as in arithmetic
+    fn bool_to_int(v: &Value) -> Value {
+        match v {
+            Value::Bool(b) => Value::Int(*b as i64),
+            _ => v.clone(),
+        }
+    }
+
+    /// Name of the type of `v` used in error messages
+    pub fn type_name(v: &Value) -> &'static str {
+        match v {
+            Value::Number(_) | Value::Int(_) => "number",
+            Value::StringLiteral(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Undefined => "undefined",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+            Value::Function(_) => "function",
+            Value::Reg(_) | Value::Pool(_) | Value::CPool(_) | Value::VAddr(_) => "operand",
+        }
     }
 
-    /// Unpacks a String from the Value enum
-    fn unpack_string(val: &Value) -> &str {
-        extract_enum_value!(val, Value::StringLiteral(c) => c)
+    /// Key of an object lookup, only strings can be used as keys
+    pub fn object_key(key: &Value) -> Result<&str, String> {
+        match key {
+            Value::StringLiteral(s) => Ok(s),
+            _ => Err("Object key must be a string".to_string()),
+        }
     }
 
-    /// Checks if provided value is of type number
-    fn check_num(v: &Value) -> bool {
-        matches!(v, Value::Number(_))
+    /// Character `index` of `s`, which has to exist. Characters are one
+    /// character strings, so they compare and concatenate like any other
+    /// string. Strings are indexed by character rather than byte, matching
+    /// their length
+    pub fn char_at(s: &str, index: usize) -> Value {
+        let c = s.chars().nth(index).expect("character index out of bounds");
+        Value::StringLiteral(c.to_string())
     }
 
     /// Checks if provided value is of type StringLiteral
@@ -134,88 +253,475 @@ impl Interpreter {
         matches!(v, Value::StringLiteral(_))
     }
 
+    /// Apply an arithmetic, comparison or bitwise instruction to two values.
+    /// Operations on two integers stay integers unless the result does not
+    /// fit, as soon as a float is involved the result is a float
+    pub fn binary_op(instr: Instr, v1: &Value, v2: &Value) -> Result<Value, String> {
+        let name = match instr {
+            Instr::Add => "Add",
+            Instr::Sub => "Sub",
+            Instr::Mul => "Mul",
+            Instr::Div => "Div",
+            Instr::Mod => "Mod",
+            Instr::CmpLT => "less than",
+            Instr::CmpLE => "less than equal",
+            Instr::CmpGT => "greater than",
+            Instr::CmpGE => "greater than equal",
+            Instr::CmpEq => "Equal",
+            Instr::CmpNe => "Not-equal",
+            _ => return Interpreter::bitwise_op(instr, v1, v2),
+        };
+        let undefined = matches!(v1, Value::Undefined) || matches!(v2, Value::Undefined);
+        if undefined && !matches!(instr, Instr::CmpEq | Instr::CmpNe) {
+            return Err(format!("{} operation used an undefined variable", name));
+        }
+        // Booleans count as 0 and 1 in arithmetic like in javascript. Next to
+        // a string they would be concatenated as text, which is not supported
+        let arith = matches!(instr, Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Mod);
+        if arith && (matches!(v1, Value::Bool(_)) || matches!(v2, Value::Bool(_)))
+            && !Interpreter::check_str(v1) && !Interpreter::check_str(v2) {
+            return Interpreter::binary_op(instr, &Interpreter::bool_to_int(v1),
+                                          &Interpreter::bool_to_int(v2));
+        }
+        let nums = Interpreter::check_num(v1) && Interpreter::check_num(v2);
+        let unsupported = || Err(format!("{} operation not supported for the specified operands",
+                                         name));
+
+        match instr {
+            Instr::Add | Instr::Sub | Instr::Mul => {
+                let (int_op, float_op): ArithOps = match instr {
+                    Instr::Add => (i64::checked_add, |a, b| a + b),
+                    Instr::Sub => (i64::checked_sub, |a, b| a - b),
+                    _ => (i64::checked_mul, |a, b| a * b),
+                };
+                match (v1, v2) {
+                    (Value::Int(a), Value::Int(b)) => Ok(int_op(*a, *b).map_or_else(
+                        || Value::Number(float_op(*a as f64, *b as f64)),
+                        Value::Int,
+                    )),
+                    _ if nums => Ok(Value::Number(float_op(Interpreter::unpack_number(v1),
+                                                           Interpreter::unpack_number(v2)))),
+                    // Strings can be concatenated with strings and numbers
+                    (Value::StringLiteral(_), Value::StringLiteral(_) | Value::Number(_) |
+                     Value::Int(_)) |
+                    (Value::Number(_) | Value::Int(_), Value::StringLiteral(_))
+                        if instr == Instr::Add =>
+                    {
+                        let a = Interpreter::format_value(v1).unwrap();
+                        let b = Interpreter::format_value(v2).unwrap();
+                        Ok(Value::StringLiteral(a + &b))
+                    }
+                    _ => unsupported(),
+                }
+            }
+            Instr::Div | Instr::Mod => {
+                if !nums {
+                    return unsupported();
+                }
+                if Interpreter::unpack_number(v2) == 0.0 {
+                    return Err(format!("{} operation attempted to divide by zero", name));
+                }
+                if let (Value::Int(a), Value::Int(b)) = (v1, v2) {
+                    // Divisions only stay integers if there is no remainder
+                    let exact = match instr {
+                        Instr::Div if a.checked_rem(*b) == Some(0) => a.checked_div(*b),
+                        Instr::Mod => a.checked_rem(*b),
+                        _ => None,
+                    };
+                    if let Some(v) = exact {
+                        return Ok(Value::Int(v));
+                    }
+                }
+                let (a, b) = (Interpreter::unpack_number(v1), Interpreter::unpack_number(v2));
+                Ok(Value::Number(if instr == Instr::Div { a / b } else { a % b }))
+            }
+            Instr::CmpEq | Instr::CmpNe => {
+                let eq = match (v1, v2) {
+                    (Value::Int(a), Value::Int(b)) => a == b,
+                    _ if nums => Interpreter::unpack_number(v1) == Interpreter::unpack_number(v2),
+                    (Value::StringLiteral(a), Value::StringLiteral(b)) => a == b,
+                    (Value::StringLiteral(s), n) | (n, Value::StringLiteral(s))
+                        if Interpreter::check_num(n) =>
+                    {
+                        Interpreter::format_value(n).unwrap() == *s
+                    }
+                    _ => return unsupported(),
+                };
+                Ok(Value::Bool(eq == (instr == Instr::CmpEq)))
+            }
+            _ => {
+                // Strings are ordered lexicographically
+                let ord = match (v1, v2) {
+                    (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+                    _ if nums => Interpreter::unpack_number(v1)
+                        .partial_cmp(&Interpreter::unpack_number(v2)),
+                    (Value::StringLiteral(a), Value::StringLiteral(b)) => Some(a.cmp(b)),
+                    _ => return Err(format!("Both values for '{}' operation need to be \
+                                             numbers or strings, got {} and {}", name,
+                                            Interpreter::type_name(v1),
+                                            Interpreter::type_name(v2))),
+                };
+                let res = match instr {
+                    Instr::CmpLT => ord == Some(Ordering::Less),
+                    Instr::CmpLE => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+                    Instr::CmpGT => ord == Some(Ordering::Greater),
+                    _ => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                };
+                Ok(Value::Bool(res))
+            }
+        }
+    }
+
+    /// Apply a bitwise instruction. Both operands have to be integers, floats
+    /// without a fractional part are converted to integers
+    pub fn bitwise_op(instr: Instr, v1: &Value, v2: &Value) -> Result<Value, String> {
+        let name = match instr {
+            Instr::BitAnd => "BitAnd",
+            Instr::BitOr => "BitOr",
+            Instr::BitXor => "BitXor",
+            Instr::Shl => "Shl",
+            _ => "Shr",
+        };
+        let int = |v: &Value| match v {
+            Value::Int(i) => Some(*i),
+            Value::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        };
+        let (a, b) = match (int(v1), int(v2)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(format!("{} operation requires integer operands", name)),
+        };
+
+        let result = match instr {
+            Instr::BitAnd => a & b,
+            Instr::BitOr => a | b,
+            Instr::BitXor => a ^ b,
+            _ if !(0..64).contains(&b) => {
+                return Err(format!("{} operation with shift amount {} out of range", name, b));
+            }
+            Instr::Shl => a << b,
+            _ => a >> b,
+        };
+        Ok(Value::Int(result))
+    }
+
+    /// Format a value the way it is printed to the console, None if the
+    /// value can not be printed
+    pub fn format_value(v: &Value) -> Option<String> {
+        match v {
+            Value::Number(v) => Some(format_number(*v)),
+            Value::Int(v) => Some(v.to_string()),
+            Value::StringLiteral(v) => Some(v.clone()),
+            Value::Bool(v) => Some(v.to_string()),
+            Value::Nil => Some("NIL".to_string()),
+            Value::Undefined => Some("undefined".to_string()),
+            Value::Function(_) => Some("[Function]".to_string()),
+            Value::Object(map) => {
+                // Keys are sorted so the output does not depend on hashing
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                let entries: Option<Vec<String>> = keys
+                    .into_iter()
+                    .map(|k| Some(format!("{}: {}", k, Interpreter::format_value(&map[k])?)))
+                    .collect();
+                Some(format!("{{{}}}", entries?.join(", ")))
+            }
+            Value::Array(elements) => {
+                let elements: Option<Vec<String>> =
+                    elements.iter().map(Interpreter::format_value).collect();
+                Some(format!("[{}]", elements?.join(", ")))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a, W: Write> Interpreter<'a, W> {
+    /// Returns new interpreter object that prints to `out`
+    pub fn with_writer(program: Program, out: W) -> Self {
+        Self::start(Cow::Owned(program.bytecode), Cow::Owned(program.lines),
+                    Cow::Owned(program.const_pool), program.entry_point, out)
+    }
+
+    /// Like `from_ref`, but prints to `out`
+    pub fn from_ref_with_writer(program: &'a Program, out: W) -> Self {
+        Self::start(Cow::Borrowed(&program.bytecode), Cow::Borrowed(&program.lines),
+                    Cow::Borrowed(&program.const_pool), program.entry_point, out)
+    }
+
+    fn start(bytecode: Cow<'a, [BcArr]>, lines: Cow<'a, [u32]>, const_pool: Cow<'a, [Value]>,
+             entry_point: usize, out: W) -> Self {
+        Self {
+            bytecode,
+            lines,
+            ip: entry_point,
+            instr_ip: entry_point,
+            // r0 is exclusively used as return value for functions so other
+            // operations do not attempt to use it
+            regs: vec![Value::Number(0.0)],
+            local_pool: Vec::new(),
+            const_pool,
+            args: Vec::new(),
+            call_stack: Vec::new(),
+            rng_state: DEFAULT_SEED,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            budget: None,
+            executed: 0,
+            exit_code: None,
+            out,
+            profile: false,
+            counts: [0; 256],
+        }
+    }
+
+    /// Reseed the generator used by `random()` and `randomInt()`
+    pub fn set_seed(&mut self, seed: u64) {
+        // Xorshift gets stuck at 0, so remap that seed to the default
+        self.rng_state = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
+
+    /// Set the maximum number of nested function calls
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Abort execution once `budget` instructions were executed. Unlike the
+    /// call depth this also stops loops that never terminate
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = Some(budget);
+    }
+
+    /// Enable or disable counting how often each instruction is executed
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile;
+    }
+
+    /// Execution counts collected while profiling, one line per executed
+    /// instruction sorted by descending count
+    pub fn profile_report(&self) -> String {
+        let mut counts: Vec<(Instr, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .filter_map(|(op, n)| Some((Instr::try_from(op as u8).ok()?, *n)))
+            .collect();
+        counts.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+
+        counts
+            .iter()
+            .map(|(instr, n)| format!("{:<12} {}\n", format!("{:?}", instr), n))
+            .collect()
+    }
+
+    /// Advance the xorshift64 generator and return its next output
+    fn next_random(&mut self) -> u64 {
+        Interpreter::xorshift(&mut self.rng_state)
+    }
+
+    /// Replace the program with `program`, which has to extend the current
+    /// one (see `Codegen::compile`). Registers and variables are kept so the
+    /// new code can use them, execution continues at the new entry point
+    pub fn load(&mut self, program: Program) {
+        self.bytecode = Cow::Owned(program.bytecode);
+        self.lines = Cow::Owned(program.lines);
+        self.const_pool = Cow::Owned(program.const_pool);
+        self.ip = program.entry_point;
+        self.instr_ip = program.entry_point;
+        self.exit_code = None;
+
+        // The previous code may have been aborted by an error halfway through
+        // a call, go back to the state of the top-level code
+        if !self.call_stack.is_empty() {
+            let frame = self.call_stack.swap_remove(0);
+            self.regs = frame.regs;
+            self.local_pool = frame.local_pool;
+            self.call_stack.clear();
+        }
+        self.args.clear();
+    }
+
+    /// Execute the bytecode until the end of it is reached or an error occurs.
+    /// Returns the exit code if the program was stopped by `exit`
+    pub fn interpret(&mut self) -> Result<Option<i32>, RuntimeError> {
+        while self.step()? == StepResult::Continue {}
+        Ok(self.exit_code)
+    }
+
+    /// Execute a single instruction
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        if self.ip < self.bytecode.len() {
+            self.execute_instr()?;
+        }
+        if self.ip < self.bytecode.len() {
+            Ok(StepResult::Continue)
+        } else {
+            Ok(StepResult::Halted)
+        }
+    }
+
+    /// Address of the next instruction to execute
+    #[allow(dead_code)]
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Contents of the registers, indexed by register number
+    #[allow(dead_code)]
+    pub fn registers(&self) -> &[Value] {
+        &self.regs
+    }
+
+    /// Contents of the local variable pool, indexed by pool slot
+    #[allow(dead_code)]
+    pub fn local_pool(&self) -> &[Value] {
+        &self.local_pool
+    }
+
+    /// Build an error for the instruction that is currently being executed
+    fn error(&self, msg: &str) -> RuntimeError {
+        let line = self.lines.get(self.instr_ip).copied().unwrap_or(0);
+        RuntimeError::new(msg.to_string(), self.instr_ip, line)
+    }
+
+    /// Retrieves the next value from the bytecode vector
+    fn fetch_val(&mut self) -> BcArr {
+        self.ip += 1;
+        self.bytecode[self.ip - 1].clone()
+    }
+
+    /// Inserts value into specified register vector slot
+    fn register_insert(&mut self, regid: usize, val: Value) {
+        // Registers are not necessarily written in order, eg. when a branch
+        // that was compiled later is the one that is taken
+        if self.regs.len() <= regid {
+            self.regs.resize(regid + 1, Value::Nil);
+        }
+        self.regs[regid] = val;
+    }
+
+    /// Inserts value into specified local pool vector slot
+    fn pool_insert(&mut self, index: usize, val: Value) {
+        // Slots are not necessarily written in order, eg. after linking modules
+        if self.local_pool.len() <= index {
+            self.local_pool.resize(index + 1, Value::Undefined);
+        }
+        self.local_pool[index] = val;
+    }
+
     /// Decode instruction and execute an appropriate function
-    fn execute_instr(&mut self) {
+    fn execute_instr(&mut self) -> Result<(), RuntimeError> {
+        self.instr_ip = self.ip;
+        if self.budget.is_some_and(|budget| self.executed >= budget) {
+            return Err(self.error("Execution budget exceeded"));
+        }
+        self.executed += 1;
         let instr = self.fetch_val();
+        if self.profile {
+            if let BcArr::I(i) = instr {
+                self.counts[u8::from(i) as usize] += 1;
+            }
+        }
         match instr {
             BcArr::I(Instr::LoadI) => {
-                self.loadi();
+                self.loadi()?;
             }
             BcArr::I(Instr::LoadR) => {
-                self.loadr();
+                self.loadr()?;
             }
             BcArr::I(Instr::PushP) => {
-                self.pushp();
+                self.pushp()?;
             }
             BcArr::I(Instr::PushA) => {
-                self.pusha();
+                self.pusha()?;
             }
             BcArr::I(Instr::LoadP) => {
-                self.loadp();
+                self.loadp()?;
             }
             BcArr::I(Instr::LoadA) => {
-                self.loada();
+                self.loada()?;
+            }
+            BcArr::I(Instr::LoadG) => {
+                self.loadg()?;
+            }
+            BcArr::I(Instr::PushG) => {
+                self.pushg()?;
+            }
+            BcArr::I(Instr::Halt) => {
+                self.halt()?;
+            }
+            BcArr::I(Instr::CallMethod) => {
+                self.call_method()?;
             }
             BcArr::I(Instr::LoadC) => {
-                self.loadc();
+                self.loadc()?;
             }
             BcArr::I(Instr::Jmp) => {
-                self.jmp();
+                self.jmp()?;
             }
             BcArr::I(Instr::Call) => {
-                self.function_call();
+                self.function_call()?;
+            }
+            BcArr::I(Instr::CallR) => {
+                self.function_call_reg()?;
             }
             BcArr::I(Instr::JmpIf) => {
-                self.jmp_if();
+                self.jmp_if()?;
             }
             BcArr::I(Instr::JmpIN) => {
-                self.jmp_if_not();
+                self.jmp_if_not()?;
             }
             BcArr::I(Instr::Print) => {
-                self.print();
+                self.print(true)?;
+            }
+            BcArr::I(Instr::PrintS) => {
+                self.print(false)?;
             }
-            BcArr::I(Instr::Add) => {
-                self.add();
+            BcArr::I(Instr::Rand) => {
+                self.rand()?;
             }
-            BcArr::I(Instr::Sub) => {
-                self.sub();
+            BcArr::I(Instr::RandInt) => {
+                self.rand_int()?;
             }
-            BcArr::I(Instr::Mul) => {
-                self.mul();
+            BcArr::I(Instr::MakeArray) => {
+                self.make_array()?;
             }
-            BcArr::I(Instr::Div) => {
-                self.div();
+            BcArr::I(Instr::MakeObject) => {
+                self.make_object()?;
             }
-            BcArr::I(Instr::CmpLT) => {
-                self.cmp_less_than();
+            BcArr::I(Instr::CallNative) => {
+                self.call_native()?;
             }
-            BcArr::I(Instr::CmpLE) => {
-                self.cmp_less_equal();
+            BcArr::I(Instr::IndexGet) => {
+                self.index_get()?;
             }
-            BcArr::I(Instr::CmpGT) => {
-                self.cmp_greater_than();
+            BcArr::I(Instr::IndexSet) => {
+                self.index_set()?;
             }
-            BcArr::I(Instr::CmpGE) => {
-                self.cmp_greater_equal();
+            BcArr::I(Instr::Len) => {
+                self.len()?;
             }
-            BcArr::I(Instr::CmpEq) => {
-                self.cmp_equals();
+            BcArr::I(instr @ (Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Mod |
+                              Instr::CmpLT | Instr::CmpLE | Instr::CmpGT | Instr::CmpGE |
+                              Instr::CmpEq | Instr::CmpNe | Instr::BitAnd | Instr::BitOr |
+                              Instr::BitXor | Instr::Shl | Instr::Shr)) => {
+                self.binary(instr)?;
             }
             BcArr::I(Instr::Ret) => {
-                self.ret();
+                self.ret()?;
             }
             _ => {
-                panic!(
-                    "Runtime Error: Instruction not implemented in vm: \
-                          {:?} at IP={}",
-                    instr, self.ip
-                );
+                return Err(self.error(&format!(
+                    "Instruction not implemented in vm: {:?}",
+                    instr
+                )));
             }
         }
+        Ok(())
     }
 
     /// Loadi instruction - Loads an immediate value into a register
-    fn loadi(&mut self) {
+    fn loadi(&mut self) -> Result<(), RuntimeError> {
         let reg = self.fetch_val();
         let v = self.fetch_val();
 
@@ -223,10 +729,11 @@ impl Interpreter {
         let val = Interpreter::unpack_value(v);
 
         self.register_insert(register_index, val);
+        Ok(())
     }
 
     /// Loadr instruction - Loads value from one register into another
-    fn loadr(&mut self) {
+    fn loadr(&mut self) -> Result<(), RuntimeError> {
         let dst = self.fetch_val();
         let src = self.fetch_val();
 
@@ -235,10 +742,11 @@ impl Interpreter {
         let val = self.regs[src_index].clone();
 
         self.register_insert(dst_index, val);
+        Ok(())
     }
 
     /// PushP instruction - Push value from register into local pool
-    fn pushp(&mut self) {
+    fn pushp(&mut self) -> Result<(), RuntimeError> {
         let pool = self.fetch_val();
         let reg = self.fetch_val();
 
@@ -247,42 +755,96 @@ impl Interpreter {
         let val = self.regs[register_index].clone();
 
         self.pool_insert(pool_index, val);
+        Ok(())
     }
 
     /// PushA instruction - Push value from register into argument register
-    fn pusha(&mut self) {
+    fn pusha(&mut self) -> Result<(), RuntimeError> {
         let reg = self.fetch_val();
 
         let register_index = Interpreter::unpack_register(reg);
         let val = self.regs[register_index].clone();
 
         self.args.push(val);
+        Ok(())
     }
 
     /// LoadP instruction - Load value from local pool into a register
-    fn loadp(&mut self) {
+    fn loadp(&mut self) -> Result<(), RuntimeError> {
         let reg = self.fetch_val();
         let pool = self.fetch_val();
 
         let register_index = Interpreter::unpack_register(reg);
         let pool_index = Interpreter::unpack_pool(pool);
-        let val = self.local_pool[pool_index].clone();
+        let val = self.local_pool.get(pool_index).cloned().unwrap_or(Value::Undefined);
 
         self.register_insert(register_index, val);
+        Ok(())
+    }
+
+    /// Pool of the top-level code, which holds the global variables. While a
+    /// function runs it is saved in the outermost frame
+    fn global_pool(&mut self) -> &mut Vec<Value> {
+        match self.call_stack.first_mut() {
+            Some(frame) => &mut frame.local_pool,
+            None => &mut self.local_pool,
+        }
+    }
+
+    /// LoadG instruction - Load a global variable into a register
+    fn loadg(&mut self) -> Result<(), RuntimeError> {
+        let reg = self.fetch_val();
+        let pool = self.fetch_val();
+
+        let register_index = Interpreter::unpack_register(reg);
+        let pool_index = Interpreter::unpack_pool(pool);
+        let val = self.global_pool().get(pool_index).cloned().unwrap_or(Value::Undefined);
+
+        self.register_insert(register_index, val);
+        Ok(())
+    }
+
+    /// PushG instruction - Store value from register into a global variable
+    fn pushg(&mut self) -> Result<(), RuntimeError> {
+        let pool = self.fetch_val();
+        let reg = self.fetch_val();
+
+        let pool_index = Interpreter::unpack_pool(pool);
+        let val = self.regs[Interpreter::unpack_register(reg)].clone();
+        let globals = self.global_pool();
+        if globals.len() <= pool_index {
+            globals.resize(pool_index + 1, Value::Undefined);
+        }
+        globals[pool_index] = val;
+        Ok(())
+    }
+
+    /// Halt instruction - Stop the program, even from inside of a function
+    fn halt(&mut self) -> Result<(), RuntimeError> {
+        let reg = self.fetch_val();
+        let code = Interpreter::exit_code(&self.regs[Interpreter::unpack_register(reg)])
+            .map_err(|e| self.error(&e))?;
+        self.exit_code = Some(code);
+        self.ip = self.bytecode.len();
+        Ok(())
     }
 
     /// LoadA instruction - Load value from an argument register into register
-    fn loada(&mut self) {
+    fn loada(&mut self) -> Result<(), RuntimeError> {
         let pool = self.fetch_val();
 
         let pool_index = Interpreter::unpack_pool(pool);
+        if self.args.is_empty() {
+            return Err(self.error("Function called with too few arguments"));
+        }
         let val = self.args.remove(0);
 
         self.pool_insert(pool_index, val);
+        Ok(())
     }
 
     /// LoadC instruction - Load value from constant pool into a register
-    fn loadc(&mut self) {
+    fn loadc(&mut self) -> Result<(), RuntimeError> {
         let reg = self.fetch_val();
         let cpool = self.fetch_val();
 
@@ -291,330 +853,673 @@ impl Interpreter {
         let val = self.const_pool[cpool_index].clone();
 
         self.register_insert(register_index, val);
+        Ok(())
     }
 
-    /// Jmp if flag is set - Adds VAddr offset to IP
-    fn jmp_if(&mut self) {
+    /// Operands of a conditional jump, the jump target is relative to the
+    /// offset operand and the condition is the truthiness of the register
+    fn jmp_cond(&mut self) -> (usize, bool) {
         let offset: isize = (Interpreter::unpack_vaddr(self.fetch_val())) as isize;
-        let mut fake_ip: isize = self.ip as isize;
+        let target = (self.ip as isize + offset) as usize;
+        let reg = Interpreter::unpack_register(self.fetch_val());
 
-        if self.flag {
-            fake_ip += offset;
-            self.ip = fake_ip as usize;
-        }
+        (target, self.regs.get(reg).is_some_and(Value::is_truthy))
     }
 
-    /// Jmp if flag is not set - Adds VAddr offset to IP
-    fn jmp_if_not(&mut self) {
-        let offset: isize = (Interpreter::unpack_vaddr(self.fetch_val())) as isize;
-        let mut fake_ip: isize = self.ip as isize;
+    /// Jmp if the condition register is truthy - Adds VAddr offset to IP
+    fn jmp_if(&mut self) -> Result<(), RuntimeError> {
+        let (target, cond) = self.jmp_cond();
+        if cond {
+            self.ip = target;
+        }
+        Ok(())
+    }
 
-        if !self.flag {
-            fake_ip += offset;
-            self.ip = fake_ip as usize;
+    /// Jmp if the condition register is falsy - Adds VAddr offset to IP
+    fn jmp_if_not(&mut self) -> Result<(), RuntimeError> {
+        let (target, cond) = self.jmp_cond();
+        if !cond {
+            self.ip = target;
         }
+        Ok(())
     }
 
     /// Unconditional jmp - Adds VAddr offset to IP
-    fn jmp(&mut self) {
+    fn jmp(&mut self) -> Result<(), RuntimeError> {
         let offset: isize = (Interpreter::unpack_vaddr(self.fetch_val())) as isize;
         let mut fake_ip: isize = self.ip as isize;
         fake_ip += offset;
         self.ip = fake_ip as usize;
+        Ok(())
     }
 
-    /// Function Call - set IP to specified VAddr
-    fn function_call(&mut self) {
-        self.call_stack.push(self.ip + 1);
+    /// Function Call - save the caller's frame and set IP to specified VAddr.
+    /// The arguments pushed for this call are handed to the callee
+    fn function_call(&mut self) -> Result<(), RuntimeError> {
         let ip: usize = Interpreter::unpack_vaddr(self.fetch_val());
+        self.enter_function(ip)
+    }
+
+    /// CallR instruction - Call the function stored in a register
+    fn function_call_reg(&mut self) -> Result<(), RuntimeError> {
+        let reg = Interpreter::unpack_register(self.fetch_val());
+        match self.regs[reg] {
+            Value::Function(ip) => self.enter_function(ip),
+            _ => Err(self.error("Only functions can be called")),
+        }
+    }
+
+    /// Save the caller's frame and continue at `ip`
+    fn enter_function(&mut self, ip: usize) -> Result<(), RuntimeError> {
+        if self.call_stack.len() >= self.max_call_depth {
+            return Err(self.error("Stack overflow, maximum call depth exceeded"));
+        }
+        self.call_stack.push(Frame {
+            ret_ip: self.ip,
+            regs: self.regs.clone(),
+            local_pool: self.local_pool.clone(),
+        });
         self.ip = ip;
+        Ok(())
     }
 
-    /// Return from function by retrieving a value from callstack
-    fn ret(&mut self) {
-        self.ip = self.call_stack.pop().unwrap();
+    /// Return from function by restoring the caller's frame from the callstack.
+    /// r0 holds the return value so it is kept
+    fn ret(&mut self) -> Result<(), RuntimeError> {
+        let frame = match self.call_stack.pop() {
+            Some(frame) => frame,
+            None => return Err(self.error("Cannot return from outside of a function")),
+        };
+        let ret_val = self.regs[0].clone();
+
+        self.ip = frame.ret_ip;
+        self.regs = frame.regs;
+        self.regs[0] = ret_val;
+        self.local_pool = frame.local_pool;
+
+        // Arguments are all pushed right before the call, so anything the
+        // callee did not consume is stale
+        self.args.clear();
+        Ok(())
     }
 
-    /// Print instruction
-    fn print(&mut self) {
+    /// Print & PrintS instructions, PrintS writes a space instead of ending
+    /// the line
+    fn print(&mut self, newline: bool) -> Result<(), RuntimeError> {
         let reg = self.fetch_val();
         let register_index = Interpreter::unpack_register(reg);
         let val = &self.regs[register_index];
 
-        match val {
-            Value::Number(v) => {
-                println!("{}", v);
-            }
-            Value::StringLiteral(v) => {
-                println!("{}", v);
+        let s = match Interpreter::format_value(val) {
+            Some(s) => s,
+            None => {
+                return Err(self.error(&format!(
+                    "Type not implemented in print: {:?}",
+                    val
+                )));
             }
-            Value::Bool(v) => {
-                println!("{}", v);
-            }
-            Value::Nil => {
-                println!("NIL");
-            }
-            _ => {
-                panic!(
-                    "Runtime Error: Type not implemented in print: {:#?} \
-                          at IP={}.",
-                    val, self.ip
-                );
-            }
-        }
+        };
+        let res = if newline {
+            writeln!(self.out, "{}", s)
+        } else {
+            write!(self.out, "{} ", s)
+        };
+        res.map_err(|e| self.error(&format!("Failed to write output: {}", e)))
     }
 
-    /// Add instruction
-    fn add(&mut self) {
+    /// MakeArray instruction - Collect the last `n` pushed arguments into an
+    /// array
+    fn make_array(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
-        let r1 = Interpreter::unpack_register(self.fetch_val());
-        let r2 = Interpreter::unpack_register(self.fetch_val());
+        let n = Interpreter::unpack_number(&Interpreter::unpack_value(self.fetch_val()))
+            as usize;
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            let result = v1 + v2;
-
-            self.register_insert(res, Value::Number(result));
-        } else if Interpreter::check_num(&self.regs[r1]) && // num & str
-            Interpreter::check_str(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: &str = Interpreter::unpack_string(&self.regs[r2]);
-            let result: String = v1.to_string() + v2;
-
-            self.register_insert(res, Value::StringLiteral(result));
-        } else if Interpreter::check_str(&self.regs[r1]) && // str & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: &str = Interpreter::unpack_string(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            let result: String = v1.to_string() + &v2.to_string();
-
-            self.register_insert(res, Value::StringLiteral(result));
-        } else if Interpreter::check_str(&self.regs[r1]) && // str & str
-            Interpreter::check_str(&self.regs[r2])
-        {
-            let v1: &str = Interpreter::unpack_string(&self.regs[r1]);
-            let v2: &str = Interpreter::unpack_string(&self.regs[r2]);
-            let result: String = v1.to_string() + &v2.to_string();
-
-            self.register_insert(res, Value::StringLiteral(result));
-        } else {
-            panic!(
-                "Runtime Error: Add operation not supported for the \
-                specified operands at IP={}.",
-                self.ip
-            );
+        if self.args.len() < n {
+            return Err(self.error("Not enough values to create array"));
         }
+        let elements = self.args.split_off(self.args.len() - n);
+
+        self.register_insert(res, Value::Array(elements));
+        Ok(())
     }
 
-    /// Sub instruction
-    fn sub(&mut self) {
+    /// CallMethod instruction - Call the method named r2 on the value in r1
+    fn call_method(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
-        let r1 = Interpreter::unpack_register(self.fetch_val());
-        let r2 = Interpreter::unpack_register(self.fetch_val());
+        let receiver = Interpreter::unpack_register(self.fetch_val());
+        let name = match Interpreter::unpack_value(self.fetch_val()) {
+            Value::StringLiteral(name) => name,
+            _ => return Err(self.error("Method name has to be a string")),
+        };
+
+        let result = native::call_method(&self.regs[receiver], &name)
+            .map_err(|e| self.error(&e))?;
+        self.register_insert(res, result);
+        Ok(())
+    }
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            let result = v1 - v2;
+    /// CallNative instruction - Call native function r1 with the arguments
+    /// on top of the arguments stack
+    fn call_native(&mut self) -> Result<(), RuntimeError> {
+        let res = Interpreter::unpack_register(self.fetch_val());
+        let id = Interpreter::unpack_number(&Interpreter::unpack_value(self.fetch_val()))
+            as usize;
 
-            self.register_insert(res, Value::Number(result));
-        } else {
-            panic!(
-                "Runtime Error: Sub operation not supported for the \
-                specified operands at IP={}.",
-                self.ip
-            );
+        let native = NATIVES.get(id).ok_or_else(|| self.error("Unknown native function"))?;
+        if self.args.len() < native.arity {
+            return Err(self.error("Not enough arguments for native function"));
         }
+        let args = self.args.split_off(self.args.len() - native.arity);
+        let result = (native.func)(&args).map_err(|e| self.error(&e))?;
+
+        self.register_insert(res, result);
+        Ok(())
     }
 
-    /// Mul instruction
-    fn mul(&mut self) {
+    /// MakeObject instruction - Collect the last r1 key/value pairs from the
+    /// arguments stack into an object, later keys overwrite earlier ones
+    fn make_object(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
-        let r1 = Interpreter::unpack_register(self.fetch_val());
-        let r2 = Interpreter::unpack_register(self.fetch_val());
+        let n = Interpreter::unpack_number(&Interpreter::unpack_value(self.fetch_val()))
+            as usize;
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            let result = v1 * v2;
+        if self.args.len() < 2 * n {
+            return Err(self.error("Not enough values to create object"));
+        }
+        let entries = self.args.split_off(self.args.len() - 2 * n);
+        let mut map = HashMap::new();
+        for pair in entries.chunks(2) {
+            let key = Interpreter::object_key(&pair[0]).map_err(|e| self.error(&e))?;
+            map.insert(key.to_string(), pair[1].clone());
+        }
 
-            self.register_insert(res, Value::Number(result));
-        } else {
-            panic!(
-                "Runtime Error: Mul operation not supported for the \
-                specified operands at IP={}.",
-                self.ip
-            );
+        self.register_insert(res, Value::Object(map));
+        Ok(())
+    }
+
+    /// Validate that `r1` holds an array or a string and `r2` a valid index
+    /// into it, and return that index
+    fn array_index(&self, r1: usize, r2: usize) -> Result<usize, RuntimeError> {
+        let (len, kind) = match &self.regs[r1] {
+            Value::Array(v) => (v.len(), "array"),
+            Value::StringLiteral(s) => (s.chars().count(), "string"),
+            _ => return Err(self.error("Cannot index into a non-array value")),
+        };
+        if !Interpreter::check_num(&self.regs[r2]) {
+            return Err(self.error("Array index must be a number"));
         }
+
+        let index = Interpreter::unpack_number(&self.regs[r2]);
+        if index < 0.0 || index.fract() != 0.0 || index as usize >= len {
+            return Err(self.error(&format!(
+                "Index {} out of bounds for {} of length {}",
+                index, kind, len
+            )));
+        }
+        Ok(index as usize)
     }
 
-    /// Div instruction
-    fn div(&mut self) {
+    /// IndexGet instruction
+    fn index_get(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
         let r1 = Interpreter::unpack_register(self.fetch_val());
         let r2 = Interpreter::unpack_register(self.fetch_val());
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            let result = v1 / v2;
-
-            self.register_insert(res, Value::Number(result));
-        } else {
-            panic!(
-                "Runtime Error: Div operation not supported for the \
-                specified operands at IP={}.",
-                self.ip
-            );
+        // Missing keys are not an error, they just hold nil
+        if let Value::Object(map) = &self.regs[r1] {
+            let key = Interpreter::object_key(&self.regs[r2]).map_err(|e| self.error(&e))?;
+            let val = map.get(key).cloned().unwrap_or(Value::Nil);
+            self.register_insert(res, val);
+            return Ok(());
         }
+
+        let index = self.array_index(r1, r2)?;
+        let val = match &self.regs[r1] {
+            Value::Array(v) => v[index].clone(),
+            Value::StringLiteral(s) => Interpreter::char_at(s, index),
+            _ => unreachable!(),
+        };
+
+        self.register_insert(res, val);
+        Ok(())
     }
 
-    /// Less instruction
-    fn cmp_less_than(&mut self) {
+    /// IndexSet instruction
+    fn index_set(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
         let r1 = Interpreter::unpack_register(self.fetch_val());
         let r2 = Interpreter::unpack_register(self.fetch_val());
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            self.flag = v1 < v2;
-
-            self.register_insert(res, Value::Bool(self.flag));
-        } else {
-            panic!(
-                "Runtime Error: Both values for 'less than' operation need \
-                   to be numbers at IP={}.",
-                self.ip
-            );
+        if Interpreter::check_str(&self.regs[res]) {
+            return Err(self.error("Strings cannot be modified"));
         }
+        if let Value::Object(_) = &self.regs[res] {
+            let key = Interpreter::object_key(&self.regs[r1])
+                .map_err(|e| self.error(&e))?
+                .to_string();
+            let val = self.regs[r2].clone();
+            if let Value::Object(map) = &mut self.regs[res] {
+                map.insert(key, val);
+            }
+            return Ok(());
+        }
+        let index = self.array_index(res, r1)?;
+        let val = self.regs[r2].clone();
+        if let Value::Array(v) = &mut self.regs[res] {
+            v[index] = val;
+        }
+        Ok(())
     }
 
-    /// LessEq instruction
-    fn cmp_less_equal(&mut self) {
+    /// Len instruction - Loads the number of elements of an array, or the
+    /// number of characters of a string, into a register
+    fn len(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
         let r1 = Interpreter::unpack_register(self.fetch_val());
-        let r2 = Interpreter::unpack_register(self.fetch_val());
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            self.flag = v1 <= v2;
+        let len = match &self.regs[r1] {
+            Value::Array(v) => v.len(),
+            Value::StringLiteral(s) => s.chars().count(),
+            // Objects only have a length if they have a key called `length`
+            Value::Object(map) => {
+                let val = map.get("length").cloned().unwrap_or(Value::Nil);
+                self.register_insert(res, val);
+                return Ok(());
+            }
+            _ => return Err(self.error("Only arrays and strings have a length")),
+        };
+        self.register_insert(res, Value::Int(len as i64));
+        Ok(())
+    }
 
-            self.register_insert(res, Value::Bool(self.flag));
-        } else {
-            panic!(
-                "Runtime Error: Both values for 'less than equal' operation \
-                    need to be numbers at IP={}.",
-                self.ip
-            );
-        }
+    /// Rand instruction - Loads a random float in [0, 1) into a register
+    fn rand(&mut self) -> Result<(), RuntimeError> {
+        let res = Interpreter::unpack_register(self.fetch_val());
+
+        // Use the top 53 bits so every result is exactly representable
+        let result = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+
+        self.register_insert(res, Value::Number(result));
+        Ok(())
     }
 
-    /// Greater instruction
-    fn cmp_greater_than(&mut self) {
+    /// RandInt instruction - Loads a random integer in [r1, r2] into a register
+    fn rand_int(&mut self) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
         let r1 = Interpreter::unpack_register(self.fetch_val());
         let r2 = Interpreter::unpack_register(self.fetch_val());
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            self.flag = v1 > v2;
-
-            self.register_insert(res, Value::Bool(self.flag));
-        } else {
-            panic!(
-                "Runtime Error: Both values for 'greater than' operation \
-                    need to be numbers at IP={}.",
-                self.ip
-            );
-        }
+        let result = Interpreter::random_int(&mut self.rng_state, &self.regs[r1], &self.regs[r2])
+            .map_err(|e| self.error(&e))?;
+        self.register_insert(res, result);
+        Ok(())
     }
 
-    /// GreaterEq instruction
-    fn cmp_greater_equal(&mut self) {
+    /// Arithmetic, comparison and bitwise instructions
+    fn binary(&mut self, instr: Instr) -> Result<(), RuntimeError> {
         let res = Interpreter::unpack_register(self.fetch_val());
         let r1 = Interpreter::unpack_register(self.fetch_val());
         let r2 = Interpreter::unpack_register(self.fetch_val());
 
-        if Interpreter::check_num(&self.regs[r1]) && // num & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            self.flag = v1 >= v2;
+        let result = Interpreter::binary_op(instr, &self.regs[r1], &self.regs[r2])
+            .map_err(|e| self.error(&e))?;
+        self.register_insert(res, result);
+        Ok(())
+    }
+}
 
-            self.register_insert(res, Value::Bool(self.flag));
-        } else {
-            panic!(
-                "Runtime Error: Both values for 'greater than equal' \
-                   operation need to be numbers at IP={}.",
-                self.ip
-            );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Codegen, lexer::tokenize, parser::Parser};
+
+    /// Output printed when compiling and running `src`
+    fn run(src: &str) -> Result<String, RuntimeError> {
+        let (stmts, errors) = Parser::new(tokenize(src)).parse();
+        assert!(errors.is_empty(), "failed to parse test program");
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out).interpret()?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn output_goes_to_writer() {
+        let (stmts, errors) = Parser::new(tokenize("console.log(1, \"a\");\nconsole.log([true]);")).parse();
+        assert!(errors.is_empty());
+
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(out, b"1 a\n[true]\n");
+    }
+
+    #[test]
+    fn print_separates_arguments_with_spaces() {
+        let src = "var x = 5;\nconsole.log(\"x =\", x);\nconsole.log();\n\
+                   console.log(1, true, \"end\");";
+        assert_eq!(run(src).unwrap(), "x = 5\n\n1 true end\n");
+    }
+
+    #[test]
+    fn numbers_print_in_a_fixed_format() {
+        assert_eq!(format_number(5.0), "5");
+        assert_eq!(format_number(3.5), "3.5");
+        assert_eq!(format_number(1e20), "100000000000000000000");
+        assert_eq!(format_number(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_number(-1e21), "-1e+21");
+        assert_eq!(format_number(1.5e-7), "1.5e-7");
+
+        let src = "console.log(10.0 / 2.0, 7.0 / 2.0, 0.1 + 0.2);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(out, b"5 3.5 0.30000000000000004\n");
+    }
+
+    #[test]
+    fn borrowed_program_runs_twice() {
+        let src = "var i = 0;\nwhile (i < 3) { console.log(i * 2, random() < 2); i = i + 1; }";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+
+        let mut first = Vec::new();
+        Interpreter::from_ref_with_writer(&program, &mut first).interpret().unwrap();
+        let mut second = Vec::new();
+        Interpreter::from_ref_with_writer(&program, &mut second).interpret().unwrap();
+        assert_eq!(first, b"0 true\n2 true\n4 true\n");
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn profile_counts_instructions() {
+        let src = "var i = 0;\nwhile (i < 10) { i = i + 1; }";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut vm = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), Vec::new());
+        vm.set_profile(true);
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.counts[u8::from(Instr::Add) as usize], 10);
+        assert_eq!(vm.counts[u8::from(Instr::CmpLT) as usize], 11);
+        let report = vm.profile_report();
+        assert!(report.lines().any(|l| l.split_whitespace().eq(["Add", "10"])));
+    }
+
+    #[test]
+    fn step_executes_one_instruction() {
+        let (stmts, _) = Parser::new(tokenize("var a = 2;\nvar b = a * 3;")).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let bytecode = program.bytecode.clone();
+        let mut vm = Interpreter::with_writer(program, Vec::new());
+
+        // Run up to and including the multiplication
+        loop {
+            let ip = vm.ip();
+            assert_eq!(vm.step().unwrap(), StepResult::Continue);
+            if bytecode[ip] == BcArr::I(Instr::Mul) {
+                let res = Interpreter::unpack_register(bytecode[ip + 1].clone());
+                assert_eq!(vm.registers()[res], Value::Int(6));
+                break;
+            }
         }
+        assert_eq!(vm.local_pool(), &[Value::Int(2)]);
+
+        // Storing `b` is the last instruction
+        assert_eq!(vm.step().unwrap(), StepResult::Halted);
+        assert_eq!(vm.local_pool(), &[Value::Int(2), Value::Int(6)]);
+        assert_eq!(vm.step().unwrap(), StepResult::Halted);
     }
 
-    /// Equals instruction
-    fn cmp_equals(&mut self) {
-        let res = Interpreter::unpack_register(self.fetch_val());
-        let r1 = Interpreter::unpack_register(self.fetch_val());
-        let r2 = Interpreter::unpack_register(self.fetch_val());
+    #[test]
+    fn type_errors_are_returned() {
+        let err = run("var a = 1 - \"a\";").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(err.msg.contains("Sub operation not supported"));
+    }
 
-        if Interpreter::check_num(&self.regs[r1]) && Interpreter::check_num(&self.regs[r2]) {
-            // num & num
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            self.flag = v1 == v2;
-
-            self.register_insert(res, Value::Bool(self.flag));
-        } else if Interpreter::check_num(&self.regs[r1]) && // num & str
-            Interpreter::check_str(&self.regs[r2])
-        {
-            let v1: f64 = Interpreter::unpack_number(&self.regs[r1]);
-            let v2: &str = Interpreter::unpack_string(&self.regs[r2]);
-            self.flag = v1.to_string() == v2;
-
-            self.register_insert(res, Value::Bool(self.flag));
-        } else if Interpreter::check_str(&self.regs[r1]) && // str & num
-            Interpreter::check_num(&self.regs[r2])
-        {
-            let v1: &str = Interpreter::unpack_string(&self.regs[r1]);
-            let v2: f64 = Interpreter::unpack_number(&self.regs[r2]);
-            self.flag = v1 == v2.to_string();
-
-            self.register_insert(res, Value::Bool(self.flag));
-        } else if Interpreter::check_str(&self.regs[r1]) && // str & str
-            Interpreter::check_str(&self.regs[r2])
-        {
-            let v1: &str = Interpreter::unpack_string(&self.regs[r1]);
-            let v2: &str = Interpreter::unpack_string(&self.regs[r2]);
-            self.flag = v1 == v2;
-
-            self.register_insert(res, Value::Bool(self.flag));
-        } else {
-            panic!(
-                "Runtime Error: Add operation not supported for the \
-                specified operands at IP={}.",
-                self.ip
-            );
+    #[test]
+    fn division_by_zero_is_a_runtime_error() {
+        for op in ["/", "%"] {
+            let src = format!("var a = 10;\nvar z = a - 10;\n\nconsole.log(a {} z);", op);
+            let err = run(&src).unwrap_err();
+            assert_eq!(err.line, 4);
+            assert!(err.msg.contains("attempted to divide by zero"), "{}", err.msg);
         }
     }
+
+    #[test]
+    fn runaway_recursion_overflows_the_stack() {
+        let err = run("function f(n) { return f(n + 1); }\nf(0);").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(err.msg.contains("Stack overflow"));
+
+        let src = "function down(n) { if (n == 0) { return 0; } return down(n - 1); }\n\
+                   console.log(down(5));";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let run_with_depth = |depth| {
+            let mut vm = Interpreter::from_ref_with_writer(&program, Vec::new());
+            vm.set_max_call_depth(depth);
+            vm.interpret()
+        };
+        assert!(run_with_depth(6).is_ok());
+        assert!(run_with_depth(5).unwrap_err().msg.contains("Stack overflow"));
+    }
+
+    #[test]
+    fn arrays_are_indexed_and_updated() {
+        let src = "var a = [10, 20, 30];\nconsole.log(a[1]);\na[2] = 5;\na[0] += 1;\n\
+                   var m = [[1, 2], [3, 4]];\nm[1][0] = \"x\";\nconsole.log(a, m, []);";
+        assert_eq!(run(src).unwrap(), "20\n[11, 20, 5] [[1, 2], [x, 4]] []\n");
+
+        let err = run("var a = [1, 2, 3];\n\nconsole.log(a[3]);").unwrap_err();
+        assert_eq!(err.msg, "Index 3 out of bounds for array of length 3");
+        assert_eq!(err.line, 3);
+        let err = run("var a = [1];\nvar i = \"0\";\na[i] = 2;").unwrap_err();
+        assert_eq!((err.msg.as_str(), err.line), ("Array index must be a number", 3));
+    }
+
+    #[test]
+    fn errors_report_the_source_line() {
+        let src = "var a = 1;\nvar s = \"str\";\n\nvar b = a +\n  2 - s;";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let err = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), Vec::new())
+            .interpret()
+            .unwrap_err();
+        assert_eq!(err.line, 5);
+        assert!(err.to_string().contains("Sub operation not supported"));
+        assert!(err.to_string().contains("on line 5"));
+    }
+
+    #[test]
+    fn integers_stay_exact() {
+        // Both literals are the same f64, so this only holds as ints
+        let src = "var a = 9007199254740993;\n\
+                   console.log(a == 9007199254740993, a == 9007199254740992);\n\
+                   console.log(a + 1, a - 2, 7 / 2, 6 / 2, 7 % 4, 2 * 1.5);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "true false\n9007199254740994 9007199254740991 3.5 3 3 3\n");
+    }
+
+    #[test]
+    fn methods_need_a_string() {
+        let src = "var n = 5;\nconsole.log(n.trim());";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let err = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), Vec::new())
+            .interpret()
+            .unwrap_err();
+        assert!(err.to_string().contains("Method 'trim' can only be called on strings"));
+        assert!(err.to_string().contains("on line 2"));
+    }
+
+    #[test]
+    fn exit_stops_the_program() {
+        let src = "function stop() { exit(3); }\nstop();\nconsole.log(1);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        let code = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(code, Some(3));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn float_literals_are_f64() {
+        let src = "console.log(0.1 + 0.2, .5 * 3);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{} 1.5\n", 0.1f64 + 0.2f64));
+    }
+
+    #[test]
+    fn budget_stops_infinite_loops() {
+        let (stmts, _) = Parser::new(tokenize("var i = 0;\nwhile (1 == 1) {}")).parse();
+        let mut vm = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), Vec::new());
+        vm.set_budget(1000);
+        let err = vm.interpret().unwrap_err();
+        assert!(err.to_string().contains("Execution budget exceeded"));
+        assert_eq!(vm.executed, 1000);
+    }
+
+    #[test]
+    fn failed_assertions_name_the_line() {
+        let src = "assert(1 + 1 == 2);\nvar a = [1];\nassert(a[0]);\n\nassert(a[0] == 2);\nconsole.log(a);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        let err = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap_err();
+        assert!(out.is_empty());
+        assert_eq!(err.line, 5);
+        assert!(err.to_string().contains("Assertion failed on line 5"));
+    }
+
+    #[test]
+    fn native_type_errors_are_runtime_errors() {
+        let (stmts, _) = Parser::new(tokenize("console.log(1);\nconsole.log(sqrt(\"x\"));")).parse();
+        let mut out = Vec::new();
+        let err = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap_err();
+        assert_eq!(out, b"1\n");
+        assert_eq!(err.line, 2);
+        assert!(err.to_string().contains("Arguments of 'sqrt' need to be numbers"));
+    }
+
+    #[test]
+    fn strings_compare_lexicographically() {
+        let src = "var a = \"apple\";\nvar b = \"banana\";\n\
+                   console.log(a < b, a > b, a <= a, b >= a, \"B\" < a);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(out, b"true false true true true\n");
+
+        let err = Interpreter::binary_op(Instr::CmpLT, &Value::Int(1),
+                                         &Value::StringLiteral("1".to_string()));
+        assert!(err.unwrap_err().contains("need to be numbers or strings"));
+    }
+
+    #[test]
+    fn booleans_count_as_numbers_in_arithmetic() {
+        let src = "var t = true;\nconsole.log(t + 1, false - t, t * 2.5, t / 2, -t);";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let mut out = Vec::new();
+        Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), &mut out)
+            .interpret()
+            .unwrap();
+        assert_eq!(out, b"2 -1 2.5 0.5 -1\n");
+
+        let err = Interpreter::binary_op(Instr::Add, &Value::Bool(true),
+                                         &Value::StringLiteral("1".to_string()));
+        assert!(err.unwrap_err().contains("Add operation not supported"));
+        let err = Interpreter::binary_op(Instr::Div, &Value::Int(1), &Value::Bool(false));
+        assert!(err.unwrap_err().contains("divide by zero"));
+    }
+
+    #[test]
+    fn recursive_calls_keep_their_locals() {
+        let src = "function fib(n) {\n\
+                     if (n < 2) { return n; }\n\
+                     return fib(n - 1) + fib(n - 2);\n\
+                   }\n\
+                   console.log(fib(10));";
+        assert_eq!(run(src).unwrap(), "55\n");
+    }
+
+    #[test]
+    fn boolean_and_nil_literals_print() {
+        let src = "var b = true;\nconsole.log(b);\nconsole.log(true, false, nil);";
+        assert_eq!(run(src).unwrap(), "true\ntrue false NIL\n");
+    }
+
+    #[test]
+    fn not_equal_inverts_equality() {
+        let src = "var a = 1;\nvar b = 2;\nvar s = \"a\";\n\
+                   console.log(a != b, a != a, s != \"a\", s != \"b\", 1 != 2, 1 != 1);";
+        assert_eq!(run(src).unwrap(), "true false false true true false\n");
+    }
+
+    #[test]
+    fn seeded_random_numbers_repeat() {
+        let src = "var i = 0;\nwhile (i < 50) {\n\
+                   console.log(random(), randomInt(-3, 3), randomInt(2.5, 4));\ni += 1;\n}";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let program = Codegen::bytecode_gen(stmts).unwrap();
+        let output = |seed| {
+            let mut out = Vec::new();
+            let mut vm = Interpreter::from_ref_with_writer(&program, &mut out);
+            vm.set_seed(seed);
+            vm.interpret().unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let first = output(42);
+        assert_eq!(first, output(42));
+        assert_ne!(first, output(43));
+        for line in first.lines() {
+            let v: Vec<f64> = line.split(' ').map(|x| x.parse().unwrap()).collect();
+            assert!((0.0..1.0).contains(&v[0]));
+            assert!((-3.0..=3.0).contains(&v[1]) && v[1].fract() == 0.0);
+            assert!(v[2] == 3.0 || v[2] == 4.0);
+        }
+    }
+
+    #[test]
+    fn random_int_handles_huge_ranges() {
+        let mut state = DEFAULT_SEED;
+        for (lo, hi) in [(i64::MIN, i64::MAX), (-1, i64::MAX), (i64::MAX, i64::MAX)] {
+            let v = Interpreter::random_int(&mut state, &Value::Int(lo), &Value::Int(hi));
+            assert!(matches!(v, Ok(Value::Int(i)) if i >= lo && i <= hi));
+        }
+        let src = "var big = 1000000000000000000000000;\nconsole.log(randomInt(-big, big));";
+        let err = run(src).unwrap_err();
+        assert!(err.msg.contains("need to fit into an integer"));
+        assert!(run("console.log(randomInt(2, 1));").unwrap_err().msg.contains("empty"));
+    }
+
+    #[test]
+    fn comparison_errors_name_the_operand_types() {
+        let src = "var s = \"a\";\nvar b = s >= 1;";
+        let (stmts, _) = Parser::new(tokenize(src)).parse();
+        let err = Interpreter::with_writer(Codegen::bytecode_gen(stmts).unwrap(), Vec::new())
+            .interpret()
+            .unwrap_err();
+        assert_eq!(err.msg, "Both values for 'greater than equal' operation need to be \
+                             numbers or strings, got string and number");
+        assert_eq!(err.line, 2);
+    }
 }
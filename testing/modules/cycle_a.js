@@ -0,0 +1,5 @@
+import "cycle_b.js";
+
+function a() {
+	return 1;
+}
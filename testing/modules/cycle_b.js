@@ -0,0 +1,5 @@
+import "cycle_a.js";
+
+function b() {
+	return 2;
+}
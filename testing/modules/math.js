@@ -0,0 +1,7 @@
+function square(x) {
+	return x * x;
+}
+
+function cube(x) {
+	return square(x) * x;
+}
@@ -0,0 +1,4 @@
+var i = 17;
+var n = 5;
+var r = i % n;
+console.log(r);
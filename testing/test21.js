@@ -0,0 +1,3 @@
+console.log(1 != 2);
+console.log("a" != "a");
+console.log(3 != "3");
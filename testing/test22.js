@@ -0,0 +1,8 @@
+console.log(random());
+console.log(random());
+
+var i = 0;
+while (i < 10) {
+	console.log(randomInt(1, 6));
+	i = i + 1;
+}
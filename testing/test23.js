@@ -0,0 +1,4 @@
+var b = true;
+console.log(b);
+console.log(false);
+console.log(nil);
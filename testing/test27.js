@@ -0,0 +1,4 @@
+import "modules/math.js";
+
+console.log(square(4));
+console.log(cube(3));
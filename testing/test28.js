@@ -0,0 +1,3 @@
+import "modules/cycle_a.js";
+
+console.log(a());
@@ -0,0 +1,2 @@
+console.log(10 / 2);
+console.log(1/0);
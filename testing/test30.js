@@ -0,0 +1,5 @@
+function f(n) {
+    return f(n + 1);
+}
+
+f(0);
@@ -0,0 +1,4 @@
+console.log("x =", 5);
+console.log();
+console.log(1, true, nil, "end");
+console.log(7);
@@ -0,0 +1,7 @@
+var m = (3 > 2) ? 10 : 20;
+console.log(m);
+
+var a = 1;
+var b = 2;
+console.log(a > b ? "a" : "b");
+console.log(a < b ? a == 1 ? "one" : "other" : "never");
@@ -0,0 +1,12 @@
+var x = 5;
+x += 3;
+console.log(x);
+
+x -= 1;
+x *= 4;
+x /= 2;
+console.log(x);
+
+var s = "foo";
+s += "bar";
+console.log(s);
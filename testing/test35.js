@@ -0,0 +1,12 @@
+var a = [10,20,30];
+console.log(a[1]);
+
+a[2] = 5;
+a[0] += 1;
+console.log(a);
+
+var m = [[1, 2], [3, 4]];
+m[1][0] = "x";
+console.log(m, m[1][0], []);
+
+console.log(a[3]);
@@ -0,0 +1,17 @@
+var i = 0;
+while (i < 100) {
+    if (i == 5) {
+        break;
+    }
+    console.log(i);
+    i = i + 1;
+}
+
+var j = 0;
+while (j < 6) {
+    j = j + 1;
+    if (j % 2 == 1) {
+        continue;
+    }
+    console.log("even", j);
+}
@@ -0,0 +1,5 @@
+var i = 0;
+while (10 > i) {
+    i = i + 1;
+}
+console.log(i);
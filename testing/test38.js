@@ -0,0 +1,8 @@
+var a = 3;
+var x = 0;
+if (a > 2) {
+    x = 1;
+} else {
+    x = 5;
+}
+console.log(x);
@@ -0,0 +1,3 @@
+var x = -3;
+var y = x % 2;
+console.log(x, y);
@@ -0,0 +1,12 @@
+var c = 3;
+var a = 2;
+if (c > 1) {
+    a = 4;
+}
+var b = 10;
+
+var sum = a + b;
+var diff = a - b;
+var prod = a * b;
+var quot = b / a;
+console.log(sum, diff, prod, quot);
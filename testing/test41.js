@@ -0,0 +1,10 @@
+var i = 0;
+var d = 0;
+while (i < 3) {
+    d = i;
+    i = i + 1;
+}
+
+var a = 10;
+console.log(a / 5);
+console.log(a / d);
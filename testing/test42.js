@@ -0,0 +1,7 @@
+var a = 3;
+var b = 4;
+var c = a + b;
+
+var d = -2;
+var e = a + d;
+console.log(c, e);
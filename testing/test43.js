@@ -0,0 +1,7 @@
+var a = 1;
+var b = 5;
+var lt = a < b;
+var le = b <= a;
+var eq = a == b;
+var ne = a != b;
+console.log(lt, le, eq, ne);
@@ -0,0 +1,6 @@
+let a = 0;
+if (1 > 2) {
+    a = 1;
+    console.log("unreachable");
+}
+console.log(a);
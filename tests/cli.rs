@@ -0,0 +1,166 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+fn run(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_baby_interp"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("failed to run the interpreter");
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn dumps_are_off_by_default() {
+    let (ok, out) = run(&["testing/test1.js"]);
+    assert!(ok);
+    assert!(!out.contains("+----------------AST-----------------+"));
+    assert!(!out.contains("+-----------Bytecode--------------+"));
+}
+
+#[test]
+fn ast_flag_only_dumps_the_ast() {
+    let (ok, out) = run(&["--ast", "testing/test1.js"]);
+    assert!(ok);
+    assert!(out.contains("+----------------AST-----------------+"));
+    assert!(!out.contains("+-----------Bytecode--------------+"));
+}
+
+#[test]
+fn ast_json_prints_one_json_array() {
+    let (ok, out) = run(&["--ast-json", "--no-run", "testing/test1.js"]);
+    assert!(ok);
+    assert!(out.starts_with("[{\"type\":"));
+    assert!(!out.contains("+----------------AST-----------------+"));
+}
+
+#[test]
+fn cfg_dot_prints_a_graph_per_function() {
+    let (ok, out) = run(&["--cfg-dot", "--no-run", "testing/test1.js"]);
+    assert!(ok);
+    assert!(out.contains("// __init\ndigraph cfg {"));
+    assert!(out.contains("shape=Mdiamond"));
+}
+
+#[test]
+fn errors_on_the_last_line_show_their_context() {
+    // No newline at the end of the file
+    let path = std::env::temp_dir().join("baby_interp_last_line_error.js");
+    std::fs::write(&path, "var a = 1;\nvar b = 2;\nconsole.log(c);").unwrap();
+    let (_, out) = run(&[path.to_str().unwrap()]);
+    assert!(out.contains("2 | var b = 2;\n3 | console.log(c);\n"));
+    assert!(out.contains("  |             "));
+    assert!(out.contains("Variable 'c' used on line 3 does not exist"));
+}
+
+#[test]
+fn exit_sets_the_process_exit_code() {
+    let path = std::env::temp_dir().join("baby_interp_exit.js");
+    std::fs::write(&path, "exit(3);\nconsole.log(1);\n").unwrap();
+    for flags in [&[][..], &["--tree-walk"][..]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_baby_interp"))
+            .args(flags)
+            .arg(&path)
+            .output()
+            .expect("failed to run the interpreter");
+        assert_eq!(output.status.code(), Some(3));
+        assert!(output.stdout.is_empty());
+    }
+}
+
+#[test]
+fn runtime_errors_fail_the_process() {
+    let path = std::env::temp_dir().join("baby_interp_runtime_error.js");
+    std::fs::write(&path, "console.log(1);\nvar a = 1 - \"a\";\nconsole.log(2);\n").unwrap();
+    for flags in [&[][..], &["--tree-walk"][..]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_baby_interp"))
+            .args(flags)
+            .arg(&path)
+            .output()
+            .expect("failed to run the interpreter");
+        assert_eq!(output.status.code(), Some(1));
+        let out = String::from_utf8_lossy(&output.stdout);
+        assert!(out.starts_with("1\n") && out.contains("line 2"), "{}", out);
+    }
+}
+
+#[test]
+fn imported_functions_can_be_called() {
+    let dir = std::env::temp_dir().join("baby_interp_import");
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/math.js"), "function square(n) { return n * n; }\n").unwrap();
+    std::fs::write(dir.join("main.js"), "import \"lib/math.js\";\nconsole.log(square(7));\n")
+        .unwrap();
+    let (ok, out) = run(&[dir.join("main.js").to_str().unwrap()]);
+    assert!(ok);
+    assert_eq!(out, "49\n");
+}
+
+#[test]
+fn import_cycles_are_errors() {
+    let dir = std::env::temp_dir().join("baby_interp_import_cycle");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.js"), "import \"b.js\";\nfunction f() { return 1; }\n").unwrap();
+    std::fs::write(dir.join("b.js"), "import \"a.js\";\nfunction g() { return 2; }\n").unwrap();
+    let (ok, out) = run(&[dir.join("a.js").to_str().unwrap()]);
+    assert!(!ok);
+    assert!(out.contains("Import cycle detected: "), "{}", out);
+    assert!(out.contains("a.js -> ") && out.contains("b.js -> "), "{}", out);
+}
+
+#[test]
+fn emitted_bytecode_runs_the_same() {
+    let path = std::env::temp_dir().join("baby_interp_emit.bcode");
+    let (ok, direct) = run(&["--emit-bytecode", path.to_str().unwrap(), "testing/test1.js"]);
+    assert!(ok);
+    let (ok, from_file) = run(&[path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert!(ok);
+    assert!(!direct.is_empty());
+    assert_eq!(direct, from_file);
+}
+
+#[test]
+fn time_reports_phases_on_stderr() {
+    let (_, plain) = run(&["testing/test1.js"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_baby_interp"))
+        .args(["--time", "testing/test1.js"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("failed to run the interpreter");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), plain);
+
+    let err = String::from_utf8_lossy(&output.stderr);
+    for phase in ["Lexing:", "Parsing:", "Codegen:", "Interpreting:", "Total:"] {
+        assert!(err.lines().any(|l| l.starts_with(phase) && l.ends_with(" ms")), "{}", err);
+    }
+}
+
+#[test]
+fn unknown_flags_fail() {
+    let (ok, _) = run(&["--bogus", "testing/test1.js"]);
+    assert!(!ok);
+}
+
+#[test]
+fn repl_keeps_variables_between_lines() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_baby_interp"))
+        .arg("--repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run the interpreter");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"var x = 2;\nconsole.log(x + 3);\n")
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("5\n"));
+}